@@ -0,0 +1,105 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+pub type SharedTrade = Arc<Mutex<LastTrade>>;
+
+const AGG_TRADES_URL: &str = "https://fapi.binance.com/fapi/v1/aggTrades";
+
+/// Last aggregated trade pushed by the `<symbol>@aggTrade` stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LastTrade {
+    pub price: f64,
+    /// Exchange trade time (ms), 0 before the first trade.
+    pub timestamp: u64,
+}
+
+/// `aggTrade` event, prices are kept as sent and parsed on update.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AggTradeEvent {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "p")]
+    pub price: String,
+    #[serde(rename = "q")]
+    pub qty: String,
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+}
+
+impl AggTradeEvent {
+    pub fn last_trade(&self) -> Result<LastTrade> {
+        Ok(LastTrade {
+            price: self.price.parse()?,
+            timestamp: self.trade_time,
+        })
+    }
+}
+
+/// Row of the REST `aggTrades` response, the stream event without the
+/// symbol.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AggTrade {
+    #[serde(rename = "p")]
+    pub price: String,
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+}
+
+impl AggTrade {
+    pub fn last_trade(&self) -> Result<LastTrade> {
+        Ok(LastTrade {
+            price: self.price.parse()?,
+            timestamp: self.trade_time,
+        })
+    }
+}
+
+/// Most recent `limit` aggregated trades of `symbol`, oldest first. The
+/// endpoint serves at most 1000.
+pub async fn fetch_recent_trades(symbol: &str, limit: usize) -> Result<Vec<LastTrade>> {
+    let url = format!(
+        "{}?symbol={}&limit={}",
+        AGG_TRADES_URL,
+        symbol,
+        limit.min(1000)
+    );
+    let trades: Vec<AggTrade> = reqwest::get(&url).await?.json().await?;
+    trades.iter().map(|x| x.last_trade()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agg_trade_event() {
+        let event: AggTradeEvent = serde_json::from_str(
+            r#"{"e":"aggTrade","E":123456789,"s":"BTCUSDT","a":5933014,"p":"0.001","q":"100","f":100,"l":105,"T":123456785,"m":true}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            event.last_trade().unwrap(),
+            LastTrade {
+                price: 0.001,
+                timestamp: 123456785
+            }
+        );
+    }
+
+    #[test]
+    fn test_rest_agg_trades() {
+        let trades: Vec<AggTrade> = serde_json::from_str(
+            r#"[{"a":26129,"p":"0.01633102","q":"4.70443515","f":27781,"l":27781,"T":1498793709153,"m":true}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            trades[0].last_trade().unwrap(),
+            LastTrade {
+                price: 0.01633102,
+                timestamp: 1498793709153
+            }
+        );
+    }
+}
@@ -4,85 +4,261 @@ use exrs::binance_f::api::*;
 use exrs::binance_f::userstream::*;
 use exrs::binance_f::websockets::*;
 use exrs::binance_f::ws_model::FuturesWebsocketEvent;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{env, fs};
 use tokio::sync::mpsc;
 
 use rainmaker::strategies::avellaneda_stoikov::AvellanedaStoikov;
+use rainmaker::user_stream::KeepAlive;
 
+// the exrs websockets are awc clients, !Send futures that need the actix
+// System (a tokio runtime with a LocalSet), so the stream tasks stay on
+// actix_rt::spawn. The strategy's own tasks are plain tokio and run on it.
 #[actix_rt::main]
 async fn main() {
     println!("main started: {:?}", chrono::prelude::Local::now());
     Builder::new().parse_default_env().init();
     let args: Vec<String> = env::args().collect();
     let file = fs::File::open(&args[1]).expect("file should open read only");
-    let config: rainmaker::config::Config =
+    let value: serde_json::Value =
         serde_json::from_reader(file).expect("file shoud be proper json");
+    // a config of several pairs runs the one named by the second argument
+    let config = match rainmaker::config::select(value, args.get(2).map(String::as_str)) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = config.validate() {
+        error!("invalid config: {}", e);
+        std::process::exit(1);
+    }
     let sub = String::from(format!(
-        "{}{}{}",
-        config.base_asset.clone().to_lowercase(),
-        config.quote_asset.clone().to_lowercase(),
+        "{}{}",
+        config.symbol().to_lowercase(),
         "@bookTicker"
     ));
     println!("trading to: {:?}", sub);
 
-    let (tx, rx): (
+    let (account_tx, account_rx): (
+        mpsc::Sender<FuturesWebsocketEvent>,
+        mpsc::Receiver<FuturesWebsocketEvent>,
+    ) = mpsc::channel(256);
+    let (book_tx, book_rx): (
         mpsc::Sender<FuturesWebsocketEvent>,
         mpsc::Receiver<FuturesWebsocketEvent>,
     ) = mpsc::channel(1024);
+    let rx = rainmaker::stream::merge(vec![account_rx, book_rx], 1024);
+
+    let mut strategy = AvellanedaStoikov::new(config.clone());
+    let health = strategy.health();
 
-    let account_keep_running = AtomicBool::new(true);
-    let account_tx = tx.clone();
+    let account_keep_running = Arc::new(AtomicBool::new(true));
+    let user_stream_down = strategy.user_stream_down();
     let c = config.clone();
     actix_rt::spawn(async move {
         let userstream: FuturesUserStream = BinanceF::new(c.api_key, None);
-        match userstream.start().await {
-            Ok(answer) => {
-                debug!("listen_key: {}", &answer.listen_key);
-                let mut account_ws: FuturesWebSockets<FuturesWebsocketEvent> =
-                    FuturesWebSockets::new(account_tx);
+        let listen_key = match userstream.start().await {
+            Ok(answer) => answer.listen_key,
+            Err(_e) => panic!("Not able to start an User Stream (Check your API_KEY"),
+        };
+        debug!("listen_key: {}", &listen_key);
+        let listen_key = Arc::new(Mutex::new(listen_key));
+
+        let keepalive_key = listen_key.clone();
+        let keep_running = account_keep_running.clone();
+        let interval = c.listen_key_keepalive_ms;
+        actix_rt::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_millis(interval)).await;
+                let key = keepalive_key.lock().unwrap().clone();
+                match rainmaker::user_stream::keep_alive(&userstream, &key).await {
+                    KeepAlive::Alive => {
+                        info!("Send keep_alive: ok");
+                        user_stream_down.store(false, Ordering::SeqCst);
+                    }
+                    KeepAlive::Renewed(key) => {
+                        *keepalive_key.lock().unwrap() = key;
+                        user_stream_down.store(false, Ordering::SeqCst);
+                        // end the event loop on the dead key, it resubscribes
+                        keep_running.store(false, Ordering::SeqCst);
+                    }
+                    KeepAlive::Failed => {
+                        warn!("user data stream lost, polling positions over REST");
+                        user_stream_down.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+        });
+
+        let mut account_ws: FuturesWebSockets<FuturesWebsocketEvent> =
+            FuturesWebSockets::new(account_tx);
+        loop {
+            let key = listen_key.lock().unwrap().clone();
+            account_keep_running.store(true, Ordering::SeqCst);
+            if let Err(e) = account_ws.connect(&key).await {
+                warn!("account_ws connect Error: {}, try again...", e);
+                continue;
+            }
+            match account_ws.event_loop(&account_keep_running).await {
+                Ok(()) => info!("account_ws resubscribing with the renewed listen key"),
+                Err(e) => warn!("account_ws event_loop Error: {}, starting reconnect...", e),
+            }
+        }
+    });
 
-                let listen_key = answer.listen_key.clone();
+    if let Some(port) = config.health_port {
+        let health = health.clone();
+        let stale_ms = config.health_stale_ms;
+        actix_rt::spawn(async move {
+            if let Err(e) = rainmaker::health::serve(port, health, stale_ms).await {
+                warn!("health endpoint Error: {}", e);
+            }
+        });
+    }
 
+    if let Some(port) = config.control_port {
+        match config.control_token.clone() {
+            Some(token) => {
+                let control = strategy.control();
+                let bind = config.control_bind.clone();
                 actix_rt::spawn(async move {
-                    loop {
-                        let res = userstream.keep_alive(&listen_key).await;
-                        println!("Send keep_alive: {:?}", res);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(300)).await;
+                    if let Err(e) = rainmaker::control::serve(&bind, port, control, token).await {
+                        warn!("control endpoint Error: {}", e);
                     }
                 });
+            }
+            None => warn!("control_port set without control_token, control API disabled"),
+        }
+    }
 
-                account_ws.connect(&answer.listen_key).await.unwrap();
+    if config.pnl_price == rainmaker::config::PnlPrice::Mark
+        || config.max_wap_mark_divergence.is_some()
+    {
+        let mark = strategy.mark();
+        let mark_sub = format!("{}@markPrice@1s", config.symbol().to_lowercase());
+        let (mark_tx, mut mark_rx) = mpsc::channel::<rainmaker::mark_price::MarkPriceEvent>(64);
 
-                while let Err(e) = account_ws.event_loop(&account_keep_running).await {
-                    warn!("account_ws event_loop Error: {}, starting reconnect...", e);
+        actix_rt::spawn(async move {
+            while let Some(event) = mark_rx.recv().await {
+                match event.mark() {
+                    Ok(price) => *mark.lock().unwrap() = price,
+                    Err(e) => warn!("mark price parse Error: {}", e),
+                }
+            }
+        });
 
-                    while let Err(e) = account_ws.connect(&answer.listen_key).await {
-                        warn!("account_ws connect Error: {}, try again...", e);
-                    }
+        actix_rt::spawn(async move {
+            let mark_keep_running = AtomicBool::new(true);
+            let mut mark_ws: FuturesWebSockets<rainmaker::mark_price::MarkPriceEvent> =
+                FuturesWebSockets::new(mark_tx);
+
+            while let Err(e) = mark_ws.connect(&mark_sub).await {
+                warn!("mark_ws connect Error: {}, try again...", e);
+            }
+
+            while let Err(e) = mark_ws.event_loop(&mark_keep_running).await {
+                warn!("mark_ws event_loop Error: {}, starting reconnect...", e);
+
+                while let Err(e) = mark_ws.connect(&mark_sub).await {
+                    warn!("mark_ws connect Error: {}, try again...", e);
                 }
             }
-            Err(_e) => panic!("Not able to start an User Stream (Check your API_KEY"),
-        }
-    });
+        });
+    }
+
+    if config.fair_value == rainmaker::config::FairValue::LastTrade {
+        let trade = strategy.trade();
+        let trade_sub = format!("{}@aggTrade", config.symbol().to_lowercase());
+        let (trade_tx, mut trade_rx) = mpsc::channel::<rainmaker::agg_trade::AggTradeEvent>(1024);
+
+        actix_rt::spawn(async move {
+            while let Some(event) = trade_rx.recv().await {
+                match event.last_trade() {
+                    Ok(last_trade) => *trade.lock().unwrap() = last_trade,
+                    Err(e) => warn!("agg trade parse Error: {}", e),
+                }
+            }
+        });
+
+        actix_rt::spawn(async move {
+            let trade_keep_running = AtomicBool::new(true);
+            let mut trade_ws: FuturesWebSockets<rainmaker::agg_trade::AggTradeEvent> =
+                FuturesWebSockets::new(trade_tx);
+
+            while let Err(e) = trade_ws.connect(&trade_sub).await {
+                warn!("trade_ws connect Error: {}, try again...", e);
+            }
+
+            while let Err(e) = trade_ws.event_loop(&trade_keep_running).await {
+                warn!("trade_ws event_loop Error: {}, starting reconnect...", e);
+
+                while let Err(e) = trade_ws.connect(&trade_sub).await {
+                    warn!("trade_ws connect Error: {}, try again...", e);
+                }
+            }
+        });
+    }
+
+    if let Some(symbol) = config.reference_symbol.clone() {
+        let secondary = strategy.secondary();
+        let reference_sub = format!("{}@bookTicker", symbol.to_lowercase());
+        let (reference_tx, mut reference_rx) = mpsc::channel::<FuturesWebsocketEvent>(1024);
+
+        actix_rt::spawn(async move {
+            while let Some(event) = reference_rx.recv().await {
+                if let FuturesWebsocketEvent::BookTicker(event) = event {
+                    *secondary.lock().unwrap() =
+                        rainmaker::reference_feed::ReferencePrice::from_book(&event);
+                }
+            }
+        });
+
+        actix_rt::spawn(async move {
+            let reference_keep_running = AtomicBool::new(true);
+            let mut reference_ws: FuturesWebSockets<FuturesWebsocketEvent> =
+                FuturesWebSockets::new(reference_tx);
+
+            while let Err(e) = reference_ws.connect(&reference_sub).await {
+                warn!("reference_ws connect Error: {}, try again...", e);
+            }
+
+            while let Err(e) = reference_ws.event_loop(&reference_keep_running).await {
+                warn!(
+                    "reference_ws event_loop Error: {}, starting reconnect...",
+                    e
+                );
+
+                while let Err(e) = reference_ws.connect(&reference_sub).await {
+                    warn!("reference_ws connect Error: {}, try again...", e);
+                }
+            }
+        });
+    }
 
     let book_keep_running = AtomicBool::new(true);
     actix_rt::spawn(async move {
-        let book_tx = tx.clone();
         let mut book_ws: FuturesWebSockets<FuturesWebsocketEvent> = FuturesWebSockets::new(book_tx);
 
         book_ws.connect(&sub).await.unwrap();
+        health.lock().unwrap().feed_connected = true;
 
         while let Err(e) = book_ws.event_loop(&book_keep_running).await {
             warn!("book_ws event_loop Error: {}, starting reconnect...", e);
+            health.lock().unwrap().feed_connected = false;
 
             while let Err(e) = book_ws.connect(&sub).await {
                 warn!("book_ws connect Error: {}, try again...", e);
             }
+            health.lock().unwrap().feed_connected = true;
         }
     });
 
-    let mut strategy = AvellanedaStoikov::new(config);
-    strategy.run_forever(rx).await;
+    if let Err(e) = strategy.run_forever(rx).await {
+        error!("strategy stopped: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,56 @@
+extern crate rainmaker;
+use env_logger::Builder;
+use std::{env, fs};
+
+use rainmaker::exchange::mock::MockExchange;
+use rainmaker::replay;
+use rainmaker::strategies::avellaneda_stoikov::AvellanedaStoikov;
+
+/// Rerun the current quoting logic over recorded ticks and print where it
+/// decides differently from the recorded quotes. Passing `replayed.csv`
+/// saves this run's quotes, e.g. to record a baseline before changing the
+/// spread logic.
+///
+/// usage: replay <config.json> <ticks.jsonl> <quotes.csv> [replayed.csv]
+#[actix_rt::main]
+async fn main() {
+    Builder::new().parse_default_env().init();
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        eprintln!(
+            "usage: {} <config.json> <ticks.jsonl> <quotes.csv> [replayed.csv]",
+            args[0]
+        );
+        std::process::exit(2);
+    }
+
+    let file = fs::File::open(&args[1]).expect("file should open read only");
+    let config: rainmaker::config::Config =
+        serde_json::from_reader(file).expect("file shoud be proper json");
+    let ticks = replay::read_ticks(&args[2]).expect("ticks should be bookTicker json lines");
+    let recorded = replay::read_quotes(&args[3]).expect("quotes should be csv");
+
+    let mut strategy = AvellanedaStoikov::with_client(config, MockExchange::new());
+    let replayed = strategy.replay(ticks).await;
+    if let Some(path) = args.get(4) {
+        replay::write_quotes(path, &replayed).expect("replayed quotes should be written");
+    }
+
+    let diffs = replay::diff(&recorded, &replayed);
+    for diff in &diffs {
+        match diff.deltas() {
+            Some((bid_price, bid_qty, ask_price, ask_qty)) => println!(
+                "{} bid_price {:+} bid_qty {:+} ask_price {:+} ask_qty {:+}",
+                diff.timestamp, bid_price, bid_qty, ask_price, ask_qty
+            ),
+            None if diff.replayed.is_none() => println!("{} only recorded quoted", diff.timestamp),
+            None => println!("{} only replay quoted", diff.timestamp),
+        }
+    }
+    println!(
+        "{} recorded, {} replayed, {} ticks differ",
+        recorded.len(),
+        replayed.len(),
+        diffs.len()
+    );
+}
@@ -0,0 +1,62 @@
+extern crate rainmaker;
+use env_logger::Builder;
+use serde_json::Value;
+use std::{env, fs};
+
+use rainmaker::replay;
+use rainmaker::sweep::{self, Metric};
+
+/// Replay recorded ticks over a grid of config values and print a table of
+/// the runs, best first. `grid.json` maps config fields to the values to
+/// try, e.g. `{"gamma": [0.1, 0.2], "sigma_multiplier": [1.0, 2.0]}`.
+///
+/// usage: sweep <config.json> <ticks.jsonl> <grid.json> [Sharpe|Pnl|Drawdown]
+fn main() {
+    Builder::new().parse_default_env().init();
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        eprintln!(
+            "usage: {} <config.json> <ticks.jsonl> <grid.json> [Sharpe|Pnl|Drawdown]",
+            args[0]
+        );
+        std::process::exit(2);
+    }
+
+    let file = fs::File::open(&args[1]).expect("file should open read only");
+    let base: Value = serde_json::from_reader(file).expect("file shoud be proper json");
+    let ticks = replay::read_ticks(&args[2]).expect("ticks should be bookTicker json lines");
+    let file = fs::File::open(&args[3]).expect("file should open read only");
+    let grid: serde_json::Map<String, Value> =
+        serde_json::from_reader(file).expect("grid should map fields to arrays");
+    let axes: Vec<(String, Vec<Value>)> = grid
+        .into_iter()
+        .map(|(name, values)| match values {
+            Value::Array(values) => (name, values),
+            value => (name, vec![value]),
+        })
+        .collect();
+    let metric: Metric = match args.get(4) {
+        Some(metric) => serde_json::from_value(Value::String(metric.clone()))
+            .expect("metric should be Sharpe, Pnl or Drawdown"),
+        None => Metric::default(),
+    };
+
+    let results = sweep::sweep(&base, &axes, &ticks, metric, true).expect("sweep should run");
+    let names: Vec<&str> = axes.iter().map(|x| x.0.as_str()).collect();
+    println!(
+        "{}\tquotes\tfills\tpnl\tmax_drawdown\tsharpe",
+        names.join("\t")
+    );
+    for result in &results {
+        let params: Vec<String> = result.params.iter().map(|x| x.1.to_string()).collect();
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            params.join("\t"),
+            result.report.quotes,
+            result.report.fills,
+            result.report.pnl,
+            result.report.max_drawdown,
+            result.report.sharpe
+        );
+    }
+}
@@ -1,4 +1,316 @@
+use crate::error::{self, Error};
+use crate::util;
+
+use anyhow::{anyhow, Result};
 use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Longest estimate_window accepted, a larger one is most likely in the
+/// wrong unit.
+pub const MAX_ESTIMATE_WINDOW_MS: u64 = 7 * 86_400_000;
+
+fn default_one() -> f64 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_listen_key_keepalive_ms() -> u64 {
+    300_000
+}
+
+fn default_position_poll_ms() -> u64 {
+    5000
+}
+
+fn default_pause_file_poll_ms() -> u64 {
+    1000
+}
+
+fn default_health_stale_ms() -> u64 {
+    5000
+}
+
+fn default_stoploss_sleep_escalation() -> f64 {
+    2.0
+}
+
+fn default_flatten_retries() -> u32 {
+    3
+}
+
+fn default_mark_stale_ms() -> u64 {
+    5000
+}
+
+fn default_margin_backoff() -> f64 {
+    0.5
+}
+
+fn default_order_failure_pause_ms() -> u64 {
+    60000
+}
+
+fn default_order_probe_distance() -> f64 {
+    0.03
+}
+
+fn default_ak_series_export_ms() -> u64 {
+    60000
+}
+
+fn default_leverage() -> f64 {
+    1.0
+}
+
+fn default_fill_rate_window_ms() -> u64 {
+    60000
+}
+
+fn default_fill_rate_step() -> f64 {
+    0.01
+}
+
+fn default_spread_multiplier_min() -> f64 {
+    0.5
+}
+
+fn default_spread_multiplier_max() -> f64 {
+    2.0
+}
+
+fn default_markout_window() -> usize {
+    20
+}
+
+fn default_stoploss_limit_ticks() -> u32 {
+    5
+}
+
+fn default_divergence_widen() -> f64 {
+    2.0
+}
+
+fn default_annualization_days() -> f64 {
+    365.0
+}
+
+fn default_sigma_baseline_alpha() -> f64 {
+    0.05
+}
+
+fn default_control_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_inventory_decay_max_bps() -> f64 {
+    20.0
+}
+
+/// Price the open position is marked at for unrealized pnl and the stops.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum PnlPrice {
+    /// Best bid for a long, best ask for a short.
+    Touch,
+    /// Exchange mark price, falling back to the touch while it is stale.
+    Mark,
+}
+
+impl Default for PnlPrice {
+    fn default() -> Self {
+        PnlPrice::Touch
+    }
+}
+
+/// Unit of the spread bounds and the stop thresholds.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Units {
+    /// Spread bounds in price, stops in `pnl_units`.
+    Absolute,
+    /// Spread bounds in bps of the fair value, stops in bps of entry.
+    Bps,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units::Absolute
+    }
+}
+
+/// Form of the AS spreads.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum AsVariant {
+    /// Reservation price skewed by the inventory q.
+    InventorySkewed,
+    /// q taken as 0, both sides the same distance from fair, e.g. for a
+    /// delta hedged book or testing.
+    Symmetric,
+}
+
+impl Default for AsVariant {
+    fn default() -> Self {
+        AsVariant::InventorySkewed
+    }
+}
+
+/// Estimator sigma is refreshed from.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum SigmaEstimator {
+    /// Garman-Klass over 30 tick chunks of the wap.
+    GarmanKlass,
+    /// Parkinson range of the rolling wap high/low.
+    Range,
+    /// Wap log returns normalized by the square root of their inter-tick
+    /// time, scaled back to the mean tick interval, so bursts of fast ticks
+    /// count for the time they took.
+    TimeNormalized,
+}
+
+impl Default for SigmaEstimator {
+    fn default() -> Self {
+        SigmaEstimator::GarmanKlass
+    }
+}
+
+/// Price the quotes are centered on.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum FairValue {
+    /// Size weighted mid of the best bid/ask.
+    Wap,
+    /// Last aggregated trade, falling back to wap before the first trade.
+    LastTrade,
+}
+
+impl Default for FairValue {
+    fn default() -> Self {
+        FairValue::Wap
+    }
+}
+
+/// Order the stoploss flattens with.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum StoplossOrderType {
+    /// Market order, sure to fill at unknown slippage. In hedge mode an IOC
+    /// limit at the touch.
+    Market,
+    /// IOC limit `stoploss_limit_ticks` through the touch, slippage capped
+    /// and a residual left to the flatten retries.
+    AggressiveLimit,
+}
+
+impl Default for StoplossOrderType {
+    fn default() -> Self {
+        StoplossOrderType::Market
+    }
+}
+
+/// What the stoploss does with the position before sleeping.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum StoplossAction {
+    /// Close the position, then sleep.
+    Flatten,
+    /// Keep the position and only stop quoting, so a wick does not
+    /// crystallize the loss. Pauses again if the loss still stands when the
+    /// sleep ends.
+    HoldAndPause,
+}
+
+impl Default for StoplossAction {
+    fn default() -> Self {
+        StoplossAction::Flatten
+    }
+}
+
+/// Response to the wap drifting from the mark price.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum DivergenceAction {
+    /// Multiply both spreads by `divergence_widen`.
+    Widen,
+    /// Cancel the quotes and skip the cycle.
+    Pause,
+}
+
+impl Default for DivergenceAction {
+    fn default() -> Self {
+        DivergenceAction::Widen
+    }
+}
+
+/// What happens to a quote landing further than `max_touch_ticks` behind
+/// its touch.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum TouchDistanceAction {
+    /// Move it up to the max distance.
+    Clamp,
+    /// Leave the side out of the cycle.
+    Skip,
+}
+
+impl Default for TouchDistanceAction {
+    fn default() -> Self {
+        TouchDistanceAction::Clamp
+    }
+}
+
+/// Scheduled span (UTC ms, inclusive) with no quoting, e.g. maintenance.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PauseWindow {
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+/// How the futures account holds positions on the exchange side.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum PositionMode {
+    /// One-way mode, a single `BOTH` position per symbol.
+    OneWay,
+    /// Hedge mode, separate `LONG` and `SHORT` legs per symbol.
+    Hedge,
+}
+
+impl Default for PositionMode {
+    fn default() -> Self {
+        PositionMode::OneWay
+    }
+}
+
+/// Unit of unrealized pnl, and so of the stoploss, stopprofit and
+/// trailing_stop thresholds it is compared with.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum PnlUnits {
+    /// Price return against entry, 0.01 is 1%.
+    Ratio,
+    /// Price return against entry in basis points, 100 is 1%.
+    Bps,
+    /// Pnl of the whole position in quote asset, e.g. USDT, in the margin
+    /// asset for an inverse contract.
+    QuoteCurrency,
+}
+
+impl Default for PnlUnits {
+    fn default() -> Self {
+        PnlUnits::Ratio
+    }
+}
+
+/// How a futures contract is margined and settled.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum ContractType {
+    /// Quantity in base asset, margin and pnl in quote asset (USDT-M).
+    Linear,
+    /// Quantity in contracts worth `contract_size` quote asset each, margin
+    /// and pnl in base asset (COIN-M).
+    Inverse,
+}
+
+impl Default for ContractType {
+    fn default() -> Self {
+        ContractType::Linear
+    }
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -6,19 +318,808 @@ pub struct Config {
     pub secret_key: Option<String>,
     pub base_asset: String,
     pub quote_asset: String,
+    /// Exchange symbol, for pairs that are not base_asset + quote_asset
+    /// (e.g. 1000SHIBUSDT). Used for orders, positions and streams.
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub contract_type: ContractType,
+    /// Quote asset value of one inverse contract, e.g. 100 for BTCUSD_PERP.
+    #[serde(default = "default_one")]
+    pub contract_size: f64,
+    /// Base asset qty per quote, leave out when quoting by order_notional.
+    #[serde(default)]
     pub order_qty: f64,
+    /// May be left out with `infer_tick_size`, checked against the inferred
+    /// tick otherwise.
+    #[serde(default)]
     pub tick_size: f64,
+    /// Infer tick_size from the price grid of the first this many book
+    /// tickers, when the exchange filters are not fetched. Off when unset.
+    #[serde(default)]
+    pub infer_tick_size: Option<usize>,
     pub n_spreads: usize,
+    /// Intensity fit window in ms, the unit of transaction_time.
     pub estimate_window: u64,
     pub period: u64,
     pub sigma_tick_period: usize,
     pub gamma: f64,
     pub sigma_multiplier: f64,
+    #[serde(default)]
+    pub as_variant: AsVariant,
+    /// Inventory enters q_fix in whole buckets of this many order_qty, so
+    /// the quotes only shift when it crosses a bucket boundary. Off when
+    /// unset.
+    #[serde(default)]
+    pub inventory_bucket: Option<f64>,
+    /// Raise gamma to `gamma * (1 + beta * |q| / q_max)` as inventory fills
+    /// up, for a stronger pull back near the limit. Off when unset.
+    #[serde(default)]
+    pub gamma_inventory_beta: Option<f64>,
+    /// Cap on the inventory scaled gamma, `gamma * (1 + beta)` when unset.
+    #[serde(default)]
+    pub max_gamma: Option<f64>,
+    /// Largest change (price) of each AS offset from one spread update to
+    /// the next, a jump is walked toward over several cycles. Off when unset.
+    #[serde(default)]
+    pub max_spread_step: Option<f64>,
     pub stoploss: f64,
     pub stoploss_sleep: u64,
+    /// Window (ms) after the position opens or grows in which the stoploss
+    /// is held off, so the noise right after a fill can't stop it out. Off
+    /// when unset.
+    #[serde(default)]
+    pub stoploss_grace_ms: Option<u64>,
+    /// Toggles for the stoploss and stopprofit branches, e.g. a stoploss
+    /// alone to let winners run.
+    #[serde(default = "default_true")]
+    pub enable_stoploss: bool,
+    #[serde(default = "default_true")]
+    pub enable_stopprofit: bool,
+    /// A stoploss firing within this many ms of the previous one is a
+    /// repeat: it sleeps at least this long, and `stoploss_sleep` grows by
+    /// `stoploss_sleep_escalation` per repeat. Off when unset.
+    #[serde(default)]
+    pub stoploss_rearm_ms: Option<u64>,
+    #[serde(default = "default_stoploss_sleep_escalation")]
+    pub stoploss_sleep_escalation: f64,
+    /// Cap (ms) on the escalated stoploss sleep.
+    #[serde(default)]
+    pub max_stoploss_sleep: Option<u64>,
+    /// Margin (pnl units) the stoploss and stopprofit have to be passed by
+    /// to fire. Once fired each re-arms only after pnl is back inside its
+    /// threshold by the same margin, except a HoldAndPause stoploss which
+    /// closes nothing and may fire again after its sleep. Off when unset.
+    #[serde(default)]
+    pub stop_hysteresis: Option<f64>,
     pub stopprofit: f64,
     pub trailing_stop: f64,
     pub q_max: f64,
+    /// Set q_max to this fraction of the wallet balance over the notional of
+    /// one order, recomputed as either moves. Off when unset.
+    #[serde(default)]
+    pub q_max_equity_fraction: Option<f64>,
+    /// Bounds on the equity derived q_max.
+    #[serde(default)]
+    pub min_q_max: Option<f64>,
+    #[serde(default)]
+    pub max_q_max: Option<f64>,
+    /// Cap (quote asset) on |inventory| * mark, beyond which the side adding
+    /// to inventory is not quoted. Off when unset.
+    #[serde(default)]
+    pub max_notional_exposure: Option<f64>,
+    /// Decimals order quantities are floored to, from step_size when unset.
+    #[serde(default)]
+    pub qty_precision: Option<u32>,
+    /// Base asset qty of the bid and the ask, order_qty when unset.
+    #[serde(default)]
+    pub bid_qty: Option<f64>,
+    #[serde(default)]
+    pub ask_qty: Option<f64>,
+    /// Lean the sizes against inventory: the bid is scaled by
+    /// 1 - size_skew * q / q_max and the ask by 1 + size_skew * q / q_max.
+    #[serde(default)]
+    pub size_skew: f64,
+    /// Quote asset notional per quote, converted to base qty at the fair
+    /// value every tick. Exclusive with order_qty.
+    #[serde(default)]
+    pub order_notional: Option<f64>,
+    /// Spread buckets with fills each side needs for a new A/k fit to be
+    /// adopted, the last good fit is kept otherwise.
+    #[serde(default)]
+    pub min_nonzero_buckets: usize,
+    /// Quote refresh cadence (ms), `period` when unset.
+    #[serde(default)]
+    pub quote_period: Option<u64>,
+    /// Random extra delay (ms, at most this) before each quote refresh, so
+    /// many instances do not cancel/replace in lockstep. Like the period it
+    /// counts in whole seconds. Off when unset.
+    #[serde(default)]
+    pub quote_jitter_ms: Option<u64>,
+    /// Seed of the quote jitter, the start time when unset.
+    #[serde(default)]
+    pub jitter_seed: Option<u64>,
+    /// Draw each cycle's quote size uniformly from `[min, max]`, floored to
+    /// step_size, instead of quoting order_qty. Inventory is still counted
+    /// in order_qty. Off when unset.
+    #[serde(default)]
+    pub order_qty_range: Option<[f64; 2]>,
+    /// Seed of the size draws, the start time when unset.
+    #[serde(default)]
+    pub qty_seed: Option<u64>,
+    /// Intensity sampling step and A/k refit cadence (ms), `period` when
+    /// unset.
+    #[serde(default)]
+    pub estimate_period: Option<u64>,
+    /// Fit A/k on the blocking pool rather than inside on_tick, the fit
+    /// applying on the first tick after it finishes. A refit due while one
+    /// is running is skipped.
+    #[serde(default)]
+    pub background_estimate: bool,
+    /// Sigma recompute cadence (ms) over the last `sigma_tick_period` ticks,
+    /// `period` when unset.
+    #[serde(default)]
+    pub sigma_period: Option<u64>,
+    /// Days per year the logged annualized sigma is scaled to.
+    #[serde(default = "default_annualization_days")]
+    pub annualization_days: f64,
+    #[serde(default)]
+    pub position_mode: PositionMode,
+    #[serde(default)]
+    pub step_size: f64,
+    #[serde(default)]
+    pub min_notional: f64,
+    /// Keep tick_size/step_size/min_notional from the config instead of
+    /// resolving them from exchangeInfo at startup.
+    #[serde(default)]
+    pub pin_filters: bool,
+    /// Seed the intensity estimator on start with up to this many recent
+    /// aggregated trades (1000 at most) fetched over REST, so a restart
+    /// need not wait out estimate_window. Cold start if the fetch fails,
+    /// off when unset.
+    #[serde(default)]
+    pub warm_start_trades: Option<usize>,
+    /// Offset of the daily session boundary from UTC midnight, in ms.
+    #[serde(default)]
+    pub session_reset_utc_ms: u64,
+    /// Time of day (ms from UTC midnight) the quotes are cancelled and the
+    /// inventory flattened, once a day. Off when unset.
+    #[serde(default)]
+    pub flatten_at_utc_ms: Option<u64>,
+    #[serde(default)]
+    pub flatten_order_type: StoplossOrderType,
+    /// Stop after running this long (ms), cancelling the quotes and, with
+    /// `max_runtime_flatten`, closing the inventory. Off when unset.
+    #[serde(default)]
+    pub max_runtime_ms: Option<u64>,
+    #[serde(default)]
+    pub max_runtime_flatten: bool,
+    /// Stop quoting after the scheduled flatten until the next session.
+    #[serde(default)]
+    pub pause_after_flatten: bool,
+    /// Finite AS horizon T (ms) counted from the session start, at most a
+    /// day. The inventory and volatility part of the spread scales with
+    /// (T - t) / T, so quotes tighten toward T, and quoting pauses past T
+    /// until the next session. Infinite horizon when unset.
+    #[serde(default)]
+    pub horizon_ms: Option<u64>,
+    /// Stop quoting for the rest of the session once realized pnl falls
+    /// below minus this amount of quote asset.
+    #[serde(default)]
+    pub max_session_loss: Option<f64>,
+    /// Fraction of q_max beyond which only the inventory reducing side is
+    /// quoted.
+    #[serde(default)]
+    pub soft_inventory: Option<f64>,
+    /// Multiplier on the reducing side offset beyond soft_inventory, below 1
+    /// quotes it tighter.
+    #[serde(default = "default_one")]
+    pub soft_inventory_tightening: f64,
+    /// Shift of both quotes per unit of wap momentum, as a fraction of wap.
+    /// Positive follows the trend, negative fades it, 0 disables.
+    #[serde(default)]
+    pub momentum_skew: f64,
+    /// Shift of both quotes toward flat, as a fraction of fair value, per
+    /// order_qty of inventory per minute it has been held. 0 disables.
+    #[serde(default)]
+    pub inventory_decay: f64,
+    /// Cap (bps of fair value) on the inventory_decay shift, however long the
+    /// inventory has been held.
+    #[serde(default = "default_inventory_decay_max_bps")]
+    pub inventory_decay_max_bps: f64,
+    /// Size tilt per unit of wap momentum, positive grows the side quoting
+    /// with the trend and shrinks the other one.
+    #[serde(default)]
+    pub momentum_size: f64,
+    /// Minimum edge (bps) of both quotes over fair value net of the maker
+    /// fee, applied after the AS spread. Off at 0.
+    #[serde(default)]
+    pub min_edge_bps: f64,
+    /// Furthest (ticks) a quote may sit behind its touch, the bid below the
+    /// best bid and the ask above the best ask. Off when unset.
+    #[serde(default)]
+    pub max_touch_ticks: Option<u32>,
+    #[serde(default)]
+    pub touch_distance_action: TouchDistanceAction,
+    /// Maker fee of the account's tier in bps, negative for a rebate.
+    #[serde(default)]
+    pub maker_fee_bps: f64,
+    /// Port of the health/readiness endpoint, disabled when unset.
+    #[serde(default)]
+    pub health_port: Option<u16>,
+    /// Port of the JSON control API, disabled when unset or without
+    /// control_token.
+    #[serde(default)]
+    pub control_port: Option<u16>,
+    /// Bearer token required by every control API request.
+    #[serde(default)]
+    pub control_token: Option<String>,
+    /// Address the control API listens on, loopback unless opened up.
+    #[serde(default = "default_control_bind")]
+    pub control_bind: String,
+    /// Time calculate_spread and the A/k fit into histograms published on
+    /// the health report.
+    #[serde(default)]
+    pub time_calls: bool,
+    /// Horizon (ms) of the per-side fill markout metric. Off when unset.
+    #[serde(default)]
+    pub markout_horizon_ms: Option<u64>,
+    /// Markouts averaged per side.
+    #[serde(default = "default_markout_window")]
+    pub markout_window: usize,
+    /// Spread multiplier of a side whose full markout window averages a
+    /// loss. Off when unset.
+    #[serde(default)]
+    pub markout_widen: Option<f64>,
+    /// Fills per `fill_rate_window_ms` the spread multiplier steers to: each
+    /// quoting cycle it grows by `fill_rate_step` while fills come faster and
+    /// shrinks while slower, within `spread_multiplier_min`/`_max`. Off when
+    /// unset.
+    #[serde(default)]
+    pub fill_rate_target: Option<f64>,
+    #[serde(default = "default_fill_rate_window_ms")]
+    pub fill_rate_window_ms: u64,
+    #[serde(default = "default_fill_rate_step")]
+    pub fill_rate_step: f64,
+    #[serde(default = "default_spread_multiplier_min")]
+    pub spread_multiplier_min: f64,
+    #[serde(default = "default_spread_multiplier_max")]
+    pub spread_multiplier_max: f64,
+    /// Warn when the share of time both sides rest on the book falls below
+    /// this fraction.
+    #[serde(default)]
+    pub min_quote_uptime: Option<f64>,
+    /// Feed age (ms) beyond which the strategy is reported not ready.
+    #[serde(default = "default_health_stale_ms")]
+    pub health_stale_ms: u64,
+    #[serde(default)]
+    pub pnl_units: PnlUnits,
+    /// Unit of min_spread/max_spread and the stop thresholds, converted
+    /// where they are used. The AS offsets stay in price, A/k being fit on
+    /// the tick grid.
+    #[serde(default)]
+    pub units: Units,
+    /// Bounds on each side's distance from the fair value, in `units`. Off
+    /// when unset.
+    #[serde(default)]
+    pub min_spread: Option<f64>,
+    #[serde(default)]
+    pub max_spread: Option<f64>,
+    /// Log each quoting cycle as one json decision record.
+    #[serde(default)]
+    pub log_decisions: bool,
+    #[serde(default)]
+    pub stoploss_action: StoplossAction,
+    #[serde(default)]
+    pub stoploss_order_type: StoplossOrderType,
+    #[serde(default = "default_stoploss_limit_ticks")]
+    pub stoploss_limit_ticks: u32,
+    /// Closing orders re-sent for a residual a stoploss/stopprofit flatten
+    /// left behind.
+    #[serde(default = "default_flatten_retries")]
+    pub flatten_retries: u32,
+    /// Stopprofit exits of a position larger than this are split into IOC
+    /// limits of this size, each level `stopprofit_ladder_step` deeper into
+    /// the book. Smaller positions are flattened at market.
+    #[serde(default)]
+    pub stopprofit_ladder_qty: Option<f64>,
+    /// Price step between ladder levels, tick_size when 0.
+    #[serde(default)]
+    pub stopprofit_ladder_step: f64,
+    /// Refresh the position over REST before a stopprofit exit, so the exit
+    /// is sized to it rather than to a stale account update, and skipped
+    /// once the position is already flat.
+    #[serde(default)]
+    pub refresh_position_on_exit: bool,
+    /// File the session accounting is saved to and restored from on start.
+    #[serde(default)]
+    pub state_file: Option<String>,
+    /// Append every order and cancel call and its outcome to this JSON lines
+    /// file, see `exchange::journal`.
+    #[serde(default)]
+    pub journal_file: Option<String>,
+    /// Append every accepted A/k fit to this csv, flushed every
+    /// `ak_series_export_ms` and on shutdown.
+    #[serde(default)]
+    pub ak_series_file: Option<String>,
+    #[serde(default = "default_ak_series_export_ms")]
+    pub ak_series_export_ms: u64,
+    /// Refuse to start over open orders or a position on the pair the bot
+    /// didn't create, e.g. manual trades.
+    #[serde(default)]
+    pub startup_check: bool,
+    /// Take over what `startup_check` finds instead: the orders are tracked
+    /// and the position managed as the bot's own.
+    #[serde(default)]
+    pub adopt_existing: bool,
+    /// Before quoting, post a minimum size bid `order_probe_distance` below
+    /// the first book tick and cancel it, refusing to start if either is
+    /// rejected. Keep the distance inside the PERCENT_PRICE band.
+    #[serde(default)]
+    pub order_probe: bool,
+    #[serde(default = "default_order_probe_distance")]
+    pub order_probe_distance: f64,
+    /// Prefix of the client order ids, telling this instance's orders apart
+    /// on a shared account. Up to 12 of `[A-Za-z0-9._:/]`.
+    #[serde(default)]
+    pub strategy_tag: Option<String>,
+    #[serde(default)]
+    pub pnl_price: PnlPrice,
+    /// Mark age (ms) beyond which pnl falls back to the touch.
+    #[serde(default = "default_mark_stale_ms")]
+    pub mark_stale_ms: u64,
+    #[serde(default)]
+    pub fair_value: FairValue,
+    /// Symbol of a secondary futures book whose mid is blended into the
+    /// quoting anchor by `reference_weight`. Orders still go to `symbol`.
+    #[serde(default)]
+    pub reference_symbol: Option<String>,
+    #[serde(default)]
+    pub reference_weight: f64,
+    /// Age (ms) beyond which the secondary mid is left out of the anchor.
+    #[serde(default = "default_mark_stale_ms")]
+    pub reference_stale_ms: u64,
+    /// Quote around an EMA of the wap reference over this many ticks rather
+    /// than the last one. Off when unset.
+    #[serde(default)]
+    pub anchor_ema_span: Option<usize>,
+    /// Move (bps) of the reference away from the EMA anchor that snaps the
+    /// anchor to it, so large moves are quoted at once.
+    #[serde(default)]
+    pub anchor_ema_snap_bps: Option<f64>,
+    /// Weight of the wap in the quoting anchor, the rest on the arithmetic
+    /// mid, which is steadier on lopsided books. 1 quotes around the wap.
+    #[serde(default = "default_one")]
+    pub reference_blend: f64,
+    /// Drop book tickers whose update id is not above the last one taken,
+    /// duplicates and reordering from reconnects or merged streams.
+    #[serde(default)]
+    pub dedup_update_id: bool,
+    #[serde(default)]
+    pub sigma_estimator: SigmaEstimator,
+    /// Per tick sigma never goes below this, so the spread can't collapse
+    /// inside the tick in a dead market. Off when unset.
+    #[serde(default)]
+    pub sigma_floor: Option<f64>,
+    /// Weight in [0, 1] of the slow sigma baseline blended into every
+    /// refresh, 0 quotes the raw estimate.
+    #[serde(default)]
+    pub sigma_dampening: f64,
+    /// EMA weight of a fresh estimate in the slow sigma baseline.
+    #[serde(default = "default_sigma_baseline_alpha")]
+    pub sigma_baseline_alpha: f64,
+    /// |wap / mark - 1| beyond which `divergence_action` applies, needs the
+    /// mark price stream. Off when unset.
+    #[serde(default)]
+    pub max_wap_mark_divergence: Option<f64>,
+    #[serde(default)]
+    pub divergence_action: DivergenceAction,
+    #[serde(default = "default_divergence_widen")]
+    pub divergence_widen: f64,
+    /// Cadence (ms) of the open order reconciliation against REST. Off when
+    /// unset.
+    #[serde(default)]
+    pub reconcile_period: Option<u64>,
+    /// listenKey keepalive interval, Binance expires it after 60 minutes.
+    #[serde(default = "default_listen_key_keepalive_ms")]
+    pub listen_key_keepalive_ms: u64,
+    /// REST position poll cadence while the user data stream is down.
+    #[serde(default = "default_position_poll_ms")]
+    pub position_poll_ms: u64,
+    /// Force a cancel_all before quoting once more of our orders than this
+    /// are known open, e.g. after cancels failed.
+    #[serde(default)]
+    pub max_open_orders: Option<usize>,
+    /// Cancel only the quote sides a cycle reprices, by order id, keeping
+    /// the queue priority of unchanged ones. cancel_all still runs when
+    /// nothing is known resting or a cancel fails.
+    #[serde(default)]
+    pub targeted_cancels: bool,
+    /// With targeted_cancels, leave a quote resting at least this many ms
+    /// before repricing it. Stoploss and other cancel_all paths still pull
+    /// it. Off when unset.
+    #[serde(default)]
+    pub min_time_in_book_ms: Option<u64>,
+    /// order_qty is multiplied by this after a margin insufficient reject.
+    #[serde(default = "default_margin_backoff")]
+    pub margin_backoff: f64,
+    /// Consecutive rejected order submissions after which quoting pauses for
+    /// `order_failure_pause_ms`, then retries. Off when unset.
+    #[serde(default)]
+    pub max_order_failures: Option<u64>,
+    #[serde(default = "default_order_failure_pause_ms")]
+    pub order_failure_pause_ms: u64,
+    /// Floor of the backed off order_qty, never below step_size.
+    #[serde(default)]
+    pub min_order_qty: f64,
+    /// Only quote a side whose spread beats its expected adverse selection
+    /// by this many bps of fair value. Off when unset.
+    #[serde(default)]
+    pub adverse_margin_bps: Option<f64>,
+    /// After a fill, leave its side unquoted for this many ms. Off when
+    /// unset.
+    #[serde(default)]
+    pub post_fill_cooldown_ms: Option<u64>,
+    /// Fraction of q_max a single fill may move inventory by, each side is
+    /// also capped at what is left before q_max. Off when unset.
+    #[serde(default)]
+    pub max_cycle_inventory: Option<f64>,
+    /// Fraction of the wallet balance kept free of margin: the side adding
+    /// to inventory is cut to what the rest still covers at `leverage`. Off
+    /// when unset.
+    #[serde(default)]
+    pub margin_buffer: Option<f64>,
+    /// Leverage set on the symbol, an order's margin is its notional over it.
+    #[serde(default = "default_leverage")]
+    pub leverage: f64,
+    #[serde(default)]
+    pub pause_windows: Vec<PauseWindow>,
+    /// Also pause this many ms either side of each 8h funding time.
+    #[serde(default)]
+    pub funding_pause_ms: Option<u64>,
+    /// Cancel and pause while this file exists, empty or containing
+    /// "PAUSE", resume once it is removed.
+    #[serde(default)]
+    pub pause_file: Option<String>,
+    #[serde(default = "default_pause_file_poll_ms")]
+    pub pause_file_poll_ms: u64,
+    /// Cancel and pause once more than this many events queue behind the
+    /// one being processed, resume when caught up. Off when unset.
+    #[serde(default)]
+    pub max_feed_backlog: Option<usize>,
+}
+
+impl Config {
+    /// Exchange symbol, `symbol` or else base_asset + quote_asset.
+    pub fn symbol(&self) -> String {
+        match &self.symbol {
+            Some(symbol) => symbol.clone(),
+            None => format!("{}{}", self.base_asset, self.quote_asset),
+        }
+    }
+
+    /// Asset the margin, fees and pnl are held in.
+    pub fn margin_asset(&self) -> &str {
+        match self.contract_type {
+            ContractType::Linear => &self.quote_asset,
+            ContractType::Inverse => &self.base_asset,
+        }
+    }
+
+    /// Quote asset value of `qty` at `price`.
+    pub fn quote_value(&self, qty: f64, price: f64) -> f64 {
+        match self.contract_type {
+            ContractType::Linear => qty * price,
+            ContractType::Inverse => qty * self.contract_size,
+        }
+    }
+
+    /// Margin asset value of `qty` at `price`.
+    pub fn margin_value(&self, qty: f64, price: f64) -> f64 {
+        match self.contract_type {
+            ContractType::Linear => qty * price,
+            ContractType::Inverse => qty * self.contract_size / price,
+        }
+    }
+
+    /// Unit unrealized pnl is measured in, bps whenever `units` is.
+    pub fn stop_units(&self) -> PnlUnits {
+        match self.units {
+            Units::Absolute => self.pnl_units,
+            Units::Bps => PnlUnits::Bps,
+        }
+    }
+
+    pub fn validate(&self) -> error::Result<()> {
+        if self.units == Units::Bps && self.pnl_units == PnlUnits::QuoteCurrency {
+            return Err(Error::Config(
+                "units Bps takes the stop thresholds in bps, conflicts with pnl_units QuoteCurrency"
+                    .to_string(),
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_spread, self.max_spread) {
+            if min > max {
+                return Err(Error::Config(format!(
+                    "min_spread {} above max_spread {}",
+                    min, max
+                )));
+            }
+        }
+        match self.order_notional {
+            Some(_) if self.order_qty != 0f64 => {
+                return Err(Error::Config(
+                    "order_qty and order_notional are exclusive".to_string(),
+                ));
+            }
+            Some(notional) if !(notional.is_finite() && notional > 0f64) => {
+                return Err(Error::Config(format!(
+                    "order_notional must be positive, got {}",
+                    notional
+                )));
+            }
+            Some(_) => {}
+            None if !(self.order_qty.is_finite() && self.order_qty > 0f64) => {
+                return Err(Error::Config(format!(
+                    "order_qty must be positive, got {}",
+                    self.order_qty
+                )));
+            }
+            None => {}
+        }
+
+        if self.inventory_decay_max_bps.is_nan() || self.inventory_decay_max_bps < 0f64 {
+            return Err(Error::Config(format!(
+                "inventory_decay_max_bps must not be negative, got {}",
+                self.inventory_decay_max_bps
+            )));
+        }
+
+        if self.infer_tick_size.is_none() && !(self.tick_size > 0f64) {
+            return Err(Error::Config(format!(
+                "tick_size must be positive, got {}",
+                self.tick_size
+            )));
+        }
+        for (name, value) in [("bid_qty", self.bid_qty), ("ask_qty", self.ask_qty)].iter() {
+            match value {
+                Some(qty) if !(qty.is_finite() && *qty > 0f64) => {
+                    return Err(Error::Config(format!(
+                        "{} must be positive, got {}",
+                        name, qty
+                    )));
+                }
+                _ => {}
+            }
+        }
+        let positive = [
+            ("q_max", self.q_max),
+            ("gamma", self.gamma),
+            ("sigma_multiplier", self.sigma_multiplier),
+        ];
+        for (name, value) in positive.iter() {
+            if !(value.is_finite() && *value > 0f64) {
+                return Err(Error::Config(format!(
+                    "{} must be positive, got {}",
+                    name, value
+                )));
+            }
+        }
+        if !(0f64 < self.order_probe_distance && self.order_probe_distance < 1f64) {
+            return Err(Error::Config(format!(
+                "order_probe_distance must be in (0, 1), got {}",
+                self.order_probe_distance
+            )));
+        }
+        if let Some(tag) = &self.strategy_tag {
+            let allowed = |c: char| c.is_ascii_alphanumeric() || "._:/".contains(c);
+            if tag.is_empty() || tag.len() > 12 || !tag.chars().all(allowed) {
+                return Err(Error::Config(format!(
+                    "strategy_tag must be 1 to 12 of [A-Za-z0-9._:/], got {:?}",
+                    tag
+                )));
+            }
+        }
+        if self.min_time_in_book_ms.is_some() && !self.targeted_cancels {
+            return Err(Error::Config(
+                "min_time_in_book_ms needs targeted_cancels".to_string(),
+            ));
+        }
+        if let Some([min, max]) = self.order_qty_range {
+            if !(min > 0f64 && min <= max && max.is_finite()) {
+                return Err(Error::Config(format!(
+                    "order_qty_range must be 0 < min <= max, got [{}, {}]",
+                    min, max
+                )));
+            }
+        }
+        if let Some(buffer) = self.margin_buffer {
+            if !(0f64..1f64).contains(&buffer) {
+                return Err(Error::Config(format!(
+                    "margin_buffer must be in [0, 1), got {}",
+                    buffer
+                )));
+            }
+        }
+        if !(self.leverage.is_finite() && self.leverage > 0f64) {
+            return Err(Error::Config(format!(
+                "leverage must be positive, got {}",
+                self.leverage
+            )));
+        }
+        if let Some(target) = self.fill_rate_target {
+            if !(target.is_finite() && target > 0f64) {
+                return Err(Error::Config(format!(
+                    "fill_rate_target must be positive, got {}",
+                    target
+                )));
+            }
+            if self.fill_rate_window_ms == 0 || !(self.fill_rate_step > 0f64) {
+                return Err(Error::Config(
+                    "fill_rate_window_ms and fill_rate_step must be positive".to_string(),
+                ));
+            }
+            if !(self.spread_multiplier_min > 0f64
+                && self.spread_multiplier_min <= 1f64
+                && self.spread_multiplier_max >= 1f64)
+            {
+                return Err(Error::Config(format!(
+                    "spread multiplier bounds must hold 1, got [{}, {}]",
+                    self.spread_multiplier_min, self.spread_multiplier_max
+                )));
+            }
+        }
+        if !(self.contract_size.is_finite() && self.contract_size > 0f64) {
+            return Err(Error::Config(format!(
+                "contract_size must be positive, got {}",
+                self.contract_size
+            )));
+        }
+        if self.max_runtime_ms == Some(0) {
+            return Err(Error::Config("max_runtime_ms must be positive".to_string()));
+        }
+        if self.max_order_failures == Some(0) {
+            return Err(Error::Config(
+                "max_order_failures must be positive".to_string(),
+            ));
+        }
+        if let Some(bucket) = self.inventory_bucket {
+            if !(bucket.is_finite() && bucket > 0f64) {
+                return Err(Error::Config(format!(
+                    "inventory_bucket must be positive, got {}",
+                    bucket
+                )));
+            }
+        }
+        if let Some(beta) = self.gamma_inventory_beta {
+            if !(beta.is_finite() && beta >= 0f64) {
+                return Err(Error::Config(format!(
+                    "gamma_inventory_beta must be non-negative, got {}",
+                    beta
+                )));
+            }
+        }
+        if let Some(max) = self.max_gamma {
+            if !(max.is_finite() && max > 0f64) {
+                return Err(Error::Config(format!(
+                    "max_gamma must be positive, got {}",
+                    max
+                )));
+            }
+        }
+        if self.period == 0 {
+            return Err(Error::Config("period must be positive".to_string()));
+        }
+        if self.sigma_tick_period < 2 {
+            return Err(Error::Config(format!(
+                "sigma_tick_period must be at least 2, got {}",
+                self.sigma_tick_period
+            )));
+        }
+        if !(0f64..=1f64).contains(&self.reference_blend) {
+            return Err(Error::Config(format!(
+                "reference_blend must be in [0, 1], got {}",
+                self.reference_blend
+            )));
+        }
+        if !(0f64..=1f64).contains(&self.reference_weight) {
+            return Err(Error::Config(format!(
+                "reference_weight must be in [0, 1], got {}",
+                self.reference_weight
+            )));
+        }
+        if !(0f64..=1f64).contains(&self.sigma_dampening) {
+            return Err(Error::Config(format!(
+                "sigma_dampening must be in [0, 1], got {}",
+                self.sigma_dampening
+            )));
+        }
+
+        // the first A/k fit needs a full window of ticks, and at least one
+        // estimate_period of them
+        let estimate_period = self.estimate_period.unwrap_or(self.period);
+        if self.estimate_window < estimate_period {
+            return Err(Error::Config(format!(
+                "estimate_window {} ms is shorter than the estimate period {} ms, A/k would never be fit (estimate_window is in ms)",
+                self.estimate_window,
+                estimate_period
+            )));
+        }
+        if let Some(horizon) = self.horizon_ms {
+            if horizon == 0 || horizon > util::DAY_MS {
+                return Err(Error::Config(format!(
+                    "horizon_ms must be within one session (0, {}], got {}",
+                    util::DAY_MS,
+                    horizon
+                )));
+            }
+        }
+        if self.estimate_window > MAX_ESTIMATE_WINDOW_MS {
+            return Err(Error::Config(format!(
+                "estimate_window {} ms is longer than {} ms, quoting would not start in time (estimate_window is in ms)",
+                self.estimate_window,
+                MAX_ESTIMATE_WINDOW_MS
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Config of several pairs sharing `base`. Each entry of `symbols` is keyed
+/// by base asset and holds the fields that differ for that pair.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MultiConfig {
+    pub base: Value,
+    pub symbols: BTreeMap<String, Value>,
+}
+
+impl MultiConfig {
+    /// Config of every pair, base merged with its overrides and validated.
+    pub fn configs(&self) -> Result<Vec<Config>> {
+        self.symbols
+            .iter()
+            .map(|(base_asset, overrides)| {
+                let mut merged = self.base.clone();
+                let fields = merged
+                    .as_object_mut()
+                    .ok_or_else(|| anyhow!("base config must be an object"))?;
+                if let Some(overrides) = overrides.as_object() {
+                    for (key, value) in overrides {
+                        fields.insert(key.clone(), value.clone());
+                    }
+                } else if !overrides.is_null() {
+                    return Err(anyhow!("overrides of {} must be an object", base_asset));
+                }
+                fields.insert("base_asset".into(), Value::String(base_asset.clone()));
+
+                let config: Config = serde_json::from_value(merged)
+                    .map_err(|e| anyhow!("config of {}: {}", base_asset, e))?;
+                config
+                    .validate()
+                    .map_err(|e| anyhow!("config of {}: {}", base_asset, e))?;
+                Ok(config)
+            })
+            .collect()
+    }
+}
+
+/// The config to run from a file holding either one `Config` or a
+/// `MultiConfig`, the latter needing the base asset of the pair to run.
+pub fn select(value: Value, base_asset: Option<&str>) -> Result<Config> {
+    if value.get("symbols").is_none() {
+        return Ok(serde_json::from_value(value)?);
+    }
+    let multi: MultiConfig = serde_json::from_value(value)?;
+    let base_asset = base_asset.ok_or_else(|| {
+        anyhow!(
+            "config of several pairs, name the base asset to run, one of {:?}",
+            multi.symbols.keys().collect::<Vec<_>>()
+        )
+    })?;
+    multi
+        .configs()?
+        .into_iter()
+        .find(|x| x.base_asset == base_asset)
+        .ok_or_else(|| anyhow!("no config for {}", base_asset))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -0,0 +1,327 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub type SharedControl = Arc<Mutex<ControlState>>;
+
+/// Longest a client may take to send its whole request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest request accepted, head and body.
+const MAX_REQUEST: usize = 64 * 1024;
+
+/// Runtime tunables exposed by the control API.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize)]
+pub struct Params {
+    pub gamma: f64,
+    pub sigma_multiplier: f64,
+    pub order_qty: f64,
+    pub stoploss: f64,
+    pub stopprofit: f64,
+}
+
+/// Body of `POST /params`, fields left out keep their value. Anything
+/// outside the whitelist is rejected.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParamsUpdate {
+    pub gamma: Option<f64>,
+    pub sigma_multiplier: Option<f64>,
+    pub order_qty: Option<f64>,
+    pub stoploss: Option<f64>,
+    pub stopprofit: Option<f64>,
+}
+
+fn check(name: &str, value: Option<f64>, max: f64) -> Result<()> {
+    match value {
+        Some(v) if !(v.is_finite() && v > 0f64 && v <= max) => {
+            Err(anyhow!("{} out of range (0, {}]: {}", name, max, v))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl ParamsUpdate {
+    pub fn validate(&self) -> Result<()> {
+        check("gamma", self.gamma, 10f64)?;
+        check("sigma_multiplier", self.sigma_multiplier, 100f64)?;
+        check("order_qty", self.order_qty, f64::MAX)?;
+        check("stoploss", self.stoploss, f64::MAX)?;
+        check("stopprofit", self.stopprofit, f64::MAX)?;
+        Ok(())
+    }
+
+    fn apply(&self, params: &mut Params) {
+        params.gamma = self.gamma.unwrap_or(params.gamma);
+        params.sigma_multiplier = self.sigma_multiplier.unwrap_or(params.sigma_multiplier);
+        params.order_qty = self.order_qty.unwrap_or(params.order_qty);
+        params.stoploss = self.stoploss.unwrap_or(params.stoploss);
+        params.stopprofit = self.stopprofit.unwrap_or(params.stopprofit);
+    }
+}
+
+/// `params` mirrors the running values, published by the strategy every
+/// tick. `pending` holds an accepted update the strategy has yet to take.
+/// With `sized_by_notional` order_qty follows order_notional every tick,
+/// so an update setting it is rejected rather than silently overwritten.
+#[derive(Debug, Default)]
+pub struct ControlState {
+    pub params: Params,
+    pub pending: Option<Params>,
+    pub sized_by_notional: bool,
+}
+
+impl ControlState {
+    /// Validate the whole update before touching anything, so it is applied
+    /// all or nothing.
+    pub fn update(&mut self, update: &ParamsUpdate) -> Result<Params> {
+        update.validate()?;
+        if self.sized_by_notional && update.order_qty.is_some() {
+            return Err(anyhow!("order_qty is sized from order_notional"));
+        }
+        let mut params = self.pending.unwrap_or(self.params);
+        update.apply(&mut params);
+        self.params = params;
+        self.pending = Some(params);
+        Ok(params)
+    }
+}
+
+/// Serve `GET /params` and `POST /params` on `bind`, both answering with
+/// the JSON params. Requests need `Authorization: Bearer <token>`.
+pub async fn serve(bind: &str, port: u16, control: SharedControl, token: String) -> Result<()> {
+    let listener = TcpListener::bind((bind, port)).await?;
+    info!("control endpoint listening on {}", listener.local_addr()?);
+    serve_listener(listener, control, token).await
+}
+
+pub async fn serve_listener(
+    listener: TcpListener,
+    control: SharedControl,
+    token: String,
+) -> Result<()> {
+    let token = Arc::new(token);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        let token = token.clone();
+        // one slow client doesn't hold up the next
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &control, &token).await {
+                warn!("control request Error: {}", err);
+            }
+        });
+    }
+}
+
+/// Compare without stopping at the first difference, so the response time
+/// doesn't tell how much of the token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn authorized(request: &str, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    request.lines().any(|line| match line.split_once(':') {
+        Some((name, value)) => {
+            name.trim().eq_ignore_ascii_case("authorization")
+                && constant_time_eq(value.trim().as_bytes(), expected.as_bytes())
+        }
+        None => false,
+    })
+}
+
+fn content_length(head: &str) -> Result<usize> {
+    for line in head.lines() {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                return value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("bad Content-Length: {}", value.trim()));
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Read the head, then as much body as its Content-Length announces. A
+/// client closing early leaves what it sent.
+async fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|x| x == b"\r\n\r\n") {
+            let length = content_length(&String::from_utf8_lossy(&buf[..end]))?;
+            if end + 4 + length > MAX_REQUEST {
+                return Err(anyhow!("request over {} bytes", MAX_REQUEST));
+            }
+            if buf.len() >= end + 4 + length {
+                buf.truncate(end + 4 + length);
+                break;
+            }
+        } else if buf.len() > MAX_REQUEST {
+            return Err(anyhow!("request over {} bytes", MAX_REQUEST));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+async fn handle(mut stream: TcpStream, control: &SharedControl, token: &str) -> Result<()> {
+    let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow!("request incomplete after {:?}", READ_TIMEOUT))??;
+    let mut line = request.split_whitespace();
+    let method = line.next().unwrap_or("");
+    let path = line.next().unwrap_or("/");
+    let (head, body) = request.split_once("\r\n\r\n").unwrap_or((&request, ""));
+
+    let (status, body) = if !authorized(head, token) {
+        (
+            "401 Unauthorized",
+            r#"{"error":"unauthorized"}"#.to_string(),
+        )
+    } else {
+        match (method, path) {
+            ("GET", "/params") => {
+                let params = control.lock().unwrap().params;
+                ("200 OK", serde_json::to_string(&params)?)
+            }
+            ("POST", "/params") => {
+                let result = serde_json::from_str::<ParamsUpdate>(body)
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|update| control.lock().unwrap().update(&update));
+                match result {
+                    Ok(params) => {
+                        info!("control update accepted: {:?}", params);
+                        ("200 OK", serde_json::to_string(&params)?)
+                    }
+                    Err(err) => {
+                        warn!("control update rejected: {}", err);
+                        (
+                            "400 Bad Request",
+                            serde_json::json!({ "error": err.to_string() }).to_string(),
+                        )
+                    }
+                }
+            }
+            _ => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[actix_rt::test]
+    async fn test_token_and_validation() {
+        let control: SharedControl = Arc::new(Mutex::new(ControlState::default()));
+        control.lock().unwrap().params.gamma = 0.1;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        actix_rt::spawn(serve_listener(listener, control.clone(), "secret".into()));
+
+        let response = request(addr, "GET /params HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        let response = request(
+            addr,
+            "GET /params HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let response = request(
+            addr,
+            "GET /params HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("\"gamma\":0.1"));
+
+        // one bad field rejects the whole update
+        let body = r#"{"gamma":0.3,"stoploss":-1}"#;
+        let response = request(
+            addr,
+            &format!(
+                "POST /params HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(control.lock().unwrap().pending.is_none());
+
+        let body = r#"{"tick_size":0.5}"#;
+        let response = request(
+            addr,
+            &format!(
+                "POST /params HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    #[actix_rt::test]
+    async fn test_split_request_and_idle_client() {
+        let control: SharedControl = Arc::new(Mutex::new(ControlState::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        actix_rt::spawn(serve_listener(listener, control.clone(), "secret".into()));
+
+        // a client that connects and sends nothing holds up no one
+        let _idle = TcpStream::connect(addr).await.unwrap();
+
+        // the body arrives after the head
+        let body = r#"{"gamma":0.3}"#;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /params HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream.write_all(body.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(control.lock().unwrap().pending.unwrap().gamma, 0.3);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secreT", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secret!", b"Bearer secret"));
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt;
+
+/// Failures the strategy hands back to its caller, by where they came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A market or user data event that can't be used, e.g. a crossed or
+    /// non-finite book.
+    Feed(String),
+    /// A REST call to the exchange failed.
+    Exchange(String),
+    /// Settings that are invalid or conflict.
+    Config(String),
+    /// A computation came out non-finite, e.g. AS offsets from a degenerate
+    /// A/k fit.
+    Math(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn exchange(err: anyhow::Error) -> Self {
+        Error::Exchange(err.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Feed(msg) => write!(f, "feed: {}", msg),
+            Error::Exchange(msg) => write!(f, "exchange: {}", msg),
+            Error::Config(msg) => write!(f, "config: {}", msg),
+            Error::Math(msg) => write!(f, "math: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,181 @@
+use super::{Balance, ExchangeClient, OpenOrder, OrderAck, OrderFlags, PositionInfo};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use exrs::binance_f::{
+    account::{FuturesAccount, OrderRequest, PositionSide},
+    rest_model::{OrderSide, OrderType, TimeInForce, Transaction},
+};
+
+fn order_ack(transaction: Transaction) -> OrderAck {
+    OrderAck {
+        order_id: transaction.order_id,
+        client_order_id: transaction.client_order_id,
+        avg_price: transaction.avg_price,
+        executed_qty: transaction.executed_qty,
+    }
+}
+
+/// An order named by us or sent reduce-only, the shorthand calls cover the
+/// plain ones and leave the id to the exchange.
+fn tagged(
+    symbol: &str,
+    side: OrderSide,
+    qty: f64,
+    limit: Option<(f64, PositionSide, TimeInForce)>,
+    flags: OrderFlags<'_>,
+) -> OrderRequest {
+    let (order_type, price, position_side, time_in_force) = match limit {
+        Some((price, position_side, time_in_force)) => (
+            OrderType::Limit,
+            Some(price),
+            Some(position_side),
+            Some(time_in_force),
+        ),
+        None => (OrderType::Market, None, None, None),
+    };
+    OrderRequest {
+        symbol: symbol.to_string(),
+        side: side,
+        position_side: position_side,
+        order_type: order_type,
+        time_in_force: time_in_force,
+        quantity: Some(qty),
+        price: price,
+        reduce_only: flags.reduce_only.then_some(true),
+        new_client_order_id: flags.client_order_id.map(String::from),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl ExchangeClient for FuturesAccount {
+    async fn limit_buy(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck> {
+        let answer = match flags {
+            OrderFlags {
+                client_order_id: None,
+                reduce_only: false,
+            } => {
+                FuturesAccount::limit_buy(self, symbol, qty, price, position_side, time_in_force)
+                    .await?
+            }
+            _ => {
+                let limit = Some((price, position_side, time_in_force));
+                self.place_order(tagged(symbol, OrderSide::Buy, qty, limit, flags))
+                    .await?
+            }
+        };
+        Ok(order_ack(answer))
+    }
+
+    async fn limit_sell(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck> {
+        let answer = match flags {
+            OrderFlags {
+                client_order_id: None,
+                reduce_only: false,
+            } => {
+                FuturesAccount::limit_sell(self, symbol, qty, price, position_side, time_in_force)
+                    .await?
+            }
+            _ => {
+                let limit = Some((price, position_side, time_in_force));
+                self.place_order(tagged(symbol, OrderSide::Sell, qty, limit, flags))
+                    .await?
+            }
+        };
+        Ok(order_ack(answer))
+    }
+
+    async fn market_buy(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck> {
+        let answer = match flags {
+            OrderFlags {
+                client_order_id: None,
+                reduce_only: false,
+            } => FuturesAccount::market_buy(self, symbol, qty).await?,
+            _ => {
+                self.place_order(tagged(symbol, OrderSide::Buy, qty, None, flags))
+                    .await?
+            }
+        };
+        Ok(order_ack(answer))
+    }
+
+    async fn market_sell(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck> {
+        let answer = match flags {
+            OrderFlags {
+                client_order_id: None,
+                reduce_only: false,
+            } => FuturesAccount::market_sell(self, symbol, qty).await?,
+            _ => {
+                self.place_order(tagged(symbol, OrderSide::Sell, qty, None, flags))
+                    .await?
+            }
+        };
+        Ok(order_ack(answer))
+    }
+
+    async fn cancel_all_open_orders(&self, symbol: &str) -> Result<()> {
+        FuturesAccount::cancel_all_open_orders(self, symbol).await?;
+        Ok(())
+    }
+
+    async fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<()> {
+        FuturesAccount::cancel_order(self, symbol, order_id).await?;
+        Ok(())
+    }
+
+    async fn open_orders(&self, symbol: &str) -> Result<Vec<OpenOrder>> {
+        let orders = FuturesAccount::get_open_orders(self, symbol).await?;
+        Ok(orders
+            .into_iter()
+            .map(|x| OpenOrder {
+                order_id: x.order_id,
+                client_order_id: x.client_order_id,
+                price: x.price,
+                qty: x.orig_qty,
+                update_time: x.update_time,
+            })
+            .collect())
+    }
+
+    async fn account_balance(&self) -> Result<Vec<Balance>> {
+        let balances = FuturesAccount::account_balance(self).await?;
+        Ok(balances
+            .into_iter()
+            .map(|x| Balance {
+                asset: x.asset,
+                balance: x.balance,
+                available_balance: x.available_balance,
+            })
+            .collect())
+    }
+
+    async fn position_information(&self, symbol: &str) -> Result<Vec<PositionInfo>> {
+        let positions = FuturesAccount::position_information(self, symbol).await?;
+        Ok(positions
+            .into_iter()
+            .map(|x| PositionInfo {
+                symbol: x.symbol,
+                position_side: x.position_side,
+                position_amount: x.position_amount,
+                entry_price: x.entry_price,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,399 @@
+use super::mock::Call;
+use super::{Balance, ExchangeClient, OpenOrder, OrderAck, OrderFlags, PositionInfo};
+use crate::health;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use exrs::binance_f::{account::PositionSide, rest_model::TimeInForce};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+
+/// What the exchange answered to a journaled call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Outcome {
+    Ack(OrderAck),
+    /// A cancel went through.
+    Done,
+    Error(String),
+}
+
+/// One line of the journal: a request, when (local ms) it was sent and how
+/// long it took, and its outcome.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub ts: u64,
+    pub latency_ms: u64,
+    pub request: Call,
+    pub outcome: Outcome,
+}
+
+/// ExchangeClient decorator appending every order and cancel call and its
+/// outcome to a JSON lines file. Reads pass through unjournaled.
+pub struct JournalExchange {
+    inner: Arc<dyn ExchangeClient>,
+    file: Mutex<File>,
+}
+
+impl JournalExchange {
+    /// Wrap `inner`, appending to `path`.
+    pub fn open(inner: Arc<dyn ExchangeClient>, path: &str) -> Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Arc::new(JournalExchange {
+            inner: inner,
+            file: Mutex::new(file),
+        }))
+    }
+
+    fn write(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Journal `request` with the outcome of `result`, handing `result` on.
+    fn record<T>(
+        &self,
+        ts: u64,
+        request: Call,
+        result: Result<T>,
+        outcome: fn(&T) -> Outcome,
+    ) -> Result<T> {
+        let entry = Entry {
+            ts: ts,
+            latency_ms: health::now_ms().saturating_sub(ts),
+            request: request,
+            outcome: match &result {
+                Ok(x) => outcome(x),
+                Err(e) => Outcome::Error(e.to_string()),
+            },
+        };
+        if let Err(err) = self.write(&entry) {
+            warn!("journal {:?} Error: {}", entry, err);
+        }
+        result
+    }
+}
+
+fn ack(ack: &OrderAck) -> Outcome {
+    Outcome::Ack(ack.clone())
+}
+
+fn done(_: &()) -> Outcome {
+    Outcome::Done
+}
+
+#[async_trait]
+impl ExchangeClient for JournalExchange {
+    async fn limit_buy(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck> {
+        let request = Call::LimitBuy {
+            symbol: symbol.to_string(),
+            qty: qty,
+            price: price,
+            position_side: format!("{:?}", position_side),
+            time_in_force: format!("{:?}", time_in_force),
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        };
+        let ts = health::now_ms();
+        let result = self
+            .inner
+            .limit_buy(symbol, qty, price, position_side, time_in_force, flags)
+            .await;
+        self.record(ts, request, result, ack)
+    }
+
+    async fn limit_sell(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck> {
+        let request = Call::LimitSell {
+            symbol: symbol.to_string(),
+            qty: qty,
+            price: price,
+            position_side: format!("{:?}", position_side),
+            time_in_force: format!("{:?}", time_in_force),
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        };
+        let ts = health::now_ms();
+        let result = self
+            .inner
+            .limit_sell(symbol, qty, price, position_side, time_in_force, flags)
+            .await;
+        self.record(ts, request, result, ack)
+    }
+
+    async fn market_buy(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck> {
+        let request = Call::MarketBuy {
+            symbol: symbol.to_string(),
+            qty: qty,
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        };
+        let ts = health::now_ms();
+        let result = self.inner.market_buy(symbol, qty, flags).await;
+        self.record(ts, request, result, ack)
+    }
+
+    async fn market_sell(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck> {
+        let request = Call::MarketSell {
+            symbol: symbol.to_string(),
+            qty: qty,
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        };
+        let ts = health::now_ms();
+        let result = self.inner.market_sell(symbol, qty, flags).await;
+        self.record(ts, request, result, ack)
+    }
+
+    async fn cancel_all_open_orders(&self, symbol: &str) -> Result<()> {
+        let request = Call::CancelAll {
+            symbol: symbol.to_string(),
+        };
+        let ts = health::now_ms();
+        let result = self.inner.cancel_all_open_orders(symbol).await;
+        self.record(ts, request, result, done)
+    }
+
+    async fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<()> {
+        let request = Call::Cancel {
+            symbol: symbol.to_string(),
+            order_id: order_id,
+        };
+        let ts = health::now_ms();
+        let result = self.inner.cancel_order(symbol, order_id).await;
+        self.record(ts, request, result, done)
+    }
+
+    async fn open_orders(&self, symbol: &str) -> Result<Vec<OpenOrder>> {
+        self.inner.open_orders(symbol).await
+    }
+
+    async fn account_balance(&self) -> Result<Vec<Balance>> {
+        self.inner.account_balance().await
+    }
+
+    async fn position_information(&self, symbol: &str) -> Result<Vec<PositionInfo>> {
+        self.inner.position_information(symbol).await
+    }
+}
+
+/// Entries of the journal at `path`, oldest first.
+pub fn read(path: &str) -> Result<Vec<Entry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+    Ok(entries)
+}
+
+fn flags(client_order_id: &Option<String>, reduce_only: bool) -> OrderFlags<'_> {
+    OrderFlags {
+        client_order_id: client_order_id.as_deref(),
+        reduce_only: reduce_only,
+    }
+}
+
+fn position_side(side: &str) -> Result<PositionSide> {
+    match side {
+        "Both" => Ok(PositionSide::Both),
+        "Long" => Ok(PositionSide::Long),
+        "Short" => Ok(PositionSide::Short),
+        _ => Err(anyhow!("unknown position side {}", side)),
+    }
+}
+
+fn time_in_force(tif: &str) -> Result<TimeInForce> {
+    match tif {
+        "GTC" => Ok(TimeInForce::GTC),
+        "IOC" => Ok(TimeInForce::IOC),
+        "FOK" => Ok(TimeInForce::FOK),
+        "GTX" => Ok(TimeInForce::GTX),
+        _ => Err(anyhow!("unknown time in force {}", tif)),
+    }
+}
+
+/// Send the journaled requests to `client` again, in order, and return what
+/// it answered. Against a mock this turns a journal into a regression test.
+pub async fn replay(entries: &[Entry], client: &dyn ExchangeClient) -> Result<Vec<Outcome>> {
+    let mut outcomes = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let outcome = match &entry.request {
+            Call::LimitBuy {
+                symbol,
+                qty,
+                price,
+                position_side: side,
+                time_in_force: tif,
+                client_order_id,
+                reduce_only,
+            } => client
+                .limit_buy(
+                    symbol,
+                    *qty,
+                    *price,
+                    position_side(side)?,
+                    time_in_force(tif)?,
+                    flags(client_order_id, *reduce_only),
+                )
+                .await
+                .map(|x| ack(&x)),
+            Call::LimitSell {
+                symbol,
+                qty,
+                price,
+                position_side: side,
+                time_in_force: tif,
+                client_order_id,
+                reduce_only,
+            } => client
+                .limit_sell(
+                    symbol,
+                    *qty,
+                    *price,
+                    position_side(side)?,
+                    time_in_force(tif)?,
+                    flags(client_order_id, *reduce_only),
+                )
+                .await
+                .map(|x| ack(&x)),
+            Call::MarketBuy {
+                symbol,
+                qty,
+                client_order_id,
+                reduce_only,
+            } => client
+                .market_buy(symbol, *qty, flags(client_order_id, *reduce_only))
+                .await
+                .map(|x| ack(&x)),
+            Call::MarketSell {
+                symbol,
+                qty,
+                client_order_id,
+                reduce_only,
+            } => client
+                .market_sell(symbol, *qty, flags(client_order_id, *reduce_only))
+                .await
+                .map(|x| ack(&x)),
+            Call::CancelAll { symbol } => client
+                .cancel_all_open_orders(symbol)
+                .await
+                .map(|_| Outcome::Done),
+            Call::Cancel { symbol, order_id } => client
+                .cancel_order(symbol, *order_id)
+                .await
+                .map(|_| Outcome::Done),
+        };
+        outcomes.push(outcome.unwrap_or_else(|e| Outcome::Error(e.to_string())));
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::mock::MockExchange;
+
+    #[actix_rt::test]
+    async fn test_journal() {
+        let path = std::env::temp_dir().join(format!("rainmaker-journal-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let mock = MockExchange::new();
+        let journal = JournalExchange::open(mock.clone(), &path).unwrap();
+        journal
+            .limit_buy(
+                "BTCUSDT",
+                0.001,
+                49999.,
+                PositionSide::Both,
+                TimeInForce::GTC,
+                OrderFlags::default(),
+            )
+            .await
+            .unwrap();
+        mock.fail_next("-2011 Unknown order sent");
+        assert!(journal.cancel_order("BTCUSDT", 7).await.is_err());
+        journal
+            .market_sell(
+                "BTCUSDT",
+                0.001,
+                OrderFlags {
+                    client_order_id: Some("mm-s0-1"),
+                    reduce_only: true,
+                },
+            )
+            .await
+            .unwrap();
+        journal.open_orders("BTCUSDT").await.unwrap();
+
+        let entries = read(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].request, mock.calls()[0]);
+        assert_eq!(
+            entries[0].outcome,
+            Outcome::Ack(OrderAck {
+                order_id: 1,
+                client_order_id: "mock-1".into(),
+                avg_price: 49999.,
+                executed_qty: 0.001,
+            })
+        );
+        assert_eq!(
+            entries[1].request,
+            Call::Cancel {
+                symbol: "BTCUSDT".into(),
+                order_id: 7
+            }
+        );
+        assert_eq!(
+            entries[1].outcome,
+            Outcome::Error("-2011 Unknown order sent".into())
+        );
+        assert!(matches!(entries[2].outcome, Outcome::Ack(_)));
+        assert!(matches!(
+            entries[2].request,
+            Call::MarketSell {
+                reduce_only: true,
+                ..
+            }
+        ));
+        assert!(entries.iter().all(|x| x.ts > 0));
+
+        // replayed against a fresh mock the requests are the same, only the
+        // unscripted cancel now goes through
+        let fresh = MockExchange::new();
+        let outcomes = replay(&entries, fresh.as_ref()).await.unwrap();
+        assert_eq!(fresh.calls(), mock.calls());
+        assert_eq!(outcomes[0], entries[0].outcome);
+        assert_eq!(outcomes[1], Outcome::Done);
+        assert_eq!(outcomes[2], entries[2].outcome);
+        let _ = std::fs::remove_file(&path);
+    }
+}
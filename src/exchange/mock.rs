@@ -0,0 +1,463 @@
+use super::{Balance, ExchangeClient, OpenOrder, OrderAck, OrderFlags, PositionInfo};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use exrs::binance_f::{
+    account::PositionSide, rest_model::TimeInForce, ws_model::FuturesWebsocketEvent,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// A call received by the mock, position side and time in force are kept in
+/// their debug form.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Call {
+    LimitBuy {
+        symbol: String,
+        qty: f64,
+        price: f64,
+        position_side: String,
+        time_in_force: String,
+        #[serde(default)]
+        client_order_id: Option<String>,
+        #[serde(default)]
+        reduce_only: bool,
+    },
+    LimitSell {
+        symbol: String,
+        qty: f64,
+        price: f64,
+        position_side: String,
+        time_in_force: String,
+        #[serde(default)]
+        client_order_id: Option<String>,
+        #[serde(default)]
+        reduce_only: bool,
+    },
+    MarketBuy {
+        symbol: String,
+        qty: f64,
+        #[serde(default)]
+        client_order_id: Option<String>,
+        #[serde(default)]
+        reduce_only: bool,
+    },
+    MarketSell {
+        symbol: String,
+        qty: f64,
+        #[serde(default)]
+        client_order_id: Option<String>,
+        #[serde(default)]
+        reduce_only: bool,
+    },
+    CancelAll {
+        symbol: String,
+    },
+    Cancel {
+        symbol: String,
+        order_id: u64,
+    },
+}
+
+/// In-memory exchange recording every call. Orders are acknowledged as
+/// filled at their price unless an error was scripted with `fail_next`.
+#[derive(Debug, Default)]
+pub struct MockExchange {
+    calls: Mutex<Vec<Call>>,
+    errors: Mutex<VecDeque<String>>,
+    order_errors: Mutex<VecDeque<String>>,
+    cancel_errors: Mutex<VecDeque<String>>,
+    query_errors: Mutex<VecDeque<String>>,
+    balances: Mutex<Vec<Balance>>,
+    positions: Mutex<Vec<PositionInfo>>,
+    open_orders: Mutex<Vec<OpenOrder>>,
+    next_order_id: AtomicU64,
+    delay_ms: AtomicU64,
+    unfilled: AtomicBool,
+}
+
+impl MockExchange {
+    pub fn new() -> Arc<Self> {
+        Arc::new(MockExchange::default())
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn clear_calls(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// Make the next order or cancel call fail with `msg`.
+    pub fn fail_next(&self, msg: &str) {
+        self.errors.lock().unwrap().push_back(msg.to_string());
+    }
+
+    /// Make the next order placement fail with `msg`, cancels unaffected.
+    pub fn fail_next_order(&self, msg: &str) {
+        self.order_errors.lock().unwrap().push_back(msg.to_string());
+    }
+
+    /// Make the next cancel by id fail with `msg`, cancel_all unaffected.
+    pub fn fail_next_cancel(&self, msg: &str) {
+        self.cancel_errors
+            .lock()
+            .unwrap()
+            .push_back(msg.to_string());
+    }
+
+    /// Make the next open orders, balance or position query fail with `msg`.
+    pub fn fail_next_query(&self, msg: &str) {
+        self.query_errors.lock().unwrap().push_back(msg.to_string());
+    }
+
+    fn query(&self) -> Result<()> {
+        match self.query_errors.lock().unwrap().pop_front() {
+            Some(msg) => Err(anyhow!(msg)),
+            None => Ok(()),
+        }
+    }
+
+    /// Make every order and cancel call take `ms`, so concurrent callers
+    /// can interleave.
+    pub fn set_delay(&self, ms: u64) {
+        self.delay_ms.store(ms, Ordering::SeqCst);
+    }
+
+    async fn delay(&self) {
+        let ms = self.delay_ms.load(Ordering::SeqCst);
+        if ms > 0 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(ms)).await;
+        }
+    }
+
+    /// Acknowledge orders with nothing executed yet, the way Binance acks a
+    /// market order before it fills.
+    pub fn set_unfilled(&self, unfilled: bool) {
+        self.unfilled.store(unfilled, Ordering::SeqCst);
+    }
+
+    pub fn set_balances(&self, balances: Vec<Balance>) {
+        *self.balances.lock().unwrap() = balances;
+    }
+
+    pub fn set_positions(&self, positions: Vec<PositionInfo>) {
+        *self.positions.lock().unwrap() = positions;
+    }
+
+    /// Orders reported resting by `open_orders`, a cancel removes them.
+    pub fn set_open_orders(&self, orders: Vec<OpenOrder>) {
+        *self.open_orders.lock().unwrap() = orders;
+    }
+
+    fn record(&self, call: Call) -> Result<()> {
+        let is_order = !matches!(call, Call::CancelAll { .. } | Call::Cancel { .. });
+        self.calls.lock().unwrap().push(call);
+        if is_order {
+            if let Some(msg) = self.order_errors.lock().unwrap().pop_front() {
+                return Err(anyhow!(msg));
+            }
+        }
+        match self.errors.lock().unwrap().pop_front() {
+            Some(msg) => Err(anyhow!(msg)),
+            None => Ok(()),
+        }
+    }
+
+    fn ack(&self, qty: f64, price: f64, client_order_id: Option<&str>) -> OrderAck {
+        let order_id = self.next_order_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let (price, qty) = if self.unfilled.load(Ordering::SeqCst) {
+            (0f64, 0f64)
+        } else {
+            (price, qty)
+        };
+        OrderAck {
+            order_id: order_id,
+            client_order_id: client_order_id
+                .map(String::from)
+                .unwrap_or_else(|| format!("mock-{}", order_id)),
+            avg_price: price,
+            executed_qty: qty,
+        }
+    }
+}
+
+#[async_trait]
+impl ExchangeClient for MockExchange {
+    async fn limit_buy(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck> {
+        self.delay().await;
+        self.record(Call::LimitBuy {
+            symbol: symbol.to_string(),
+            qty: qty,
+            price: price,
+            position_side: format!("{:?}", position_side),
+            time_in_force: format!("{:?}", time_in_force),
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        })?;
+        Ok(self.ack(qty, price, flags.client_order_id))
+    }
+
+    async fn limit_sell(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck> {
+        self.delay().await;
+        self.record(Call::LimitSell {
+            symbol: symbol.to_string(),
+            qty: qty,
+            price: price,
+            position_side: format!("{:?}", position_side),
+            time_in_force: format!("{:?}", time_in_force),
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        })?;
+        Ok(self.ack(qty, price, flags.client_order_id))
+    }
+
+    async fn market_buy(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck> {
+        self.delay().await;
+        self.record(Call::MarketBuy {
+            symbol: symbol.to_string(),
+            qty: qty,
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        })?;
+        Ok(self.ack(qty, 0f64, flags.client_order_id))
+    }
+
+    async fn market_sell(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck> {
+        self.delay().await;
+        self.record(Call::MarketSell {
+            symbol: symbol.to_string(),
+            qty: qty,
+            client_order_id: flags.client_order_id.map(String::from),
+            reduce_only: flags.reduce_only,
+        })?;
+        Ok(self.ack(qty, 0f64, flags.client_order_id))
+    }
+
+    async fn cancel_all_open_orders(&self, symbol: &str) -> Result<()> {
+        self.delay().await;
+        self.record(Call::CancelAll {
+            symbol: symbol.to_string(),
+        })
+    }
+
+    async fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<()> {
+        self.delay().await;
+        self.record(Call::Cancel {
+            symbol: symbol.to_string(),
+            order_id: order_id,
+        })?;
+        if let Some(msg) = self.cancel_errors.lock().unwrap().pop_front() {
+            return Err(anyhow!(msg));
+        }
+        self.open_orders
+            .lock()
+            .unwrap()
+            .retain(|x| x.order_id != order_id);
+        Ok(())
+    }
+
+    async fn open_orders(&self, _symbol: &str) -> Result<Vec<OpenOrder>> {
+        self.query()?;
+        Ok(self.open_orders.lock().unwrap().clone())
+    }
+
+    async fn account_balance(&self) -> Result<Vec<Balance>> {
+        self.query()?;
+        Ok(self.balances.lock().unwrap().clone())
+    }
+
+    async fn position_information(&self, symbol: &str) -> Result<Vec<PositionInfo>> {
+        self.query()?;
+        Ok(self
+            .positions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| x.symbol.eq(symbol))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Scripted sequence of websocket events for one symbol, built from the
+/// exchange wire format so they go through the same deserialization as the
+/// live feed.
+pub struct Scenario {
+    symbol: String,
+    ts: u64,
+    events: Vec<FuturesWebsocketEvent>,
+}
+
+impl Scenario {
+    pub fn new(symbol: &str, start_ts: u64) -> Self {
+        Scenario {
+            symbol: symbol.to_string(),
+            ts: start_ts,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.ts
+    }
+
+    /// Advance the scenario clock.
+    pub fn wait(mut self, ms: u64) -> Self {
+        self.ts += ms;
+        self
+    }
+
+    pub fn book_ticker(mut self, bid: f64, ask: f64) -> Self {
+        let event = serde_json::from_value(json!({
+            "e": "bookTicker",
+            "u": self.events.len() as u64 + 1,
+            "E": self.ts,
+            "T": self.ts,
+            "s": self.symbol,
+            "b": bid.to_string(),
+            "B": "1",
+            "a": ask.to_string(),
+            "A": "1"
+        }))
+        .unwrap();
+        self.events
+            .push(FuturesWebsocketEvent::BookTicker(Box::new(event)));
+        self
+    }
+
+    /// `count` identical book tickers `interval` ms apart.
+    pub fn ticks(mut self, bid: f64, ask: f64, interval: u64, count: usize) -> Self {
+        for _ in 0..count {
+            self = self.book_ticker(bid, ask).wait(interval);
+        }
+        self
+    }
+
+    /// One-way mode position update.
+    pub fn position(mut self, amount: f64, entry_price: f64) -> Self {
+        let event = serde_json::from_value(json!({
+            "e": "ACCOUNT_UPDATE",
+            "E": self.ts,
+            "T": self.ts,
+            "a": {
+                "m": "ORDER",
+                "B": [],
+                "P": [{
+                    "s": self.symbol,
+                    "pa": amount.to_string(),
+                    "ep": entry_price.to_string(),
+                    "cr": "0",
+                    "up": "0",
+                    "mt": "cross",
+                    "iw": "0",
+                    "ps": "BOTH"
+                }]
+            }
+        }))
+        .unwrap();
+        self.events
+            .push(FuturesWebsocketEvent::AccountUpdate(Box::new(event)));
+        self
+    }
+
+    /// A fully filled limit order, `qty` signed positive for buys.
+    pub fn fill(self, client_order_id: &str, qty: f64, price: f64, fee: f64) -> Self {
+        let order_id = self.events.len() as u64 + 1;
+        self.order_update(client_order_id, order_id, qty, price, fee, "FILLED")
+    }
+
+    /// Order lifecycle update without a fill, e.g. `NEW` or `CANCELED`.
+    pub fn order_status(self, client_order_id: &str, order_id: u64, status: &str) -> Self {
+        self.order_update(client_order_id, order_id, 0f64, 0f64, 0f64, status)
+    }
+
+    fn order_update(
+        mut self,
+        client_order_id: &str,
+        order_id: u64,
+        qty: f64,
+        price: f64,
+        fee: f64,
+        status: &str,
+    ) -> Self {
+        let execution_type = match status {
+            "FILLED" | "PARTIALLY_FILLED" => "TRADE",
+            "CANCELED" | "EXPIRED" => status,
+            _ => "NEW",
+        };
+        let event = serde_json::from_value(json!({
+            "e": "ORDER_TRADE_UPDATE",
+            "E": self.ts,
+            "T": self.ts,
+            "o": {
+                "s": self.symbol,
+                "c": client_order_id,
+                "S": if qty >= 0f64 { "BUY" } else { "SELL" },
+                "o": "LIMIT",
+                "f": "GTC",
+                "q": qty.abs().to_string(),
+                "p": price.to_string(),
+                "ap": price.to_string(),
+                "sp": "0",
+                "x": execution_type,
+                "X": status,
+                "i": order_id,
+                "l": qty.abs().to_string(),
+                "z": qty.abs().to_string(),
+                "L": price.to_string(),
+                "N": "USDT",
+                "n": fee.to_string(),
+                "T": self.ts,
+                "t": self.events.len() as u64 + 1,
+                "b": "0",
+                "a": "0",
+                "m": true,
+                "R": false,
+                "wt": "CONTRACT_PRICE",
+                "ot": "LIMIT",
+                "ps": "BOTH",
+                "cp": false,
+                "rp": "0"
+            }
+        }))
+        .unwrap();
+        self.events
+            .push(FuturesWebsocketEvent::OrderTradeUpdate(Box::new(event)));
+        self
+    }
+
+    pub fn events(self) -> Vec<FuturesWebsocketEvent> {
+        self.events
+    }
+
+    /// A receiver yielding the scripted events, closed after the last one.
+    pub fn channel(self) -> mpsc::Receiver<FuturesWebsocketEvent> {
+        let (tx, rx) = mpsc::channel(self.events.len().max(1));
+        for event in self.events {
+            tx.try_send(event).unwrap();
+        }
+        rx
+    }
+}
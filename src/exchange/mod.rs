@@ -0,0 +1,154 @@
+pub mod binance;
+pub mod journal;
+pub mod mock;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use exrs::binance_f::{account::PositionSide, rest_model::TimeInForce};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Binance rejects an order with -2019 when the account lacks the margin.
+pub fn is_margin_insufficient(err: &anyhow::Error) -> bool {
+    let msg = err.to_string();
+    msg.contains("-2019") || msg.contains("Margin is insufficient")
+}
+
+/// Binance rejects a limit price outside the PERCENT_PRICE band around the
+/// mark with -4016 (too high) or -4024 (too low), -4131 for market orders.
+pub fn is_percent_price(err: &anyhow::Error) -> bool {
+    let msg = err.to_string();
+    ["-4016", "-4024", "-4131", "PERCENT_PRICE"]
+        .iter()
+        .any(|x| msg.contains(x))
+}
+
+/// Binance rejects a reduce-only order with -2022 when there is no position
+/// left for it to reduce.
+pub fn is_reduce_only_rejected(err: &anyhow::Error) -> bool {
+    let msg = err.to_string();
+    ["-2022", "ReduceOnly Order is rejected", "position is zero"]
+        .iter()
+        .any(|x| msg.contains(x))
+}
+
+/// Exchange acknowledgement of a submitted order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderAck {
+    pub order_id: u64,
+    pub client_order_id: String,
+    pub avg_price: f64,
+    pub executed_qty: f64,
+}
+
+/// An order resting on the exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenOrder {
+    pub order_id: u64,
+    pub client_order_id: String,
+    pub price: f64,
+    pub qty: f64,
+    /// Last update time (ms) of the order.
+    pub update_time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionInfo {
+    pub symbol: String,
+    pub position_side: String,
+    pub position_amount: f64,
+    pub entry_price: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Balance {
+    pub asset: String,
+    pub balance: f64,
+    pub available_balance: f64,
+}
+
+/// Client order ids `<tag>-<side><level>-<seq>` for the orders of a tagged
+/// strategy, side `b` or `s` and level the ladder rung, 0 for a quote. The
+/// sequence starts at the creation time (ms) so ids don't repeat across
+/// restarts. Untagged, orders are left to the exchange to name.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOrderIds {
+    tag: Option<String>,
+    seq: Arc<AtomicU64>,
+}
+
+impl ClientOrderIds {
+    pub fn new(tag: Option<String>, start: u64) -> Self {
+        ClientOrderIds {
+            tag: tag,
+            seq: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    pub fn next(&self, buy: bool, level: u32) -> Option<String> {
+        let tag = self.tag.as_ref()?;
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let side = if buy { 'b' } else { 's' };
+        Some(format!("{}-{}{}-{}", tag, side, level, seq))
+    }
+
+    /// Whether `client_order_id` was named by this strategy. Nothing is
+    /// untagged, the orders can't be told apart from manual ones.
+    pub fn owns(&self, client_order_id: &str) -> bool {
+        match &self.tag {
+            Some(tag) => client_order_id
+                .strip_prefix(tag.as_str())
+                .is_some_and(|rest| rest.starts_with('-')),
+            None => false,
+        }
+    }
+}
+
+/// How an order is placed beyond its price and size. Without an id the
+/// exchange names the order, `reduce_only` orders can only shrink the
+/// position and are rejected with -2022 once it is flat.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OrderFlags<'a> {
+    pub client_order_id: Option<&'a str>,
+    pub reduce_only: bool,
+}
+
+/// The order and account calls the strategies make, so they can run against
+/// a mock as well as the real exchange.
+#[async_trait]
+pub trait ExchangeClient: Send + Sync {
+    async fn limit_buy(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck>;
+
+    async fn limit_sell(
+        &self,
+        symbol: &str,
+        qty: f64,
+        price: f64,
+        position_side: PositionSide,
+        time_in_force: TimeInForce,
+        flags: OrderFlags<'_>,
+    ) -> Result<OrderAck>;
+
+    async fn market_buy(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck>;
+
+    async fn market_sell(&self, symbol: &str, qty: f64, flags: OrderFlags<'_>) -> Result<OrderAck>;
+
+    async fn cancel_all_open_orders(&self, symbol: &str) -> Result<()>;
+
+    async fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<()>;
+
+    async fn open_orders(&self, symbol: &str) -> Result<Vec<OpenOrder>>;
+
+    async fn account_balance(&self) -> Result<Vec<Balance>>;
+
+    async fn position_information(&self, symbol: &str) -> Result<Vec<PositionInfo>>;
+}
@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::Deserialize;
+
+const EXCHANGE_INFO_URL: &str = "https://fapi.binance.com/fapi/v1/exchangeInfo";
+
+/// Trading rules of a symbol as published by `exchangeInfo`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SymbolFilters {
+    pub tick_size: f64,
+    pub step_size: f64,
+    pub min_qty: f64,
+    pub min_notional: f64,
+    /// PERCENT_PRICE multipliers of the mark price bounding an order price,
+    /// 0 when the symbol has no such filter.
+    pub multiplier_up: f64,
+    pub multiplier_down: f64,
+}
+
+/// Prices an order may be placed at, both bounds on the tick grid.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PriceBand {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl PriceBand {
+    pub fn contains(&self, price: f64) -> bool {
+        self.low <= price && price <= self.high
+    }
+
+    pub fn clamp(&self, price: f64) -> f64 {
+        price.max(self.low).min(self.high)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExchangeInfo {
+    pub symbols: Vec<SymbolInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SymbolInfo {
+    pub symbol: String,
+    pub filters: Vec<Filter>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "filterType")]
+pub enum Filter {
+    #[serde(rename = "PRICE_FILTER", rename_all = "camelCase")]
+    PriceFilter { tick_size: String },
+    #[serde(rename = "LOT_SIZE", rename_all = "camelCase")]
+    LotSize { step_size: String, min_qty: String },
+    #[serde(rename = "MIN_NOTIONAL")]
+    MinNotional { notional: String },
+    #[serde(rename = "PERCENT_PRICE", rename_all = "camelCase")]
+    PercentPrice {
+        multiplier_up: String,
+        multiplier_down: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+impl ExchangeInfo {
+    pub fn filters(&self, symbol: &str) -> Result<SymbolFilters> {
+        let info = self
+            .symbols
+            .iter()
+            .find(|x| x.symbol.eq(symbol))
+            .ok_or_else(|| anyhow!("symbol {} not found in exchangeInfo", symbol))?;
+
+        let mut filters = SymbolFilters {
+            tick_size: 0f64,
+            step_size: 0f64,
+            min_qty: 0f64,
+            min_notional: 0f64,
+            multiplier_up: 0f64,
+            multiplier_down: 0f64,
+        };
+
+        for filter in &info.filters {
+            match filter {
+                Filter::PriceFilter { tick_size } => filters.tick_size = tick_size.parse()?,
+                Filter::LotSize { step_size, min_qty } => {
+                    filters.step_size = step_size.parse()?;
+                    filters.min_qty = min_qty.parse()?;
+                }
+                Filter::MinNotional { notional } => filters.min_notional = notional.parse()?,
+                Filter::PercentPrice {
+                    multiplier_up,
+                    multiplier_down,
+                } => {
+                    filters.multiplier_up = multiplier_up.parse()?;
+                    filters.multiplier_down = multiplier_down.parse()?;
+                }
+                Filter::Other => {}
+            }
+        }
+
+        if filters.tick_size <= 0f64 || filters.step_size <= 0f64 {
+            return Err(anyhow!("incomplete filters for {}: {:?}", symbol, filters));
+        }
+
+        Ok(filters)
+    }
+}
+
+pub async fn fetch_symbol_filters(symbol: &str) -> Result<SymbolFilters> {
+    let exchange_info: ExchangeInfo = reqwest::get(EXCHANGE_INFO_URL).await?.json().await?;
+    let filters = exchange_info.filters(symbol)?;
+    info!("{} filters: {:?}", symbol, filters);
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        let body = r#"{
+            "symbols": [
+                {
+                    "symbol": "BTCUSDT",
+                    "pricePrecision": 2,
+                    "quantityPrecision": 3,
+                    "filters": [
+                        {"filterType": "PRICE_FILTER", "minPrice": "556.80", "maxPrice": "4529764", "tickSize": "0.10"},
+                        {"filterType": "LOT_SIZE", "stepSize": "0.001", "maxQty": "1000", "minQty": "0.001"},
+                        {"filterType": "MARKET_LOT_SIZE", "stepSize": "0.001", "maxQty": "120", "minQty": "0.001"},
+                        {"filterType": "MAX_NUM_ORDERS", "limit": 200},
+                        {"filterType": "MIN_NOTIONAL", "notional": "5"},
+                        {"filterType": "PERCENT_PRICE", "multiplierUp": "1.0500", "multiplierDown": "0.9500", "multiplierDecimal": "4"}
+                    ]
+                }
+            ]
+        }"#;
+        let exchange_info: ExchangeInfo = serde_json::from_str(body).unwrap();
+
+        let filters = exchange_info.filters("BTCUSDT").unwrap();
+        assert_eq!(filters.tick_size, 0.1);
+        assert_eq!(filters.step_size, 0.001);
+        assert_eq!(filters.min_qty, 0.001);
+        assert_eq!(filters.min_notional, 5.);
+        assert_eq!(filters.multiplier_up, 1.05);
+        assert_eq!(filters.multiplier_down, 0.95);
+
+        assert!(exchange_info.filters("ETHUSDT").is_err());
+    }
+}
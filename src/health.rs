@@ -0,0 +1,209 @@
+use crate::metrics::{AkRecord, TimingSummary};
+use crate::replay::QuoteDecision;
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub type SharedHealth = Arc<Mutex<HealthState>>;
+
+/// Liveness state published by the strategy and the websocket tasks.
+#[derive(Debug, Clone, Default)]
+pub struct HealthState {
+    pub feed_connected: bool,
+    pub warmed_up: bool,
+    /// Local time (ms) the last book ticker was processed, 0 before any.
+    pub last_tick_ms: u64,
+    pub in_stoploss: bool,
+    pub paused: bool,
+    pub inventory: f64,
+    /// Share of time both quotes rested, see `metrics::QuoteUptime`.
+    pub quote_uptime: Option<f64>,
+    pub buy_fills: u64,
+    pub sell_fills: u64,
+    /// Average markout (bps) of each side's recent fills.
+    pub buy_markout_bps: Option<f64>,
+    pub sell_markout_bps: Option<f64>,
+    /// Session cost (quote asset) of stoploss exits against the wap.
+    pub exit_slippage: f64,
+    /// Order submissions rejected in a row.
+    pub order_failures: u64,
+    /// Book tickers dropped as stale or duplicate, see `dedup_update_id`.
+    pub dropped_ticks: u64,
+    /// Multiplier the fill rate controller applies, with `fill_rate_target`.
+    pub spread_multiplier: Option<f64>,
+    /// Why the last cycle posted nothing, None once one posts.
+    pub skip_reason: Option<&'static str>,
+    /// Last cycle that did post.
+    pub last_good_quote: Option<QuoteDecision>,
+    /// Round trip (ms) of the startup probe order and its cancel, with
+    /// `order_probe`.
+    pub probe_round_trip_ms: Option<u64>,
+    /// A/k fits accepted so far and the last one.
+    pub ak_fits: u64,
+    pub last_fit: Option<AkRecord>,
+    /// Call timings, with `time_calls`.
+    pub spread_timing: Option<TimingSummary>,
+    pub estimate_timing: Option<TimingSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub feed_connected: bool,
+    pub warmed_up: bool,
+    pub last_tick_age_ms: Option<u64>,
+    pub in_stoploss: bool,
+    pub paused: bool,
+    pub inventory: f64,
+    pub quote_uptime: Option<f64>,
+    pub buy_fills: u64,
+    pub sell_fills: u64,
+    pub buy_markout_bps: Option<f64>,
+    pub sell_markout_bps: Option<f64>,
+    pub exit_slippage: f64,
+    pub order_failures: u64,
+    pub dropped_ticks: u64,
+    pub spread_multiplier: Option<f64>,
+    pub skip_reason: Option<&'static str>,
+    pub last_good_quote: Option<QuoteDecision>,
+    pub probe_round_trip_ms: Option<u64>,
+    pub ak_fits: u64,
+    pub last_fit: Option<AkRecord>,
+    pub spread_timing: Option<TimingSummary>,
+    pub estimate_timing: Option<TimingSummary>,
+    pub ready: bool,
+}
+
+impl HealthState {
+    /// Ready means warmed up on a live feed that ticked within `stale_ms`.
+    pub fn report(&self, now_ms: u64, stale_ms: u64) -> HealthReport {
+        let last_tick_age_ms = match self.last_tick_ms {
+            0 => None,
+            ts => Some(now_ms.saturating_sub(ts)),
+        };
+        let fresh = last_tick_age_ms.is_some_and(|age| age <= stale_ms);
+
+        HealthReport {
+            feed_connected: self.feed_connected,
+            warmed_up: self.warmed_up,
+            last_tick_age_ms: last_tick_age_ms,
+            in_stoploss: self.in_stoploss,
+            paused: self.paused,
+            inventory: self.inventory,
+            quote_uptime: self.quote_uptime,
+            buy_fills: self.buy_fills,
+            sell_fills: self.sell_fills,
+            buy_markout_bps: self.buy_markout_bps,
+            sell_markout_bps: self.sell_markout_bps,
+            exit_slippage: self.exit_slippage,
+            order_failures: self.order_failures,
+            dropped_ticks: self.dropped_ticks,
+            spread_multiplier: self.spread_multiplier,
+            skip_reason: self.skip_reason,
+            last_good_quote: self.last_good_quote.clone(),
+            probe_round_trip_ms: self.probe_round_trip_ms,
+            ak_fits: self.ak_fits,
+            last_fit: self.last_fit,
+            spread_timing: self.spread_timing.clone(),
+            estimate_timing: self.estimate_timing.clone(),
+            ready: self.feed_connected && self.warmed_up && fresh,
+        }
+    }
+}
+
+pub fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+/// Serve `GET /health` (liveness, always 200) and `GET /ready` (200 when
+/// ready, 503 otherwise), both with the JSON report as body.
+pub async fn serve(port: u16, health: SharedHealth, stale_ms: u64) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("health endpoint listening on {}", listener.local_addr()?);
+    serve_listener(listener, health, stale_ms).await
+}
+
+pub async fn serve_listener(
+    listener: TcpListener,
+    health: SharedHealth,
+    stale_ms: u64,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        if let Err(err) = handle(stream, &health, stale_ms).await {
+            warn!("health request Error: {}", err);
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, health: &SharedHealth, stale_ms: u64) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let report = health.lock().unwrap().report(now_ms(), stale_ms);
+    let status = match path {
+        "/health" => "200 OK",
+        "/ready" if report.ready => "200 OK",
+        "/ready" => "503 Service Unavailable",
+        _ => "404 Not Found",
+    };
+    let body = serde_json::to_string(&report)?;
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[actix_rt::test]
+    async fn test_readiness_flips_after_warmup() {
+        let health: SharedHealth = Arc::new(Mutex::new(HealthState::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        actix_rt::spawn(serve_listener(listener, health.clone(), 5000));
+
+        {
+            let mut state = health.lock().unwrap();
+            state.feed_connected = true;
+            state.last_tick_ms = now_ms();
+        }
+        let response = get(addr, "/ready").await;
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains("\"warmed_up\":false"));
+        assert!(get(addr, "/health").await.starts_with("HTTP/1.1 200"));
+
+        health.lock().unwrap().warmed_up = true;
+        let response = get(addr, "/ready").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("\"ready\":true"));
+
+        // a stale feed is not ready even when warmed up
+        health.lock().unwrap().last_tick_ms = now_ms() - 10000;
+        assert!(get(addr, "/ready").await.starts_with("HTTP/1.1 503"));
+    }
+}
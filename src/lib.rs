@@ -1,3 +1,16 @@
+pub mod agg_trade;
 pub mod config;
+pub mod control;
+pub mod error;
+pub mod exchange;
+pub mod exchange_info;
+pub mod health;
+pub mod mark_price;
+pub mod metrics;
+pub mod reference_feed;
+pub mod replay;
 pub mod strategies;
+pub mod stream;
+pub mod sweep;
+pub mod user_stream;
 pub mod util;
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+pub type SharedMark = Arc<Mutex<MarkPrice>>;
+
+/// Last mark price pushed by the `<symbol>@markPrice@1s` stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MarkPrice {
+    pub price: f64,
+    /// Exchange event time (ms), 0 before the first update.
+    pub timestamp: u64,
+}
+
+impl MarkPrice {
+    /// The mark, if one arrived within `stale_ms` of `now`.
+    pub fn fresh(&self, now: u64, stale_ms: u64) -> Option<f64> {
+        if self.timestamp == 0 || now.saturating_sub(self.timestamp) > stale_ms {
+            None
+        } else {
+            Some(self.price)
+        }
+    }
+}
+
+/// `markPriceUpdate` event, prices are kept as sent and parsed on update.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarkPriceEvent {
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "p")]
+    pub mark_price: String,
+}
+
+impl MarkPriceEvent {
+    pub fn mark(&self) -> Result<MarkPrice> {
+        Ok(MarkPrice {
+            price: self.mark_price.parse()?,
+            timestamp: self.event_time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mark_price_event() {
+        let event: MarkPriceEvent = serde_json::from_str(
+            r#"{"e":"markPriceUpdate","E":1562305380000,"s":"BTCUSDT","p":"11794.15000000","i":"11784.62659091","P":"11784.25641265","r":"0.00038167","T":1562306400000}"#,
+        )
+        .unwrap();
+        let mark = event.mark().unwrap();
+
+        assert_eq!(mark.price, 11794.15);
+        assert_eq!(mark.fresh(1562305381000, 5000), Some(11794.15));
+        assert_eq!(mark.fresh(1562305386000, 5000), None);
+        assert_eq!(MarkPrice::default().fresh(1562305380000, 5000), None);
+    }
+}
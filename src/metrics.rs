@@ -0,0 +1,297 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::time::Duration;
+
+const TIMING_BUCKETS: usize = 32;
+
+/// Call durations (µs) in power of two buckets, bucket `i` counting the ones
+/// below 2^i µs.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: [u64; TIMING_BUCKETS],
+    count: u64,
+    sum_us: u64,
+    max_us: u64,
+}
+
+/// What the health report shows of a `Histogram`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimingSummary {
+    pub count: u64,
+    pub mean_us: f64,
+    /// Upper bound of the bucket holding the median and the 99th percentile.
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (64 - us.leading_zeros() as usize).min(TIMING_BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    /// Upper bound (µs) of the bucket the `q` quantile falls in, capped at
+    /// the max seen.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some((1u64 << i).min(self.max_us));
+            }
+        }
+        Some(self.max_us)
+    }
+
+    pub fn summary(&self) -> Option<TimingSummary> {
+        Some(TimingSummary {
+            count: self.count,
+            mean_us: self.sum_us as f64 / self.count as f64,
+            p50_us: self.quantile(0.5)?,
+            p99_us: self.quantile(0.99)?,
+            max_us: self.max_us,
+        })
+    }
+}
+
+/// An accepted A/k fit, one row of the `ak_series_file` csv.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AkRecord {
+    pub timestamp: u64,
+    pub buy_a: f64,
+    pub buy_k: f64,
+    pub sell_a: f64,
+    pub sell_k: f64,
+    /// Spread buckets with fills on the sparser side, what
+    /// `min_nonzero_buckets` gates the fit on.
+    pub fit_quality: usize,
+}
+
+/// Append `rows` to the csv at `path`, with the header if the file is new.
+pub fn append_ak_series(path: &str, rows: &[AkRecord]) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let header = file.metadata()?.len() == 0;
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(header)
+        .from_writer(file);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_ak_series(path: &str) -> Result<Vec<AkRecord>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut rows = Vec::new();
+    for row in reader.deserialize() {
+        rows.push(row?);
+    }
+    Ok(rows)
+}
+
+/// Share of time both a bid and an ask of ours were resting, from the order
+/// updates, counted since the first one.
+#[derive(Debug, Default)]
+pub struct QuoteUptime {
+    bids: HashSet<u64>,
+    asks: HashSet<u64>,
+    start: Option<u64>,
+    last: u64,
+    both_ms: u64,
+}
+
+impl QuoteUptime {
+    fn both(&self) -> bool {
+        !self.bids.is_empty() && !self.asks.is_empty()
+    }
+
+    fn advance(&mut self, ts: u64) {
+        if self.start.is_none() {
+            self.start = Some(ts);
+            self.last = ts;
+        }
+        if ts > self.last {
+            if self.both() {
+                self.both_ms += ts - self.last;
+            }
+            self.last = ts;
+        }
+    }
+
+    /// Order `order_id` placed (`open`) or gone (filled, canceled, expired)
+    /// at `ts` ms.
+    pub fn on_order(&mut self, ts: u64, order_id: u64, buy: bool, open: bool) {
+        self.advance(ts);
+        let side = if buy { &mut self.bids } else { &mut self.asks };
+        if open {
+            side.insert(order_id);
+        } else {
+            side.remove(&order_id);
+        }
+    }
+
+    /// Uptime fraction up to `now` ms, None before any time has passed.
+    pub fn fraction(&self, now: u64) -> Option<f64> {
+        let start = self.start?;
+        if now <= start {
+            return None;
+        }
+        let mut both_ms = self.both_ms;
+        if self.both() && now > self.last {
+            both_ms += now - self.last;
+        }
+        Some(both_ms as f64 / (now - start) as f64)
+    }
+}
+
+/// Fills of one side and their last `window` markouts (bps).
+#[derive(Debug, Default, Clone)]
+pub struct SideMarkout {
+    pub fills: u64,
+    markouts: VecDeque<f64>,
+}
+
+impl SideMarkout {
+    pub fn average(&self) -> Option<f64> {
+        if self.markouts.is_empty() {
+            return None;
+        }
+        Some(self.markouts.iter().sum::<f64>() / self.markouts.len() as f64)
+    }
+
+    /// A full window averaging a loss.
+    pub fn adverse(&self, window: usize) -> bool {
+        self.markouts.len() >= window && self.average().is_some_and(|x| x < 0f64)
+    }
+}
+
+/// Move of the wap `horizon_ms` after each fill, in bps of the fill price and
+/// signed so that positive means the price went our way.
+#[derive(Debug, Clone)]
+pub struct Markout {
+    pub horizon_ms: u64,
+    pub window: usize,
+    /// (fill time, buy, price) waiting for the horizon to pass.
+    pending: VecDeque<(u64, bool, f64)>,
+    pub buy: SideMarkout,
+    pub sell: SideMarkout,
+}
+
+impl Markout {
+    pub fn new(horizon_ms: u64, window: usize) -> Self {
+        Markout {
+            horizon_ms: horizon_ms,
+            window: window,
+            pending: VecDeque::new(),
+            buy: SideMarkout::default(),
+            sell: SideMarkout::default(),
+        }
+    }
+
+    pub fn on_fill(&mut self, ts: u64, buy: bool, price: f64) {
+        if buy {
+            self.buy.fills += 1;
+        } else {
+            self.sell.fills += 1;
+        }
+        self.pending.push_back((ts, buy, price));
+    }
+
+    /// Settle the fills whose horizon passed by `ts` against `wap`.
+    pub fn on_wap(&mut self, ts: u64, wap: f64) {
+        while let Some(&(fill_ts, buy, price)) = self.pending.front() {
+            if fill_ts + self.horizon_ms > ts {
+                break;
+            }
+            self.pending.pop_front();
+
+            let (side, sign) = if buy {
+                (&mut self.buy, 1f64)
+            } else {
+                (&mut self.sell, -1f64)
+            };
+            side.markouts.push_back(sign * (wap - price) / price * 1e4);
+            if side.markouts.len() > self.window {
+                side.markouts.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.summary(), None);
+
+        for us in [3, 5, 6, 7, 900] {
+            histogram.record(Duration::from_micros(us));
+        }
+        let summary = histogram.summary().unwrap();
+        assert_eq!(summary.count, 5);
+        assert!((summary.mean_us - 184.2).abs() < 1e-9);
+        // 5, 6 and 7 share the [4, 8) bucket
+        assert_eq!(summary.p50_us, 8);
+        assert_eq!(summary.p99_us, 900);
+        assert_eq!(summary.max_us, 900);
+    }
+
+    #[test]
+    fn test_markout() {
+        let mut markout = Markout::new(1000, 2);
+
+        markout.on_fill(0, true, 50000.);
+        markout.on_fill(100, false, 50010.);
+        // horizon not reached yet
+        markout.on_wap(500, 49990.);
+        assert_eq!(markout.buy.average(), None);
+
+        // both sides run over, price falls after the buy and the sell
+        markout.on_wap(1100, 49995.);
+        assert_eq!(markout.buy.fills, 1);
+        assert_eq!(markout.sell.fills, 1);
+        assert!((markout.buy.average().unwrap() + 1.).abs() < 1e-9);
+        assert!((markout.sell.average().unwrap() - 15. / 50010. * 1e4).abs() < 1e-9);
+        assert!(!markout.buy.adverse(2));
+
+        markout.on_fill(2000, true, 50000.);
+        markout.on_wap(3000, 49975.);
+        assert!((markout.buy.average().unwrap() + 3.).abs() < 1e-9);
+        assert!(markout.buy.adverse(2));
+        assert!(!markout.sell.adverse(2));
+    }
+
+    #[test]
+    fn test_quote_uptime() {
+        let mut uptime = QuoteUptime::default();
+        assert_eq!(uptime.fraction(1000), None);
+
+        uptime.on_order(1000, 1, true, true);
+        // only the bid rests until the ask is placed
+        uptime.on_order(2000, 2, false, true);
+        uptime.on_order(4000, 1, true, false);
+        uptime.on_order(4000, 3, true, true);
+        // the ask is canceled and the repost fails
+        uptime.on_order(5000, 2, false, false);
+        assert_eq!(uptime.fraction(5000), Some(3000. / 4000.));
+        assert_eq!(uptime.fraction(9000), Some(3000. / 8000.));
+
+        uptime.on_order(9000, 4, false, true);
+        assert_eq!(uptime.fraction(11000), Some(5000. / 10000.));
+    }
+}
@@ -0,0 +1,51 @@
+use exrs::binance_f::ws_model::BookTickerEvent;
+use std::sync::{Arc, Mutex};
+
+pub type SharedReference = Arc<Mutex<ReferencePrice>>;
+
+/// Last mid of the secondary book the quotes are partly anchored on, e.g. a
+/// more liquid contract of the same asset.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReferencePrice {
+    pub mid: f64,
+    /// Exchange transaction time (ms), 0 before the first update.
+    pub timestamp: u64,
+}
+
+impl ReferencePrice {
+    pub fn from_book(event: &BookTickerEvent) -> Self {
+        ReferencePrice {
+            mid: (event.best_bid + event.best_ask) / 2f64,
+            timestamp: event.transaction_time,
+        }
+    }
+
+    /// The mid, if one arrived within `stale_ms` of `now`.
+    pub fn fresh(&self, now: u64, stale_ms: u64) -> Option<f64> {
+        if self.timestamp == 0 || now.saturating_sub(self.timestamp) > stale_ms {
+            None
+        } else {
+            Some(self.mid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reference_price() {
+        let event: BookTickerEvent = serde_json::from_str(
+            r#"{"e":"bookTicker","u":400900217,"E":1568014460893,"T":1568014460891,"s":"BTCBUSD","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#,
+        )
+        .unwrap();
+        let reference = ReferencePrice::from_book(&event);
+
+        assert!((reference.mid - 25.35855).abs() < 1e-9);
+        assert_eq!(reference.timestamp, 1568014460891);
+        assert!(reference.fresh(1568014462891, 5000).is_some());
+        assert_eq!(reference.fresh(1568014470000, 5000), None);
+        assert_eq!(ReferencePrice::default().fresh(1568014460891, 5000), None);
+    }
+}
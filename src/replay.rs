@@ -0,0 +1,324 @@
+use anyhow::Result;
+use exrs::binance_f::ws_model::BookTickerEvent;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+
+/// A quoting decision, one row of the recorded quotes csv.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuoteRecord {
+    /// Transaction time (ms) of the tick the quote was decided on.
+    pub timestamp: u64,
+    pub bid_price: f64,
+    pub bid_qty: f64,
+    pub ask_price: f64,
+    pub ask_qty: f64,
+}
+
+impl QuoteRecord {
+    fn same(&self, other: &QuoteRecord) -> bool {
+        let eq = |a: f64, b: f64| a == b || (a.is_nan() && b.is_nan());
+        eq(self.bid_price, other.bid_price)
+            && eq(self.bid_qty, other.bid_qty)
+            && eq(self.ask_price, other.ask_price)
+            && eq(self.ask_qty, other.ask_qty)
+    }
+}
+
+/// Everything that went into one quoting cycle, logged as a single json
+/// line with `log_decisions`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuoteDecision {
+    pub timestamp: u64,
+    pub wap: f64,
+    pub sigma: f64,
+    pub q: f64,
+    pub buy_a: f64,
+    pub buy_k: f64,
+    pub sell_a: f64,
+    pub sell_k: f64,
+    /// Model spreads (price units) below and above the fair value.
+    pub bid_offset: f64,
+    pub ask_offset: f64,
+    pub bid_price: f64,
+    pub bid_qty: f64,
+    pub ask_price: f64,
+    pub ask_qty: f64,
+    pub bid_placed: bool,
+    pub ask_placed: bool,
+    /// Why a side was not placed.
+    pub bid_skip: Option<&'static str>,
+    pub ask_skip: Option<&'static str>,
+}
+
+impl QuoteDecision {
+    pub fn record(&self) -> QuoteRecord {
+        QuoteRecord {
+            timestamp: self.timestamp,
+            bid_price: self.bid_price,
+            bid_qty: self.bid_qty,
+            ask_price: self.ask_price,
+            ask_qty: self.ask_qty,
+        }
+    }
+}
+
+/// A tick where the replayed decision differs from the recorded one. A side
+/// is `None` when only the other run quoted on that tick.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteDiff {
+    pub timestamp: u64,
+    pub recorded: Option<QuoteRecord>,
+    pub replayed: Option<QuoteRecord>,
+}
+
+impl QuoteDiff {
+    /// Replayed minus recorded (bid_price, bid_qty, ask_price, ask_qty), when
+    /// both runs quoted.
+    pub fn deltas(&self) -> Option<(f64, f64, f64, f64)> {
+        match (self.recorded, self.replayed) {
+            (Some(a), Some(b)) => Some((
+                b.bid_price - a.bid_price,
+                b.bid_qty - a.bid_qty,
+                b.ask_price - a.ask_price,
+                b.ask_qty - a.ask_qty,
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Per-tick differences between two runs, matched on timestamp. Both inputs
+/// are expected in time order.
+pub fn diff(recorded: &[QuoteRecord], replayed: &[QuoteRecord]) -> Vec<QuoteDiff> {
+    let mut diffs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < recorded.len() || j < replayed.len() {
+        let a = recorded.get(i);
+        let b = replayed.get(j);
+        let diff = match (a, b) {
+            (Some(a), Some(b)) if a.timestamp == b.timestamp => {
+                i += 1;
+                j += 1;
+                if a.same(b) {
+                    continue;
+                }
+                QuoteDiff {
+                    timestamp: a.timestamp,
+                    recorded: Some(*a),
+                    replayed: Some(*b),
+                }
+            }
+            (Some(a), Some(b)) if a.timestamp < b.timestamp => {
+                i += 1;
+                QuoteDiff {
+                    timestamp: a.timestamp,
+                    recorded: Some(*a),
+                    replayed: None,
+                }
+            }
+            (Some(a), None) => {
+                i += 1;
+                QuoteDiff {
+                    timestamp: a.timestamp,
+                    recorded: Some(*a),
+                    replayed: None,
+                }
+            }
+            (_, Some(b)) => {
+                j += 1;
+                QuoteDiff {
+                    timestamp: b.timestamp,
+                    recorded: None,
+                    replayed: Some(*b),
+                }
+            }
+            (None, None) => break,
+        };
+        diffs.push(diff);
+    }
+
+    diffs
+}
+
+pub fn read_quotes(path: &str) -> Result<Vec<QuoteRecord>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut quotes = Vec::new();
+    for record in reader.deserialize() {
+        quotes.push(record?);
+    }
+    Ok(quotes)
+}
+
+pub fn write_quotes(path: &str, quotes: &[QuoteRecord]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for quote in quotes {
+        writer.serialize(quote)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Outcome of replayed quotes against the ticks that followed them. A quote
+/// rests from the tick after the one it was decided on and each side fills
+/// once, at its price, when the touch trades through it. Fees are left out.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct Report {
+    pub quotes: usize,
+    pub fills: usize,
+    /// Cash plus inventory marked at the last mid, quote asset.
+    pub pnl: f64,
+    pub inventory: f64,
+    pub max_drawdown: f64,
+    /// Mean over std of the per tick pnl changes, not annualized.
+    pub sharpe: f64,
+}
+
+pub fn simulate(ticks: &[Box<BookTickerEvent>], quotes: &[QuoteRecord]) -> Report {
+    let mut report = Report {
+        quotes: quotes.len(),
+        ..Report::default()
+    };
+    let mut next = 0;
+    let mut resting: Option<QuoteRecord> = None;
+    let mut cash = 0f64;
+    let (mut last, mut peak) = (0f64, 0f64);
+    let mut changes = Vec::with_capacity(ticks.len());
+    for tick in ticks {
+        if let Some(quote) = resting.as_mut() {
+            if quote.bid_qty > 0f64 && tick.best_ask <= quote.bid_price {
+                cash -= quote.bid_qty * quote.bid_price;
+                report.inventory += quote.bid_qty;
+                report.fills += 1;
+                quote.bid_qty = 0f64;
+            }
+            if quote.ask_qty > 0f64 && tick.best_bid >= quote.ask_price {
+                cash += quote.ask_qty * quote.ask_price;
+                report.inventory -= quote.ask_qty;
+                report.fills += 1;
+                quote.ask_qty = 0f64;
+            }
+        }
+        while next < quotes.len() && quotes[next].timestamp <= tick.transaction_time {
+            resting = Some(quotes[next]);
+            next += 1;
+        }
+
+        let pnl = cash + report.inventory * (tick.best_bid + tick.best_ask) / 2f64;
+        changes.push(pnl - last);
+        last = pnl;
+        peak = peak.max(pnl);
+        report.max_drawdown = report.max_drawdown.max(peak - pnl);
+    }
+    report.pnl = last;
+    report.sharpe = sharpe(&changes);
+    report
+}
+
+fn sharpe(changes: &[f64]) -> f64 {
+    if changes.len() < 2 {
+        return 0f64;
+    }
+    let n = changes.len() as f64;
+    let mean = changes.iter().sum::<f64>() / n;
+    let std = (changes.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+    if std > 0f64 {
+        mean / std
+    } else {
+        0f64
+    }
+}
+
+/// Recorded book tickers, one wire-format `bookTicker` event per line.
+pub fn read_ticks(path: &str) -> Result<Vec<Box<BookTickerEvent>>> {
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut ticks = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        ticks.push(Box::new(serde_json::from_str(&line)?));
+    }
+    Ok(ticks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn tick(bid: f64, ask: f64, ts: u64) -> Box<BookTickerEvent> {
+        Box::new(
+            serde_json::from_value(json!({
+                "e": "bookTicker",
+                "u": ts,
+                "E": ts,
+                "T": ts,
+                "s": "BTCUSDT",
+                "b": bid.to_string(),
+                "B": "1",
+                "a": ask.to_string(),
+                "A": "1"
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn quote(timestamp: u64, bid_price: f64) -> QuoteRecord {
+        QuoteRecord {
+            timestamp: timestamp,
+            bid_price: bid_price,
+            bid_qty: 0.001,
+            ask_price: 50010.,
+            ask_qty: 0.001,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let recorded = vec![quote(1, 49990.), quote(2, 49990.), quote(3, 49990.)];
+        assert!(diff(&recorded, &recorded).is_empty());
+
+        let replayed = vec![quote(1, 49990.), quote(2, 49980.), quote(4, 49990.)];
+        let diffs = diff(&recorded, &replayed);
+
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(diffs[0].deltas(), Some((-10., 0., 0., 0.)));
+        assert_eq!(diffs[1].timestamp, 3);
+        assert_eq!(diffs[1].replayed, None);
+        assert_eq!(diffs[2].timestamp, 4);
+        assert_eq!(diffs[2].recorded, None);
+    }
+
+    #[test]
+    fn test_simulate() {
+        let ticks = vec![
+            tick(99.5, 100.5, 1),
+            tick(99.5, 100.5, 2),
+            // trades through the bid, then the ask
+            tick(98.5, 99., 3),
+            tick(101., 101.5, 4),
+            tick(98., 98.5, 5),
+        ];
+        let quotes = vec![QuoteRecord {
+            timestamp: 1,
+            bid_price: 99.,
+            bid_qty: 1.,
+            ask_price: 101.,
+            ask_qty: 1.,
+        }];
+        let report = simulate(&ticks, &quotes);
+        assert_eq!(report.quotes, 1);
+        // each side fills once
+        assert_eq!(report.fills, 2);
+        assert_eq!(report.inventory, 0.);
+        assert_eq!(report.pnl, 2.);
+        // long at 99 marked at 98.75
+        assert_eq!(report.max_drawdown, 0.25);
+        assert!(report.sharpe > 0.);
+
+        assert_eq!(simulate(&ticks, &[]).fills, 0);
+        assert_eq!(simulate(&ticks, &[]).sharpe, 0.);
+    }
+}
@@ -1,28 +1,147 @@
 use super::eie::{
     calibration::aksolver_factory::{AkSolverFactory, SolverType},
+    estimator_executor::EstimatorExecutor,
     intensity_estimator::IntensityEstimator,
     intensity_info::IntensityInfo,
 };
-use crate::{config::Config, util};
+use crate::{
+    agg_trade::{self, LastTrade, SharedTrade},
+    config::{
+        AsVariant, Config, ContractType, DivergenceAction, FairValue, PnlPrice, PnlUnits,
+        PositionMode, SigmaEstimator, StoplossAction, StoplossOrderType, TouchDistanceAction,
+        Units,
+    },
+    control::{ControlState, Params, SharedControl},
+    error::{self, Error},
+    exchange::{
+        self, journal::JournalExchange, ClientOrderIds, ExchangeClient, OrderAck, OrderFlags,
+        PositionInfo,
+    },
+    exchange_info::{self, PriceBand, SymbolFilters},
+    health::{self, HealthState, SharedHealth},
+    mark_price::{MarkPrice, SharedMark},
+    metrics::{self, AkRecord, Histogram, Markout, QuoteUptime},
+    reference_feed::{ReferencePrice, SharedReference},
+    replay::{QuoteDecision, QuoteRecord},
+    util::{self, RoundMode},
+};
 
 use anyhow::Result;
 use exrs::binance_f::{
     account::{FuturesAccount, PositionSide},
     api::BinanceF,
-    rest_model::TimeInForce,
+    rest_model::{OrderSide, OrderStatus, TimeInForce},
     util::get_timestamp,
-    ws_model::{AccountUpdateEvent, BookTickerEvent, FuturesWebsocketEvent},
+    ws_model::{AccountUpdateEvent, BookTickerEvent, FuturesWebsocketEvent, OrderTradeUpdateEvent},
 };
-use log::{debug, info, warn};
-use std::collections::VecDeque;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Spread {
     ask: f64,
     bid: f64,
 }
 
+/// One side of the quote left resting by a cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestingOrder {
+    pub order_id: u64,
+    pub price: f64,
+    pub qty: f64,
+    /// Transaction time (ms) of the tick it was quoted on.
+    pub posted_at: u64,
+}
+
+/// Quotes resting since the last cycle, kept with `targeted_cancels`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RestingQuotes {
+    pub bid: Option<RestingOrder>,
+    pub ask: Option<RestingOrder>,
+}
+
+impl RestingQuotes {
+    /// Forget an order no longer open.
+    pub fn close(&mut self, order_id: u64) {
+        if self.bid.is_some_and(|x| x.order_id == order_id) {
+            self.bid = None;
+        }
+        if self.ask.is_some_and(|x| x.order_id == order_id) {
+            self.ask = None;
+        }
+    }
+}
+
+/// SIGINT or SIGTERM, the operator stopping the bot. Never fires when the
+/// handlers couldn't be installed.
+struct StopSignal {
+    signals: Option<(Signal, Signal)>,
+}
+
+impl StopSignal {
+    fn new() -> Self {
+        let signals = signal(SignalKind::interrupt())
+            .and_then(|interrupt| Ok((interrupt, signal(SignalKind::terminate())?)));
+        if let Err(err) = &signals {
+            warn!("signal handlers Error: {}, stop with the feed only", err);
+        }
+        StopSignal {
+            signals: signals.ok(),
+        }
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        match self.signals.as_mut() {
+            Some((interrupt, terminate)) => tokio::select! {
+                _ = interrupt.recv() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Handles a detached quoting cycle shares with the strategy.
+#[derive(Debug, Clone)]
+struct QuoteContext {
+    margin_rejected: Arc<AtomicBool>,
+    order_failures: Arc<AtomicU64>,
+    order_ids: ClientOrderIds,
+    quote_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Set with `targeted_cancels`.
+    resting: Option<Arc<Mutex<RestingQuotes>>>,
+}
+
+/// Orders to post for one quoting cycle, a side with zero qty is skipped.
+#[derive(Debug, Copy, Clone)]
+pub struct Quote {
+    pub bid_price: f64,
+    pub bid_qty: f64,
+    pub ask_price: f64,
+    pub ask_qty: f64,
+    /// PERCENT_PRICE band a rejected side is clamped into for its retry.
+    pub band: Option<PriceBand>,
+    /// First reason a side was zeroed.
+    pub bid_skip: Option<&'static str>,
+    pub ask_skip: Option<&'static str>,
+    /// Transaction time (ms) of the tick the quote was built on.
+    pub timestamp: u64,
+}
+
+/// Keep the first reason `qty` was zeroed for.
+fn note_skip(qty: f64, skip: &mut Option<&'static str>, reason: &'static str) {
+    if qty <= 0f64 && skip.is_none() {
+        *skip = Some(reason);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct StrategyData {
     pub capacity: usize,
@@ -35,6 +154,22 @@ pub struct StrategyData {
     pub imb: VecDeque<f64>,
     pub spread: VecDeque<f64>,
     pub tv: VecDeque<f64>,
+    /// Quoting anchor, `reference_blend * wap + (1 - reference_blend) * mid`.
+    pub reference: VecDeque<f64>,
+    pub reference_blend: f64,
+    /// Ticks pushed so far, indexing the high/low deques.
+    pub seq: u64,
+    /// Monotonic (seq, wap) deques, decreasing and increasing, their fronts
+    /// are the high and low of the window.
+    pub wap_high: VecDeque<(u64, f64)>,
+    pub wap_low: VecDeque<(u64, f64)>,
+    /// Price of the last aggregated trade, when the trade stream is on.
+    pub last_trade: Option<f64>,
+    /// Drop events at or below `last_update_id`.
+    pub dedup: bool,
+    pub last_update_id: Option<u64>,
+    /// Events dropped as stale or duplicate.
+    pub dropped: u64,
 }
 
 impl StrategyData {
@@ -50,10 +185,29 @@ impl StrategyData {
             imb: VecDeque::with_capacity(capacity),
             spread: VecDeque::with_capacity(capacity),
             tv: VecDeque::with_capacity(capacity),
+            reference: VecDeque::with_capacity(capacity),
+            reference_blend: 1f64,
+            seq: 0,
+            wap_high: VecDeque::with_capacity(capacity),
+            wap_low: VecDeque::with_capacity(capacity),
+            last_trade: None,
+            dedup: false,
+            last_update_id: None,
+            dropped: 0,
         }
     }
 
-    pub fn push(&mut self, event: Box<BookTickerEvent>) {
+    /// Take in a book ticker, false when `dedup` dropped it as stale or a
+    /// duplicate.
+    pub fn push(&mut self, event: Box<BookTickerEvent>) -> bool {
+        if self.dedup {
+            if self.last_update_id.is_some_and(|x| event.update_id <= x) {
+                self.dropped += 1;
+                return false;
+            }
+            self.last_update_id = Some(event.update_id);
+        }
+
         if self.timestamp.len() > self.capacity - 1 {
             self.timestamp.pop_front();
             self.ask_price.pop_front();
@@ -64,6 +218,7 @@ impl StrategyData {
             self.imb.pop_front();
             self.spread.pop_front();
             self.tv.pop_front();
+            self.reference.pop_front();
         }
 
         self.timestamp.push_back(event.transaction_time);
@@ -83,6 +238,94 @@ impl StrategyData {
 
         let tv = (wap / self.wap.front().unwrap() - 1f64).abs() + (spread / wap);
         self.tv.push_back(tv);
+
+        let mid = (event.best_bid + event.best_ask) / 2f64;
+        self.reference
+            .push_back(self.reference_blend * wap + (1f64 - self.reference_blend) * mid);
+
+        // drop what the new wap dominates, then what left the window
+        let seq = self.seq;
+        self.seq += 1;
+        let oldest = self.seq - self.wap.len() as u64;
+        while self.wap_high.back().is_some_and(|x| x.1 <= wap) {
+            self.wap_high.pop_back();
+        }
+        self.wap_high.push_back((seq, wap));
+        while self.wap_low.back().is_some_and(|x| x.1 >= wap) {
+            self.wap_low.pop_back();
+        }
+        self.wap_low.push_back((seq, wap));
+        while self.wap_high.front().is_some_and(|x| x.0 < oldest) {
+            self.wap_high.pop_front();
+        }
+        while self.wap_low.front().is_some_and(|x| x.0 < oldest) {
+            self.wap_low.pop_front();
+        }
+        true
+    }
+
+    /// Highest wap in the window.
+    pub fn high(&self) -> Option<f64> {
+        self.wap_high.front().map(|x| x.1)
+    }
+
+    /// Lowest wap in the window.
+    pub fn low(&self) -> Option<f64> {
+        self.wap_low.front().map(|x| x.1)
+    }
+
+    /// Parkinson volatility of the window range, scaled per tick:
+    /// ln(high / low) / sqrt(4 ln 2 * (n - 1)).
+    pub fn range_volatility(&self) -> Option<f64> {
+        let n = self.wap.len();
+        match (self.high(), self.low()) {
+            (Some(high), Some(low)) if n > 1 && low > 0f64 => {
+                let hl = (high / low).ln();
+                Some((hl * hl / (4f64 * 2f64.ln() * (n - 1) as f64)).sqrt())
+            }
+            _ => None,
+        }
+    }
+
+    /// Per tick sigma of the wap log returns, each divided by the square root
+    /// of its interval before aggregating, then scaled to the mean interval.
+    /// Ticks in the same ms are merged into the next return.
+    pub fn time_normalized_volatility(&self) -> Option<f64> {
+        let mut sum = 0f64;
+        let mut n = 0;
+        let (mut ts, mut wap) = (*self.timestamp.front()?, *self.wap.front()?);
+        for (&next_ts, &next_wap) in self.timestamp.iter().zip(self.wap.iter()).skip(1) {
+            if next_ts <= ts {
+                continue;
+            }
+            let r = (next_wap / wap).ln();
+            sum += r * r / (next_ts - ts) as f64;
+            n += 1;
+            ts = next_ts;
+            wap = next_wap;
+        }
+        if n == 0 {
+            return None;
+        }
+        Some((sum / n as f64 * self.tick_interval_ms()?).sqrt())
+    }
+
+    /// Mean time (ms) between the ticks in the window.
+    pub fn tick_interval_ms(&self) -> Option<f64> {
+        match (self.timestamp.front(), self.timestamp.back()) {
+            (Some(first), Some(last)) if last > first => {
+                Some((last - first) as f64 / (self.timestamp.len() - 1) as f64)
+            }
+            _ => None,
+        }
+    }
+
+    /// Signed counterpart of `tv`, the wap return over the window.
+    pub fn momentum(&self) -> f64 {
+        match (self.wap.front(), self.wap.back()) {
+            (Some(first), Some(last)) => last / first - 1f64,
+            _ => 0f64,
+        }
     }
 }
 
@@ -93,44 +336,281 @@ struct Position {
     pub entry_price: f64,
 }
 
+/// A single execution of one of our orders.
+#[derive(Debug, Clone)]
+pub struct Fill {
+    pub client_order_id: String,
+    /// Signed quantity, positive for buys.
+    pub qty: f64,
+    pub price: f64,
+    /// Commission in quote asset.
+    pub fee: f64,
+    pub timestamp: u64,
+}
+
+/// Accounting of a finished daily session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSummary {
+    pub start: u64,
+    pub end: u64,
+    pub total_profit: f64,
+    pub total_fees: f64,
+    pub order_count: u64,
+    pub fill_count: u64,
+}
+
+/// Session accounting persisted to `state_file` so a restart keeps counting
+/// toward the same daily loss limit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountingState {
+    pub session_start: u64,
+    pub total_profit: f64,
+    pub total_fees: f64,
+    pub order_count: u64,
+    pub fill_count: u64,
+}
+
+/// Write `state` to a temp file renamed over `path`, a crash mid-write
+/// leaves the previous state rather than a torn file.
+fn write_state(path: &str, state: &AccountingState) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_string(state)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Save the accounting on a blocking thread off the tick loop, in order,
+/// skipping to the latest state when saves queue up. Ends once the sender
+/// is dropped and the queue is written.
+fn spawn_state_writer(path: String) -> (mpsc::UnboundedSender<AccountingState>, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<AccountingState>();
+    let writer = tokio::spawn(async move {
+        while let Some(mut state) = rx.recv().await {
+            while let Ok(next) = rx.try_recv() {
+                state = next;
+            }
+            let target = path.clone();
+            match tokio::task::spawn_blocking(move || write_state(&target, &state)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("save state to {} Error: {}", path, err),
+                Err(err) => warn!("save state to {} Error: {}", path, err),
+            }
+        }
+    });
+    (tx, writer)
+}
+
+/// Average-cost book of the filled inventory, used to realize pnl when
+/// fills reduce the position. Fed 1 / price for an inverse contract.
+#[derive(Debug, Clone, Default)]
+pub struct CostBasis {
+    pub qty: f64,
+    pub avg_price: f64,
+}
+
+impl CostBasis {
+    /// Apply a signed fill and return the gross pnl it realized.
+    pub fn on_fill(&mut self, qty: f64, price: f64) -> f64 {
+        if self.qty * qty >= 0f64 {
+            let new_qty = self.qty + qty;
+            if new_qty != 0f64 {
+                self.avg_price = (self.qty * self.avg_price + qty * price) / new_qty;
+            }
+            self.qty = new_qty;
+            return 0f64;
+        }
+
+        let closed = qty.abs().min(self.qty.abs());
+        let realized = closed * (price - self.avg_price) * self.qty.signum();
+
+        self.qty += qty;
+        if self.qty.abs() < 1e-12 {
+            self.qty = 0f64;
+            self.avg_price = 0f64;
+        } else if self.qty.signum() == qty.signum() {
+            // flipped through flat, the remainder was opened at this price
+            self.avg_price = price;
+        }
+
+        realized
+    }
+}
+
 pub struct AvellanedaStoikov {
     config: Config,
+    /// Local ms, see `clock` for the exchange time.
     start_time: u64,
+    clock: util::ClockOffset,
     timer: u64,
-    account_client: FuturesAccount,
+    account_client: Arc<dyn ExchangeClient>,
     strategy_data: StrategyData,
     base_asset: String,
-    quote_asset: String,
+    /// Asset of the balance and fees counted, see `Config::margin_asset`.
+    margin_asset: String,
     pair: String,
     order_qty: f64,
+    order_notional: Option<f64>,
     tick_size: f64,
     tick_round: u32,
+    /// Decimals of the order quantities, None leaves them as they are.
+    qty_round: Option<u32>,
+    /// Book prices sampled until tick_size is inferred.
+    tick_samples: Option<Vec<f64>>,
+    /// PERCENT_PRICE (multiplier_up, multiplier_down) from the filters.
+    percent_price: Option<(f64, f64)>,
+    step_size: f64,
+    min_notional: f64,
     n_spreads: usize,
     estimate_window: u64,
-    period: u64,
+    quote_period: u64,
+    quote_jitter: Option<util::Jitter>,
+    /// Delay (ms) added to the quote period before the next refresh.
+    jitter_ms: u64,
+    /// Draws of `order_qty_range` and the size they gave this cycle.
+    qty_draws: Option<util::Jitter>,
+    drawn_qty: Option<f64>,
+    estimate_cadence: util::Cadence,
+    /// Fits not yet appended to `ak_series_file`.
+    ak_series: Vec<AkRecord>,
+    ak_export_cadence: util::Cadence,
+    sigma_cadence: util::Cadence,
+    intensity_info: Option<IntensityInfo>,
     gamma: f64,
     sigma_multiplier: f64,
     ie: IntensityEstimator,
+    /// Runs the fits with `background_estimate`.
+    executor: Option<EstimatorExecutor>,
     sigma: f64,
+    sigma_baseline: Option<f64>,
     buy_a: f64,
     buy_k: f64,
     sell_a: f64,
     sell_k: f64,
     position: Position,
+    long: Position,
+    short: Position,
     cash: f64,
+    cost_basis: CostBasis,
     total_profit: f64,
+    total_fees: f64,
+    order_count: u64,
+    fill_count: u64,
+    session_start: u64,
+    session_loss_breached: bool,
     stoploss: f64,
+    /// Sleep of the current stoploss, escalated on repeats.
     stoploss_sleep: u64,
+    last_stoploss: u64,
+    stoploss_repeats: i32,
+    stoploss_latch: util::Latch,
+    stopprofit_latch: util::Latch,
     stopprofit: f64,
     in_stoploss: bool,
     unrealized_pnl: f64,
+    in_pause: bool,
+    /// Last scheduled flatten time (ms) handled, and the end of the pause
+    /// that follows it.
+    flattened_at: u64,
+    flatten_pause_until: u64,
+    pause_file_cadence: util::Cadence,
+    /// Behind the feed by more than `max_feed_backlog` events.
+    lagging: bool,
+    lag_pauses: u64,
+    /// Pause flag file seen at the last poll.
+    file_paused: bool,
+    /// False while the position has no valid entry price to mark against.
+    pnl_context: bool,
     trailing_stop: f64,
     active_trailing_stop: bool,
+    peak_pnl: f64,
+    peak_side: i8,
+    /// Reason and order type of a flatten awaiting confirmation.
+    pending_flatten: Option<(String, StoplossOrderType)>,
+    flatten_attempts: u32,
+    /// Exit orders acked with qty left to fill, by order id. The residual is
+    /// only re-sent once they reach a terminal state.
+    exits_in_flight: HashMap<u64, f64>,
+    /// Pre-trade wap of the stoploss exits awaiting their fill, by client
+    /// order id.
+    exit_wap: HashMap<String, f64>,
+    total_slippage: f64,
+    open_orders: HashSet<u64>,
+    resting: Arc<Mutex<RestingQuotes>>,
+    reconcile_cadence: Option<util::Cadence>,
+    /// Inventory (order_qty units) integrated over the minutes it has been
+    /// held on the same side, reset when flat or flipped.
+    held_inventory: f64,
+    held_ts: u64,
+    /// Time (ms) of the last buy and sell fill, 0 before any.
+    last_buy_fill: u64,
+    last_sell_fill: u64,
+    quote_uptime: QuoteUptime,
+    markout: Option<Markout>,
+    /// Durations of calculate_spread and the A/k fit, with `time_calls`.
+    spread_timing: Option<Histogram>,
+    estimate_timing: Option<Histogram>,
+    low_uptime: bool,
+    /// EMA of the reference quoted around, with `anchor_ema_span`.
+    anchor: Option<util::Ema>,
+    /// Position seen on the last tick and when (exchange ms) it last opened
+    /// or grew, for `stoploss_grace_ms`.
+    grace_amount: f64,
+    grace_since: u64,
+    margin_rejected: Arc<AtomicBool>,
+    /// Order submissions rejected in a row, reset by an accepted one.
+    order_failures: Arc<AtomicU64>,
+    /// Names the orders after `strategy_tag`.
+    order_ids: ClientOrderIds,
+    /// End (ms) of the pause `max_order_failures` tripped, 0 when none.
+    failure_pause_until: u64,
+    /// Set while the user data stream could not be kept alive, positions are
+    /// polled over REST meanwhile.
+    user_stream_down: Arc<AtomicBool>,
+    position_poll_cadence: util::Cadence,
+    quote_task: Option<JoinHandle<()>>,
+    /// Set by run_forever, see `spawn_state_writer`. Without it the state is
+    /// written inline.
+    state_writer: Option<(mpsc::UnboundedSender<AccountingState>, JoinHandle<()>)>,
+    quote_lock: Arc<tokio::sync::Mutex<()>>,
+    last_quote: Option<QuoteRecord>,
+    /// Last finite spread computed, posted or not, and why the last cycle
+    /// posted nothing.
+    last_spread: Option<Spread>,
+    skip_reason: Option<&'static str>,
+    /// Offsets quoted last, the base of the `max_spread_step` cap.
+    applied_spread: Option<Spread>,
+    /// Fill times (ms) within `fill_rate_window_ms`, with `fill_rate_target`.
+    fill_times: VecDeque<u64>,
+    spread_multiplier: f64,
     q_max: f64,
+    warmed_up: bool,
+    health: SharedHealth,
+    control: SharedControl,
+    mark: SharedMark,
+    trade: SharedTrade,
+    /// Mid of the `reference_symbol` book.
+    secondary: SharedReference,
 }
 
 impl AvellanedaStoikov {
     pub fn new(config: Config) -> Box<Self> {
+        let account_client: FuturesAccount =
+            BinanceF::new(config.api_key.clone(), config.secret_key.clone());
+
+        Self::with_client(config, Arc::new(account_client))
+    }
+
+    pub fn with_client(config: Config, account_client: Arc<dyn ExchangeClient>) -> Box<Self> {
+        let account_client: Arc<dyn ExchangeClient> = match &config.journal_file {
+            Some(path) => match JournalExchange::open(account_client.clone(), path) {
+                Ok(journal) => journal,
+                Err(err) => {
+                    warn!("open journal {} Error: {}, not journaling", path, err);
+                    account_client
+                }
+            },
+            None => account_client,
+        };
         let solver_type = SolverType::LogRegression;
 
         let sf = AkSolverFactory::new(&solver_type);
@@ -138,44 +618,69 @@ impl AvellanedaStoikov {
             config.tick_size.clone(),
             config.n_spreads.clone(),
             config.estimate_window.clone(),
-            config.period.clone(),
+            config.estimate_period.unwrap_or(config.period),
             sf,
         );
 
-        let account_client: FuturesAccount =
-            BinanceF::new(config.api_key.clone(), config.secret_key.clone());
-
-        let tick_round = config
-            .tick_size
-            .to_string()
-            .split(".")
-            .collect::<Vec<&str>>()[1]
-            .len() as u32;
-        let pair = format!(
-            "{}{}",
-            config.base_asset.clone(),
-            config.quote_asset.clone()
-        );
+        let tick_round = util::decimals(config.tick_size);
+        let pair = config.symbol();
 
         Box::new(AvellanedaStoikov {
             config: config.clone(),
             start_time: get_timestamp().unwrap(),
+            clock: util::ClockOffset::default(),
             timer: 0,
             account_client: account_client,
-            strategy_data: StrategyData::with_capacity(config.sigma_tick_period),
+            strategy_data: StrategyData {
+                reference_blend: config.reference_blend,
+                dedup: config.dedup_update_id,
+                ..StrategyData::with_capacity(config.sigma_tick_period)
+            },
+            margin_asset: config.margin_asset().to_string(),
             base_asset: config.base_asset,
-            quote_asset: config.quote_asset,
             pair: pair.clone(),
             order_qty: config.order_qty,
+            order_notional: config.order_notional,
             tick_size: config.tick_size,
             tick_round: tick_round,
+            qty_round: config.qty_precision.or(if config.step_size > 0f64 {
+                Some(util::decimals(config.step_size))
+            } else {
+                None
+            }),
+            tick_samples: config.infer_tick_size.map(|n| Vec::with_capacity(2 * n)),
+            percent_price: None,
+            step_size: config.step_size,
+            min_notional: config.min_notional,
             n_spreads: config.n_spreads,
             estimate_window: config.estimate_window,
-            period: config.period,
-            gamma: 0.1,
+            quote_period: config.quote_period.unwrap_or(config.period),
+            quote_jitter: config.quote_jitter_ms.map(|max_ms| {
+                util::Jitter::new(
+                    config.jitter_seed.unwrap_or(get_timestamp().unwrap()),
+                    max_ms,
+                )
+            }),
+            jitter_ms: 0,
+            qty_draws: config
+                .order_qty_range
+                .map(|_| util::Jitter::new(config.qty_seed.unwrap_or(get_timestamp().unwrap()), 0)),
+            drawn_qty: None,
+            estimate_cadence: util::Cadence::new(config.estimate_period.unwrap_or(config.period)),
+            ak_series: Vec::new(),
+            ak_export_cadence: util::Cadence::new(config.ak_series_export_ms),
+            sigma_cadence: util::Cadence::new(config.sigma_period.unwrap_or(config.period)),
+            intensity_info: None,
+            gamma: config.gamma,
             sigma_multiplier: config.sigma_multiplier,
             ie: ie,
+            executor: if config.background_estimate {
+                Some(EstimatorExecutor::default())
+            } else {
+                None
+            },
             sigma: 1.0,
+            sigma_baseline: None,
             buy_a: 0.4,
             buy_k: 0.2,
             sell_a: 0.4,
@@ -185,16 +690,101 @@ impl AvellanedaStoikov {
                 position_amount: 0f64,
                 entry_price: 0f64,
             },
+            long: Position {
+                symbol: pair.clone(),
+                position_amount: 0f64,
+                entry_price: 0f64,
+            },
+            short: Position {
+                symbol: pair.clone(),
+                position_amount: 0f64,
+                entry_price: 0f64,
+            },
             cash: 0f64,
+            cost_basis: CostBasis::default(),
             total_profit: 0f64,
+            total_fees: 0f64,
+            order_count: 0,
+            fill_count: 0,
+            session_start: 0,
+            session_loss_breached: false,
             stoploss: config.stoploss,
             stoploss_sleep: config.stoploss_sleep,
+            last_stoploss: 0,
+            stoploss_repeats: 0,
+            stoploss_latch: util::Latch::new(config.stop_hysteresis.unwrap_or(0f64)),
+            stopprofit_latch: util::Latch::new(config.stop_hysteresis.unwrap_or(0f64)),
             in_stoploss: false,
             unrealized_pnl: 0f64,
+            in_pause: false,
+            flattened_at: 0,
+            flatten_pause_until: 0,
+            pause_file_cadence: util::Cadence::new(config.pause_file_poll_ms),
+            file_paused: false,
+            lagging: false,
+            lag_pauses: 0,
+            pnl_context: true,
             stopprofit: config.stopprofit,
             trailing_stop: config.trailing_stop,
             active_trailing_stop: false,
+            peak_pnl: 0f64,
+            peak_side: 0,
+            pending_flatten: None,
+            exits_in_flight: HashMap::new(),
+            flatten_attempts: 0,
+            exit_wap: HashMap::new(),
+            total_slippage: 0f64,
+            open_orders: HashSet::new(),
+            resting: Arc::new(Mutex::new(RestingQuotes::default())),
+            reconcile_cadence: config.reconcile_period.map(util::Cadence::new),
+            held_inventory: 0f64,
+            held_ts: 0,
+            last_buy_fill: 0,
+            last_sell_fill: 0,
+            quote_uptime: QuoteUptime::default(),
+            markout: config
+                .markout_horizon_ms
+                .map(|horizon| Markout::new(horizon, config.markout_window)),
+            spread_timing: config.time_calls.then(Histogram::default),
+            estimate_timing: config.time_calls.then(Histogram::default),
+            low_uptime: false,
+            anchor: config
+                .anchor_ema_span
+                .map(|span| util::Ema::new(span, config.anchor_ema_snap_bps.map(|x| x / 1e4))),
+            grace_amount: 0f64,
+            grace_since: 0,
+            margin_rejected: Arc::new(AtomicBool::new(false)),
+            order_failures: Arc::new(AtomicU64::new(0)),
+            order_ids: ClientOrderIds::new(config.strategy_tag.clone(), get_timestamp().unwrap()),
+            failure_pause_until: 0,
+            user_stream_down: Arc::new(AtomicBool::new(false)),
+            position_poll_cadence: util::Cadence::new(config.position_poll_ms),
+            quote_task: None,
+            state_writer: None,
+            quote_lock: Arc::new(tokio::sync::Mutex::new(())),
+            last_quote: None,
+            last_spread: None,
+            skip_reason: None,
+            applied_spread: None,
+            fill_times: VecDeque::new(),
+            spread_multiplier: 1f64,
             q_max: config.q_max,
+            warmed_up: false,
+            health: Arc::new(Mutex::new(HealthState::default())),
+            control: Arc::new(Mutex::new(ControlState {
+                params: Params {
+                    gamma: config.gamma,
+                    sigma_multiplier: config.sigma_multiplier,
+                    order_qty: config.order_qty,
+                    stoploss: config.stoploss,
+                    stopprofit: config.stopprofit,
+                },
+                pending: None,
+                sized_by_notional: config.order_notional.is_some(),
+            })),
+            mark: Arc::new(Mutex::new(MarkPrice::default())),
+            trade: Arc::new(Mutex::new(LastTrade::default())),
+            secondary: Arc::new(Mutex::new(ReferencePrice::default())),
         })
     }
 
@@ -202,15 +792,149 @@ impl AvellanedaStoikov {
         "Avellaneda_Stoikov".into()
     }
 
-    pub async fn run_forever(&mut self, mut rx: mpsc::Receiver<FuturesWebsocketEvent>) {
+    /// State backing the health endpoint, the feed tasks set
+    /// `feed_connected` on it.
+    pub fn health(&self) -> SharedHealth {
+        self.health.clone()
+    }
+
+    /// Flag the user data stream keepalive raises when the stream is lost.
+    pub fn user_stream_down(&self) -> Arc<AtomicBool> {
+        self.user_stream_down.clone()
+    }
 
-        let account_balance = self.account_client.account_balance().await.unwrap();
+    /// Params shared with the control API.
+    pub fn control(&self) -> SharedControl {
+        self.control.clone()
+    }
 
-        info!("account_balance: {:?}", account_balance);
+    /// Take an update accepted by the control API, then publish the running
+    /// values back for `GET /params`.
+    fn sync_params(&mut self) {
+        let mut control = self.control.lock().unwrap();
+        if let Some(params) = control.pending.take() {
+            info!("apply control params: {:?}", params);
+            self.gamma = params.gamma;
+            self.sigma_multiplier = params.sigma_multiplier;
+            self.order_qty = params.order_qty;
+            self.stoploss = params.stoploss;
+            self.stopprofit = params.stopprofit;
+        }
+        control.params = Params {
+            gamma: self.gamma,
+            sigma_multiplier: self.sigma_multiplier,
+            order_qty: self.order_qty,
+            stoploss: self.stoploss,
+            stopprofit: self.stopprofit,
+        };
+    }
+
+    /// Publish the quote uptime gauge and warn once each time it drops below
+    /// `min_quote_uptime`.
+    fn check_quote_uptime(&mut self, now: u64) {
+        let uptime = self.quote_uptime.fraction(now);
+        self.health.lock().unwrap().quote_uptime = uptime;
+
+        if let (Some(uptime), Some(target)) = (uptime, self.config.min_quote_uptime) {
+            let low = uptime < target;
+            if low && !self.low_uptime {
+                warn!("quote uptime {} below target {}", uptime, target);
+            } else if !low && self.low_uptime {
+                info!("quote uptime {} back above target {}", uptime, target);
+            }
+            self.low_uptime = low;
+        }
+    }
+
+    /// Last finite spread computed, whether it was quoted or not.
+    pub fn last_spread(&self) -> Option<Spread> {
+        self.last_spread
+    }
+
+    /// Why the last cycle posted nothing, None once one posts.
+    pub fn skip_reason(&self) -> Option<&'static str> {
+        self.skip_reason
+    }
+
+    fn skip_cycle(&mut self, reason: &'static str) {
+        if self.skip_reason != Some(reason) {
+            info!("quoting skipped: {}", reason);
+        }
+        self.skip_reason = Some(reason);
+        self.health.lock().unwrap().skip_reason = Some(reason);
+    }
+
+    /// Mark price slot for the mark-price stream to update.
+    pub fn mark(&self) -> SharedMark {
+        self.mark.clone()
+    }
+
+    /// Last trade slot for the aggTrade stream to update.
+    pub fn trade(&self) -> SharedTrade {
+        self.trade.clone()
+    }
+
+    /// Secondary mid slot for the `reference_symbol` stream to update.
+    pub fn secondary(&self) -> SharedReference {
+        self.secondary.clone()
+    }
+
+    fn publish_health(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.warmed_up = self.warmed_up;
+        health.in_stoploss = self.in_stoploss;
+        health.paused = self.session_loss_breached || self.in_pause;
+        health.inventory = self.position.position_amount;
+    }
+
+    pub async fn run_forever(
+        &mut self,
+        mut rx: mpsc::Receiver<FuturesWebsocketEvent>,
+    ) -> error::Result<()> {
+        self.restore_state(get_timestamp().unwrap());
+        if let Some(path) = &self.config.state_file {
+            self.state_writer = Some(spawn_state_writer(path.clone()));
+        }
+
+        if self.config.pin_filters {
+            info!(
+                "pinned filters, tick_size: {}, step_size: {}, min_notional: {}",
+                self.tick_size, self.step_size, self.min_notional
+            );
+        } else {
+            match exchange_info::fetch_symbol_filters(&self.pair).await {
+                Ok(filters) => self.apply_filters(&filters),
+                Err(err) => warn!("fetch symbol filters Error: {}, keep config values", err),
+            }
+        }
+
+        if let Some(limit) = self.config.warm_start_trades {
+            match agg_trade::fetch_recent_trades(&self.pair, limit).await {
+                Ok(trades) => self.seed_from_trades(&trades),
+                Err(err) => warn!("fetch recent trades Error: {}, cold start", err),
+            }
+        }
+
+        if self.config.startup_check {
+            if let Err(err) = self.check_existing().await {
+                error!("startup check failed: {}, not starting", err);
+                return Err(err);
+            }
+        }
+
+        let account_balance = self
+            .account_client
+            .account_balance()
+            .await
+            .map_err(Error::exchange)?;
 
-        let pair = "DOGEUSDT";
+        info!("account_balance: {:?}", account_balance);
 
-        let positions = self.account_client.position_information(pair).await.unwrap();
+        let positions = self
+            .account_client
+            .position_information(&self.pair)
+            .await
+            .map_err(Error::exchange)?;
 
         info!("position_information: {:?}", positions);
 
@@ -228,39 +952,219 @@ impl AvellanedaStoikov {
         //     Err(err) => warn!("market sell Error: {}", err),
         // }
 
+        let mut stop = StopSignal::new();
+
+        // events pulled off the channel ahead of processing to measure the
+        // backlog, at most max_feed_backlog + 1
+        let mut backlog: VecDeque<FuturesWebsocketEvent> = VecDeque::new();
+
+        if self.config.order_probe {
+            // the probe is priced off the book, hold the events until it ticks
+            let bid = loop {
+                match rx.recv().await {
+                    Some(FuturesWebsocketEvent::BookTicker(event)) => {
+                        let bid = event.best_bid;
+                        backlog.push_back(FuturesWebsocketEvent::BookTicker(event));
+                        break bid;
+                    }
+                    Some(event) => backlog.push_back(event),
+                    None => {
+                        return Err(Error::Feed(
+                            "websocket channel closed before the order probe".to_string(),
+                        ))
+                    }
+                }
+            };
+            if let Err(err) = self.probe_order(bid).await {
+                error!("order probe failed: {}, not starting", err);
+                return Err(err);
+            }
+        }
         loop {
-            if let Some(event) = rx.recv().await {
-                match event {
+            let event = match backlog.pop_front() {
+                Some(event) => Some(event),
+                None => tokio::select! {
+                    event = rx.recv() => event,
+                    name = stop.recv() => {
+                        info!("{} received, stop running", name);
+                        self.shutdown().await;
+                        return Ok(());
+                    }
+                    // roll on time too, a quiet feed would leave the
+                    // session and its loss breaker open
+                    _ = tokio::time::sleep(std::time::Duration::from_millis(
+                        self.until_session_roll(get_timestamp().unwrap()),
+                    )) => {
+                        let now = self.clock.to_exchange(get_timestamp().unwrap());
+                        self.roll_session(now);
+                        continue;
+                    }
+                },
+            };
+            if let Some(event) = event {
+                if let Some(max) = self.config.max_feed_backlog {
+                    while backlog.len() <= max {
+                        match rx.try_recv() {
+                            Ok(next) => backlog.push_back(next),
+                            Err(_) => break,
+                        }
+                    }
+                    self.check_lag(backlog.len());
+                }
+                let result = match event {
                     FuturesWebsocketEvent::BookTicker(book_event) => {
                         // debug!("book_event: {:?}", book_event);
-                        self.on_tick(book_event).await.unwrap();
+                        self.on_tick(book_event).await
                     }
                     FuturesWebsocketEvent::AccountUpdate(account_event) => {
                         // debug!("account_event: {:?}", account_event);
-                        self.on_account(account_event).await.unwrap();
+                        self.on_account(account_event).await
                     }
                     FuturesWebsocketEvent::OrderTradeUpdate(order_event) => {
                         debug!("ORDER_TRADE_UPDATE: {:?}", order_event);
+                        self.on_order_update(order_event).await
                     }
                     FuturesWebsocketEvent::AccountConfigUpdate(config_event) => {
                         debug!("ACCOUNT_CONFIG_UPDATE: {:?}", config_event);
+                        Ok(())
                     }
                     _ => {
                         warn!("Websockets parse error! {:?}", event);
+                        Ok(())
+                    }
+                };
+                // one bad event is skipped, it doesn't stop the bot
+                if let Err(err) = result {
+                    warn!("event skipped, {}", err);
+                }
+                if let Some(&ts) = self.strategy_data.timestamp.back() {
+                    if self.runtime_expired(ts) {
+                        self.stop_at_deadline(ts).await;
+                        return Ok(());
                     }
                 }
+            } else {
+                warn!("websocket channel closed, stop running");
+                self.shutdown().await;
+                return Ok(());
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Feed recorded ticks through on_tick, clocked from the first tick, and
+    /// collect the quotes decided. Meant to run against a mock client.
+    pub async fn replay(&mut self, ticks: Vec<Box<BookTickerEvent>>) -> Vec<QuoteRecord> {
+        if let Some(first) = ticks.first() {
+            self.start_time = first.transaction_time;
+            self.clock = util::ClockOffset::pinned(0);
+        }
+
+        let mut quotes = Vec::new();
+        for tick in ticks {
+            if let Err(err) = self.on_tick(tick).await {
+                warn!("replay tick skipped, {}", err);
             }
-            actix_rt::task::yield_now().await;
+            if let Some(quote) = self.last_quote.take() {
+                quotes.push(quote);
+            }
+        }
+        self.shutdown().await;
+        quotes
+    }
+
+    /// Check a book ticker is usable before it reaches StrategyData, the wap
+    /// divides by the sizes.
+    fn validate_tick(data: &BookTickerEvent) -> error::Result<()> {
+        let prices = data.best_bid.is_finite()
+            && data.best_ask.is_finite()
+            && data.best_bid > 0f64
+            && data.best_bid <= data.best_ask;
+        let sizes = data.best_bid_qty >= 0f64
+            && data.best_ask_qty >= 0f64
+            && data.best_bid_qty + data.best_ask_qty > 0f64;
+        if prices && sizes {
+            Ok(())
+        } else {
+            Err(Error::Feed(format!(
+                "unusable book {}@{} / {}@{}",
+                data.best_bid_qty, data.best_bid, data.best_ask_qty, data.best_ask
+            )))
         }
     }
 
-    async fn on_tick(&mut self, data: Box<BookTickerEvent>) -> Result<()> {
+    async fn on_tick(&mut self, data: Box<BookTickerEvent>) -> error::Result<()> {
         debug!("on_ticker: {:?}", data);
-        self.strategy_data.push(data.clone());
+        if let Err(err) = Self::validate_tick(&data) {
+            self.skip_cycle("unusable_book");
+            return Err(err);
+        }
+        let now = get_timestamp().map_err(|e| Error::Feed(format!("local clock: {:?}", e)))?;
+        self.clock.observe(data.transaction_time, now);
+        if !self.strategy_data.push(data.clone()) {
+            debug!(
+                "drop book ticker {} at or below update id {:?}",
+                data.update_id, self.strategy_data.last_update_id
+            );
+            self.health.lock().unwrap().dropped_ticks = self.strategy_data.dropped;
+            return Ok(());
+        }
+        if let Some(anchor) = self.anchor.as_mut() {
+            anchor.update(*self.strategy_data.reference.back().unwrap());
+        }
+        if let Some(samples) = self.tick_samples.as_mut() {
+            samples.push(data.best_bid);
+            samples.push(data.best_ask);
+            if samples.len() < 2 * self.config.infer_tick_size.unwrap_or(0) {
+                info!("sampling prices for tick_size...");
+                return Ok(());
+            }
+            self.infer_tick_size();
+            if self.tick_samples.is_some() {
+                return Ok(());
+            }
+        }
+        self.sync_params();
+        self.size_from_notional();
+        self.q_max_from_equity();
+        let trade = *self.trade.lock().unwrap();
+        if trade.timestamp > 0 {
+            self.strategy_data.last_trade = Some(trade.price);
+        }
+        self.roll_session(data.transaction_time);
+        self.health.lock().unwrap().last_tick_ms = health::now_ms();
+        self.check_quote_uptime(data.transaction_time);
+        self.update_held_inventory(data.transaction_time);
+        self.update_markout(data.transaction_time);
+        // keep marking the position during the stoploss sleep, only quoting
+        // is paused
+        self.update_unrealized_pnl();
+        self.update_stoploss_grace(data.transaction_time);
+        self.stoploss_latch
+            .update(-self.unrealized_pnl, self.stoploss);
+        self.stopprofit_latch
+            .update(self.unrealized_pnl, self.stopprofit);
+
+        if self.user_stream_down.load(Ordering::SeqCst)
+            && self.position_poll_cadence.fire(data.transaction_time)
+        {
+            self.poll_positions().await;
+        }
+
+        let reconcile = match self.reconcile_cadence.as_mut() {
+            Some(cadence) => cadence.fire(data.transaction_time),
+            None => false,
+        };
+        if reconcile {
+            self.reconcile_open_orders(data.transaction_time).await;
+        }
+
+        self.check_scheduled_flatten(data.transaction_time).await;
 
         if let Some(intensity_info) =
             self.calculate_intensity_info(data.best_ask, data.best_bid, data.transaction_time)
         {
+            self.warmed_up = true;
             let (buy_a, buy_k, sell_a, sell_k) = intensity_info.get_ak();
 
             self.buy_a = buy_a + std::f64::EPSILON;
@@ -268,22 +1172,25 @@ impl AvellanedaStoikov {
             self.sell_a = sell_a + std::f64::EPSILON;
             self.sell_k = sell_k + std::f64::EPSILON;
 
+            let started = Instant::now();
             let spread = self.calculate_spread();
+            if let Some(timing) = self.spread_timing.as_mut() {
+                timing.record(started.elapsed());
+                self.health.lock().unwrap().spread_timing = timing.summary();
+            }
+            let spread = self.adapt_spread(spread, data.transaction_time);
+            let spread = self.step_spread(spread);
+            let spread = match self.finite_spread(spread) {
+                Ok(spread) => spread,
+                Err(err) => {
+                    self.skip_cycle("non_finite_spread");
+                    return Err(err);
+                }
+            };
+            self.last_spread = Some(spread);
             info!("speard: {:?}", spread);
 
             if !self.in_stoploss {
-                if self.position.position_amount > 0f64 {
-                    self.unrealized_pnl = (self.strategy_data.bid_price.back().unwrap()
-                        * self.position.position_amount)
-                        / (self.position.entry_price * self.position.position_amount)
-                        - 1f64;
-                } else if self.position.position_amount < 0f64 {
-                    self.unrealized_pnl = -((self.strategy_data.ask_price.back().unwrap()
-                        * self.position.position_amount)
-                        / (self.position.entry_price * self.position.position_amount)
-                        - 1f64);
-                }
-
                 info!(
                     "unrealized_pnl: {}, -stoploss: {}, stoploss?: {}, stopprofit: {}",
                     self.unrealized_pnl,
@@ -292,31 +1199,15 @@ impl AvellanedaStoikov {
                     self.stopprofit
                 );
 
-                if self.unrealized_pnl > self.trailing_stop
-                    && (self.timer <= data.transaction_time / 1e3 as u64 - (10000 / 1000))
-                {
-                    self.active_trailing_stop = true;
-                }
-
-                if self.active_trailing_stop && (self.unrealized_pnl < self.trailing_stop) {
-                    if self.position.position_amount > 0f64 {
-                        match self
-                            .account_client
-                            .market_sell(&self.pair, self.position.position_amount)
-                            .await
-                        {
-                            Ok(answer) => info!("Trailing stop market sell {:?}", answer),
-                            Err(err) => warn!("Trailing stop market sell Error: {}", err),
-                        }
-                    } else if self.position.position_amount < 0f64 {
-                        match self
-                            .account_client
-                            .market_buy(&self.pair, self.position.position_amount.abs())
-                            .await
-                        {
-                            Ok(answer) => info!("Trailing stop market buy {:?}", answer),
-                            Err(err) => warn!("Trailing stop market buy Error: {}", err),
-                        }
+                if self.pnl_context && self.trailing_stop_hit() {
+                    warn!(
+                        "unrealized_pnl: {:?}, gave back trailing_stop: {:?} from peak: {:?}",
+                        self.unrealized_pnl, self.trailing_stop, self.peak_pnl
+                    );
+
+                    if self.position.position_amount != 0f64 {
+                        self.flatten("Trailing stop", StoplossOrderType::Market)
+                            .await;
                     } else {
                         info!("Already Trailing Stoped, pass.")
                     }
@@ -324,143 +1215,173 @@ impl AvellanedaStoikov {
                     self.unrealized_pnl = 0f64;
 
                     self.active_trailing_stop = false;
+                    self.peak_pnl = 0f64;
 
                     self.timer = data.transaction_time / 1e3 as u64;
+                    self.skip_cycle("trailing_stop");
                 }
 
-                if self.unrealized_pnl < -self.stoploss {
+                if self.config.enable_stoploss
+                    && self.pnl_context
+                    && !self.in_stoploss_grace(data.transaction_time)
+                    && self
+                        .stoploss_latch
+                        .breached(-self.unrealized_pnl, self.stoploss)
+                {
+                    // a held position is still at risk once the sleep is
+                    // over, only a close waits for pnl to come back
+                    if self.config.stop_hysteresis.is_some()
+                        && self.config.stoploss_action == StoplossAction::Flatten
+                    {
+                        self.stoploss_latch.disarm();
+                    }
+                    self.escalate_stoploss_sleep(data.transaction_time);
                     warn!("unrealized_pnl: {:?}, small than stoploss: {:?} stoploss then sleep: {:?}ms", self.unrealized_pnl, self.stoploss, self.stoploss_sleep);
 
-                    match self.account_client.cancel_all_open_orders(&self.pair).await {
+                    match self.cancel_all_orders().await {
                         Ok(answer) => info!("Cancel all open orders: {:?}", answer),
                         Err(err) => warn!("Cancel all open orders Error: {:?}", err),
                     }
 
-                    if self.position.position_amount > 0f64 {
-                        match self
-                            .account_client
-                            .market_sell(&self.pair, self.position.position_amount)
-                            .await
-                        {
-                            Ok(answer) => info!("Stop loss market sell {:?}", answer),
-                            Err(err) => warn!("Stop loss market sell Error: {}", err),
+                    match self.config.stoploss_action {
+                        StoplossAction::Flatten => {
+                            self.flatten_and_verify("Stop loss", self.config.stoploss_order_type)
+                                .await;
+                            self.unrealized_pnl = 0f64;
                         }
-                    } else {
-                        match self
-                            .account_client
-                            .market_buy(&self.pair, self.position.position_amount.abs())
-                            .await
-                        {
-                            Ok(answer) => info!("Stop loss market buy {:?}", answer),
-                            Err(err) => warn!("Stop loss market buy Error: {}", err),
+                        // the position stays on and is still marked every tick
+                        StoplossAction::HoldAndPause => {
+                            info!(
+                                "Stop loss: holding {}, quoting paused",
+                                self.position.position_amount
+                            )
                         }
                     }
 
-                    self.unrealized_pnl = 0f64;
-
                     self.in_stoploss = true;
 
                     self.active_trailing_stop = false;
 
                     self.timer = data.transaction_time / 1e3 as u64;
-                } else if (self.unrealized_pnl > self.stopprofit)
-                    && (self.timer <= data.transaction_time / 1e3 as u64 - (self.period / 1000))
+                    self.skip_cycle("stoploss");
+                } else if self.config.enable_stopprofit
+                    && self.pnl_context
+                    && self
+                        .stopprofit_latch
+                        .breached(self.unrealized_pnl, self.stopprofit)
+                    && (self.timer
+                        <= data.transaction_time / 1e3 as u64 - (self.quote_period / 1000))
                 {
                     warn!(
                         "unrealized_pnl: {:?}, bigger than stopprofit: {:?}",
                         self.unrealized_pnl, self.stopprofit
                     );
 
-                    match self.account_client.cancel_all_open_orders(&self.pair).await {
+                    match self.cancel_all_orders().await {
                         Ok(answer) => info!("Cancel all open orders: {:?}", answer),
                         Err(err) => warn!("Cancel all open orders Error: {:?}", err),
                     }
 
-                    if self.position.position_amount > 0f64 {
-                        match self
-                            .account_client
-                            .market_sell(&self.pair, self.position.position_amount)
-                            .await
-                        {
-                            Ok(answer) => info!("Stop stopprofit market sell {:?}", answer),
-                            Err(err) => warn!("Stop stopprofit market sell Error: {}", err),
-                        }
-                    } else {
-                        match self
-                            .account_client
-                            .market_buy(&self.pair, self.position.position_amount.abs())
-                            .await
-                        {
-                            Ok(answer) => info!("Stop stopprofit market buy {:?}", answer),
-                            Err(err) => warn!("Stop stopprofit market buy Error: {}", err),
-                        }
+                    if self.config.stop_hysteresis.is_some() {
+                        self.stopprofit_latch.disarm();
                     }
+                    self.exit_profit("Stop stopprofit").await;
 
                     self.unrealized_pnl = 0f64;
 
                     self.timer = data.transaction_time / 1e3 as u64;
-                } else if self.timer <= data.transaction_time / 1e3 as u64 - (self.period / 1000) {
+                    self.skip_cycle("stopprofit");
+                } else if self.session_loss_breached
+                    && (self.timer
+                        <= data.transaction_time / 1e3 as u64 - (self.quote_period / 1000))
+                {
+                    info!(
+                        "session loss breaker active, total_profit: {}, no quoting until next session",
+                        self.total_profit
+                    );
+
+                    match self.cancel_all_orders().await {
+                        Ok(answer) => info!("Cancel all open orders: {:?}", answer),
+                        Err(err) => warn!("Cancel all open orders Error: {:?}", err),
+                    }
+
+                    self.timer = data.transaction_time / 1e3 as u64;
+                    self.skip_cycle("session_loss");
+                } else if self.update_pause(data.transaction_time).await {
+                    debug!("in pause window, no quoting");
+                    self.skip_cycle("paused");
+                } else if self.timer
+                    <= data.transaction_time / 1e3 as u64
+                        - ((self.quote_period + self.jitter_ms) / 1000)
+                {
                     debug!(
                         "timer: {}, now - {} = {}",
                         self.timer,
-                        (self.period / 1000),
+                        (self.quote_period / 1000),
                         data.transaction_time / 1e3 as u64 - 2
                     );
 
-                    let account_client = self.account_client.clone();
-                    let last_wap = self.strategy_data.wap.back().unwrap().clone();
-                    let pair = self.pair.clone();
-                    let order_qty = self.order_qty.clone();
-                    let tick_round = self.tick_round.clone();
-
-                    actix_rt::spawn(async move {
-                        debug!("on_ticker thread");
+                    if !self.enforce_open_order_limit().await {
+                        self.timer = data.transaction_time / 1e3 as u64;
+                        self.skip_cycle("open_order_limit");
+                        self.publish_health();
+                        return Ok(());
+                    }
 
-                        match account_client.cancel_all_open_orders(&pair).await {
-                            Ok(answer) => info!("Cancel all open orders: {:?}", answer),
-                            Err(err) => warn!("Cancel all open orders Error: {:?}", err),
+                    let spread = match self.check_divergence(spread) {
+                        Some(spread) => spread,
+                        None => {
+                            match self.cancel_all_orders().await {
+                                Ok(answer) => info!("Cancel all open orders: {:?}", answer),
+                                Err(err) => warn!("Cancel all open orders Error: {:?}", err),
+                            }
+                            self.timer = data.transaction_time / 1e3 as u64;
+                            self.skip_cycle("divergence");
+                            self.publish_health();
+                            return Ok(());
                         }
+                    };
 
-                        let sell_price = util::round_to(last_wap + spread.ask, tick_round);
+                    self.apply_margin_backoff();
+                    self.draw_quote_qty();
+                    self.prune_resting();
+                    let quote = self.hold_young_sides(self.build_quote(spread));
+                    let (buy_side, sell_side) = self.quote_position_sides();
+                    self.order_count +=
+                        (quote.bid_qty > 0f64) as u64 + (quote.ask_qty > 0f64) as u64;
 
-                        let buy_price = util::round_to(last_wap - spread.bid, tick_round);
-
-                        debug!(
-                            "wap: {}, ask_spread: {}, bid_spread: {}, sell_price {}, buy_price {}",
-                            last_wap, spread.ask, spread.bid, sell_price, buy_price
-                        );
-
-                        match account_client
-                            .limit_buy(
-                                &pair,
-                                order_qty,
-                                buy_price,
-                                PositionSide::Both,
-                                TimeInForce::GTC,
-                            )
-                            .await
-                        {
-                            Ok(answer) => info!("Limit buy {:?}", answer),
-                            Err(err) => warn!("Limit buy Error: {}", err),
-                        }
+                    debug!(
+                        "wap: {}, ask_spread: {}, bid_spread: {}, quote {:?}",
+                        self.strategy_data.wap.back().unwrap(),
+                        spread.ask,
+                        spread.bid,
+                        quote
+                    );
 
-                        match account_client
-                            .limit_sell(
-                                &pair,
-                                order_qty,
-                                sell_price,
-                                PositionSide::Both,
-                                TimeInForce::GTC,
-                            )
-                            .await
-                        {
-                            Ok(answer) => info!("Limit sell {:?}", answer),
-                            Err(err) => warn!("Limit sell Error: {}", err),
+                    let decision = self.decision(data.transaction_time, spread, &quote);
+                    if self.config.log_decisions {
+                        match serde_json::to_string(&decision) {
+                            Ok(record) => info!(target: "decision", "{}", record),
+                            Err(err) => warn!("decision record Error: {}", err),
                         }
-                    });
+                    }
+                    self.last_quote = Some(decision.record());
+                    self.skip_reason = None;
+                    {
+                        let mut health = self.health.lock().unwrap();
+                        health.skip_reason = None;
+                        health.last_good_quote = Some(decision);
+                    }
+                    self.spawn_quote(quote, buy_side, sell_side);
 
                     self.timer = data.transaction_time / 1e3 as u64;
+                    if let Some(jitter) = self.quote_jitter.as_mut() {
+                        self.jitter_ms = jitter.next();
+                        debug!("next quote jitter {}ms", self.jitter_ms);
+                    }
                     debug!("new timer {}", self.timer);
+
+                    self.save_state();
                 }
             } else if self.timer
                 <= data.transaction_time / 1e3 as u64 - (self.stoploss_sleep / 1000)
@@ -469,160 +1390,5979 @@ impl AvellanedaStoikov {
                 info!("stoploss sleep finished!");
             } else {
                 info!("in stoploss sleep, please wait...");
+                self.skip_cycle("stoploss_sleep");
             }
         } else {
             info!("waiting for get more data...");
         }
+        self.publish_health();
         Ok(())
     }
 
-    async fn on_account(&mut self, data: Box<AccountUpdateEvent>) -> Result<()> {
+    async fn on_account(&mut self, data: Box<AccountUpdateEvent>) -> error::Result<()> {
         info!("on_account: {:?}", data);
 
         for balance in &data.account_update.balances {
-            if balance.asset.eq(&self.quote_asset) {
+            if balance.asset.eq(&self.margin_asset) {
                 self.cash = balance.cross_wallet_balance;
             }
         }
 
-        let tmp_q = data
-            .account_update
-            .positions
-            .iter()
-            .find(|&x| x.symbol.eq(&self.pair) && x.position_side.eq("BOTH"))
-            .and_then(|x| Some(x.position_amount));
+        match self.config.position_mode {
+            PositionMode::OneWay => {
+                let tmp_q = data
+                    .account_update
+                    .positions
+                    .iter()
+                    .find(|&x| x.symbol.eq(&self.pair) && x.position_side.eq("BOTH"))
+                    .and_then(|x| Some(x.position_amount));
 
-        let entry_price = data
-            .account_update
-            .positions
-            .iter()
-            .find(|&x| x.symbol.eq(&self.pair) && x.position_side.eq("BOTH"))
-            .and_then(|x| Some(x.entry_price));
+                let entry_price = data
+                    .account_update
+                    .positions
+                    .iter()
+                    .find(|&x| x.symbol.eq(&self.pair) && x.position_side.eq("BOTH"))
+                    .and_then(|x| Some(x.entry_price));
+
+                self.position.entry_price =
+                    entry_price.unwrap_or_else(|| self.position.entry_price);
+                self.position.position_amount =
+                    tmp_q.unwrap_or_else(|| self.position.position_amount);
+            }
+            PositionMode::Hedge => {
+                for x in data
+                    .account_update
+                    .positions
+                    .iter()
+                    .filter(|&x| x.symbol.eq(&self.pair))
+                {
+                    if x.position_side.eq("LONG") {
+                        self.long.position_amount = x.position_amount;
+                        self.long.entry_price = x.entry_price;
+                    } else if x.position_side.eq("SHORT") {
+                        self.short.position_amount = x.position_amount;
+                        self.short.entry_price = x.entry_price;
+                    }
+                }
+
+                self.net_legs();
 
-        self.position.entry_price = entry_price.unwrap_or_else(|| self.position.entry_price);
-        self.position.position_amount = tmp_q.unwrap_or_else(|| self.position.position_amount);
+                info!(
+                    "long {:?}@{:?}, short {:?}@{:?}",
+                    self.long.position_amount,
+                    self.long.entry_price,
+                    self.short.position_amount,
+                    self.short.entry_price
+                );
+            }
+        }
 
         info!(
             "cash {:?}, q {:?}",
             self.cash, self.position.position_amount
         );
+        self.check_flatten().await;
+        self.publish_health();
         Ok(())
     }
 
-    fn calculate_intensity_info(&mut self, ask: f64, bid: f64, ts: u64) -> Option<IntensityInfo> {
-        let can_get = self.ie.on_tick(bid, ask, ts);
+    async fn on_order_update(&mut self, data: Box<OrderTradeUpdateEvent>) -> error::Result<()> {
+        let order = &data.order;
+        if !order.symbol.eq(&self.pair) {
+            return Ok(());
+        }
 
-        // wait to get more data
-        if can_get && ts > self.start_time + self.estimate_window + 1 {
-            let ii = self.ie.estimate(ts);
-            debug!("intensity_info {:#?}", ii);
-            return Some(ii);
-        } else {
-            None
+        let open = match order.order_status {
+            OrderStatus::New | OrderStatus::PartiallyFilled => {
+                self.open_orders.insert(order.order_id);
+                if let Some(remaining) = self.exits_in_flight.get_mut(&order.order_id) {
+                    *remaining = util::round_to(*remaining - order.qty_last_filled_trade, 9);
+                }
+                true
+            }
+            _ => {
+                self.open_orders.remove(&order.order_id);
+                self.exits_in_flight.remove(&order.order_id);
+                self.resting.lock().unwrap().close(order.order_id);
+                false
+            }
+        };
+        self.quote_uptime.on_order(
+            order.trade_order_time,
+            order.order_id,
+            matches!(order.side, OrderSide::Buy),
+            open,
+        );
+
+        if order.qty_last_filled_trade <= 0f64 {
+            return Ok(());
         }
-    }
 
-    fn calculate_tv_mean(&mut self) -> Option<f64> {
-        let sum: f64 = self.strategy_data.tv.iter().sum();
-        let count = self.strategy_data.tv.len();
+        let fee = match &order.asset_commissioned {
+            Some(asset) if asset.eq(&self.margin_asset) => order.commission.unwrap_or(0f64),
+            Some(asset) => {
+                warn!("commission paid in {}, not counted in total_profit", asset);
+                0f64
+            }
+            None => 0f64,
+        };
+        let qty = match order.side {
+            OrderSide::Buy => order.qty_last_filled_trade,
+            OrderSide::Sell => -order.qty_last_filled_trade,
+        };
 
-        match count {
-            positive if positive > 0 => Some(sum / count as f64),
-            _ => None,
+        self.on_fill(&Fill {
+            client_order_id: order.new_client_order_id.clone().unwrap_or_default(),
+            qty: qty,
+            price: order.last_filled_price,
+            fee: fee,
+            timestamp: order.trade_order_time,
+        });
+        Ok(())
+    }
+
+    fn on_fill(&mut self, fill: &Fill) {
+        let realized = match self.config.contract_type {
+            ContractType::Linear => self.cost_basis.on_fill(fill.qty, fill.price),
+            // a contract realizes size * (1 / entry - 1 / exit)
+            ContractType::Inverse => {
+                -self.config.contract_size * self.cost_basis.on_fill(fill.qty, 1f64 / fill.price)
+            }
+        };
+
+        self.total_fees += fill.fee;
+        self.total_profit += realized - fill.fee;
+        self.fill_count += 1;
+        if fill.qty > 0f64 {
+            self.last_buy_fill = fill.timestamp;
+        } else {
+            self.last_sell_fill = fill.timestamp;
+        }
+        if let Some(markout) = self.markout.as_mut() {
+            markout.on_fill(fill.timestamp, fill.qty > 0f64, fill.price);
+        }
+        if self.config.fill_rate_target.is_some() {
+            self.fill_times.push_back(fill.timestamp);
+        }
+        if let Some(wap) = self.exit_wap.get(&fill.client_order_id) {
+            let wap = *wap;
+            self.record_slippage(fill.qty, fill.price, wap);
         }
+
+        self.check_session_loss();
+
+        info!(
+            "fill {:?}, realized: {}, total_profit: {}, total_fees: {}",
+            fill, realized, self.total_profit, self.total_fees
+        );
+        self.save_state();
     }
 
-    fn calculate_classical_volatility(&mut self) -> Option<f64> {
-        let t = 10.;
-        let mut classical_hv = 0.;
-        for i in 0..self.strategy_data.wap.iter().len() - 1 {
-            let res =
-                self.strategy_data.wap.get(i + 1).unwrap() - self.strategy_data.wap.get(i).unwrap();
-            classical_hv += res.powi(2)
+    fn quote_context(&self) -> QuoteContext {
+        QuoteContext {
+            margin_rejected: self.margin_rejected.clone(),
+            order_failures: self.order_failures.clone(),
+            order_ids: self.order_ids.clone(),
+            quote_lock: self.quote_lock.clone(),
+            resting: if self.config.targeted_cancels {
+                Some(self.resting.clone())
+            } else {
+                None
+            },
         }
+    }
 
-        let res = (classical_hv / t).sqrt();
+    fn spawn_quote(&mut self, quote: Quote, buy_side: PositionSide, sell_side: PositionSide) {
+        self.quote_task = Some(tokio::spawn(Self::post_quote(
+            self.account_client.clone(),
+            self.pair.clone(),
+            quote,
+            buy_side,
+            sell_side,
+            self.quote_context(),
+        )));
+    }
 
-        Some(res)
+    /// cancel_all outside a quoting cycle. The cycle in flight finishes its
+    /// posts first, under `quote_lock`, rather than landing them after. What
+    /// rested is gone, or unknown when the cancel failed, so the resting
+    /// quotes are forgotten and the next cycle starts from a cancel_all.
+    async fn cancel_all_orders(&mut self) -> Result<()> {
+        if let Some(task) = self.quote_task.take() {
+            if let Err(err) = task.await {
+                warn!("quoting task Error: {}", err);
+            }
+        }
+        let quote_lock = self.quote_lock.clone();
+        let _cycle = quote_lock.lock().await;
+        let result = self.account_client.cancel_all_open_orders(&self.pair).await;
+        *self.resting.lock().unwrap() = RestingQuotes::default();
+        result
     }
 
-    fn calculate_spread_volatility(&mut self) -> Option<f64> {
-        let sum: f64 = self.strategy_data.spread.iter().map(|x| x.powi(2)).sum();
-        let count = self.strategy_data.spread.len();
-        let t = 10.;
+    /// Forget the resting quotes when one has no open order behind it, e.g.
+    /// its FILLED update was handled before the cycle that posted it kept
+    /// it. A dead side must not pass as unchanged, and the live one can't
+    /// be kept alone without reposting over it, so both go.
+    fn prune_resting(&self) {
+        let mut resting = self.resting.lock().unwrap();
+        let dead = [resting.bid, resting.ask]
+            .iter()
+            .flatten()
+            .any(|x| !self.open_orders.contains(&x.order_id));
+        if dead {
+            debug!("resting {:?} no longer all open, forget them", *resting);
+            *resting = RestingQuotes::default();
+        }
+    }
 
-        let res = (sum / t).sqrt();
+    /// Cancel everything, after the quoting task in flight so no order lands
+    /// later, then save the accounting.
+    pub async fn shutdown(&mut self) {
+        match self.cancel_all_orders().await {
+            Ok(answer) => info!("Shutdown cancel all open orders: {:?}", answer),
+            Err(err) => warn!("Shutdown cancel all open orders Error: {:?}", err),
+        }
 
-        match count {
-            positive if positive > 0 => Some(res),
-            _ => None,
+        self.export_ak_series();
+        self.save_state();
+        // wait out the queued saves, the last one is the final state
+        if let Some((tx, writer)) = self.state_writer.take() {
+            drop(tx);
+            if let Err(err) = writer.await {
+                warn!("state writer Error: {}", err);
+            }
         }
     }
 
-    /// https://github.com/TommasoBelluzzo/HistoricalVolatility
-    fn calculate_p_volatility(&mut self) -> Option<f64> {
-        let wap_vec = self.strategy_data.wap.iter().cloned().collect::<Vec<f64>>();
-        let t = 10.;
-        let mut parkinson_hv = 0.;
-        for chunk in wap_vec.chunks(30) {
-            let hl = (chunk.iter().cloned().fold(0. / 0., f64::max)
-                / chunk.iter().cloned().fold(0. / 0., f64::min))
-            .ln();
-            let res = hl.powi(2);
-            parkinson_hv += res;
+    /// Replace the resting quotes, run detached from the tick loop. Cycles
+    /// take `quote_lock` in turn so a cycle's cancel and posts never
+    /// interleave with the next one. A margin insufficient reject is flagged
+    /// for the next cycle to size down.
+    async fn post_quote(
+        account_client: Arc<dyn ExchangeClient>,
+        pair: String,
+        quote: Quote,
+        buy_side: PositionSide,
+        sell_side: PositionSide,
+        ctx: QuoteContext,
+    ) {
+        let QuoteContext {
+            margin_rejected,
+            order_failures,
+            order_ids,
+            quote_lock,
+            resting,
+        } = ctx;
+        let _cycle = quote_lock.lock().await;
+        debug!("on_ticker thread");
+
+        let (post_bid, post_ask) = match &resting {
+            Some(resting) => Self::cancel_changed(&account_client, &pair, &quote, resting).await,
+            None => {
+                match account_client.cancel_all_open_orders(&pair).await {
+                    Ok(answer) => info!("Cancel all open orders: {:?}", answer),
+                    Err(err) => warn!("Cancel all open orders Error: {:?}", err),
+                }
+                (true, true)
+            }
+        };
+        let keep = |bid: bool, answer: &OrderAck, price: f64, qty: f64| {
+            if let Some(resting) = &resting {
+                let order = Some(RestingOrder {
+                    order_id: answer.order_id,
+                    price: price,
+                    qty: qty,
+                    posted_at: quote.timestamp,
+                });
+                let mut resting = resting.lock().unwrap();
+                if bid {
+                    resting.bid = order;
+                } else {
+                    resting.ask = order;
+                }
+            }
+        };
+
+        if post_bid && quote.bid_qty > 0f64 {
+            match Self::post_limit(
+                &account_client,
+                &pair,
+                true,
+                &quote,
+                buy_side,
+                order_ids.next(true, 0),
+            )
+            .await
+            {
+                Ok(answer) => {
+                    info!("Limit buy {:?}", answer);
+                    order_failures.store(0, Ordering::SeqCst);
+                    keep(true, &answer, quote.bid_price, quote.bid_qty);
+                }
+                Err(err) => {
+                    warn!("Limit buy Error: {}", err);
+                    order_failures.fetch_add(1, Ordering::SeqCst);
+                    if exchange::is_margin_insufficient(&err) {
+                        margin_rejected.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
         }
-        let res = (parkinson_hv / (4. * t * (2f64.ln()))).sqrt();
 
-        Some(res)
+        if post_ask && quote.ask_qty > 0f64 {
+            match Self::post_limit(
+                &account_client,
+                &pair,
+                false,
+                &quote,
+                sell_side,
+                order_ids.next(false, 0),
+            )
+            .await
+            {
+                Ok(answer) => {
+                    info!("Limit sell {:?}", answer);
+                    order_failures.store(0, Ordering::SeqCst);
+                    keep(false, &answer, quote.ask_price, quote.ask_qty);
+                }
+                Err(err) => {
+                    warn!("Limit sell Error: {}", err);
+                    order_failures.fetch_add(1, Ordering::SeqCst);
+                    if exchange::is_margin_insufficient(&err) {
+                        margin_rejected.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
     }
 
-    fn calculate_gk_volatility(&mut self) -> Option<f64> {
-        let wap_vec = self.strategy_data.wap.iter().cloned().collect::<Vec<f64>>();
-        let t = 10.;
+    /// Leave a side resting younger than `min_time_in_book_ms` where it is
+    /// rather than reprice it, for its queue priority. A side the quote
+    /// drops is still cancelled.
+    fn hold_young_sides(&self, quote: Quote) -> Quote {
+        let min = match self.config.min_time_in_book_ms {
+            Some(min) => min,
+            None => return quote,
+        };
+        let resting = *self.resting.lock().unwrap();
+        let now = quote.timestamp;
+        let young = |order: Option<RestingOrder>| order.filter(|x| now < x.posted_at + min);
+        let mut quote = quote;
+        if let Some(order) = young(resting.bid) {
+            if quote.bid_qty > 0f64 {
+                debug!("bid {:?} younger than {}ms, hold", order, min);
+                quote.bid_price = order.price;
+                quote.bid_qty = order.qty;
+            }
+        }
+        if let Some(order) = young(resting.ask) {
+            if quote.ask_qty > 0f64 {
+                debug!("ask {:?} younger than {}ms, hold", order, min);
+                quote.ask_price = order.price;
+                quote.ask_qty = order.qty;
+            }
+        }
+        quote
+    }
 
-        let mut garman_klass_hv = 0.;
-        for chunk in wap_vec.chunks(30) {
-            let co = (chunk.last().unwrap() / chunk.first().unwrap()).ln();
-            let hl = (chunk.iter().cloned().fold(0. / 0., f64::max)
-                / chunk.iter().cloned().fold(0. / 0., f64::min))
-            .ln();
-            let res = 0.5 * hl.powi(2) - ((2. * 2f64.ln()) - 1.) * co.powi(2);
-            garman_klass_hv += res;
+    /// Cancel by id only the resting sides `quote` reprices or drops, so
+    /// the unchanged ones keep their queue priority. Falls back to
+    /// cancel_all when nothing is known resting or a cancel fails. Returns
+    /// which sides (bid, ask) are to be posted.
+    async fn cancel_changed(
+        account_client: &Arc<dyn ExchangeClient>,
+        pair: &str,
+        quote: &Quote,
+        resting: &Arc<Mutex<RestingQuotes>>,
+    ) -> (bool, bool) {
+        let current = *resting.lock().unwrap();
+        let unchanged = |order: Option<RestingOrder>, price: f64, qty: f64| {
+            qty > 0f64 && order.is_some_and(|x| x.price == price && x.qty == qty)
+        };
+        let keep_bid = unchanged(current.bid, quote.bid_price, quote.bid_qty);
+        let keep_ask = unchanged(current.ask, quote.ask_price, quote.ask_qty);
+
+        let mut fallback = current.bid.is_none() && current.ask.is_none();
+        let stale = [(current.bid, keep_bid), (current.ask, keep_ask)];
+        for (order, keep) in stale.iter() {
+            let order_id = match order {
+                Some(order) if !fallback && !keep => order.order_id,
+                _ => continue,
+            };
+            match account_client.cancel_order(pair, order_id).await {
+                Ok(()) => {
+                    info!("Cancel order {}", order_id);
+                    resting.lock().unwrap().close(order_id);
+                }
+                Err(err) => {
+                    warn!("Cancel order {} Error: {}, cancel all", order_id, err);
+                    fallback = true;
+                }
+            }
         }
-        let res = (garman_klass_hv / t).sqrt();
+        if fallback {
+            match account_client.cancel_all_open_orders(pair).await {
+                Ok(answer) => info!("Cancel all open orders: {:?}", answer),
+                Err(err) => warn!("Cancel all open orders Error: {:?}", err),
+            }
+            *resting.lock().unwrap() = RestingQuotes::default();
+            return (true, true);
+        }
+        debug!("keep resting bid: {}, ask: {}", keep_bid, keep_ask);
+        (!keep_bid, !keep_ask)
+    }
 
-        Some(res)
+    /// Post one side of `quote` as a GTC limit, retried once clamped into
+    /// its band when rejected by the PERCENT_PRICE filter. The retry reuses
+    /// the rejected order's id.
+    async fn post_limit(
+        account_client: &Arc<dyn ExchangeClient>,
+        pair: &str,
+        buy: bool,
+        quote: &Quote,
+        position_side: PositionSide,
+        client_order_id: Option<String>,
+    ) -> Result<OrderAck> {
+        let (qty, price) = if buy {
+            (quote.bid_qty, quote.bid_price)
+        } else {
+            (quote.ask_qty, quote.ask_price)
+        };
+        let band = quote.band;
+        let flags = OrderFlags {
+            client_order_id: client_order_id.as_deref(),
+            reduce_only: false,
+        };
+        let limit = |price: f64, position_side: PositionSide| async move {
+            if buy {
+                account_client
+                    .limit_buy(pair, qty, price, position_side, TimeInForce::GTC, flags)
+                    .await
+            } else {
+                account_client
+                    .limit_sell(pair, qty, price, position_side, TimeInForce::GTC, flags)
+                    .await
+            }
+        };
+
+        match limit(price, position_side.clone()).await {
+            Err(err) if exchange::is_percent_price(&err) => match band {
+                Some(band) if !band.contains(price) => {
+                    let clamped = band.clamp(price);
+                    warn!(
+                        "limit {} outside price band {:?}: {}, retry at {}",
+                        price, band, err, clamped
+                    );
+                    limit(clamped, position_side).await
+                }
+                _ => Err(err),
+            },
+            res => res,
+        }
     }
 
-    fn calculate_spread(&mut self) -> Spread {
-        // self.sigma = self.calculate_tv_mean().unwrap();
-        // self.sigma = self.calculate_p_volatility().unwrap();
-        self.sigma = self.calculate_gk_volatility().unwrap();
-        // self.sigma = self.calculate_spread_volatility().unwrap();
-        let sigma_fix = self.sigma * self.sigma_multiplier.clone();
-        let q_fix = self.position.position_amount / self.order_qty;
+    /// Shrink order_qty by `margin_backoff` if the last cycle was rejected
+    /// for margin, down to `min_order_qty` or the step size.
+    fn apply_margin_backoff(&mut self) {
+        if !self.margin_rejected.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let floor = self.config.min_order_qty.max(self.step_size);
+        let order_qty =
+            util::floor_to_step(self.order_qty * self.config.margin_backoff, self.step_size)
+                .max(floor);
+        warn!(
+            "margin insufficient, order_qty {} -> {} (floor {})",
+            self.order_qty, order_qty, floor
+        );
+        self.order_qty = order_qty;
+        if let Some(notional) = self.order_notional.as_mut() {
+            *notional *= self.config.margin_backoff;
+        }
+    }
+
+    /// Set the sleep of a stoploss firing at `now`: the configured sleep,
+    /// escalated and at least the re-arm interval when the previous stoploss
+    /// fired less than `stoploss_rearm_ms` ago.
+    fn escalate_stoploss_sleep(&mut self, now: u64) {
+        let repeat = match self.config.stoploss_rearm_ms {
+            Some(rearm) => self.last_stoploss > 0 && now < self.last_stoploss + rearm,
+            None => false,
+        };
+        self.stoploss_repeats = if repeat { self.stoploss_repeats + 1 } else { 0 };
+        self.last_stoploss = now;
+
+        let mut sleep = self.config.stoploss_sleep as f64
+            * self
+                .config
+                .stoploss_sleep_escalation
+                .powi(self.stoploss_repeats);
+        if let Some(max) = self.config.max_stoploss_sleep {
+            sleep = sleep.min(max as f64);
+        }
+        if repeat {
+            sleep = sleep.max(self.config.stoploss_rearm_ms.unwrap() as f64);
+        }
+        self.stoploss_sleep = sleep as u64;
+        if repeat {
+            warn!(
+                "stoploss repeated {} time(s) within {:?}ms, sleep {}ms",
+                self.stoploss_repeats, self.config.stoploss_rearm_ms, self.stoploss_sleep
+            );
+        }
+    }
+
+    /// Settle the fill markouts against the current wap and publish them.
+    fn update_markout(&mut self, ts: u64) {
+        let markout = match self.markout.as_mut() {
+            Some(markout) => markout,
+            None => return,
+        };
+        markout.on_wap(ts, *self.strategy_data.wap.back().unwrap());
+
+        let mut health = self.health.lock().unwrap();
+        health.buy_fills = markout.buy.fills;
+        health.sell_fills = markout.sell.fills;
+        health.buy_markout_bps = markout.buy.average();
+        health.sell_markout_bps = markout.sell.average();
+    }
+
+    /// Integrate the inventory over time, from zero again when it went flat
+    /// or changed side since the last tick.
+    fn update_held_inventory(&mut self, ts: u64) {
+        let q = self.position.position_amount / self.order_qty;
+        if q == 0f64 || q.signum() != self.held_inventory.signum() || self.held_ts == 0 {
+            self.held_inventory = 0f64;
+        }
+        if q != 0f64 && self.held_ts > 0 && ts > self.held_ts {
+            self.held_inventory += q * (ts - self.held_ts) as f64 / 60000f64;
+        }
+        self.held_ts = ts;
+    }
+
+    /// Apply `divergence_action` while the wap is further than
+    /// `max_wap_mark_divergence` from a fresh mark. None to pause.
+    fn check_divergence(&self, spread: Spread) -> Option<Spread> {
+        let max = match self.config.max_wap_mark_divergence {
+            Some(max) => max,
+            None => return Some(spread),
+        };
+        let now = *self.strategy_data.timestamp.back().unwrap();
+        let mark = match self
+            .mark
+            .lock()
+            .unwrap()
+            .fresh(now, self.config.mark_stale_ms)
+        {
+            Some(mark) => mark,
+            None => return Some(spread),
+        };
+        let wap = *self.strategy_data.wap.back().unwrap();
+        let divergence = (wap / mark - 1f64).abs();
+        if divergence <= max {
+            return Some(spread);
+        }
+
+        match self.config.divergence_action {
+            DivergenceAction::Widen => {
+                warn!(
+                    "wap {} diverges {} from mark {}, widen spreads by {}",
+                    wap, divergence, mark, self.config.divergence_widen
+                );
+                Some(Spread {
+                    bid: spread.bid * self.config.divergence_widen,
+                    ask: spread.ask * self.config.divergence_widen,
+                })
+            }
+            DivergenceAction::Pause => {
+                warn!(
+                    "wap {} diverges {} from mark {}, no quoting",
+                    wap, divergence, mark
+                );
+                None
+            }
+        }
+    }
+
+    /// Convert order_notional to a qty at the fair value.
+    fn size_from_notional(&mut self) {
+        if let Some(notional) = self.order_notional {
+            let unit = self.config.quote_value(1f64, self.fair_value());
+            let qty = util::floor_to_step(notional / unit, self.step_size).max(self.step_size);
+            if qty != self.order_qty {
+                debug!("order_notional {} -> order_qty {}", notional, qty);
+                self.order_qty = qty;
+            }
+        }
+    }
+
+    /// Draw the size of the coming quotes from `order_qty_range`.
+    fn draw_quote_qty(&mut self) {
+        let (draws, [min, max]) = match (self.qty_draws.as_mut(), self.config.order_qty_range) {
+            (Some(draws), Some(range)) => (draws, range),
+            _ => return,
+        };
+        let qty = util::floor_to_step(min + draws.uniform() * (max - min), self.step_size);
+        debug!("drew quote qty {} from [{}, {}]", qty, min, max);
+        self.drawn_qty = Some(qty);
+    }
+
+    /// Scale q_max with the wallet balance, in orders of the current size.
+    fn q_max_from_equity(&mut self) {
+        let fraction = match self.config.q_max_equity_fraction {
+            Some(fraction) => fraction,
+            None => return,
+        };
+        let notional = self.config.margin_value(self.order_qty, self.fair_value());
+        if self.cash <= 0f64 || notional <= 0f64 {
+            return;
+        }
+
+        let mut q_max = fraction * self.cash / notional;
+        if let Some(min) = self.config.min_q_max {
+            q_max = q_max.max(min);
+        }
+        if let Some(max) = self.config.max_q_max {
+            q_max = q_max.min(max);
+        }
+        if q_max != self.q_max {
+            debug!("cash {} -> q_max {}", self.cash, q_max);
+            self.q_max = q_max;
+        }
+    }
+
+    fn in_pause_window(&self, ts: u64) -> bool {
+        if let Some(funding_pause_ms) = self.config.funding_pause_ms {
+            let since = ts % util::FUNDING_INTERVAL_MS;
+            if since <= funding_pause_ms || util::FUNDING_INTERVAL_MS - since <= funding_pause_ms {
+                return true;
+            }
+        }
+        self.config
+            .pause_windows
+            .iter()
+            .any(|x| x.start_ms <= ts && ts <= x.end_ms)
+    }
+
+    /// Whether the pause flag file is up, polled every `pause_file_poll_ms`.
+    fn poll_pause_file(&mut self, ts: u64) -> bool {
+        let path = match &self.config.pause_file {
+            Some(path) => path,
+            None => return false,
+        };
+        if self.pause_file_cadence.fire(ts) {
+            self.file_paused = match std::fs::read_to_string(path) {
+                Ok(content) => content.trim().is_empty() || content.contains("PAUSE"),
+                Err(_) => false,
+            };
+        }
+        self.file_paused
+    }
+
+    /// Lag guard, `backlog` events waiting behind the one being processed.
+    /// Engages past `max_feed_backlog` and holds until the backlog is
+    /// drained, so it does not flap around the threshold.
+    fn check_lag(&mut self, backlog: usize) {
+        let max = match self.config.max_feed_backlog {
+            Some(max) => max,
+            None => return,
+        };
+        if !self.lagging && backlog > max {
+            warn!(
+                "{} events behind the feed, over max_feed_backlog {}, pause quoting",
+                backlog, max
+            );
+            self.lagging = true;
+            self.lag_pauses += 1;
+        } else if self.lagging && backlog == 0 {
+            info!("caught up with the feed, resume quoting");
+            self.lagging = false;
+        }
+    }
+
+    /// Track pause window and flag file entry/exit at `ts`, cancelling the
+    /// resting quotes on entry. True while paused.
+    async fn update_pause(&mut self, ts: u64) -> bool {
+        let paused = self.poll_pause_file(ts)
+            | self.in_pause_window(ts)
+            | self.past_horizon(ts)
+            | self.lagging
+            | (ts < self.flatten_pause_until)
+            | self.order_failures_paused(ts);
+        if paused && !self.in_pause {
+            info!("enter pause at {}, cancel quotes", ts);
+            match self.cancel_all_orders().await {
+                Ok(answer) => info!("Cancel all open orders: {:?}", answer),
+                Err(err) => warn!("Cancel all open orders Error: {:?}", err),
+            }
+        } else if !paused && self.in_pause {
+            info!("exit pause at {}, resume quoting", ts);
+        }
+        self.in_pause = paused;
+        paused
+    }
+
+    /// Whether `max_order_failures` rejects in a row paused quoting at `ts`.
+    /// Once the pause is over the next cycle is a retry: one more reject
+    /// pauses again, an accepted order resets the count.
+    fn order_failures_paused(&mut self, ts: u64) -> bool {
+        let failures = self.order_failures.load(Ordering::SeqCst);
+        self.health.lock().unwrap().order_failures = failures;
+        let max = match self.config.max_order_failures {
+            Some(max) => max,
+            None => return false,
+        };
+        if failures < max {
+            self.failure_pause_until = 0;
+            return false;
+        }
+        if self.failure_pause_until == 0 {
+            self.failure_pause_until = ts + self.config.order_failure_pause_ms;
+            error!(
+                "{} order submissions rejected in a row, pause quoting until {}",
+                failures, self.failure_pause_until
+            );
+        }
+        if ts < self.failure_pause_until {
+            return true;
+        }
+        info!("order failure pause over, retry quoting");
+        self.order_failures.store(max - 1, Ordering::SeqCst);
+        self.failure_pause_until = 0;
+        false
+    }
+
+    /// Cancel the quotes and flatten once `flatten_at_utc_ms` passed. The
+    /// first tick only arms it, a start after the time of day waits for the
+    /// next one.
+    async fn check_scheduled_flatten(&mut self, ts: u64) {
+        let at = match self.config.flatten_at_utc_ms {
+            Some(at) => at,
+            None => return,
+        };
+        let due = util::session_start(ts, at);
+        if self.flattened_at == 0 {
+            self.flattened_at = due;
+            return;
+        }
+        if due <= self.flattened_at {
+            return;
+        }
+        self.flattened_at = due;
 
         info!(
-            "sigma: {}, sigma_multiplier {}, sigma_fix {}, q {}, q_fix {}",
-            self.sigma, self.sigma_multiplier, sigma_fix, self.position.position_amount, q_fix,
+            "scheduled flatten at {}, inventory {}",
+            ts, self.position.position_amount
         );
+        match self.cancel_all_orders().await {
+            Ok(answer) => info!("Scheduled flatten cancel all open orders: {:?}", answer),
+            Err(err) => warn!("Scheduled flatten cancel all open orders Error: {:?}", err),
+        }
+        for (amount, result) in self
+            .flatten("Scheduled flatten", self.config.flatten_order_type)
+            .await
+        {
+            if let Err(err) = result {
+                warn!("scheduled flatten of {} Error: {}", amount, err);
+            }
+        }
+        if self.config.pause_after_flatten {
+            self.flatten_pause_until =
+                util::session_start(ts, self.config.session_reset_utc_ms) + util::DAY_MS;
+            info!("no quoting until {}", self.flatten_pause_until);
+        }
+    }
+
+    /// Whether `max_runtime_ms` has passed since start at exchange time `ts`.
+    fn runtime_expired(&self, ts: u64) -> bool {
+        match self.config.max_runtime_ms {
+            Some(max) => ts >= self.clock.to_exchange(self.start_time).saturating_add(max),
+            None => false,
+        }
+    }
+
+    /// Shut down for `max_runtime_ms`, then close the inventory with
+    /// `max_runtime_flatten` once no quote can land behind the flatten.
+    async fn stop_at_deadline(&mut self, ts: u64) {
         info!(
-            "buy_k: {}, buy_a: {}, sell_k {}, sell_a {}",
-            self.buy_k, self.buy_a, self.sell_k, self.sell_a
+            "max_runtime_ms {:?} reached at {}, stopping with inventory {}",
+            self.config.max_runtime_ms, ts, self.position.position_amount
         );
+        self.shutdown().await;
+        if !self.config.max_runtime_flatten {
+            return;
+        }
+        for (amount, result) in self
+            .flatten("Max runtime", self.config.flatten_order_type)
+            .await
+        {
+            if let Err(err) = result {
+                warn!("max runtime flatten of {} Error: {}", amount, err);
+            }
+        }
+    }
 
-        let bid = (1. + self.gamma / self.sell_k).ln() / self.gamma
-            + ((q_fix + 0.5)
-                * ((sigma_fix * sigma_fix * self.gamma) / (2. * self.sell_k * self.sell_a)
-                    * (1. + self.gamma / self.sell_k).powf(1. + self.sell_k / self.gamma))
-                .sqrt());
+    /// Without order updates an exit in flight is done once it no longer
+    /// rests on the book.
+    async fn poll_exits(&mut self) {
+        if self.exits_in_flight.is_empty() {
+            return;
+        }
+        match self.account_client.open_orders(&self.pair).await {
+            Ok(orders) => self
+                .exits_in_flight
+                .retain(|id, _| orders.iter().any(|x| x.order_id == *id)),
+            Err(err) => warn!("poll exits Error: {}", err),
+        }
+    }
 
-        let ask = (1. + self.gamma / self.buy_k).ln() / self.gamma
-            - ((q_fix - (0.5))
-                * ((sigma_fix * sigma_fix * self.gamma) / (2. * self.buy_k * self.buy_a)
-                    * (1. + self.gamma / self.buy_k).powf(1. + self.buy_k / self.gamma))
-                .sqrt());
+    /// Stand in for the account updates while the user data stream is down.
+    async fn poll_positions(&mut self) {
+        match self.account_client.position_information(&self.pair).await {
+            Ok(positions) => {
+                debug!("polled positions: {:?}", positions);
+                self.apply_positions(&positions);
+                self.poll_exits().await;
+                self.check_flatten().await;
+            }
+            Err(err) => warn!("poll positions Error: {}", err),
+        }
+    }
 
-        Spread { ask: ask, bid: bid }
+    fn apply_positions(&mut self, positions: &[PositionInfo]) {
+        let pair = self.pair.clone();
+        let ours = positions.iter().filter(|x| x.symbol.eq(&pair));
+        match self.config.position_mode {
+            PositionMode::OneWay => {
+                for x in ours.filter(|x| x.position_side.eq("BOTH")) {
+                    self.position.position_amount = x.position_amount;
+                    self.position.entry_price = x.entry_price;
+                }
+            }
+            PositionMode::Hedge => {
+                for x in ours {
+                    if x.position_side.eq("LONG") {
+                        self.long.position_amount = x.position_amount;
+                        self.long.entry_price = x.entry_price;
+                    } else if x.position_side.eq("SHORT") {
+                        self.short.position_amount = x.position_amount;
+                        self.short.entry_price = x.entry_price;
+                    }
+                }
+                self.net_legs();
+            }
+        }
+    }
+
+    /// Look for open orders and a position on the pair at startup, none of
+    /// them created by this run. They are an error unless `adopt_existing`,
+    /// which tracks the orders and takes the position over.
+    async fn check_existing(&mut self) -> error::Result<()> {
+        let orders = self
+            .account_client
+            .open_orders(&self.pair)
+            .await
+            .map_err(Error::exchange)?;
+        let positions = self
+            .account_client
+            .position_information(&self.pair)
+            .await
+            .map_err(Error::exchange)?;
+        let held = positions
+            .iter()
+            .filter(|x| x.symbol.eq(&self.pair) && x.position_amount != 0f64)
+            .count();
+        if orders.is_empty() && held == 0 {
+            return Ok(());
+        }
+        if !self.config.adopt_existing {
+            return Err(Error::Config(format!(
+                "{} open orders and {} positions on {} not created by the bot, set adopt_existing to take them over",
+                orders.len(),
+                held,
+                self.pair
+            )));
+        }
+
+        warn!(
+            "adopt existing orders: {:?}, positions: {:?}",
+            orders, positions
+        );
+        for order in &orders {
+            self.open_orders.insert(order.order_id);
+        }
+        self.apply_positions(&positions);
+        Ok(())
+    }
+
+    /// Post the smallest bid the filters allow `order_probe_distance` below
+    /// `bid` and cancel it, proving the keys may trade the pair. The round
+    /// trip is kept as the latency baseline.
+    async fn probe_order(&mut self, bid: f64) -> error::Result<()> {
+        let price = util::round_to_mode(
+            bid * (1f64 - self.config.order_probe_distance),
+            self.tick_round,
+            RoundMode::Floor,
+        );
+        let mut qty =
+            util::floor_to_step(self.min_notional / price, self.step_size).max(self.step_size);
+        if qty * price < self.min_notional {
+            qty = self.round_qty(qty + self.step_size);
+        }
+        if qty <= 0f64 {
+            qty = self.order_qty;
+        }
+        let (buy_side, _) = self.quote_position_sides();
+
+        let id = self.order_ids.next(true, 0);
+        let started = Instant::now();
+        let ack = self
+            .account_client
+            .limit_buy(
+                &self.pair,
+                qty,
+                price,
+                buy_side,
+                TimeInForce::GTC,
+                OrderFlags {
+                    client_order_id: id.as_deref(),
+                    reduce_only: false,
+                },
+            )
+            .await
+            .map_err(|e| {
+                Error::Exchange(format!("probe bid {} @ {} rejected: {}", qty, price, e))
+            })?;
+        if let Err(err) = self
+            .account_client
+            .cancel_order(&self.pair, ack.order_id)
+            .await
+        {
+            // the probe must not be left on the book whatever happens next
+            let fallback = match self.cancel_all_orders().await {
+                Ok(()) => "cancel all sent".to_string(),
+                Err(e) => format!("cancel all failed too: {}", e),
+            };
+            return Err(Error::Exchange(format!(
+                "probe cancel of {} rejected: {}, {}",
+                ack.order_id, err, fallback
+            )));
+        }
+        let round_trip = started.elapsed().as_millis() as u64;
+
+        info!(
+            "order probe {} @ {} round trip {}ms",
+            qty, price, round_trip
+        );
+        self.health.lock().unwrap().probe_round_trip_ms = Some(round_trip);
+        Ok(())
+    }
+
+    /// Compare the orders tracked from order updates with the ones REST
+    /// reports resting. An untracked order updated within a quote period is
+    /// taken as a missed NEW and tracked, an older one is cancelled. Tracked
+    /// orders no longer resting are dropped.
+    async fn reconcile_open_orders(&mut self, now: u64) {
+        // a cycle in flight would race its own NEW updates
+        let quote_lock = self.quote_lock.clone();
+        let _cycle = quote_lock.lock().await;
+
+        let orders = match self.account_client.open_orders(&self.pair).await {
+            Ok(orders) => orders,
+            Err(err) => {
+                warn!("open orders Error: {}, skip reconciliation", err);
+                return;
+            }
+        };
+
+        let resting: HashSet<u64> = orders.iter().map(|x| x.order_id).collect();
+        let stale: Vec<u64> = self.open_orders.difference(&resting).cloned().collect();
+        for order_id in stale {
+            warn!("tracked order {} is not resting, drop it", order_id);
+            self.open_orders.remove(&order_id);
+            self.resting.lock().unwrap().close(order_id);
+        }
+
+        for order in orders {
+            if self.open_orders.contains(&order.order_id) {
+                continue;
+            }
+            // manual orders and other instances' share the account
+            if !self.order_ids.owns(&order.client_order_id) {
+                debug!("untracked order {:?} is not ours, leave it", order);
+                continue;
+            }
+            if now.saturating_sub(order.update_time) <= self.quote_period {
+                warn!("untracked recent order {:?}, track it", order);
+                self.open_orders.insert(order.order_id);
+            } else {
+                warn!("untracked order {:?}, cancel it", order);
+                if let Err(err) = self
+                    .account_client
+                    .cancel_order(&self.pair, order.order_id)
+                    .await
+                {
+                    warn!("cancel order {} Error: {}", order.order_id, err);
+                }
+            }
+        }
+    }
+
+    /// Cancel everything when more orders than `max_open_orders` are known
+    /// open. Returns false if that cancel failed too, so this cycle should
+    /// not add more.
+    async fn enforce_open_order_limit(&mut self) -> bool {
+        let max_open_orders = match self.config.max_open_orders {
+            Some(max_open_orders) => max_open_orders,
+            None => return true,
+        };
+        if self.open_orders.len() <= max_open_orders {
+            return true;
+        }
+
+        warn!(
+            "{} open orders over max_open_orders: {}, force cancel all",
+            self.open_orders.len(),
+            max_open_orders
+        );
+        match self.cancel_all_orders().await {
+            Ok(answer) => {
+                info!("Force cancel all open orders: {:?}", answer);
+                true
+            }
+            Err(err) => {
+                warn!(
+                    "Force cancel all open orders Error: {:?}, skip quoting",
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    fn check_session_loss(&mut self) {
+        if let Some(max_session_loss) = self.config.max_session_loss {
+            if !self.session_loss_breached && self.total_profit < -max_session_loss {
+                warn!(
+                    "total_profit: {} breached max_session_loss: {}, pause quoting",
+                    self.total_profit, max_session_loss
+                );
+                self.session_loss_breached = true;
+            }
+        }
+    }
+
+    fn accounting_state(&self) -> AccountingState {
+        AccountingState {
+            session_start: self.session_start,
+            total_profit: self.total_profit,
+            total_fees: self.total_fees,
+            order_count: self.order_count,
+            fill_count: self.fill_count,
+        }
+    }
+
+    fn save_state(&self) {
+        let path = match &self.config.state_file {
+            Some(path) => path,
+            None => return,
+        };
+        let state = self.accounting_state();
+        let state = match &self.state_writer {
+            Some((tx, _)) => match tx.send(state) {
+                Ok(()) => return,
+                Err(err) => err.0,
+            },
+            None => state,
+        };
+        if let Err(err) = write_state(path, &state) {
+            warn!("save state to {} Error: {}", path, err);
+        }
+    }
+
+    /// Load the accounting saved by a previous run, if it belongs to the
+    /// session `now` falls in. A stale file is ignored.
+    fn restore_state(&mut self, now: u64) {
+        let path = match &self.config.state_file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let state: AccountingState = match std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| serde_json::from_str(&body).map_err(anyhow::Error::from))
+        {
+            Ok(state) => state,
+            Err(err) => {
+                info!("no state restored from {}: {}", path, err);
+                return;
+            }
+        };
+
+        let session_start = util::session_start(now, self.config.session_reset_utc_ms);
+        if state.session_start != session_start {
+            info!("state in {} is from a previous session, ignored", path);
+            return;
+        }
+
+        info!("restored {:?}", state);
+        self.session_start = state.session_start;
+        self.total_profit = state.total_profit;
+        self.total_fees = state.total_fees;
+        self.order_count = state.order_count;
+        self.fill_count = state.fill_count;
+        self.check_session_loss();
+    }
+
+    pub fn total_profit(&self) -> f64 {
+        self.total_profit
+    }
+
+    /// Ms from local time `now` to the next `session_reset_utc_ms` boundary
+    /// on the exchange clock.
+    fn until_session_roll(&self, now: u64) -> u64 {
+        let ts = self.clock.to_exchange(now);
+        util::session_start(ts, self.config.session_reset_utc_ms) + util::DAY_MS - ts
+    }
+
+    /// Reset the session accounting when `ts` crosses the daily boundary,
+    /// returning the summary of the session that ended. The session loss
+    /// breaker is re-armed with the new session.
+    fn roll_session(&mut self, ts: u64) -> Option<SessionSummary> {
+        let session_start = util::session_start(ts, self.config.session_reset_utc_ms);
+        if self.session_start == 0 {
+            self.session_start = session_start;
+            return None;
+        }
+        if session_start <= self.session_start {
+            return None;
+        }
+
+        let summary = SessionSummary {
+            start: self.session_start,
+            end: session_start,
+            total_profit: self.total_profit,
+            total_fees: self.total_fees,
+            order_count: self.order_count,
+            fill_count: self.fill_count,
+        };
+        info!("session finished: {:?}", summary);
+
+        self.session_start = session_start;
+        self.total_profit = 0f64;
+        self.total_fees = 0f64;
+        self.order_count = 0;
+        self.fill_count = 0;
+        self.session_loss_breached = false;
+
+        Some(summary)
+    }
+
+    /// Mark the position at `pnl_price`, in `pnl_units`.
+    fn update_unrealized_pnl(&mut self) {
+        let mut bid = *self.strategy_data.bid_price.back().unwrap();
+        let mut ask = *self.strategy_data.ask_price.back().unwrap();
+        if self.config.pnl_price == PnlPrice::Mark {
+            let now = *self.strategy_data.timestamp.back().unwrap();
+            match self
+                .mark
+                .lock()
+                .unwrap()
+                .fresh(now, self.config.mark_stale_ms)
+            {
+                Some(mark) => {
+                    bid = mark;
+                    ask = mark;
+                }
+                None => debug!("mark price stale, mark pnl at the touch"),
+            }
+        }
+
+        // an inventory without a usable entry yet, e.g. the account update
+        // lags the fill, has no pnl to act on
+        let entry_price = self.position.entry_price;
+        let pnl_context = self.position.position_amount == 0f64
+            || (entry_price.is_finite() && entry_price > 0f64);
+        if !pnl_context && self.pnl_context {
+            warn!(
+                "position {} without entry_price ({}), skip pnl actions",
+                self.position.position_amount, entry_price
+            );
+        }
+        self.pnl_context = pnl_context;
+        if !pnl_context {
+            self.unrealized_pnl = 0f64;
+            return;
+        }
+
+        // return on the margin asset value at entry, an inverse long earns
+        // size * (1 / entry - 1 / exit) on size / entry
+        let entry = self.position.entry_price;
+        let ratio = match (self.config.contract_type, self.position.position_amount) {
+            (_, q) if q == 0f64 => {
+                self.unrealized_pnl = 0f64;
+                return;
+            }
+            (ContractType::Linear, q) if q > 0f64 => bid / entry - 1f64,
+            (ContractType::Linear, _) => -(ask / entry - 1f64),
+            (ContractType::Inverse, q) if q > 0f64 => 1f64 - entry / bid,
+            (ContractType::Inverse, _) => entry / ask - 1f64,
+        };
+
+        self.unrealized_pnl = match self.config.stop_units() {
+            PnlUnits::Ratio => ratio,
+            PnlUnits::Bps => ratio * 1e4,
+            PnlUnits::QuoteCurrency => {
+                ratio
+                    * self
+                        .config
+                        .margin_value(self.position.position_amount.abs(), entry)
+            }
+        };
+    }
+
+    /// Restart the stoploss grace when the position opened, flipped or grew
+    /// since the last tick.
+    fn update_stoploss_grace(&mut self, ts: u64) {
+        let amount = self.position.position_amount;
+        let last = self.grace_amount;
+        if amount != 0f64
+            && (last == 0f64 || amount.signum() != last.signum() || amount.abs() > last.abs())
+        {
+            self.grace_since = ts;
+        }
+        self.grace_amount = amount;
+    }
+
+    fn in_stoploss_grace(&self, ts: u64) -> bool {
+        match self.config.stoploss_grace_ms {
+            Some(grace) => {
+                self.position.position_amount != 0f64 && ts < self.grace_since.saturating_add(grace)
+            }
+            None => false,
+        }
+    }
+
+    /// Ratchet the best pnl of the current position and report whether pnl
+    /// gave back `trailing_stop` from it. The stop arms once the peak itself
+    /// reached `trailing_stop`, so it only locks in gains. A flip or a
+    /// flatten starts a new peak.
+    fn trailing_stop_hit(&mut self) -> bool {
+        let side = if self.position.position_amount > 0f64 {
+            1
+        } else if self.position.position_amount < 0f64 {
+            -1
+        } else {
+            0
+        };
+        if side != self.peak_side {
+            self.peak_side = side;
+            self.peak_pnl = self.unrealized_pnl;
+            self.active_trailing_stop = false;
+        }
+        if side == 0 {
+            return false;
+        }
+
+        if self.unrealized_pnl > self.peak_pnl {
+            self.peak_pnl = self.unrealized_pnl;
+        }
+        if !self.active_trailing_stop && self.peak_pnl >= self.trailing_stop {
+            info!("trailing stop armed at peak pnl: {}", self.peak_pnl);
+            self.active_trailing_stop = true;
+        }
+
+        self.active_trailing_stop && self.unrealized_pnl <= self.peak_pnl - self.trailing_stop
+    }
+
+    /// Price the quotes are centered on, per `fair_value`.
+    fn fair_value(&self) -> f64 {
+        let reference = *self.strategy_data.reference.back().unwrap();
+        let mut reference = self.anchor.and_then(|x| x.value()).unwrap_or(reference);
+        if self.config.reference_weight > 0f64 {
+            let now = *self.strategy_data.timestamp.back().unwrap();
+            let secondary = self
+                .secondary
+                .lock()
+                .unwrap()
+                .fresh(now, self.config.reference_stale_ms);
+            if let Some(mid) = secondary {
+                let weight = self.config.reference_weight;
+                reference = (1f64 - weight) * reference + weight * mid;
+            }
+        }
+        match self.config.fair_value {
+            FairValue::Wap => reference,
+            FairValue::LastTrade => self.strategy_data.last_trade.unwrap_or(reference),
+        }
+    }
+
+    /// Price distance of `v`, given in `units`, at fair value `fair`.
+    fn to_price(&self, v: f64, fair: f64) -> f64 {
+        match self.config.units {
+            Units::Absolute => v,
+            Units::Bps => v * fair / 1e4,
+        }
+    }
+
+    /// Turn the AS offsets into the orders to post around the fair value.
+    fn build_quote(&self, spread: Spread) -> Quote {
+        let fair = self.fair_value();
+        let mut spread = spread;
+        if let (Some(markout), Some(widen)) = (&self.markout, self.config.markout_widen) {
+            // a side whose fills keep losing is being picked off
+            if markout.buy.adverse(markout.window) {
+                debug!("bid markout {:?} adverse, widen", markout.buy.average());
+                spread.bid *= widen;
+            }
+            if markout.sell.adverse(markout.window) {
+                debug!("ask markout {:?} adverse, widen", markout.sell.average());
+                spread.ask *= widen;
+            }
+        }
+        if let Some(min) = self.config.min_spread {
+            let min = self.to_price(min, fair);
+            spread.bid = spread.bid.max(min);
+            spread.ask = spread.ask.max(min);
+        }
+        if let Some(max) = self.config.max_spread {
+            let max = self.to_price(max, fair);
+            spread.bid = spread.bid.min(max);
+            spread.ask = spread.ask.min(max);
+        }
+        let base_qty = self.drawn_qty.unwrap_or(self.order_qty);
+        let mut bid_qty = self.config.bid_qty.unwrap_or(base_qty);
+        let mut ask_qty = self.config.ask_qty.unwrap_or(base_qty);
+        if self.config.size_skew != 0f64 {
+            let q = self.position.position_amount / self.order_qty / self.q_max;
+            let lean = (self.config.size_skew * q).max(-1f64).min(1f64);
+            bid_qty *= 1f64 - lean;
+            ask_qty *= 1f64 + lean;
+            debug!(
+                "size skew {}, bid_qty: {}, ask_qty: {}",
+                lean, bid_qty, ask_qty
+            );
+        }
+        let mut bid_skip = None;
+        let mut ask_skip = None;
+
+        if let Some(soft_inventory) = self.config.soft_inventory {
+            let q = self.position.position_amount / self.order_qty;
+            let tightening = self.config.soft_inventory_tightening;
+
+            if q.abs() >= soft_inventory * self.q_max {
+                if q > 0f64 {
+                    bid_qty = 0f64;
+                    if spread.ask > 0f64 {
+                        spread.ask *= tightening;
+                    }
+                } else {
+                    ask_qty = 0f64;
+                    if spread.bid > 0f64 {
+                        spread.bid *= tightening;
+                    }
+                }
+                info!(
+                    "q {} beyond soft inventory {}, quote reducing side only",
+                    q,
+                    soft_inventory * self.q_max
+                );
+            }
+            note_skip(bid_qty, &mut bid_skip, "soft_inventory");
+            note_skip(ask_qty, &mut ask_skip, "soft_inventory");
+        }
+
+        if let Some(max_exposure) = self.config.max_notional_exposure {
+            let now = *self.strategy_data.timestamp.back().unwrap();
+            let mark = self
+                .mark
+                .lock()
+                .unwrap()
+                .fresh(now, self.config.mark_stale_ms)
+                .unwrap_or(fair);
+            let q = self.position.position_amount;
+            let exposure = self.config.quote_value(q.abs(), mark);
+            if exposure >= max_exposure {
+                warn!(
+                    "notional exposure {} reached max {}, quote reducing side only",
+                    exposure, max_exposure
+                );
+                if q > 0f64 {
+                    bid_qty = 0f64;
+                } else {
+                    ask_qty = 0f64;
+                }
+                note_skip(bid_qty, &mut bid_skip, "max_notional_exposure");
+                note_skip(ask_qty, &mut ask_skip, "max_notional_exposure");
+            }
+        }
+
+        if let Some(margin_bps) = self.config.adverse_margin_bps {
+            let (bid_ok, ask_ok) = self.adverse_selection_gate(spread, fair, margin_bps);
+            if !bid_ok {
+                bid_qty = 0f64;
+            }
+            if !ask_ok {
+                ask_qty = 0f64;
+            }
+            note_skip(bid_qty, &mut bid_skip, "adverse_selection");
+            note_skip(ask_qty, &mut ask_skip, "adverse_selection");
+        }
+
+        let momentum = self.strategy_data.momentum();
+        let max_decay = fair * self.config.inventory_decay_max_bps / 1e4;
+        let decay = (-self.config.inventory_decay * self.held_inventory * fair)
+            .max(-max_decay)
+            .min(max_decay);
+        let shift = self.config.momentum_skew * momentum * fair + decay;
+        if decay != 0f64 {
+            debug!(
+                "held inventory: {}, decay shift: {}",
+                self.held_inventory, decay
+            );
+        }
+        if self.config.momentum_size != 0f64 {
+            let tilt = (self.config.momentum_size * momentum).max(-1f64).min(1f64);
+            bid_qty *= 1f64 + tilt;
+            ask_qty *= 1f64 - tilt;
+            note_skip(bid_qty, &mut bid_skip, "momentum_size");
+            note_skip(ask_qty, &mut ask_skip, "momentum_size");
+        }
+        if shift != 0f64 || self.config.momentum_size != 0f64 {
+            debug!(
+                "momentum: {}, shift: {}, bid_qty: {}, ask_qty: {}",
+                momentum, shift, bid_qty, ask_qty
+            );
+        }
+
+        if let Some(cooldown) = self.config.post_fill_cooldown_ms {
+            let now = *self.strategy_data.timestamp.back().unwrap();
+            let cooling = |last: u64| last > 0 && now < last + cooldown;
+            if cooling(self.last_buy_fill) {
+                debug!("bid cooling down since fill at {}", self.last_buy_fill);
+                bid_qty = 0f64;
+            }
+            if cooling(self.last_sell_fill) {
+                debug!("ask cooling down since fill at {}", self.last_sell_fill);
+                ask_qty = 0f64;
+            }
+            note_skip(bid_qty, &mut bid_skip, "post_fill_cooldown");
+            note_skip(ask_qty, &mut ask_skip, "post_fill_cooldown");
+        }
+
+        if let Some(fraction) = self.config.max_cycle_inventory {
+            let limit = self.q_max * self.order_qty;
+            let position = self.position.position_amount;
+            // a bid fill moves inventory up, an ask fill down
+            bid_qty = bid_qty
+                .min(fraction * limit)
+                .min((limit - position).max(0f64));
+            ask_qty = ask_qty
+                .min(fraction * limit)
+                .min((limit + position).max(0f64));
+            note_skip(bid_qty, &mut bid_skip, "max_cycle_inventory");
+            note_skip(ask_qty, &mut ask_skip, "max_cycle_inventory");
+        }
+
+        if let Some(buffer) = self.config.margin_buffer {
+            // no balance reported yet, nothing to size against
+            if self.cash > 0f64 {
+                let limit = self.cash * (1f64 - buffer) * self.config.leverage
+                    / self.config.margin_value(1f64, fair);
+                let position = self.position.position_amount;
+                bid_qty = bid_qty.min((limit - position).max(0f64));
+                ask_qty = ask_qty.min((limit + position).max(0f64));
+                debug!(
+                    "margin covers {} at cash {}, bid_qty: {}, ask_qty: {}",
+                    limit, self.cash, bid_qty, ask_qty
+                );
+                note_skip(bid_qty, &mut bid_skip, "margin_buffer");
+                note_skip(ask_qty, &mut ask_skip, "margin_buffer");
+            }
+        }
+
+        let mut bid_price = fair + shift - spread.bid;
+        let mut ask_price = fair + shift + spread.ask;
+        if self.config.min_edge_bps > 0f64 {
+            // the gross distance has to pay the maker fee, a rebate lowers it
+            let min_edge_bps = (self.config.min_edge_bps + self.config.maker_fee_bps).max(0f64);
+            let min_edge = fair * min_edge_bps / 1e4;
+            bid_price = bid_price.min(fair - min_edge);
+            ask_price = ask_price.max(fair + min_edge);
+        }
+
+        // round away from fair so neither side quotes inside its spread
+        bid_price = util::round_to_mode(bid_price, self.tick_round, RoundMode::Floor);
+        ask_price = util::round_to_mode(ask_price, self.tick_round, RoundMode::Ceil);
+        debug!(
+            "net edge bps, bid: {}, ask: {}, maker_fee_bps: {}",
+            (fair - bid_price) / fair * 1e4 - self.config.maker_fee_bps,
+            (ask_price - fair) / fair * 1e4 - self.config.maker_fee_bps,
+            self.config.maker_fee_bps
+        );
+
+        if let Some(max_ticks) = self.config.max_touch_ticks {
+            let max_distance = max_ticks as f64 * self.tick_size;
+            let best_bid = *self.strategy_data.bid_price.back().unwrap();
+            let best_ask = *self.strategy_data.ask_price.back().unwrap();
+            let lowest_bid = util::round_to(best_bid - max_distance, self.tick_round);
+            let highest_ask = util::round_to(best_ask + max_distance, self.tick_round);
+            let action = self.config.touch_distance_action;
+            if bid_price < lowest_bid {
+                debug!(
+                    "bid {} beyond {} ticks of the touch, {:?}",
+                    bid_price, max_ticks, action
+                );
+                match action {
+                    TouchDistanceAction::Clamp => bid_price = lowest_bid,
+                    TouchDistanceAction::Skip => bid_qty = 0f64,
+                }
+            }
+            if ask_price > highest_ask {
+                debug!(
+                    "ask {} beyond {} ticks of the touch, {:?}",
+                    ask_price, max_ticks, action
+                );
+                match action {
+                    TouchDistanceAction::Clamp => ask_price = highest_ask,
+                    TouchDistanceAction::Skip => ask_qty = 0f64,
+                }
+            }
+            note_skip(bid_qty, &mut bid_skip, "max_touch_ticks");
+            note_skip(ask_qty, &mut ask_skip, "max_touch_ticks");
+        }
+
+        let (bid_qty, bid_dropped) = self.order_size(bid_qty, bid_price);
+        let (ask_qty, ask_dropped) = self.order_size(ask_qty, ask_price);
+        if bid_dropped || ask_dropped {
+            warn!(
+                "qty rounds to zero, skip bid: {}, skip ask: {} (step_size: {}, min_notional: {})",
+                bid_dropped, ask_dropped, self.step_size, self.min_notional
+            );
+        }
+        note_skip(bid_qty, &mut bid_skip, "min_size");
+        note_skip(ask_qty, &mut ask_skip, "min_size");
+
+        Quote {
+            bid_price: bid_price,
+            bid_qty: bid_qty,
+            ask_price: ask_price,
+            ask_qty: ask_qty,
+            band: self.price_band(fair),
+            bid_skip: bid_skip,
+            ask_skip: ask_skip,
+            timestamp: *self.strategy_data.timestamp.back().unwrap(),
+        }
+    }
+
+    fn decision(&self, ts: u64, spread: Spread, quote: &Quote) -> QuoteDecision {
+        QuoteDecision {
+            timestamp: ts,
+            wap: *self.strategy_data.wap.back().unwrap(),
+            sigma: self.sigma,
+            q: self.position.position_amount,
+            buy_a: self.buy_a,
+            buy_k: self.buy_k,
+            sell_a: self.sell_a,
+            sell_k: self.sell_k,
+            bid_offset: spread.bid,
+            ask_offset: spread.ask,
+            bid_price: quote.bid_price,
+            bid_qty: quote.bid_qty,
+            ask_price: quote.ask_price,
+            ask_qty: quote.ask_qty,
+            bid_placed: quote.bid_qty > 0f64,
+            ask_placed: quote.ask_qty > 0f64,
+            bid_skip: quote.bid_skip,
+            ask_skip: quote.ask_skip,
+        }
+    }
+
+    /// PERCENT_PRICE band around the mark price, or `fair` while the mark is
+    /// stale, None without the filter.
+    fn price_band(&self, fair: f64) -> Option<PriceBand> {
+        let (up, down) = self.percent_price?;
+        let now = *self.strategy_data.timestamp.back().unwrap();
+        let reference = self
+            .mark
+            .lock()
+            .unwrap()
+            .fresh(now, self.config.mark_stale_ms)
+            .unwrap_or(fair);
+        Some(PriceBand {
+            low: util::round_to_mode(reference * down, self.tick_round, RoundMode::Ceil),
+            high: util::round_to_mode(reference * up, self.tick_round, RoundMode::Floor),
+        })
+    }
+
+    /// Whether each side's spread covers its expected adverse selection and
+    /// the maker fee plus `margin_bps`. The cost of a side is the move
+    /// volatility allows while the quote waits for a fill,
+    /// fair * sigma * sqrt(1 / lambda), with lambda = A * exp(-k * spread)
+    /// from the intensity fit of the market orders that would hit it.
+    fn adverse_selection_gate(&self, spread: Spread, fair: f64, margin_bps: f64) -> (bool, bool) {
+        let sigma = self.sigma * self.sigma_multiplier;
+        let margin = fair * (margin_bps + self.config.maker_fee_bps) / 1e4;
+        let cost = |a: f64, k: f64, delta: f64| {
+            let intensity = a * (-k * delta).exp();
+            fair * sigma * (1f64 / intensity).sqrt()
+        };
+        let bid_cost = cost(self.sell_a, self.sell_k, spread.bid);
+        let ask_cost = cost(self.buy_a, self.buy_k, spread.ask);
+        let bid_ok = spread.bid - bid_cost >= margin;
+        let ask_ok = spread.ask - ask_cost >= margin;
+
+        info!(
+            "adverse selection, bid spread: {} cost: {} quote: {}, ask spread: {} cost: {} quote: {}, margin: {}",
+            spread.bid, bid_cost, bid_ok, spread.ask, ask_cost, ask_ok, margin
+        );
+        (bid_ok, ask_ok)
+    }
+
+    /// Floor `qty` to the step size and drop it below min_notional. The flag
+    /// tells a size that rounded away from one that was zero to begin with.
+    fn order_size(&self, qty: f64, price: f64) -> (f64, bool) {
+        if qty <= 0f64 {
+            return (0f64, false);
+        }
+        let rounded = self.round_qty(util::floor_to_step(qty, self.step_size));
+        if rounded <= 0f64 || rounded * price < self.min_notional {
+            return (0f64, true);
+        }
+        (rounded, false)
+    }
+
+    /// Floor an order quantity to the qty precision.
+    fn round_qty(&self, qty: f64) -> f64 {
+        match self.qty_round {
+            Some(decimals) => util::round_to_mode(qty, decimals, RoundMode::Floor),
+            None => qty,
+        }
+    }
+
+    /// Adopt the exchange filters. The intensity estimator buckets spreads by
+    /// tick_size, so it is rebuilt with the resolved tick.
+    fn apply_filters(&mut self, filters: &SymbolFilters) {
+        // the exchange tick wins over an inferred one
+        self.tick_samples = None;
+        self.step_size = filters.step_size;
+        if self.config.qty_precision.is_none() && filters.step_size > 0f64 {
+            self.qty_round = Some(util::decimals(filters.step_size));
+        }
+        self.min_notional = filters.min_notional;
+        if filters.multiplier_up > 0f64 && filters.multiplier_down > 0f64 {
+            self.percent_price = Some((filters.multiplier_up, filters.multiplier_down));
+        }
+        self.set_tick_size(filters.tick_size);
+
+        info!(
+            "resolved filters, tick_size: {}, tick_round: {}, step_size: {}, min_notional: {}",
+            self.tick_size, self.tick_round, self.step_size, self.min_notional
+        );
+    }
+
+    /// Lock in the grid of the sampled prices as tick_size. Keeps sampling
+    /// while there is no configured tick to fall back on.
+    fn infer_tick_size(&mut self) {
+        let samples = self.tick_samples.take().unwrap_or_default();
+        match util::price_grid(&samples) {
+            Some(tick) => {
+                if self.config.tick_size > 0f64 && (tick - self.config.tick_size).abs() > 1e-12 {
+                    warn!(
+                        "inferred tick_size {} mismatches configured {}",
+                        tick, self.config.tick_size
+                    );
+                }
+                info!("inferred tick_size {} from {} prices", tick, samples.len());
+                self.set_tick_size(tick);
+            }
+            None if self.tick_size > 0f64 => {
+                warn!(
+                    "no price grid in {} prices, keep tick_size {}",
+                    samples.len(),
+                    self.tick_size
+                );
+            }
+            None => self.tick_samples = Some(samples),
+        }
+    }
+
+    /// The intensity estimator buckets spreads by tick_size, so it is
+    /// rebuilt with the new tick.
+    fn set_tick_size(&mut self, tick_size: f64) {
+        self.tick_size = tick_size;
+        self.tick_round = util::decimals(tick_size);
+
+        self.ie = IntensityEstimator::new(
+            self.tick_size,
+            self.n_spreads,
+            self.estimate_window,
+            self.estimate_cadence.period,
+            AkSolverFactory::new(&SolverType::LogRegression),
+        );
+        self.estimate_cadence = util::Cadence::new(self.estimate_cadence.period);
+        self.intensity_info = None;
+    }
+
+    /// Net the hedge-mode legs into `position`, which drives pnl and quoting.
+    /// The SHORT leg is reported with a negative amount, so the net is a sum.
+    fn net_legs(&mut self) {
+        self.position.position_amount = self.long.position_amount + self.short.position_amount;
+        self.position.entry_price = if self.position.position_amount > 0f64 {
+            self.long.entry_price
+        } else if self.position.position_amount < 0f64 {
+            self.short.entry_price
+        } else {
+            0f64
+        };
+    }
+
+    /// Position sides for the (buy, sell) quotes. In hedge mode a quote closes
+    /// the opposite leg when that leg can absorb a full order, otherwise it
+    /// opens its own leg.
+    fn quote_position_sides(&self) -> (PositionSide, PositionSide) {
+        match self.config.position_mode {
+            PositionMode::OneWay => (PositionSide::Both, PositionSide::Both),
+            PositionMode::Hedge => {
+                let buy_side = if self.short.position_amount.abs() >= self.order_qty {
+                    PositionSide::Short
+                } else {
+                    PositionSide::Long
+                };
+                let sell_side = if self.long.position_amount >= self.order_qty {
+                    PositionSide::Long
+                } else {
+                    PositionSide::Short
+                };
+                (buy_side, sell_side)
+            }
+        }
+    }
+
+    /// Close every leg, returning the outcome of each order with the amount
+    /// of the leg it closes. A `Market` close in one-way mode is a market
+    /// order for the net position. Otherwise each leg gets an IOC limit
+    /// carrying its position side, at the touch for `Market` in hedge mode
+    /// or `stoploss_limit_ticks` through it for `AggressiveLimit`.
+    async fn flatten(
+        &self,
+        reason: &str,
+        order_type: StoplossOrderType,
+    ) -> Vec<(f64, Result<OrderAck>)> {
+        let offset = match order_type {
+            StoplossOrderType::Market => 0f64,
+            StoplossOrderType::AggressiveLimit => {
+                self.config.stoploss_limit_ticks as f64 * self.tick_size
+            }
+        };
+        let legs = match self.config.position_mode {
+            PositionMode::OneWay => vec![self.position.position_amount],
+            PositionMode::Hedge => vec![self.long.position_amount, self.short.position_amount],
+        };
+
+        let mut acks = Vec::new();
+        for amount in legs {
+            if amount == 0f64 {
+                continue;
+            }
+            let market = self.config.position_mode == PositionMode::OneWay
+                && order_type == StoplossOrderType::Market;
+            let qty = self.round_qty(amount.abs());
+            let id = self.order_ids.next(amount < 0f64, 0);
+            let flags = self.exit_flags(id.as_deref());
+            let result = match (market, amount > 0f64) {
+                (true, true) => {
+                    self.account_client
+                        .market_sell(&self.pair, qty, flags)
+                        .await
+                }
+                (true, false) => self.account_client.market_buy(&self.pair, qty, flags).await,
+                (false, true) => {
+                    let best_bid = *self.strategy_data.bid_price.back().unwrap();
+                    let price = util::round_to(best_bid - offset, self.tick_round);
+                    self.account_client
+                        .limit_sell(
+                            &self.pair,
+                            qty,
+                            price,
+                            self.exit_side(amount),
+                            TimeInForce::IOC,
+                            flags,
+                        )
+                        .await
+                }
+                (false, false) => {
+                    let best_ask = *self.strategy_data.ask_price.back().unwrap();
+                    let price = util::round_to(best_ask + offset, self.tick_round);
+                    self.account_client
+                        .limit_buy(
+                            &self.pair,
+                            qty,
+                            price,
+                            self.exit_side(amount),
+                            TimeInForce::IOC,
+                            flags,
+                        )
+                        .await
+                }
+            };
+            match &result {
+                Ok(answer) => info!("{} close {} {:?}", reason, amount, answer),
+                Err(err) if exchange::is_reduce_only_rejected(err) => {
+                    info!(
+                        "{} close {}: position already flat ({})",
+                        reason, amount, err
+                    )
+                }
+                Err(err) => warn!("{} close {} Error: {}", reason, amount, err),
+            }
+            acks.push((amount, result));
+        }
+        acks
+    }
+
+    /// Legs the exchange reported as already closed.
+    fn flat_legs(results: &[(f64, Result<OrderAck>)]) -> Vec<f64> {
+        results
+            .iter()
+            .filter(|(_, result)| match result {
+                Err(err) => exchange::is_reduce_only_rejected(err),
+                Ok(_) => false,
+            })
+            .map(|(amount, _)| *amount)
+            .collect()
+    }
+
+    /// Zero the legs that closed before their exit reached the exchange, and
+    /// drop the pending exit once nothing is left.
+    fn clear_flat_legs(&mut self, legs: &[f64]) {
+        if legs.is_empty() {
+            return;
+        }
+        for amount in legs {
+            match self.config.position_mode {
+                PositionMode::OneWay => self.position.position_amount = 0f64,
+                PositionMode::Hedge if *amount > 0f64 => self.long.position_amount = 0f64,
+                PositionMode::Hedge => self.short.position_amount = 0f64,
+            }
+        }
+        if self.config.position_mode == PositionMode::Hedge {
+            self.net_legs();
+        }
+        if self.is_flat() {
+            info!("position already flat, exit done");
+            self.pending_flatten = None;
+            self.flatten_attempts = 0;
+        }
+    }
+
+    /// Flatten and keep checking the following account updates until the
+    /// position is confirmed closed.
+    async fn flatten_and_verify(&mut self, reason: &str, order_type: StoplossOrderType) {
+        if self.is_flat() {
+            return;
+        }
+        let wap = self.strategy_data.wap.back().copied();
+        let results = self.flatten(reason, order_type).await;
+        self.pending_flatten = Some((reason.to_string(), order_type));
+        self.flatten_attempts = 0;
+        self.track_exits(&results);
+
+        // a market order usually acks before it fills, its price then comes
+        // with the fill
+        self.exit_wap.clear();
+        if let Some(wap) = wap {
+            for (amount, result) in results.iter() {
+                if let Ok(ack) = result {
+                    if ack.avg_price > 0f64 && ack.executed_qty > 0f64 {
+                        self.record_slippage(
+                            -amount.signum() * ack.executed_qty,
+                            ack.avg_price,
+                            wap,
+                        );
+                    } else {
+                        self.exit_wap.insert(ack.client_order_id.clone(), wap);
+                    }
+                }
+            }
+        }
+        self.clear_flat_legs(&Self::flat_legs(&results));
+    }
+
+    /// Cost of an exit fill of signed `qty` at `price` against the wap when
+    /// it was sent.
+    fn record_slippage(&mut self, qty: f64, price: f64, wap: f64) {
+        let slippage = (price - wap) * qty;
+        let bps = (price - wap) / wap * 1e4 * qty.signum();
+        self.total_slippage += slippage;
+        self.health.lock().unwrap().exit_slippage = self.total_slippage;
+        warn!(
+            "exit slippage {} ({} bps) filling {} at {} vs wap {}, session total {}",
+            slippage, bps, qty, price, wap, self.total_slippage
+        );
+    }
+
+    /// Take profit on the position, laddered when it is too large to exit in
+    /// one clip at the touch.
+    async fn exit_profit(&mut self, reason: &str) {
+        if self.config.refresh_position_on_exit {
+            match self.account_client.position_information(&self.pair).await {
+                Ok(positions) => self.apply_positions(&positions),
+                Err(err) => warn!(
+                    "{} position refresh Error: {}, exit on the last update",
+                    reason, err
+                ),
+            }
+        }
+        if self.is_flat() {
+            info!("{}: position already flat, no exit", reason);
+            return;
+        }
+        let size = match self.config.position_mode {
+            PositionMode::OneWay => self.position.position_amount.abs(),
+            PositionMode::Hedge => self.long.position_amount.max(-self.short.position_amount),
+        };
+        match self.config.stopprofit_ladder_qty {
+            Some(ladder_qty) if ladder_qty > 0f64 && size > ladder_qty => {
+                let results = self.ladder_exit(reason, ladder_qty).await;
+                self.pending_flatten = Some((reason.to_string(), StoplossOrderType::Market));
+                self.flatten_attempts = 0;
+                self.track_exits(&results);
+                self.clear_flat_legs(&Self::flat_legs(&results));
+            }
+            _ => {
+                self.flatten_and_verify(reason, StoplossOrderType::Market)
+                    .await
+            }
+        }
+    }
+
+    /// Close each leg in `ladder_qty` IOC clips, walking one step further
+    /// through the book per clip so a large position still gets out.
+    /// Returns the outcome of each clip with its qty, signed as the leg it
+    /// closes.
+    async fn ladder_exit(&self, reason: &str, ladder_qty: f64) -> Vec<(f64, Result<OrderAck>)> {
+        let best_bid = *self.strategy_data.bid_price.back().unwrap();
+        let best_ask = *self.strategy_data.ask_price.back().unwrap();
+        let step = if self.config.stopprofit_ladder_step > 0f64 {
+            self.config.stopprofit_ladder_step
+        } else {
+            self.tick_size
+        };
+        let legs = match self.config.position_mode {
+            PositionMode::OneWay => vec![self.position.position_amount],
+            PositionMode::Hedge => vec![self.long.position_amount, self.short.position_amount],
+        };
+
+        let mut results = Vec::new();
+        for amount in legs {
+            let mut remaining = amount.abs();
+            let mut level = 0f64;
+            while remaining > 0f64 {
+                let qty = self.round_qty(util::round_to(remaining.min(ladder_qty), 9));
+                if qty <= 0f64 {
+                    warn!(
+                        "{} ladder residual {} below qty precision",
+                        reason, remaining
+                    );
+                    break;
+                }
+                let id = self.order_ids.next(amount < 0f64, level as u32);
+                let flags = self.exit_flags(id.as_deref());
+                let result = if amount > 0f64 {
+                    let price = util::round_to(best_bid - level * step, self.tick_round);
+                    self.account_client
+                        .limit_sell(
+                            &self.pair,
+                            qty,
+                            price,
+                            self.exit_side(amount),
+                            TimeInForce::IOC,
+                            flags,
+                        )
+                        .await
+                } else {
+                    let price = util::round_to(best_ask + level * step, self.tick_round);
+                    self.account_client
+                        .limit_buy(
+                            &self.pair,
+                            qty,
+                            price,
+                            self.exit_side(amount),
+                            TimeInForce::IOC,
+                            flags,
+                        )
+                        .await
+                };
+                let flat = match &result {
+                    Ok(answer) => {
+                        info!("{} ladder level {} {:?}", reason, level, answer);
+                        false
+                    }
+                    Err(err) if exchange::is_reduce_only_rejected(err) => {
+                        info!("{} ladder: position already flat ({})", reason, err);
+                        true
+                    }
+                    Err(err) => {
+                        warn!("{} ladder level {} Error: {}", reason, level, err);
+                        false
+                    }
+                };
+                results.push((amount.signum() * qty, result));
+                if flat {
+                    break;
+                }
+                remaining = util::round_to(remaining - qty, 9);
+                level += 1f64;
+            }
+        }
+        results
+    }
+
+    /// Position side an order closing `amount` has to carry.
+    fn exit_side(&self, amount: f64) -> PositionSide {
+        match self.config.position_mode {
+            PositionMode::OneWay => PositionSide::Both,
+            PositionMode::Hedge if amount > 0f64 => PositionSide::Long,
+            PositionMode::Hedge => PositionSide::Short,
+        }
+    }
+
+    /// Exits in one-way mode go reduce-only, so a close racing a fill can't
+    /// open the opposite position. Hedge mode exits carry their position
+    /// side instead, Binance refuses reduce-only there.
+    fn exit_flags<'a>(&self, client_order_id: Option<&'a str>) -> OrderFlags<'a> {
+        OrderFlags {
+            client_order_id: client_order_id,
+            reduce_only: self.config.position_mode == PositionMode::OneWay,
+        }
+    }
+
+    /// Remember the exits acked with qty still to fill, `amount` being the
+    /// signed qty each order closes.
+    fn track_exits(&mut self, results: &[(f64, Result<OrderAck>)]) {
+        for (amount, result) in results {
+            if let Ok(ack) = result {
+                let remaining = util::round_to(amount.abs() - ack.executed_qty, 9);
+                if remaining > 0f64 {
+                    self.exits_in_flight.insert(ack.order_id, remaining);
+                }
+            }
+        }
+    }
+
+    /// Re-issue the close for whatever a pending flatten left behind, e.g. a
+    /// market order that partial-filled on a thin book. Nothing is re-sent
+    /// while an earlier exit is still working.
+    async fn check_flatten(&mut self) {
+        let (reason, order_type) = match &self.pending_flatten {
+            Some(pending) => pending.clone(),
+            None => return,
+        };
+
+        if self.is_flat() {
+            info!("{} flatten confirmed", reason);
+            self.pending_flatten = None;
+            self.exits_in_flight.clear();
+            return;
+        }
+
+        if !self.exits_in_flight.is_empty() {
+            debug!(
+                "{} exits in flight {:?}, wait for them",
+                reason, self.exits_in_flight
+            );
+            return;
+        }
+
+        if self.flatten_attempts >= self.config.flatten_retries {
+            warn!(
+                "{} left residual {} after {} retries, give up",
+                reason, self.position.position_amount, self.flatten_attempts
+            );
+            self.pending_flatten = None;
+            return;
+        }
+
+        self.flatten_attempts += 1;
+        warn!(
+            "{} left residual {}, retry {}/{}",
+            reason,
+            self.position.position_amount,
+            self.flatten_attempts,
+            self.config.flatten_retries
+        );
+        let results = self.flatten(&reason, order_type).await;
+        self.clear_flat_legs(&Self::flat_legs(&results));
+    }
+
+    fn is_flat(&self) -> bool {
+        match self.config.position_mode {
+            PositionMode::OneWay => self.position.position_amount == 0f64,
+            PositionMode::Hedge => {
+                self.long.position_amount == 0f64 && self.short.position_amount == 0f64
+            }
+        }
+    }
+
+    /// Replay recent trades through the intensity estimator, each as a
+    /// touch at the trade price, and count the window from the first one.
+    /// Trades spanning estimate_window leave the estimator ready to fit.
+    fn seed_from_trades(&mut self, trades: &[LastTrade]) {
+        let (first, last) = match (trades.first(), trades.last()) {
+            (Some(first), Some(last)) => (first.timestamp, last.timestamp),
+            _ => return,
+        };
+        for trade in trades {
+            self.ie.on_tick(trade.price, trade.price, trade.timestamp);
+        }
+        self.start_time = self.start_time.min(first);
+        info!(
+            "seeded the intensity estimator with {} trades over {} ms, estimate_window {} ms",
+            trades.len(),
+            last - first,
+            self.estimate_window
+        );
+    }
+
+    /// Whether a full estimate_window has passed since start at exchange
+    /// time `ts`. start_time is local, so it is moved onto the exchange clock
+    /// first.
+    fn window_filled(&self, ts: u64) -> bool {
+        let start = self.clock.to_exchange(self.start_time);
+        ts > start.saturating_add(self.estimate_window) + 1
+    }
+
+    fn calculate_intensity_info(&mut self, ask: f64, bid: f64, ts: u64) -> Option<IntensityInfo> {
+        let can_get = self.ie.on_tick(bid, ask, ts);
+
+        // wait to get more data
+        if can_get && self.window_filled(ts) {
+            // refit A/k every estimate_period, reuse the last fit in between
+            if self.estimate_cadence.fire(ts) {
+                match self.executor.as_mut() {
+                    Some(executor) => {
+                        if !executor.submit(&self.ie, ts) {
+                            debug!("A/k fit still running, skip the refit at {}", ts);
+                        }
+                    }
+                    None => {
+                        let started = Instant::now();
+                        let ii = self.ie.estimate(ts);
+                        if let Some(timing) = self.estimate_timing.as_mut() {
+                            timing.record(started.elapsed());
+                            self.health.lock().unwrap().estimate_timing = timing.summary();
+                        }
+                        let buckets = self.ie.nonzero_buckets();
+                        self.accept_fit(ts, ii, buckets);
+                    }
+                }
+            }
+            if let Some((fit_ts, ii, buckets)) = self.executor.as_mut().and_then(|x| x.poll()) {
+                self.accept_fit(fit_ts, ii, buckets);
+            }
+            if self.ak_export_cadence.fire(ts) {
+                self.export_ak_series();
+            }
+            self.intensity_info
+        } else {
+            None
+        }
+    }
+
+    /// Adopt a fit made at `ts` unless too few spread buckets had fills.
+    fn accept_fit(&mut self, ts: u64, ii: IntensityInfo, buckets: (usize, usize)) {
+        debug!("intensity_info {:#?}", ii);
+        let (buy_buckets, sell_buckets) = buckets;
+        if buy_buckets.min(sell_buckets) < self.config.min_nonzero_buckets {
+            info!(
+                "A/k fit on {} buy / {} sell buckets, below min_nonzero_buckets {}, keep the last one",
+                buy_buckets, sell_buckets, self.config.min_nonzero_buckets
+            );
+        } else {
+            self.intensity_info = Some(ii);
+            self.record_fit(ts, &ii, buy_buckets.min(sell_buckets));
+        }
+    }
+
+    fn record_fit(&mut self, ts: u64, ii: &IntensityInfo, fit_quality: usize) {
+        let record = AkRecord {
+            timestamp: ts,
+            buy_a: ii.buy_a,
+            buy_k: ii.buy_k,
+            sell_a: ii.sell_a,
+            sell_k: ii.sell_k,
+            fit_quality: fit_quality,
+        };
+        {
+            let mut health = self.health.lock().unwrap();
+            health.ak_fits += 1;
+            health.last_fit = Some(record);
+        }
+        if self.config.ak_series_file.is_some() {
+            self.ak_series.push(record);
+        }
+    }
+
+    /// Append the fits buffered since the last export to `ak_series_file`,
+    /// kept for the next try if that fails.
+    fn export_ak_series(&mut self) {
+        let path = match &self.config.ak_series_file {
+            Some(path) => path,
+            None => return,
+        };
+        if self.ak_series.is_empty() {
+            return;
+        }
+        match metrics::append_ak_series(path, &self.ak_series) {
+            Ok(()) => self.ak_series.clear(),
+            Err(err) => warn!("A/k series export to {} Error: {}", path, err),
+        }
+    }
+
+    fn calculate_tv_mean(&mut self) -> Option<f64> {
+        let sum: f64 = self.strategy_data.tv.iter().sum();
+        let count = self.strategy_data.tv.len();
+
+        match count {
+            positive if positive > 0 => Some(sum / count as f64),
+            _ => None,
+        }
+    }
+
+    fn calculate_classical_volatility(&mut self) -> Option<f64> {
+        let t = 10.;
+        let mut classical_hv = 0.;
+        for i in 0..self.strategy_data.wap.iter().len() - 1 {
+            let res =
+                self.strategy_data.wap.get(i + 1).unwrap() - self.strategy_data.wap.get(i).unwrap();
+            classical_hv += res.powi(2)
+        }
+
+        let res = (classical_hv / t).sqrt();
+
+        Some(res)
+    }
+
+    fn calculate_spread_volatility(&mut self) -> Option<f64> {
+        let sum: f64 = self.strategy_data.spread.iter().map(|x| x.powi(2)).sum();
+        let count = self.strategy_data.spread.len();
+        let t = 10.;
+
+        let res = (sum / t).sqrt();
+
+        match count {
+            positive if positive > 0 => Some(res),
+            _ => None,
+        }
+    }
+
+    /// https://github.com/TommasoBelluzzo/HistoricalVolatility
+    fn calculate_p_volatility(&mut self) -> Option<f64> {
+        let wap_vec = self.strategy_data.wap.iter().cloned().collect::<Vec<f64>>();
+        let t = 10.;
+        let mut parkinson_hv = 0.;
+        for chunk in wap_vec.chunks(30) {
+            let hl = (chunk.iter().cloned().fold(0. / 0., f64::max)
+                / chunk.iter().cloned().fold(0. / 0., f64::min))
+            .ln();
+            let res = hl.powi(2);
+            parkinson_hv += res;
+        }
+        let res = (parkinson_hv / (4. * t * (2f64.ln()))).sqrt();
+
+        Some(res)
+    }
+
+    fn calculate_gk_volatility(&mut self) -> Option<f64> {
+        let wap_vec = self.strategy_data.wap.iter().cloned().collect::<Vec<f64>>();
+        let t = 10.;
+
+        let mut garman_klass_hv = 0.;
+        for chunk in wap_vec.chunks(30) {
+            let co = (chunk.last().unwrap() / chunk.first().unwrap()).ln();
+            let hl = (chunk.iter().cloned().fold(0. / 0., f64::max)
+                / chunk.iter().cloned().fold(0. / 0., f64::min))
+            .ln();
+            let res = 0.5 * hl.powi(2) - ((2. * 2f64.ln()) - 1.) * co.powi(2);
+            garman_klass_hv += res;
+        }
+        let res = (garman_klass_hv / t).sqrt();
+
+        Some(res)
+    }
+
+    /// Blend a fresh sigma estimate with the slow baseline by
+    /// `sigma_dampening` and hold it at `sigma_floor`.
+    fn dampen_sigma(&mut self, raw: f64) -> f64 {
+        if !raw.is_finite() {
+            return raw;
+        }
+        let baseline = match self.sigma_baseline {
+            Some(baseline) => baseline + self.config.sigma_baseline_alpha * (raw - baseline),
+            None => raw,
+        };
+        self.sigma_baseline = Some(baseline);
+
+        let dampening = self.config.sigma_dampening;
+        let sigma = (1f64 - dampening) * raw + dampening * baseline;
+        match self.config.sigma_floor {
+            Some(floor) => sigma.max(floor),
+            None => sigma,
+        }
+    }
+
+    /// Whether `now` is past the finite horizon T, with no time left for
+    /// the model to quote for until the next session.
+    fn past_horizon(&self, now: u64) -> bool {
+        match self.config.horizon_ms {
+            Some(horizon) if horizon > 0 => now > self.session_start + horizon,
+            _ => false,
+        }
+    }
+
+    /// (T - t) / T of the finite horizon at `now`, 1 without one.
+    fn horizon_fraction(&self, now: u64) -> f64 {
+        match self.config.horizon_ms {
+            Some(horizon) if horizon > 0 => {
+                let end = self.session_start + horizon;
+                (end.saturating_sub(now) as f64 / horizon as f64).min(1f64)
+            }
+            _ => 1f64,
+        }
+    }
+
+    /// Refuse offsets a degenerate A/k fit or sigma made non-finite, there
+    /// is nothing sane to quote on them.
+    fn finite_spread(&mut self, spread: Spread) -> error::Result<Spread> {
+        if spread.bid.is_finite() && spread.ask.is_finite() {
+            return Ok(spread);
+        }
+        // or it would be the base of the next step
+        self.applied_spread = None;
+        Err(Error::Math(format!(
+            "non-finite spread {:?} from buy A/k {}/{}, sell A/k {}/{}, sigma {}",
+            spread, self.buy_a, self.buy_k, self.sell_a, self.sell_k, self.sigma
+        )))
+    }
+
+    /// Nudge the spread multiplier toward `fill_rate_target` fills per
+    /// window, fills coming fast meaning the quotes are too tight, and
+    /// apply it.
+    fn adapt_spread(&mut self, spread: Spread, now: u64) -> Spread {
+        let target = match self.config.fill_rate_target {
+            Some(target) => target,
+            None => return spread,
+        };
+        let since = now.saturating_sub(self.config.fill_rate_window_ms);
+        while self.fill_times.front().is_some_and(|ts| *ts < since) {
+            self.fill_times.pop_front();
+        }
+        let fills = self.fill_times.len() as f64;
+        let step = 1f64 + self.config.fill_rate_step;
+        if fills > target {
+            self.spread_multiplier *= step;
+        } else if fills < target {
+            self.spread_multiplier /= step;
+        }
+        self.spread_multiplier = self
+            .spread_multiplier
+            .max(self.config.spread_multiplier_min)
+            .min(self.config.spread_multiplier_max);
+        debug!(
+            "{} fills in window, spread multiplier {}",
+            fills, self.spread_multiplier
+        );
+        self.health.lock().unwrap().spread_multiplier = Some(self.spread_multiplier);
+        Spread {
+            ask: spread.ask * self.spread_multiplier,
+            bid: spread.bid * self.spread_multiplier,
+        }
+    }
+
+    /// Move the applied offsets toward `target` by at most
+    /// `max_spread_step` a side.
+    fn step_spread(&mut self, target: Spread) -> Spread {
+        let step = match self.config.max_spread_step {
+            Some(step) => step,
+            None => return target,
+        };
+        let spread = match self.applied_spread {
+            Some(applied) => Spread {
+                ask: target.ask.max(applied.ask - step).min(applied.ask + step),
+                bid: target.bid.max(applied.bid - step).min(applied.bid + step),
+            },
+            None => target,
+        };
+        if spread != target {
+            debug!("spread target {:?} capped to {:?}", target, spread);
+        }
+        self.applied_spread = Some(spread);
+        spread
+    }
+
+    /// Gamma raised with `gamma_inventory_beta` as |q| nears q_max, bounded
+    /// by `max_gamma`.
+    fn effective_gamma(&self) -> f64 {
+        let beta = match self.config.gamma_inventory_beta {
+            Some(beta) => beta,
+            None => return self.gamma,
+        };
+        let fill = (self.position.position_amount / self.order_qty / self.q_max)
+            .abs()
+            .min(1f64);
+        let gamma = self.gamma * (1f64 + beta * fill);
+        match self.config.max_gamma {
+            Some(max) => gamma.min(max.max(self.gamma)),
+            None => gamma,
+        }
+    }
+
+    fn calculate_spread(&mut self) -> Spread {
+        // self.sigma = self.calculate_tv_mean().unwrap();
+        // self.sigma = self.calculate_p_volatility().unwrap();
+        let now = *self.strategy_data.timestamp.back().unwrap();
+        if self.sigma_cadence.fire(now) {
+            let raw = match self.config.sigma_estimator {
+                SigmaEstimator::GarmanKlass => self.calculate_gk_volatility(),
+                SigmaEstimator::Range => self.strategy_data.range_volatility(),
+                SigmaEstimator::TimeNormalized => self.strategy_data.time_normalized_volatility(),
+            };
+            // too few ticks in the window to measure, keep the last sigma
+            match raw {
+                Some(raw) => self.sigma = self.dampen_sigma(raw),
+                None => debug!(
+                    "{:?} sigma undefined over the window, keep {}",
+                    self.config.sigma_estimator, self.sigma
+                ),
+            }
+            if let Some(interval) = self.strategy_data.tick_interval_ms() {
+                info!(
+                    "sigma: {} per tick, tick interval: {} ms, annualized: {}",
+                    self.sigma,
+                    interval,
+                    util::annualize(self.sigma, interval, self.config.annualization_days)
+                );
+            }
+        }
+        // self.sigma = self.calculate_spread_volatility().unwrap();
+        let sigma_fix = self.sigma * self.sigma_multiplier.clone();
+        let mut q_fix = match self.config.as_variant {
+            AsVariant::InventorySkewed => self.position.position_amount / self.order_qty,
+            AsVariant::Symmetric => 0f64,
+        };
+        if let Some(bucket) = self.config.inventory_bucket {
+            // toward zero, with slack for the float noise of amount / order_qty
+            let buckets = q_fix / bucket;
+            q_fix = buckets.signum() * (buckets.abs() + 1e-9).floor() * bucket;
+        }
+        let tau = self.horizon_fraction(now);
+        let gamma = self.effective_gamma();
+
+        info!(
+            "sigma: {}, sigma_multiplier {}, sigma_fix {}, q {}, q_fix {}, tau {}, gamma {}",
+            self.sigma,
+            self.sigma_multiplier,
+            sigma_fix,
+            self.position.position_amount,
+            q_fix,
+            tau,
+            gamma,
+        );
+        info!(
+            "buy_k: {}, buy_a: {}, sell_k {}, sell_a {}",
+            self.buy_k, self.buy_a, self.sell_k, self.sell_a
+        );
+
+        let bid = (1. + gamma / self.sell_k).ln() / gamma
+            + tau
+                * (q_fix + 0.5)
+                * ((sigma_fix * sigma_fix * gamma) / (2. * self.sell_k * self.sell_a)
+                    * (1. + gamma / self.sell_k).powf(1. + self.sell_k / gamma))
+                .sqrt();
+
+        let ask = (1. + gamma / self.buy_k).ln() / gamma
+            - tau
+                * (q_fix - (0.5))
+                * ((sigma_fix * sigma_fix * gamma) / (2. * self.buy_k * self.buy_a)
+                    * (1. + gamma / self.buy_k).powf(1. + self.buy_k / gamma))
+                .sqrt();
+
+        Spread { ask: ask, bid: bid }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::mock::{Call, MockExchange, Scenario};
+    use crate::exchange::OpenOrder;
+    use serde_json::json;
+
+    fn config(extra: serde_json::Value) -> Config {
+        let mut config = json!({
+            "api_key": null,
+            "secret_key": null,
+            "base_asset": "BTC",
+            "quote_asset": "USDT",
+            "order_qty": 0.001,
+            "tick_size": 0.1,
+            "n_spreads": 10,
+            "estimate_window": 600000,
+            "period": 2000,
+            "sigma_tick_period": 100,
+            "gamma": 0.1,
+            "sigma_multiplier": 1.0,
+            "stoploss": 0.01,
+            "stoploss_sleep": 300000,
+            "stopprofit": 0.01,
+            "trailing_stop": 0.005,
+            "q_max": 30.0
+        });
+        for (k, v) in extra.as_object().unwrap() {
+            config[k] = v.clone();
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn book_ticker(bid: f64, ask: f64, ts: u64) -> Box<BookTickerEvent> {
+        Box::new(
+            serde_json::from_value(json!({
+                "e": "bookTicker",
+                "u": ts,
+                "E": ts,
+                "T": ts,
+                "s": "BTCUSDT",
+                "b": bid.to_string(),
+                "B": "1",
+                "a": ask.to_string(),
+                "A": "1"
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn account_event(positions: serde_json::Value) -> Box<AccountUpdateEvent> {
+        Box::new(
+            serde_json::from_value(json!({
+                "e": "ACCOUNT_UPDATE",
+                "E": 1637000000000u64,
+                "T": 1637000000000u64,
+                "a": {
+                    "m": "ORDER",
+                    "B": [{"a": "USDT", "wb": "1000", "cw": "1000", "bc": "0"}],
+                    "P": positions
+                }
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn position(side: &str, amount: &str, entry: &str) -> serde_json::Value {
+        json!({
+            "s": "BTCUSDT",
+            "pa": amount,
+            "ep": entry,
+            "cr": "0",
+            "up": "0",
+            "mt": "cross",
+            "iw": "0",
+            "ps": side
+        })
+    }
+
+    #[actix_rt::test]
+    async fn test_on_account_hedge_mode() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"position_mode": "Hedge"})));
+
+        strategy
+            .on_account(account_event(json!([
+                position("LONG", "0.003", "50000"),
+                position("SHORT", "-0.001", "51000")
+            ])))
+            .await
+            .unwrap();
+
+        assert_eq!(strategy.long.position_amount, 0.003);
+        assert_eq!(strategy.long.entry_price, 50000.);
+        assert_eq!(strategy.short.position_amount, -0.001);
+        assert_eq!(strategy.short.entry_price, 51000.);
+        assert!((strategy.position.position_amount - 0.002).abs() < 1e-12);
+        assert_eq!(strategy.position.entry_price, 50000.);
+
+        // a later update only carries the leg that changed
+        strategy
+            .on_account(account_event(json!([position("LONG", "0", "0")])))
+            .await
+            .unwrap();
+
+        assert_eq!(strategy.long.position_amount, 0.);
+        assert_eq!(strategy.short.position_amount, -0.001);
+        assert_eq!(strategy.position.position_amount, -0.001);
+        assert_eq!(strategy.position.entry_price, 51000.);
+    }
+
+    fn fill(qty: f64, price: f64, fee: f64) -> Fill {
+        Fill {
+            client_order_id: "test".into(),
+            qty: qty,
+            price: price,
+            fee: fee,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_total_profit_round_trip() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({})));
+
+        strategy.on_fill(&fill(0.002, 50000., 0.02));
+        assert_eq!(strategy.total_profit(), -0.02);
+
+        strategy.on_fill(&fill(-0.001, 50500., 0.0101));
+        strategy.on_fill(&fill(-0.001, 50600., 0.0101));
+
+        let expected = 0.001 * 500. + 0.001 * 600. - 0.02 - 0.0101 - 0.0101;
+        assert!((strategy.total_profit() - expected).abs() < 1e-9);
+        assert_eq!(strategy.cost_basis.qty, 0.);
+    }
+
+    #[test]
+    fn test_session_reset() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"max_session_loss": 1.0})));
+        let day = 1637020800000; // 2021-11-16T00:00:00Z
+
+        strategy.clock = util::ClockOffset::pinned(600000);
+        assert_eq!(
+            strategy.until_session_roll(day + 1000),
+            util::DAY_MS - 601000
+        );
+        assert_eq!(strategy.until_session_roll(day - 600000), util::DAY_MS);
+
+        assert_eq!(strategy.roll_session(day + 1000), None);
+        strategy.on_fill(&fill(0.01, 50000., 0.2));
+        strategy.on_fill(&fill(-0.01, 49900., 0.2));
+        assert!(strategy.session_loss_breached);
+
+        assert_eq!(strategy.roll_session(day + util::DAY_MS - 1), None);
+
+        let summary = strategy.roll_session(day + util::DAY_MS).unwrap();
+        assert_eq!(summary.start, day);
+        assert_eq!(summary.end, day + util::DAY_MS);
+        assert!((summary.total_profit + 1.4).abs() < 1e-9);
+        assert!((summary.total_fees - 0.4).abs() < 1e-9);
+        assert_eq!(summary.fill_count, 2);
+
+        assert_eq!(strategy.total_profit(), 0.);
+        assert_eq!(strategy.total_fees, 0.);
+        assert_eq!(strategy.fill_count, 0);
+        assert!(!strategy.session_loss_breached);
+    }
+
+    #[test]
+    fn test_soft_inventory_one_sided() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "soft_inventory": 0.5,
+            "soft_inventory_tightening": 0.5
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.001);
+        assert_eq!(quote.ask_qty, 0.001);
+        assert_eq!(quote.ask_price, 50010.);
+
+        // long 15 order_qty = 0.5 * q_max
+        strategy.position.position_amount = 0.015;
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.);
+        assert_eq!(quote.ask_qty, 0.001);
+        assert_eq!(quote.ask_price, 50005.);
+
+        strategy.position.position_amount = -0.015;
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.ask_qty, 0.);
+        assert_eq!(quote.bid_qty, 0.001);
+        assert_eq!(quote.bid_price, 49995.);
+    }
+
+    #[test]
+    fn test_symbol_overrides() {
+        let multi: crate::config::MultiConfig = serde_json::from_value(json!({
+            "base": {
+                "quote_asset": "USDT",
+                "order_qty": 0.001,
+                "tick_size": 0.1,
+                "n_spreads": 10,
+                "estimate_window": 600000,
+                "period": 2000,
+                "sigma_tick_period": 500,
+                "gamma": 0.2,
+                "sigma_multiplier": 1.0,
+                "stoploss": 0.01,
+                "stoploss_sleep": 60000,
+                "stopprofit": 0.01,
+                "trailing_stop": 0.005,
+                "q_max": 30.0
+            },
+            "symbols": {
+                "BTC": {},
+                "ETH": {"order_qty": 0.01}
+            }
+        }))
+        .unwrap();
+
+        let strategies: Vec<_> = multi
+            .configs()
+            .unwrap()
+            .into_iter()
+            .map(AvellanedaStoikov::new)
+            .collect();
+        assert_eq!(strategies.len(), 2);
+        assert_eq!(strategies[0].pair, "BTCUSDT");
+        assert_eq!(strategies[0].order_qty, 0.001);
+        assert_eq!(strategies[1].pair, "ETHUSDT");
+        assert_eq!(strategies[1].order_qty, 0.01);
+        assert_eq!(strategies[1].q_max, 30.0);
+
+        // each merged config is validated
+        let mut multi = multi;
+        multi
+            .symbols
+            .insert("SOL".into(), json!({"order_qty": 0.0}));
+        let err = multi.configs().unwrap_err();
+        assert!(err.to_string().contains("SOL"));
+    }
+
+    #[test]
+    fn test_select_config() {
+        let multi = json!({
+            "base": {
+                "quote_asset": "USDT",
+                "order_qty": 0.001,
+                "tick_size": 0.1,
+                "n_spreads": 10,
+                "estimate_window": 600000,
+                "period": 2000,
+                "sigma_tick_period": 100,
+                "gamma": 0.2,
+                "sigma_multiplier": 1.0,
+                "stoploss": 0.01,
+                "stoploss_sleep": 60000,
+                "stopprofit": 0.01,
+                "trailing_stop": 0.005,
+                "q_max": 30.0
+            },
+            "symbols": {"BTC": {}, "ETH": {"gamma": 0.3}}
+        });
+
+        let eth = crate::config::select(multi.clone(), Some("ETH")).unwrap();
+        assert_eq!(eth.base_asset, "ETH");
+        assert_eq!(AvellanedaStoikov::new(eth).gamma, 0.3);
+        assert!(crate::config::select(multi.clone(), None).is_err());
+        assert!(crate::config::select(multi, Some("SOL")).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_control_api_sets_gamma() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({})));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        actix_rt::spawn(crate::control::serve_listener(
+            listener,
+            strategy.control(),
+            "secret".into(),
+        ));
+        let post = |body: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "POST /params HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        assert!(post(r#"{"gamma":0.3}"#).await.starts_with("HTTP/1.1 200"));
+        assert_eq!(strategy.gamma, 0.1);
+        strategy.sync_params();
+        assert_eq!(strategy.gamma, 0.3);
+        assert_eq!(strategy.order_qty, 0.001);
+
+        assert!(post(r#"{"gamma":50.0}"#).await.starts_with("HTTP/1.1 400"));
+        strategy.sync_params();
+        assert_eq!(strategy.gamma, 0.3);
+        assert_eq!(strategy.control().lock().unwrap().params.gamma, 0.3);
+    }
+
+    #[test]
+    fn test_control_rejects_qty_sized_by_notional() {
+        let notional = json!({"order_qty": 0.0, "order_notional": 100.0});
+        let strategy = AvellanedaStoikov::new(config(notional));
+        let update = crate::control::ParamsUpdate {
+            order_qty: Some(0.01),
+            ..Default::default()
+        };
+        assert!(strategy.control().lock().unwrap().update(&update).is_err());
+
+        let update = crate::control::ParamsUpdate {
+            gamma: Some(0.3),
+            ..Default::default()
+        };
+        assert!(strategy.control().lock().unwrap().update(&update).is_ok());
+    }
+
+    #[test]
+    fn test_wap_mark_divergence() {
+        let spread = Spread { ask: 10., bid: 10. };
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "max_wap_mark_divergence": 0.001,
+            "divergence_widen": 3.0
+        })));
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 1000));
+
+        // no mark yet, nothing to compare with
+        assert_eq!(strategy.check_divergence(spread), Some(spread));
+
+        *strategy.mark().lock().unwrap() = MarkPrice {
+            price: 50020.,
+            timestamp: 1000,
+        };
+        assert_eq!(strategy.check_divergence(spread), Some(spread));
+
+        *strategy.mark().lock().unwrap() = MarkPrice {
+            price: 50100.,
+            timestamp: 1000,
+        };
+        assert_eq!(
+            strategy.check_divergence(spread),
+            Some(Spread { ask: 30., bid: 30. })
+        );
+
+        strategy.config.divergence_action = DivergenceAction::Pause;
+        assert_eq!(strategy.check_divergence(spread), None);
+    }
+
+    #[test]
+    fn test_markout_widens_adverse_side() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "markout_horizon_ms": 1000,
+            "markout_window": 2,
+            "markout_widen": 2.0
+        })));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        // two buys, each followed by a drop
+        for ts in [0u64, 2000].iter() {
+            strategy
+                .strategy_data
+                .push(book_ticker(49999., 50001., *ts));
+            strategy.on_fill(&Fill {
+                timestamp: *ts,
+                ..fill(0.001, 50000., 0.)
+            });
+            strategy.on_fill(&Fill {
+                timestamp: *ts,
+                ..fill(-0.001, 50000., 0.)
+            });
+            strategy
+                .strategy_data
+                .push(book_ticker(49989., 49991., ts + 1000));
+            strategy.update_markout(ts + 1000);
+        }
+
+        let health = strategy.health().lock().unwrap().clone();
+        assert_eq!(health.buy_fills, 2);
+        assert!((health.buy_markout_bps.unwrap() + 2.).abs() < 1e-9);
+        assert!((health.sell_markout_bps.unwrap() - 2.).abs() < 1e-9);
+
+        let quote = strategy.build_quote(spread);
+        let fair = strategy.fair_value();
+        assert!((fair - quote.bid_price - 20.).abs() < 0.1 + 1e-9);
+        assert!((quote.ask_price - fair - 10.).abs() < 0.1 + 1e-9);
+    }
+
+    #[test]
+    fn test_inventory_decay() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"inventory_decay": 1e-5})));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+        strategy.position.position_amount = 0.005;
+
+        strategy.update_held_inventory(1000);
+        let fresh = strategy.build_quote(spread);
+        assert_eq!(fresh.ask_price, 50010.);
+
+        // 5 order_qty held for one then three minutes
+        strategy.update_held_inventory(61000);
+        let one = strategy.build_quote(spread);
+        strategy.update_held_inventory(181000);
+        let three = strategy.build_quote(spread);
+        assert!((strategy.held_inventory - 15.).abs() < 1e-9);
+        assert!(one.ask_price < fresh.ask_price);
+        assert!(three.ask_price < one.ask_price);
+        assert!(three.bid_price < one.bid_price);
+
+        // flipping short starts over, and skews the other way
+        strategy.position.position_amount = -0.005;
+        strategy.update_held_inventory(241000);
+        assert!((strategy.held_inventory + 5.).abs() < 1e-9);
+        assert!(strategy.build_quote(spread).bid_price > fresh.bid_price);
+
+        // held long enough, the shift stops at inventory_decay_max_bps
+        strategy.config.inventory_decay_max_bps = 0.2;
+        assert_eq!(strategy.build_quote(spread).bid_price, 49991.);
+        strategy.update_held_inventory(24 * 3600000);
+        assert_eq!(strategy.build_quote(spread).bid_price, 49991.);
+    }
+
+    #[actix_rt::test]
+    async fn test_horizon_tightens_spread() {
+        let day = 1637020800000; // 2021-11-16T00:00:00Z
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "horizon_ms": 3600000,
+                "sigma_period": 86400000
+            })),
+            MockExchange::new(),
+        );
+        strategy.session_start = day;
+        let width = |strategy: &mut AvellanedaStoikov, ts: u64| {
+            strategy.strategy_data.push(book_ticker(49999., 50001., ts));
+            let spread = strategy.calculate_spread();
+            spread.bid + spread.ask
+        };
+
+        // first call fits sigma, pin it after
+        width(&mut strategy, day + 360000);
+        strategy.sigma = 0.01;
+        let early = width(&mut strategy, day + 360000);
+        let late = width(&mut strategy, day + 3240000);
+        let end = width(&mut strategy, day + 3600000);
+        assert!(late < early);
+        assert!(end < late);
+        let ln = 2. * (1. + strategy.gamma / strategy.buy_k).ln() / strategy.gamma;
+        assert!((end - ln).abs() < 1e-9);
+
+        // past T quoting pauses until the next session
+        assert!(!strategy.past_horizon(day + 3600000));
+        assert!(strategy.past_horizon(day + 3600001));
+        assert!(strategy.update_pause(day + 3600001).await);
+        strategy.session_start = day + util::DAY_MS;
+        assert!(!strategy.update_pause(day + util::DAY_MS + 1).await);
+
+        // no horizon, no decay
+        strategy.config.horizon_ms = None;
+        assert!(width(&mut strategy, day + 3600000) > early);
+        assert!(!strategy.past_horizon(day + 7200000));
+
+        assert!(config(json!({"horizon_ms": 86400001})).validate().is_err());
+        assert!(config(json!({"horizon_ms": 0})).validate().is_err());
+        assert!(config(json!({"horizon_ms": 86400000})).validate().is_ok());
+    }
+
+    #[test]
+    fn test_inventory_bucket() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "inventory_bucket": 3.0,
+            "sigma_period": 86400000
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        strategy.calculate_spread();
+        strategy.sigma = 0.01;
+        let mut spread_at = |q: f64| {
+            strategy.position.position_amount = q;
+            strategy.calculate_spread()
+        };
+
+        // 1 and 2 order_qty stay in the flat bucket, 3 and 5 share the next
+        let flat = spread_at(0.);
+        assert_eq!(spread_at(0.001), flat);
+        assert_eq!(spread_at(0.002), flat);
+        let long = spread_at(0.003);
+        assert_ne!(long, flat);
+        assert_eq!(spread_at(0.005), long);
+        assert_eq!(spread_at(-0.002), flat);
+        assert_ne!(spread_at(-0.003), flat);
+    }
+
+    #[test]
+    fn test_gamma_inventory_beta() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "gamma_inventory_beta": 2.0,
+            "max_gamma": 0.25,
+            "sigma_period": 86400000
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        strategy.calculate_spread();
+        strategy.sigma = 0.01;
+        let at = |strategy: &mut AvellanedaStoikov, q: f64| {
+            strategy.position.position_amount = q * 0.001;
+            let spread = strategy.calculate_spread();
+            (strategy.effective_gamma(), spread.bid - spread.ask)
+        };
+
+        // gamma 0.1 flat, 0.15 at a quarter of q_max 30, capped at 0.25
+        let (flat, _) = at(&mut strategy, 0.);
+        assert_eq!(flat, 0.1);
+        let (quarter, quarter_skew) = at(&mut strategy, 7.5);
+        assert!((quarter - 0.15).abs() < 1e-12);
+        let (near, near_skew) = at(&mut strategy, 27.);
+        assert_eq!(near, 0.25);
+        assert_eq!(at(&mut strategy, -27.).0, 0.25);
+        assert!(near_skew > quarter_skew);
+
+        // the scaled gamma skews harder than the constant one
+        strategy.config.gamma_inventory_beta = None;
+        let (gamma, skew) = at(&mut strategy, 27.);
+        assert_eq!(gamma, 0.1);
+        assert!(near_skew > skew);
+    }
+
+    #[test]
+    fn test_symmetric_as_variant() {
+        let spreads = |variant: &str| {
+            let mut strategy = AvellanedaStoikov::new(config(json!({
+                "as_variant": variant,
+                "sigma_period": 86400000
+            })));
+            strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+            strategy.calculate_spread();
+            strategy.sigma = 0.01;
+            [-0.01, 0., 0.02]
+                .iter()
+                .map(|q| {
+                    strategy.position.position_amount = *q;
+                    let spread = strategy.calculate_spread();
+                    (spread.bid, spread.ask)
+                })
+                .collect::<Vec<(f64, f64)>>()
+        };
+
+        let symmetric = spreads("Symmetric");
+        for (bid, ask) in symmetric.iter() {
+            assert_eq!(bid, ask);
+            assert_eq!((*bid, *ask), symmetric[1]);
+        }
+        // the skewed offsets only match when flat
+        let skewed = spreads("InventorySkewed");
+        assert_eq!(skewed[1], symmetric[1]);
+        assert!(skewed[0].0 < skewed[0].1);
+        assert!(skewed[2].0 > skewed[2].1);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_fill_cooldown() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"post_fill_cooldown_ms": 5000})));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        let scenario = Scenario::new("BTCUSDT", 10000).fill("bid", 0.001, 50000., 0.);
+        feed_order_updates(&mut strategy, scenario).await;
+
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 12000));
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.);
+        assert_eq!(quote.ask_qty, 0.001);
+
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 15000));
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.001);
+        assert_eq!(quote.ask_qty, 0.001);
+    }
+
+    #[test]
+    fn test_order_notional() {
+        let notional = json!({"order_qty": 0.0, "order_notional": 100.0, "step_size": 0.001});
+        assert!(config(notional.clone()).validate().is_ok());
+        assert!(config(json!({"order_notional": 100.0})).validate().is_err());
+
+        let mut strategy = AvellanedaStoikov::new(config(notional));
+        let spread = Spread { ask: 10., bid: 10. };
+        for (price, qty) in [(50000., 0.002), (25000., 0.004), (40000., 0.002)].iter() {
+            strategy
+                .strategy_data
+                .push(book_ticker(price - 1., price + 1., 1));
+            strategy.size_from_notional();
+            assert_eq!(strategy.order_qty, *qty);
+            let quote = strategy.build_quote(spread);
+            assert_eq!(quote.bid_qty, *qty);
+            assert!(quote.bid_qty * price <= 100.);
+            assert!((quote.bid_qty + 0.001) * price > 100.);
+        }
+    }
+
+    #[test]
+    fn test_q_max_from_equity() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "q_max_equity_fraction": 0.5,
+            "min_q_max": 2.0,
+            "max_q_max": 50.0
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        // no balance reported yet
+        strategy.q_max_from_equity();
+        assert_eq!(strategy.q_max, 30.);
+
+        // 0.5 * 1000 / (0.001 * 50000)
+        for (cash, q_max) in [(1000., 10.), (2000., 20.), (100., 2.), (10000., 50.)].iter() {
+            strategy.cash = *cash;
+            strategy.q_max_from_equity();
+            assert!((strategy.q_max - q_max).abs() < 1e-9, "{}", cash);
+        }
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"estimate_window": 60000})));
+        let local = 1637000000000;
+        strategy.start_time = local;
+
+        // the exchange runs 10 minutes ahead of the local clock
+        let skew = 600000;
+        for i in 0..10 {
+            strategy
+                .clock
+                .observe(local + skew + i * 1000 + 5, local + i * 1000);
+        }
+        assert!(!strategy.window_filled(local + skew + 30000));
+        assert!(!strategy.window_filled(local + skew + 60000));
+        assert!(strategy.window_filled(local + skew + 60010));
+
+        // and behind
+        let mut strategy = AvellanedaStoikov::new(config(json!({"estimate_window": 60000})));
+        strategy.start_time = local;
+        strategy.clock.observe(local - skew, local);
+        assert!(!strategy.window_filled(local - skew + 59000));
+        assert!(strategy.window_filled(local - skew + 60002));
+    }
+
+    #[test]
+    fn test_min_nonzero_buckets() {
+        let feed = |strategy: &mut AvellanedaStoikov| {
+            strategy.start_time = 0;
+            let mut fitted = false;
+            for i in 0..40u64 {
+                // the bid crossing the last mid only fills the 0 spread
+                // sell bucket, the ask coming back the 0 spread buy bucket
+                let (bid, ask) = if i % 2 == 0 {
+                    (49999., 50001.)
+                } else {
+                    (50000.05, 50002.05)
+                };
+                fitted |= strategy
+                    .calculate_intensity_info(ask, bid, 1000 + i * 100)
+                    .is_some();
+            }
+            fitted
+        };
+        let window = json!({"estimate_window": 1000, "period": 100});
+
+        let mut strategy = AvellanedaStoikov::new(config(window.clone()));
+        assert!(feed(&mut strategy));
+        assert_eq!(strategy.ie.nonzero_buckets(), (1, 1));
+
+        let mut window = window;
+        window["min_nonzero_buckets"] = json!(3);
+        let mut strategy = AvellanedaStoikov::new(config(window));
+        assert!(!feed(&mut strategy));
+        assert!(strategy.intensity_info.is_none());
+        assert_eq!(strategy.buy_a, 0.4);
+    }
+
+    #[actix_rt::test]
+    async fn test_background_estimate() {
+        let tick = |i: u64| {
+            if i % 2 == 0 {
+                (49999., 50001., 1000 + i * 100)
+            } else {
+                (50000.05, 50002.05, 1000 + i * 100)
+            }
+        };
+        let window = json!({"estimate_window": 1000, "period": 100});
+        let mut inline = AvellanedaStoikov::new(config(window.clone()));
+        let mut window = window;
+        window["background_estimate"] = json!(true);
+        let mut strategy = AvellanedaStoikov::new(config(window));
+        inline.start_time = 0;
+        strategy.start_time = 0;
+
+        // the tick a fit is due on hands it off and returns without it
+        let mut due = None;
+        for i in 0..40u64 {
+            let (bid, ask, ts) = tick(i);
+            let fitted = inline.calculate_intensity_info(ask, bid, ts);
+            assert!(strategy.calculate_intensity_info(ask, bid, ts).is_none());
+            if strategy.executor.as_ref().unwrap().running() {
+                assert!(fitted.is_some());
+                due = Some((bid, ask, ts));
+                break;
+            }
+        }
+        let (bid, ask, ts) = due.unwrap();
+        assert!(strategy.intensity_info.is_none());
+
+        // applied on a tick after the blocking pool is done with it
+        for _ in 0..1000 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            if strategy.calculate_intensity_info(ask, bid, ts).is_some() {
+                break;
+            }
+        }
+        // the same fit as inline, NaN included
+        let bits = |ii: Option<IntensityInfo>| {
+            let (a, b, c, d) = ii.unwrap().get_ak();
+            [a.to_bits(), b.to_bits(), c.to_bits(), d.to_bits()]
+        };
+        assert_eq!(bits(strategy.intensity_info), bits(inline.intensity_info));
+        assert!(!strategy.executor.as_ref().unwrap().running());
+        assert_eq!(
+            strategy
+                .health()
+                .lock()
+                .unwrap()
+                .last_fit
+                .unwrap()
+                .timestamp,
+            ts
+        );
+    }
+
+    #[test]
+    fn test_ak_series() {
+        let path = std::env::temp_dir().join("rainmaker_test_ak_series.csv");
+        let _ = std::fs::remove_file(&path);
+        let feed = |strategy: &mut AvellanedaStoikov| {
+            strategy.start_time = 0;
+            for i in 0..40u64 {
+                let (bid, ask) = if i % 2 == 0 {
+                    (49999., 50001.)
+                } else {
+                    (50000.05, 50002.05)
+                };
+                strategy.calculate_intensity_info(ask, bid, 1000 + i * 100);
+            }
+            strategy.export_ak_series();
+        };
+        let window = json!({
+            "estimate_window": 1000,
+            "period": 100,
+            "time_calls": true,
+            "ak_series_file": path.to_str().unwrap(),
+            "ak_series_export_ms": 1000
+        });
+
+        let mut strategy = AvellanedaStoikov::new(config(window.clone()));
+        feed(&mut strategy);
+        let estimates = strategy.estimate_timing.as_ref().unwrap().summary();
+        let rows = metrics::read_ak_series(path.to_str().unwrap()).unwrap();
+        assert!(rows.len() > 1);
+        assert_eq!(rows.len() as u64, estimates.unwrap().count);
+        assert!(rows.windows(2).all(|x| x[0].timestamp < x[1].timestamp));
+        assert_eq!(rows.last().unwrap().fit_quality, 1);
+        let health = strategy.health().lock().unwrap().clone();
+        assert_eq!(health.ak_fits, rows.len() as u64);
+        assert_eq!(health.last_fit, rows.last().cloned());
+        assert!(strategy.ak_series.is_empty());
+
+        // rejected fits are left out
+        let _ = std::fs::remove_file(&path);
+        let mut window = window;
+        window["min_nonzero_buckets"] = json!(3);
+        let mut strategy = AvellanedaStoikov::new(config(window));
+        feed(&mut strategy);
+        assert!(!path.exists());
+        assert_eq!(strategy.health().lock().unwrap().ak_fits, 0);
+    }
+
+    #[test]
+    fn test_estimate_window_validation() {
+        assert!(config(json!({})).validate().is_ok());
+
+        // seconds instead of ms, shorter than one estimate period
+        let err = config(json!({"estimate_window": 600}))
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("estimate_window 600 ms"));
+
+        // microseconds instead of ms
+        assert!(config(json!({"estimate_window": 600_000_000u64}))
+            .validate()
+            .is_err());
+
+        assert!(
+            config(json!({"estimate_window": 1000, "estimate_period": 1000}))
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_rolling_high_low() {
+        let mut data = StrategyData::with_capacity(4);
+        let series = [
+            100., 103., 101., 99., 102., 102., 98., 100., 100., 100., 100.,
+        ];
+        let expected = [
+            (100., 100.),
+            (103., 100.),
+            (103., 100.),
+            (103., 99.),
+            (103., 99.),
+            (102., 99.),
+            (102., 98.),
+            (102., 98.),
+            (102., 98.),
+            (100., 98.),
+            (100., 100.),
+        ];
+        for (i, (price, (high, low))) in series.iter().zip(expected.iter()).enumerate() {
+            data.push(book_ticker(*price, *price, i as u64));
+            assert_eq!(
+                (data.high(), data.low()),
+                (Some(*high), Some(*low)),
+                "{}",
+                i
+            );
+            assert!(data.wap_high.len() <= 4 && data.wap_low.len() <= 4);
+        }
+        assert_eq!(data.range_volatility(), Some(0.));
+
+        let mut data = StrategyData::with_capacity(3);
+        for (i, price) in [100., 110., 105.].iter().enumerate() {
+            data.push(book_ticker(*price, *price, i as u64));
+        }
+        let expected = ((1.1f64).ln().powi(2) / (4. * 2f64.ln() * 2.)).sqrt();
+        assert!((data.range_volatility().unwrap() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_undefined_sigma_keeps_last() {
+        assert!(config(json!({"sigma_tick_period": 1})).validate().is_err());
+
+        // one tick has no range, the sigma in place is kept
+        let mut strategy = AvellanedaStoikov::new(config(json!({"sigma_estimator": "Range"})));
+        strategy.sigma = 0.01;
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        strategy.calculate_spread();
+        assert_eq!(strategy.sigma, 0.01);
+    }
+
+    #[test]
+    fn test_max_spread_step() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"max_spread_step": 2.})));
+        let first = strategy.step_spread(Spread { ask: 5., bid: 4. });
+        assert_eq!(first, Spread { ask: 5., bid: 4. });
+
+        // an estimate widening the ask by 7 and tightening the bid by 3 is
+        // walked toward 2 a cycle
+        let target = Spread { ask: 12., bid: 1. };
+        assert_eq!(strategy.step_spread(target), Spread { ask: 7., bid: 2. });
+        assert_eq!(strategy.step_spread(target), Spread { ask: 9., bid: 1. });
+        assert_eq!(strategy.step_spread(target), Spread { ask: 11., bid: 1. });
+        assert_eq!(strategy.step_spread(target), target);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({})));
+        strategy.step_spread(Spread { ask: 5., bid: 4. });
+        assert_eq!(strategy.step_spread(target), target);
+    }
+
+    #[actix_rt::test]
+    async fn test_error_variants() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"estimate_window": 1000, "period": 1000})),
+            exchange.clone(),
+        );
+        strategy
+            .on_tick(book_ticker(49999., 50001., 1637000000000))
+            .await
+            .unwrap();
+
+        // crossed and non-finite books are refused before StrategyData
+        let crossed = strategy.on_tick(book_ticker(50002., 50001., 1637000000100));
+        assert!(matches!(crossed.await, Err(Error::Feed(_))));
+        let nan = strategy.on_tick(book_ticker(f64::NAN, 50001., 1637000000200));
+        assert!(matches!(nan.await, Err(Error::Feed(_))));
+        assert_eq!(strategy.strategy_data.timestamp.len(), 1);
+
+        // a degenerate fit yields no quote
+        let nan = Spread {
+            ask: f64::NAN,
+            bid: 1.,
+        };
+        assert!(matches!(strategy.finite_spread(nan), Err(Error::Math(_))));
+        assert!(strategy.finite_spread(Spread { ask: 1., bid: 1. }).is_ok());
+
+        let config_error = config(json!({"gamma": -1.0})).validate();
+        assert!(matches!(config_error, Err(Error::Config(_))));
+
+        exchange.fail_next_query("503 Service Unavailable");
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"startup_check": true})),
+            exchange.clone(),
+        );
+        let result = strategy.check_existing().await;
+        assert!(matches!(result, Err(Error::Exchange(_))), "{:?}", result);
+    }
+
+    #[actix_rt::test]
+    async fn test_dedup_update_id() {
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"dedup_update_id": true})),
+            MockExchange::new(),
+        );
+        strategy
+            .on_tick(book_ticker(49999., 50001., 5))
+            .await
+            .unwrap();
+        strategy
+            .on_tick(book_ticker(50009., 50011., 7))
+            .await
+            .unwrap();
+
+        // a replayed and an older event change nothing
+        strategy
+            .on_tick(book_ticker(40000., 40002., 7))
+            .await
+            .unwrap();
+        strategy
+            .on_tick(book_ticker(40000., 40002., 6))
+            .await
+            .unwrap();
+        let data = &strategy.strategy_data;
+        assert_eq!(data.timestamp, vec![5, 7]);
+        assert_eq!(data.bid_price, vec![49999., 50009.]);
+        assert_eq!(data.wap.back(), Some(&50010.));
+        assert_eq!(data.high(), Some(50010.));
+        assert_eq!(data.last_update_id, Some(7));
+        assert_eq!(data.dropped, 2);
+        assert_eq!(strategy.health().lock().unwrap().dropped_ticks, 2);
+
+        strategy
+            .on_tick(book_ticker(50019., 50021., 8))
+            .await
+            .unwrap();
+        assert_eq!(strategy.strategy_data.timestamp.len(), 3);
+
+        // off by default, everything is taken
+        let mut data = StrategyData::with_capacity(10);
+        assert!(data.push(book_ticker(49999., 50001., 5)));
+        assert!(data.push(book_ticker(49999., 50001., 5)));
+        assert_eq!(data.dropped, 0);
+    }
+
+    #[test]
+    fn test_order_qty_range() {
+        let sizes = || {
+            let mut strategy = AvellanedaStoikov::new(config(json!({
+                "order_qty_range": [0.002, 0.005],
+                "qty_seed": 7,
+                "step_size": 0.001
+            })));
+            strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+            (0..50)
+                .map(|_| {
+                    strategy.draw_quote_qty();
+                    let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+                    assert_eq!(quote.bid_qty, quote.ask_qty);
+                    quote.bid_qty
+                })
+                .collect::<Vec<f64>>()
+        };
+        let drawn = sizes();
+        assert!(drawn.iter().all(|x| 0.002 <= *x && *x <= 0.005));
+        // on the step grid
+        assert!(drawn
+            .iter()
+            .all(|x| ((x * 1000.).round() - x * 1000.).abs() < 1e-9));
+        assert!(drawn.iter().any(|x| *x != drawn[0]));
+        assert_eq!(drawn, sizes());
+
+        assert!(config(json!({"order_qty_range": [0.005, 0.002]}))
+            .validate()
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_margin_buffer() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "order_qty": 0.01,
+            "margin_buffer": 0.2,
+            "leverage": 10.0
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        // long 0.01, 0.8 * cash * 10 / 50000 covered
+        let mut sizes = Vec::new();
+        for cash in [1000., 100., 50.] {
+            let mut event = account_event(json!([position("BOTH", "0.01", "50000")]));
+            event.account_update.balances[0].cross_wallet_balance = cash;
+            strategy.on_account(event).await.unwrap();
+            let quote = strategy.build_quote(spread);
+            // reducing inventory frees margin
+            assert_eq!(quote.ask_qty, 0.01);
+            sizes.push(quote.bid_qty);
+        }
+        assert_eq!(sizes[0], 0.01);
+        assert!(0. < sizes[1] && sizes[1] < sizes[0]);
+        assert_eq!(sizes[2], 0.);
+        assert_eq!(strategy.build_quote(spread).bid_skip, Some("margin_buffer"));
+    }
+
+    #[test]
+    fn test_fill_rate_multiplier() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "fill_rate_target": 1.0,
+            "fill_rate_window_ms": 10000,
+            "fill_rate_step": 0.1,
+            "spread_multiplier_max": 1.5
+        })));
+        let spread = Spread { ask: 10., bid: 10. };
+        let mut ts = 1637000000000;
+
+        // both sides filling every second
+        let mut multipliers = Vec::new();
+        for i in 0..6 {
+            for qty in [0.001, -0.001] {
+                strategy.on_fill(&Fill {
+                    timestamp: ts,
+                    ..fill(qty, 50000., 0.)
+                });
+            }
+            let widened = strategy.adapt_spread(spread, ts);
+            assert_eq!(widened.ask, widened.bid);
+            multipliers.push(widened.ask / 10.);
+            if i > 0 {
+                assert!(multipliers[i] > multipliers[i - 1]);
+            }
+            ts += 1000;
+        }
+        assert!((multipliers[0] - 1.1).abs() < 1e-9);
+        assert_eq!(
+            strategy.health().lock().unwrap().spread_multiplier,
+            Some(strategy.spread_multiplier)
+        );
+
+        // capped at spread_multiplier_max
+        for _ in 0..10 {
+            strategy.adapt_spread(spread, ts);
+        }
+        assert!((strategy.spread_multiplier - 1.5).abs() < 1e-9);
+
+        // the fills age out of the window, quotes tighten again
+        ts += 20000;
+        let tightened = strategy.adapt_spread(spread, ts);
+        assert!(tightened.ask < 15.);
+        assert!(strategy.fill_times.is_empty());
+    }
+
+    #[test]
+    fn test_secondary_reference() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "reference_symbol": "BTCBUSD",
+            "reference_weight": 0.25
+        })));
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 1637000000000));
+        // nothing from the secondary feed yet
+        assert_eq!(strategy.fair_value(), 50000.);
+
+        *strategy.secondary().lock().unwrap() = ReferencePrice {
+            mid: 50100.,
+            timestamp: 1637000000000 - 1000,
+        };
+        assert_eq!(strategy.fair_value(), 50025.);
+
+        // stale, the local book alone
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 1637000000000 + 5000));
+        assert_eq!(strategy.fair_value(), 50000.);
+    }
+
+    #[actix_rt::test]
+    async fn test_anchor_ema() {
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"anchor_ema_span": 10, "anchor_ema_snap_bps": 20.})),
+            MockExchange::new(),
+        );
+        let variance = |xs: &[f64]| {
+            let mean = xs.iter().sum::<f64>() / xs.len() as f64;
+            xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / xs.len() as f64
+        };
+
+        // the touch flickers a few ticks around 50000
+        let (mut waps, mut anchors) = (Vec::new(), Vec::new());
+        for i in 0..50u64 {
+            let noise = [0., 3., -2., 4., -3.][(i % 5) as usize];
+            let ts = 1637000000000 + i * 100;
+            strategy
+                .on_tick(book_ticker(49999. + noise, 50001. + noise, ts))
+                .await
+                .unwrap();
+            waps.push(*strategy.strategy_data.wap.back().unwrap());
+            anchors.push(strategy.fair_value());
+        }
+        assert!(variance(&anchors[10..]) < variance(&waps[10..]) / 4.);
+
+        // a 1% move is past the snap, quoted around at once
+        let ts = 1637000000000 + 5000;
+        strategy
+            .on_tick(book_ticker(50499., 50501., ts))
+            .await
+            .unwrap();
+        assert_eq!(strategy.fair_value(), 50500.);
+
+        let mut raw = AvellanedaStoikov::new(config(json!({})));
+        raw.strategy_data.push(book_ticker(50002., 50004., 1));
+        assert_eq!(raw.fair_value(), 50003.);
+    }
+
+    #[test]
+    fn test_reference_blend() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"reference_blend": 0.25})));
+        assert_eq!(strategy.strategy_data.reference_blend, 0.25);
+        let mut event = book_ticker(100., 102., 1);
+        event.best_bid_qty = 3.;
+        event.best_ask_qty = 1.;
+        strategy.strategy_data.push(event);
+
+        // wap leans to the ask, (100 * 1 + 102 * 3) / 4
+        let wap = *strategy.strategy_data.wap.back().unwrap();
+        assert_eq!(wap, 101.5);
+        assert_eq!(strategy.fair_value(), 0.25 * 101.5 + 0.75 * 101.);
+
+        let mut data = StrategyData::with_capacity(3);
+        data.push(book_ticker(100., 102., 1));
+        assert_eq!(data.reference.back(), data.wap.back());
+    }
+
+    #[test]
+    fn test_tick_interval() {
+        let mut data = StrategyData::with_capacity(3);
+        assert_eq!(data.tick_interval_ms(), None);
+        data.push(book_ticker(49999., 50001., 1000));
+        assert_eq!(data.tick_interval_ms(), None);
+        data.push(book_ticker(49999., 50001., 1100));
+        data.push(book_ticker(49999., 50001., 1400));
+        assert_eq!(data.tick_interval_ms(), Some(200.));
+        // the oldest tick leaves the window
+        data.push(book_ticker(49999., 50001., 1500));
+        assert_eq!(data.tick_interval_ms(), Some(200.));
+    }
+
+    #[test]
+    fn test_max_notional_exposure() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"max_notional_exposure": 1000.0})));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        strategy.position.position_amount = 0.019;
+        let quote = strategy.build_quote(spread);
+        assert_eq!((quote.bid_qty, quote.ask_qty), (0.001, 0.001));
+
+        // 0.02 * 50000 at the cap
+        strategy.position.position_amount = 0.02;
+        let quote = strategy.build_quote(spread);
+        assert_eq!((quote.bid_qty, quote.ask_qty), (0., 0.001));
+        assert_eq!(quote.bid_skip, Some("max_notional_exposure"));
+
+        strategy.position.position_amount = -0.03;
+        let quote = strategy.build_quote(spread);
+        assert_eq!((quote.bid_qty, quote.ask_qty), (0.001, 0.));
+    }
+
+    #[actix_rt::test]
+    async fn test_qty_precision() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "base_asset": "ETH",
+                "order_qty": 0.3333,
+                "step_size": 0.0,
+                "qty_precision": 2
+            })),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(3999., 4001., 1));
+
+        let quote = strategy.build_quote(Spread { ask: 1., bid: 1. });
+        assert_eq!(quote.bid_qty, 0.33);
+        assert_eq!(quote.ask_qty, 0.33);
+
+        strategy.position.position_amount = -1.23456;
+        strategy
+            .flatten_and_verify("Stop loss", StoplossOrderType::Market)
+            .await;
+        assert_eq!(
+            exchange.calls(),
+            vec![Call::MarketBuy {
+                symbol: "ETHUSDT".into(),
+                qty: 1.23,
+                client_order_id: None,
+                reduce_only: true,
+            }]
+        );
+
+        // from step_size when not configured
+        let strategy =
+            AvellanedaStoikov::new(config(json!({"order_qty": 0.3333, "step_size": 0.001})));
+        assert_eq!(strategy.round_qty(0.3333), 0.333);
+    }
+
+    #[test]
+    fn test_asymmetric_sizes() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "bid_qty": 0.002,
+            "ask_qty": 0.0013,
+            "step_size": 0.001
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.002);
+        assert_eq!(quote.ask_qty, 0.001);
+
+        // long half of q_max leans the size to the ask
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "order_qty": 0.01,
+            "size_skew": 1.0,
+            "step_size": 0.001
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        strategy.position.position_amount = 0.15;
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.005);
+        assert_eq!(quote.ask_qty, 0.015);
+    }
+
+    #[test]
+    fn test_max_cycle_inventory() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "max_cycle_inventory": 0.02,
+            "momentum_size": 0.0
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        // 0.02 * 30 * 0.001
+        let quote = strategy.build_quote(spread);
+        assert!((quote.bid_qty - 0.0006).abs() < 1e-9);
+        assert!((quote.ask_qty - 0.0006).abs() < 1e-9);
+
+        // long 29.8 order_qty, only 0.2 left before q_max
+        strategy.position.position_amount = 0.0298;
+        let quote = strategy.build_quote(spread);
+        assert!((quote.bid_qty - 0.0002).abs() < 1e-9);
+        assert!((quote.ask_qty - 0.0006).abs() < 1e-9);
+
+        strategy.position.position_amount = 0.03;
+        assert_eq!(strategy.build_quote(spread).bid_qty, 0.);
+    }
+
+    #[actix_rt::test]
+    async fn test_scenario_stoploss_flatten() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pin_filters": true,
+                "estimate_window": 1000,
+                "period": 1000
+            })),
+            exchange.clone(),
+        );
+
+        let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .position(0.01, 50000.)
+            .ticks(50000., 50000.2, 100, 30)
+            .book_ticker(49000., 49000.2)
+            .channel();
+        strategy.run_forever(rx).await.unwrap();
+
+        let calls = exchange.calls();
+        let flatten = calls
+            .iter()
+            .position(|x| {
+                x.eq(&Call::MarketSell {
+                    symbol: "BTCUSDT".into(),
+                    qty: 0.01,
+                    client_order_id: None,
+                    reduce_only: true,
+                })
+            })
+            .expect("stoploss should flatten the long");
+        assert_eq!(
+            calls[flatten - 1],
+            Call::CancelAll {
+                symbol: "BTCUSDT".into()
+            }
+        );
+        assert!(strategy.in_stoploss);
+    }
+
+    #[actix_rt::test]
+    async fn test_stoploss_grace() {
+        let run = |grace: u64, scenario: Scenario| async move {
+            let exchange = MockExchange::new();
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "stoploss_grace_ms": grace
+                })),
+                exchange.clone(),
+            );
+            strategy.run_forever(scenario.channel()).await.unwrap();
+            (strategy, exchange.calls())
+        };
+        let flattened = |calls: &[Call], qty: f64| {
+            calls.iter().any(|x| {
+                x.eq(&Call::MarketSell {
+                    symbol: "BTCUSDT".into(),
+                    qty: qty,
+                    client_order_id: None,
+                    reduce_only: true,
+                })
+            })
+        };
+
+        // a dip within the grace of the opening fill doesn't stop it out
+        let scenario = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .position(0.01, 50000.)
+            .ticks(50000., 50000.2, 100, 30)
+            .book_ticker(49000., 49000.2);
+        let (strategy, calls) = run(60000, scenario).await;
+        assert!(!flattened(&calls, 0.01));
+        assert!(!strategy.in_stoploss);
+
+        // adding to the position restarts the grace, a dip that outlasts it
+        // stops out
+        let added = |dip_ticks: usize| {
+            Scenario::new("BTCUSDT", get_timestamp().unwrap())
+                .position(0.01, 50000.)
+                .ticks(50000., 50000.2, 100, 30)
+                .position(0.02, 50000.)
+                .ticks(49000., 49000.2, 100, dip_ticks)
+        };
+        let (_, calls) = run(1000, added(1)).await;
+        assert!(!flattened(&calls, 0.02));
+        let (strategy, calls) = run(1000, added(15)).await;
+        assert!(flattened(&calls, 0.02));
+        assert!(strategy.in_stoploss);
+    }
+
+    #[test]
+    fn test_lag_guard_hysteresis() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"max_feed_backlog": 5})));
+        strategy.check_lag(5);
+        assert!(!strategy.lagging);
+        strategy.check_lag(6);
+        assert!(strategy.lagging);
+        // holds until fully caught up
+        strategy.check_lag(3);
+        assert!(strategy.lagging);
+        strategy.check_lag(0);
+        assert!(!strategy.lagging);
+        assert_eq!(strategy.lag_pauses, 1);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({})));
+        strategy.check_lag(1000);
+        assert!(!strategy.lagging);
+    }
+
+    #[actix_rt::test]
+    async fn test_lag_guard_flooded_feed() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pin_filters": true,
+                "estimate_window": 1000,
+                "period": 1000,
+                "max_feed_backlog": 5
+            })),
+            exchange,
+        );
+
+        // the whole feed queued before the first event is processed
+        let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .ticks(50000., 50000.2, 100, 60)
+            .channel();
+        strategy.run_forever(rx).await.unwrap();
+
+        // engaged once, released on the last event
+        assert_eq!(strategy.lag_pauses, 1);
+        assert!(!strategy.lagging);
+    }
+
+    #[actix_rt::test]
+    async fn test_stopprofit_disabled() {
+        let run = |enable_stopprofit: bool| async move {
+            let exchange = MockExchange::new();
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "enable_stopprofit": enable_stopprofit
+                })),
+                exchange.clone(),
+            );
+            let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+                .position(0.01, 50000.)
+                .ticks(50000., 50000.2, 100, 30)
+                .ticks(51000., 51000.2, 100, 30)
+                .channel();
+            strategy.run_forever(rx).await.unwrap();
+            exchange
+                .calls()
+                .iter()
+                .any(|x| matches!(x, Call::MarketSell { .. }))
+        };
+
+        assert!(run(true).await);
+        // pnl well past the threshold and still held
+        assert!(!run(false).await);
+    }
+
+    #[actix_rt::test]
+    async fn test_stoploss_hold_and_pause() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pin_filters": true,
+                "estimate_window": 1000,
+                "period": 1000,
+                "stoploss_action": "HoldAndPause"
+            })),
+            exchange.clone(),
+        );
+
+        let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .position(0.01, 50000.)
+            .ticks(50000., 50000.2, 100, 30)
+            .book_ticker(49000., 49000.2)
+            .channel();
+        strategy.run_forever(rx).await.unwrap();
+
+        let calls = exchange.calls();
+        assert!(!calls.iter().any(|x| match x {
+            Call::MarketSell { .. } => true,
+            Call::LimitSell { qty, .. } => *qty == 0.01,
+            _ => false,
+        }));
+        assert!(calls.iter().any(|x| matches!(x, Call::CancelAll { .. })));
+        assert!(strategy.in_stoploss);
+        assert_eq!(strategy.position.position_amount, 0.01);
+        // still marked while held
+        assert!(strategy.unrealized_pnl < -strategy.stoploss);
+    }
+
+    #[actix_rt::test]
+    async fn test_stop_hysteresis() {
+        let run = |action: &'static str, hysteresis: Option<f64>| async move {
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "stoploss_sleep": 1000,
+                    "stoploss_action": action,
+                    "stop_hysteresis": hysteresis
+                })),
+                MockExchange::new(),
+            );
+            let start = get_timestamp().unwrap();
+            // -1.2% and -0.96% around the 1% stoploss, never back under
+            // 0.9% to re-arm
+            let mut scenario = Scenario::new("BTCUSDT", start)
+                .position(0.01, 50000.)
+                .ticks(50000., 50000.2, 100, 30);
+            for _ in 0..6 {
+                scenario = scenario
+                    .ticks(49400., 49400.2, 100, 15)
+                    .ticks(49520., 49520.2, 100, 15);
+            }
+            strategy.run_forever(scenario.channel()).await.unwrap();
+            strategy.last_stoploss - start
+        };
+
+        // the close is out, fired on the first breach only
+        assert!(run("Flatten", Some(0.001)).await < 4000);
+        // fires again after every sleep
+        assert!(run("Flatten", None).await > 10000);
+        // still held, the stop is live again once the sleep is over
+        assert!(run("HoldAndPause", Some(0.001)).await > 10000);
+    }
+
+    #[test]
+    fn test_stoploss_rearm_escalation() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "stoploss_sleep": 60000,
+            "stoploss_rearm_ms": 600000,
+            "max_stoploss_sleep": 200000
+        })));
+
+        strategy.escalate_stoploss_sleep(1_000_000);
+        assert_eq!(strategy.stoploss_sleep, 60000);
+        // resumed after the sleep and fired again straight away
+        strategy.escalate_stoploss_sleep(1_061_000);
+        assert_eq!(strategy.stoploss_sleep, 600000);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "stoploss_sleep": 60000,
+            "stoploss_rearm_ms": 100000,
+            "max_stoploss_sleep": 200000
+        })));
+        strategy.escalate_stoploss_sleep(1_000_000);
+        strategy.escalate_stoploss_sleep(1_061_000);
+        assert_eq!(strategy.stoploss_sleep, 120000);
+        strategy.escalate_stoploss_sleep(1_122_000);
+        assert_eq!(strategy.stoploss_sleep, 200000);
+        // quiet for longer than the re-arm interval
+        strategy.escalate_stoploss_sleep(1_500_000);
+        assert_eq!(strategy.stoploss_sleep, 60000);
+    }
+
+    #[test]
+    fn test_momentum_skew() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "momentum_skew": 1.0,
+            "momentum_size": 100.0
+        })));
+        for i in 0..=10 {
+            let mid = 50000. + 10. * i as f64;
+            strategy
+                .strategy_data
+                .push(book_ticker(mid - 0.5, mid + 0.5, i));
+        }
+        let momentum = strategy.strategy_data.momentum();
+        assert!((momentum - 0.002).abs() < 1e-12);
+
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        // shifted up by momentum * wap = 100.2 on both sides
+        assert!((quote.ask_price - 50210.2).abs() < 0.1 + 1e-9);
+        assert!((quote.bid_price - 50190.2).abs() < 0.1 + 1e-9);
+        assert!(quote.bid_qty > 0.001 && quote.ask_qty < 0.001);
+        assert!((quote.bid_qty - 0.0012).abs() < 1e-9);
+        assert!((quote.ask_qty - 0.0008).abs() < 1e-9);
+
+        strategy.config.momentum_skew = -1.0;
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        assert!(quote.ask_price < 50100.);
+    }
+
+    #[test]
+    fn test_stoploss_pnl_units() {
+        for (units, stoploss, expected) in [
+            ("Ratio", 0.01, -0.02),
+            ("Bps", 100., -200.),
+            ("QuoteCurrency", 5., -10.),
+        ] {
+            let mut strategy = AvellanedaStoikov::new(config(json!({
+                "pnl_units": units,
+                "stoploss": stoploss
+            })));
+            strategy.position.position_amount = 0.01;
+            strategy.position.entry_price = 50000.;
+            strategy.strategy_data.push(book_ticker(49000., 49000.1, 1));
+
+            strategy.update_unrealized_pnl();
+            assert!(
+                (strategy.unrealized_pnl - expected).abs() < 1e-9,
+                "{}",
+                units
+            );
+            assert!(strategy.unrealized_pnl < -strategy.stoploss, "{}", units);
+
+            strategy.stoploss = stoploss * 3.;
+            assert!(!(strategy.unrealized_pnl < -strategy.stoploss), "{}", units);
+        }
+    }
+
+    #[test]
+    fn test_inverse_contract() {
+        let cfg = json!({
+            "contract_type": "Inverse",
+            "contract_size": 100.0,
+            "pnl_units": "QuoteCurrency",
+            "stoploss": 0.001
+        });
+        let mut strategy = AvellanedaStoikov::new(config(cfg.clone()));
+        assert_eq!(strategy.margin_asset, "BTC");
+
+        // long 10 contracts from 50000 marked at 40000: 1000 * (1/50000 -
+        // 1/40000) BTC, where a linear position would lose 20%
+        strategy.position.position_amount = 10.;
+        strategy.position.entry_price = 50000.;
+        strategy.strategy_data.push(book_ticker(40000., 40000.1, 1));
+        strategy.update_unrealized_pnl();
+        assert!((strategy.unrealized_pnl - -0.005).abs() < 1e-12);
+
+        strategy.config.pnl_units = PnlUnits::Ratio;
+        strategy.update_unrealized_pnl();
+        assert!((strategy.unrealized_pnl - -0.25).abs() < 1e-12);
+
+        // short gains 50000 / 40000.1 - 1
+        strategy.position.position_amount = -10.;
+        strategy.update_unrealized_pnl();
+        assert!((strategy.unrealized_pnl - (50000. / 40000.1 - 1.)).abs() < 1e-12);
+
+        // realized on the close, 1000 * (1/50000 - 1/40000) less the fee
+        let mut strategy = AvellanedaStoikov::new(config(cfg));
+        strategy.on_fill(&fill(10., 50000., 0.));
+        strategy.on_fill(&fill(-10., 40000., 0.0001));
+        assert!((strategy.total_profit - -0.0051).abs() < 1e-12);
+
+        // a 100 USD contract is 100 USD of exposure whatever the price
+        assert_eq!(strategy.config.quote_value(10., 40000.), 1000.);
+        assert_eq!(strategy.config.margin_value(10., 40000.), 0.025);
+    }
+
+    #[test]
+    fn test_bps_units() {
+        let quote = |units: &str, min: f64, max: f64| {
+            let mut strategy = AvellanedaStoikov::new(config(json!({
+                "units": units,
+                "min_spread": min,
+                "max_spread": max
+            })));
+            strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+            let tight = strategy.build_quote(Spread { ask: 1., bid: 1. });
+            let wide = strategy.build_quote(Spread {
+                ask: 100.,
+                bid: 100.,
+            });
+            (
+                tight.bid_price,
+                tight.ask_price,
+                wide.bid_price,
+                wide.ask_price,
+            )
+        };
+        // 2 and 10 bps of a 50000 wap are 10 and 50 in price
+        let bps = quote("Bps", 2., 10.);
+        assert_eq!(bps, (49990., 50010., 49950., 50050.));
+        assert_eq!(quote("Absolute", 10., 50.), bps);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "units": "Bps",
+            "stoploss": 100.
+        })));
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+        strategy.strategy_data.push(book_ticker(49000., 49000.1, 1));
+        strategy.update_unrealized_pnl();
+        assert!((strategy.unrealized_pnl + 200.).abs() < 1e-9);
+
+        let conflicting = config(json!({"units": "Bps", "pnl_units": "QuoteCurrency"}));
+        assert!(conflicting.validate().is_err());
+    }
+
+    #[test]
+    fn test_trailing_stop_ratchet() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"trailing_stop": 0.01})));
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+
+        let hit = |strategy: &mut AvellanedaStoikov, bid: f64| {
+            strategy.strategy_data.push(book_ticker(bid, bid + 0.1, 1));
+            strategy.update_unrealized_pnl();
+            strategy.trailing_stop_hit()
+        };
+
+        assert!(!hit(&mut strategy, 49800.));
+        assert!(!strategy.active_trailing_stop);
+        assert!(!hit(&mut strategy, 50600.));
+        assert!(strategy.active_trailing_stop);
+        assert!(!hit(&mut strategy, 50300.));
+        assert!(!hit(&mut strategy, 50500.));
+        assert!((strategy.peak_pnl - 0.012).abs() < 1e-9);
+        assert!(hit(&mut strategy, 50050.));
+
+        // flipping short starts from a fresh, unarmed peak
+        strategy.position.position_amount = -0.01;
+        assert!(!hit(&mut strategy, 50050.));
+        assert!(!strategy.active_trailing_stop);
+    }
+
+    #[actix_rt::test]
+    async fn test_symbol_override() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "base_asset": "SHIB",
+                "symbol": "1000SHIBUSDT"
+            })),
+            exchange.clone(),
+        );
+        assert_eq!(strategy.pair, "1000SHIBUSDT");
+
+        let mut shib = position("BOTH", "1000", "0.01");
+        shib["s"] = json!("1000SHIBUSDT");
+        let mut other = position("BOTH", "5", "0.00001");
+        other["s"] = json!("SHIBUSDT");
+        strategy
+            .on_account(account_event(json!([other, shib])))
+            .await
+            .unwrap();
+        assert_eq!(strategy.position.position_amount, 1000.);
+
+        strategy
+            .flatten_and_verify("Stop loss", StoplossOrderType::Market)
+            .await;
+        strategy.shutdown().await;
+
+        let calls = exchange.calls();
+        assert_eq!(
+            calls,
+            vec![
+                Call::MarketSell {
+                    symbol: "1000SHIBUSDT".into(),
+                    qty: 1000.,
+                    client_order_id: None,
+                    reduce_only: true,
+                },
+                Call::CancelAll {
+                    symbol: "1000SHIBUSDT".into()
+                },
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_flatten_residual_retry() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+
+        strategy
+            .flatten_and_verify("Stop loss", StoplossOrderType::Market)
+            .await;
+
+        // the market sell only partially filled
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0.004", "50000")])))
+            .await
+            .unwrap();
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0", "0")])))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            exchange.calls(),
+            vec![
+                Call::MarketSell {
+                    symbol: "BTCUSDT".into(),
+                    qty: 0.01,
+                    client_order_id: None,
+                    reduce_only: true,
+                },
+                Call::MarketSell {
+                    symbol: "BTCUSDT".into(),
+                    qty: 0.004,
+                    client_order_id: None,
+                    reduce_only: true,
+                },
+            ]
+        );
+        assert!(strategy.pending_flatten.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_flatten_waits_for_exit() {
+        let exchange = MockExchange::new();
+        exchange.set_unfilled(true);
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+
+        strategy
+            .flatten_and_verify("Stop loss", StoplossOrderType::Market)
+            .await;
+        assert_eq!(strategy.exits_in_flight.get(&1), Some(&0.01));
+
+        // the first fill of the market sell reaches the account first, its
+        // order is still working
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0.004", "50000")])))
+            .await
+            .unwrap();
+        assert_eq!(exchange.calls().len(), 1);
+
+        // it expired with the residual left, now the residual goes out
+        feed_order_updates(
+            &mut strategy,
+            Scenario::new("BTCUSDT", 2).order_status("mock-1", 1, "EXPIRED"),
+        )
+        .await;
+        assert!(strategy.exits_in_flight.is_empty());
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0.004", "50000")])))
+            .await
+            .unwrap();
+        assert_eq!(
+            exchange.calls()[1],
+            Call::MarketSell {
+                symbol: "BTCUSDT".into(),
+                qty: 0.004,
+                client_order_id: None,
+                reduce_only: true,
+            }
+        );
+        assert_eq!(strategy.exits_in_flight.get(&2), Some(&0.004));
+    }
+
+    #[actix_rt::test]
+    async fn test_exit_slippage() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 51000.;
+
+        strategy
+            .flatten_and_verify("Stop loss", StoplossOrderType::Market)
+            .await;
+        strategy.on_fill(&Fill {
+            client_order_id: "mock-1".into(),
+            ..fill(-0.004, 49990., 0.)
+        });
+        strategy.on_fill(&Fill {
+            client_order_id: "mock-1".into(),
+            ..fill(-0.006, 49980., 0.)
+        });
+        // a quote fill is not an exit
+        strategy.on_fill(&fill(0.001, 49000., 0.));
+
+        // sold 0.004 10 and 0.006 20 under the wap
+        assert!((strategy.total_slippage - 0.16).abs() < 1e-9);
+        assert!((strategy.health().lock().unwrap().exit_slippage - 0.16).abs() < 1e-9);
+    }
+
+    #[actix_rt::test]
+    async fn test_poll_positions_while_user_stream_down() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"position_poll_ms": 5000})),
+            exchange.clone(),
+        );
+        exchange.set_positions(vec![PositionInfo {
+            symbol: "BTCUSDT".into(),
+            position_side: "BOTH".into(),
+            position_amount: 0.003,
+            entry_price: 50000.,
+        }]);
+
+        strategy
+            .on_tick(book_ticker(49999., 50001., 1637000000000))
+            .await
+            .unwrap();
+        assert_eq!(strategy.position.position_amount, 0.);
+
+        strategy.user_stream_down().store(true, Ordering::SeqCst);
+        strategy
+            .on_tick(book_ticker(49999., 50001., 1637000001000))
+            .await
+            .unwrap();
+        assert_eq!(strategy.position.position_amount, 0.003);
+        assert_eq!(strategy.position.entry_price, 50000.);
+    }
+
+    #[actix_rt::test]
+    async fn test_reduce_only_rejected() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.strategy_data.push(book_ticker(50999., 51001., 1));
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+
+        // closed by hand between the check and the exit
+        exchange
+            .fail_next_order("code: -2022, msg: ReduceOnly Order is rejected, position is zero");
+        strategy.exit_profit("Stop stopprofit").await;
+
+        assert_eq!(exchange.calls().len(), 1);
+        assert_eq!(strategy.position.position_amount, 0.);
+        assert!(strategy.pending_flatten.is_none());
+
+        // nothing left to retry on the next account update
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0", "0")])))
+            .await
+            .unwrap();
+        assert_eq!(exchange.calls().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_exit_sized_to_refreshed_position() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"refresh_position_on_exit": true})),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(50999., 51001., 1));
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+
+        // partly closed by hand, the account update not in yet
+        exchange.set_positions(vec![PositionInfo {
+            symbol: "BTCUSDT".into(),
+            position_side: "BOTH".into(),
+            position_amount: 0.004,
+            entry_price: 50000.,
+        }]);
+        strategy.exit_profit("Stop stopprofit").await;
+        assert_eq!(
+            exchange.calls(),
+            vec![Call::MarketSell {
+                symbol: "BTCUSDT".into(),
+                qty: 0.004,
+                client_order_id: None,
+                reduce_only: true,
+            }]
+        );
+
+        // flat by the time of the exit
+        exchange.clear_calls();
+        strategy.pending_flatten = None;
+        strategy.position.position_amount = 0.01;
+        exchange.set_positions(vec![PositionInfo {
+            symbol: "BTCUSDT".into(),
+            position_side: "BOTH".into(),
+            position_amount: 0.,
+            entry_price: 0.,
+        }]);
+        strategy.exit_profit("Stop stopprofit").await;
+        assert!(exchange.calls().is_empty());
+        assert_eq!(strategy.position.position_amount, 0.);
+        assert!(strategy.pending_flatten.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_stoploss_aggressive_limit() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "stoploss_order_type": "AggressiveLimit",
+                "stoploss_limit_ticks": 3
+            })),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        strategy.position.position_amount = 0.01;
+        strategy
+            .flatten_and_verify("Stop loss", strategy.config.stoploss_order_type)
+            .await;
+        strategy.position.position_amount = -0.01;
+        strategy
+            .flatten_and_verify("Stop loss", strategy.config.stoploss_order_type)
+            .await;
+
+        assert_eq!(
+            exchange.calls(),
+            vec![
+                Call::LimitSell {
+                    symbol: "BTCUSDT".into(),
+                    qty: 0.01,
+                    price: 49998.7,
+                    position_side: format!("{:?}", PositionSide::Both),
+                    time_in_force: format!("{:?}", TimeInForce::IOC),
+                    client_order_id: None,
+                    reduce_only: true,
+                },
+                Call::LimitBuy {
+                    symbol: "BTCUSDT".into(),
+                    qty: 0.01,
+                    price: 50001.3,
+                    position_side: format!("{:?}", PositionSide::Both),
+                    time_in_force: format!("{:?}", TimeInForce::IOC),
+                    client_order_id: None,
+                    reduce_only: true,
+                },
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_resync_during_stoploss_sleep() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.in_stoploss = true;
+        strategy.timer = 1637000000;
+
+        // position opened by hand while the bot sleeps
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0.02", "50000")])))
+            .await
+            .unwrap();
+        strategy
+            .on_tick(book_ticker(50500., 50500.1, 1637000001000))
+            .await
+            .unwrap();
+
+        assert!(strategy.in_stoploss);
+        assert_eq!(strategy.cash, 1000.);
+        assert_eq!(strategy.position.position_amount, 0.02);
+        assert!((strategy.unrealized_pnl - 0.01).abs() < 1e-9);
+        assert!(exchange.calls().is_empty());
+
+        strategy
+            .on_account(account_event(json!([position("BOTH", "0", "0")])))
+            .await
+            .unwrap();
+        strategy
+            .on_tick(book_ticker(50500., 50500.1, 1637000002000))
+            .await
+            .unwrap();
+
+        assert_eq!(strategy.unrealized_pnl, 0.);
+    }
+
+    #[test]
+    fn test_min_edge_bps() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"min_edge_bps": 5.01})));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let wap = *strategy.strategy_data.wap.back().unwrap();
+
+        // AS spread tighter than the edge is widened
+        let quote = strategy.build_quote(Spread { ask: 1., bid: 1. });
+        assert!(quote.bid_price <= wap * (1. - 5.01e-4));
+        assert!(quote.ask_price >= wap * (1. + 5.01e-4));
+        assert_eq!(quote.bid_price, 49974.9);
+        assert_eq!(quote.ask_price, 50025.1);
+
+        // a wider AS spread is left alone
+        let quote = strategy.build_quote(Spread { ask: 40., bid: 40. });
+        assert_eq!(quote.bid_price, 49960.);
+        assert_eq!(quote.ask_price, 50040.);
+    }
+
+    #[test]
+    fn test_accounting_state_round_trip() {
+        let path = std::env::temp_dir().join("rainmaker_test_accounting_state.json");
+        let _ = std::fs::remove_file(&path);
+        let config = config(json!({
+            "state_file": path.to_str().unwrap(),
+            "max_session_loss": 1.0
+        }));
+        let now = 1637000000000u64;
+
+        let mut strategy = AvellanedaStoikov::new(config.clone());
+        strategy.roll_session(now);
+        strategy.on_fill(&fill(0.01, 50000., 0.2));
+        strategy.on_fill(&fill(-0.01, 49900., 0.2));
+        assert!(strategy.session_loss_breached);
+
+        let mut restarted = AvellanedaStoikov::new(config.clone());
+        restarted.restore_state(now + 1000);
+        assert_eq!(restarted.accounting_state(), strategy.accounting_state());
+        assert!((restarted.total_profit() + 1.4).abs() < 1e-9);
+        assert!(restarted.session_loss_breached);
+
+        // a file from yesterday does not carry over
+        let mut next_day = AvellanedaStoikov::new(config);
+        next_day.restore_state(now + util::DAY_MS);
+        assert_eq!(next_day.total_profit(), 0.);
+        assert!(!next_day.session_loss_breached);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_writer() {
+        let path = std::env::temp_dir().join("rainmaker_test_state_writer.json");
+        let _ = std::fs::remove_file(&path);
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"state_file": path.to_str().unwrap()})),
+            MockExchange::new(),
+        );
+        strategy.state_writer = Some(spawn_state_writer(path.to_str().unwrap().to_string()));
+        strategy.roll_session(1637000000000);
+        strategy.on_fill(&fill(0.01, 50000., 0.2));
+        strategy.on_fill(&fill(-0.01, 49900., 0.2));
+
+        // the saves land by the end of shutdown, renamed into place
+        strategy.shutdown().await;
+        assert!(strategy.state_writer.is_none());
+        let saved: AccountingState =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, strategy.accounting_state());
+        assert!(!std::path::Path::new(&format!("{}.tmp", path.to_str().unwrap())).exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pnl_at_mark_price() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"pnl_price": "Mark"})));
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+        *strategy.mark().lock().unwrap() = MarkPrice {
+            price: 50500.,
+            timestamp: 1000,
+        };
+
+        strategy
+            .strategy_data
+            .push(book_ticker(50000., 50000.1, 2000));
+        strategy.update_unrealized_pnl();
+        assert!((strategy.unrealized_pnl - 0.01).abs() < 1e-9);
+
+        // mark stream went quiet, back to the touch
+        strategy
+            .strategy_data
+            .push(book_ticker(49500., 49500.1, 7000));
+        strategy.update_unrealized_pnl();
+        assert!((strategy.unrealized_pnl + 0.01).abs() < 1e-9);
+    }
+
+    #[actix_rt::test]
+    async fn test_sub_step_qty_not_sent() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pin_filters": true,
+                "estimate_window": 1000,
+                "period": 1000,
+                "step_size": 0.01
+            })),
+            exchange.clone(),
+        );
+
+        let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .ticks(50000., 50000.2, 100, 30)
+            .channel();
+        strategy.run_forever(rx).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        let calls = exchange.calls();
+        assert!(calls.iter().any(|x| matches!(x, Call::CancelAll { .. })));
+        assert!(!calls
+            .iter()
+            .any(|x| matches!(x, Call::LimitBuy { .. } | Call::LimitSell { .. })));
+    }
+
+    #[actix_rt::test]
+    async fn test_fair_value_last_trade() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"fair_value": "LastTrade"})));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        // no trade yet, anchored on wap
+        strategy
+            .on_tick(book_ticker(49999., 50001., 1))
+            .await
+            .unwrap();
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_price, 49990.);
+        assert_eq!(quote.ask_price, 50010.);
+
+        *strategy.trade().lock().unwrap() = LastTrade {
+            price: 50100.,
+            timestamp: 2,
+        };
+        strategy
+            .on_tick(book_ticker(49999., 50001., 3))
+            .await
+            .unwrap();
+        assert_eq!(strategy.strategy_data.last_trade, Some(50100.));
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_price, 50090.);
+        assert_eq!(quote.ask_price, 50110.);
+    }
+
+    async fn feed_order_updates(strategy: &mut AvellanedaStoikov, scenario: Scenario) {
+        for event in scenario.events() {
+            if let FuturesWebsocketEvent::OrderTradeUpdate(event) = event {
+                strategy.on_order_update(event).await.unwrap();
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_time_calls() {
+        let run = |time_calls: bool| async move {
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "time_calls": time_calls
+                })),
+                MockExchange::new(),
+            );
+            let health = strategy.health();
+            let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+                .ticks(50000., 50000.2, 100, 30)
+                .channel();
+            strategy.run_forever(rx).await.unwrap();
+            let report = health.lock().unwrap().report(0, 0);
+            report
+        };
+
+        let report = run(true).await;
+        let spread = report.spread_timing.unwrap();
+        let estimate = report.estimate_timing.unwrap();
+        assert!(spread.count > 0);
+        assert!(estimate.count > 0);
+        assert!(estimate.p50_us <= estimate.max_us);
+
+        let report = run(false).await;
+        assert_eq!(report.spread_timing, None);
+        assert_eq!(report.estimate_timing, None);
+    }
+
+    #[actix_rt::test]
+    async fn test_startup_check() {
+        let existing = || {
+            let exchange = MockExchange::new();
+            exchange.set_open_orders(vec![OpenOrder {
+                order_id: 7,
+                client_order_id: "manual".into(),
+                price: 49000.,
+                qty: 0.002,
+                update_time: 1637000000000,
+            }]);
+            exchange
+        };
+        let run = |exchange: MockExchange, adopt: bool| async move {
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "startup_check": true,
+                    "adopt_existing": adopt
+                })),
+                exchange.clone(),
+            );
+            let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+                .ticks(50000., 50000.2, 100, 30)
+                .channel();
+            let result = strategy.run_forever(rx).await;
+            (strategy, result)
+        };
+
+        // a foreign order aborts the start before anything is sent
+        let exchange = existing();
+        let (strategy, result) = run(exchange.clone(), false).await;
+        assert!(matches!(result, Err(Error::Config(_))));
+        assert!(exchange.calls().is_empty());
+        assert!(strategy.open_orders.is_empty());
+
+        let exchange = existing();
+        let (strategy, result) = run(exchange.clone(), true).await;
+        assert!(result.is_ok());
+        assert!(!exchange.calls().is_empty());
+        assert!(strategy.open_orders.contains(&7));
+
+        // nothing to adopt, nothing to refuse
+        let exchange = MockExchange::new();
+        assert!(run(exchange.clone(), false).await.1.is_ok());
+        assert!(!exchange.calls().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_skip_reason() {
+        let run = |extra: serde_json::Value, scenario: Scenario| async move {
+            let mut cfg = json!({"pin_filters": true, "estimate_window": 1000, "period": 1000});
+            for (k, v) in extra.as_object().unwrap() {
+                cfg[k] = v.clone();
+            }
+            let mut strategy = AvellanedaStoikov::with_client(config(cfg), MockExchange::new());
+            strategy.run_forever(scenario.channel()).await.unwrap();
+            strategy
+        };
+
+        // quoted, then a crossed book
+        let scenario = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .ticks(50000., 50000.2, 100, 30)
+            .book_ticker(50001., 49999.);
+        let strategy = run(json!({}), scenario).await;
+        assert_eq!(strategy.skip_reason(), Some("unusable_book"));
+        let report = strategy
+            .health()
+            .lock()
+            .unwrap()
+            .report(health::now_ms(), 5000);
+        assert_eq!(report.skip_reason, Some("unusable_book"));
+        let last = report.last_good_quote.unwrap();
+        assert!(last.bid_price < 50000. && last.ask_price > 50000.2);
+        assert!(strategy.last_spread().is_some());
+
+        // paused throughout, what it would have quoted is still known
+        let scenario =
+            Scenario::new("BTCUSDT", get_timestamp().unwrap()).ticks(50000., 50000.2, 100, 30);
+        let strategy = run(
+            json!({"pause_windows": [{"start_ms": 0, "end_ms": u64::MAX}]}),
+            scenario,
+        )
+        .await;
+        assert_eq!(strategy.skip_reason(), Some("paused"));
+        assert!(strategy.last_spread().is_some());
+        assert!(strategy.health().lock().unwrap().last_good_quote.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_startup_query_failed() {
+        let exchange = MockExchange::new();
+        exchange.fail_next_query("503 Service Unavailable");
+        let mut strategy =
+            AvellanedaStoikov::with_client(config(json!({"pin_filters": true})), exchange.clone());
+        let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .ticks(50000., 50000.2, 100, 5)
+            .channel();
+        let result = strategy.run_forever(rx).await;
+        assert!(matches!(result, Err(Error::Exchange(_))));
+        assert!(exchange.calls().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_order_probe() {
+        let run = |exchange: MockExchange| async move {
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "order_probe": true
+                })),
+                exchange.clone(),
+            );
+            let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+                .ticks(50000., 50000.2, 100, 30)
+                .channel();
+            let result = strategy.run_forever(rx).await;
+            (strategy, result)
+        };
+
+        // keys without trading permission, nothing quoted
+        let exchange = MockExchange::new();
+        exchange
+            .fail_next_order("code: -2015, msg: Invalid API-key, IP, or permissions for action.");
+        let (strategy, result) = run(exchange.clone()).await;
+        assert!(matches!(result, Err(Error::Exchange(_))));
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 1);
+        assert!(matches!(calls[0], Call::LimitBuy { .. }));
+        assert_eq!(strategy.health().lock().unwrap().probe_round_trip_ms, None);
+        assert_eq!(strategy.fill_count, 0);
+        assert!(strategy.last_quote.is_none());
+
+        // the probe can't be cancelled, cancel_all pulls it and the start
+        // is still refused
+        let exchange = MockExchange::new();
+        exchange.fail_next_cancel("code: -1001, msg: Internal error");
+        let (strategy, result) = run(exchange.clone()).await;
+        assert!(matches!(result, Err(Error::Exchange(_))));
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(matches!(calls[1], Call::Cancel { .. }));
+        assert!(matches!(calls[2], Call::CancelAll { .. }));
+        assert!(strategy.last_quote.is_none());
+
+        let exchange = MockExchange::new();
+        let (strategy, result) = run(exchange.clone()).await;
+        assert!(result.is_ok());
+        let calls = exchange.calls();
+        assert!(matches!(calls[0], Call::LimitBuy { price, .. } if price <= 50000. * 0.97));
+        assert_eq!(
+            calls[1],
+            Call::Cancel {
+                symbol: "BTCUSDT".into(),
+                order_id: 1
+            }
+        );
+        assert!(calls.len() > 2);
+        assert!(strategy
+            .health()
+            .lock()
+            .unwrap()
+            .probe_round_trip_ms
+            .is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_reconcile_open_orders() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"reconcile_period": 60000, "strategy_tag": "mm"})),
+            exchange.clone(),
+        );
+        let order = |order_id: u64, update_time: u64| OpenOrder {
+            order_id: order_id,
+            client_order_id: format!("mm-b0-{}", order_id),
+            price: 50000.,
+            qty: 0.001,
+            update_time: update_time,
+        };
+
+        // 1 is known, 2 was missed long ago, 3 just placed, 4 already gone,
+        // 5 placed by hand
+        strategy.open_orders.insert(1);
+        strategy.open_orders.insert(4);
+        strategy.resting.lock().unwrap().bid = Some(RestingOrder {
+            order_id: 4,
+            price: 49990.,
+            qty: 0.001,
+            posted_at: 1,
+        });
+        exchange.set_open_orders(vec![
+            order(1, 90000),
+            order(2, 10000),
+            order(3, 99000),
+            OpenOrder {
+                client_order_id: "manual".into(),
+                ..order(5, 10000)
+            },
+        ]);
+
+        strategy.reconcile_open_orders(100000).await;
+
+        assert_eq!(
+            exchange.calls(),
+            vec![Call::Cancel {
+                symbol: "BTCUSDT".into(),
+                order_id: 2
+            }]
+        );
+        let mut tracked: Vec<_> = strategy.open_orders.iter().cloned().collect();
+        tracked.sort();
+        assert_eq!(tracked, vec![1, 3]);
+        assert!(strategy.resting.lock().unwrap().bid.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_max_open_orders() {
+        let exchange = MockExchange::new();
+        let mut strategy =
+            AvellanedaStoikov::with_client(config(json!({"max_open_orders": 2})), exchange.clone());
+
+        // the cycles' cancels failed, so every quote stayed open
+        let scenario = Scenario::new("BTCUSDT", 1)
+            .order_status("a", 1, "NEW")
+            .order_status("b", 2, "NEW");
+        feed_order_updates(&mut strategy, scenario).await;
+        assert!(strategy.enforce_open_order_limit().await);
+        assert!(exchange.calls().is_empty());
+
+        feed_order_updates(
+            &mut strategy,
+            Scenario::new("BTCUSDT", 2).order_status("c", 3, "NEW"),
+        )
+        .await;
+        assert_eq!(strategy.open_orders.len(), 3);
+        assert!(strategy.enforce_open_order_limit().await);
+        assert_eq!(
+            exchange.calls(),
+            vec![Call::CancelAll {
+                symbol: "BTCUSDT".into()
+            }]
+        );
+
+        // a failing force cancel holds back new quotes
+        exchange.fail_next("cancel failed");
+        assert!(!strategy.enforce_open_order_limit().await);
+
+        let scenario = Scenario::new("BTCUSDT", 3)
+            .order_status("a", 1, "CANCELED")
+            .order_status("b", 2, "CANCELED");
+        feed_order_updates(&mut strategy, scenario).await;
+        assert_eq!(strategy.open_orders.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_margin_insufficient_desize() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"order_qty": 0.004, "step_size": 0.001})),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        exchange.fail_next_order("code: -2019, msg: Margin is insufficient.");
+        let quote = strategy.build_quote(spread);
+        AvellanedaStoikov::post_quote(
+            exchange.clone(),
+            strategy.pair.clone(),
+            quote,
+            PositionSide::Both,
+            PositionSide::Both,
+            strategy.quote_context(),
+        )
+        .await;
+
+        strategy.apply_margin_backoff();
+        assert_eq!(strategy.build_quote(spread).bid_qty, 0.002);
+
+        // no new reject, size is kept
+        strategy.apply_margin_backoff();
+        assert_eq!(strategy.order_qty, 0.002);
+
+        strategy.margin_rejected.store(true, Ordering::SeqCst);
+        strategy.apply_margin_backoff();
+        strategy.margin_rejected.store(true, Ordering::SeqCst);
+        strategy.apply_margin_backoff();
+        assert_eq!(strategy.order_qty, 0.001);
+    }
+
+    #[test]
+    fn test_time_normalized_sigma() {
+        let mut data = StrategyData::with_capacity(10);
+        assert_eq!(data.time_normalized_volatility(), None);
+
+        // same size moves, alternately 10 and 190 ms apart
+        let r = (50010f64 / 50000.).ln();
+        for (i, ts) in [0u64, 10, 200, 210, 400].iter().enumerate() {
+            let mid = if i % 2 == 0 { 50000. } else { 50010. };
+            data.push(book_ticker(mid - 0.1, mid + 0.1, *ts));
+        }
+        // per tick, ignoring time
+        let naive = r.abs();
+        let sigma = data.time_normalized_volatility().unwrap();
+        let expected = naive * ((1. / 10. + 1. / 190.) / 2. * 100.).sqrt();
+        assert!(
+            (sigma - expected).abs() < 1e-12,
+            "{} vs {}",
+            sigma,
+            expected
+        );
+        // the burst moves weigh more than their tick count
+        assert!(sigma > 2. * naive);
+
+        // on a regular clock both agree
+        let mut data = StrategyData::with_capacity(10);
+        for i in 0..5u64 {
+            let mid = if i % 2 == 0 { 50000. } else { 50010. };
+            data.push(book_ticker(mid - 0.1, mid + 0.1, i * 100));
+        }
+        let sigma = data.time_normalized_volatility().unwrap();
+        assert!((sigma - naive).abs() < 1e-12);
+
+        // a burst within one ms has no interval to normalize by, the spread
+        // keeps the sigma in place
+        let mut strategy =
+            AvellanedaStoikov::new(config(json!({"sigma_estimator": "TimeNormalized"})));
+        strategy.sigma = 0.01;
+        for bid in [49999., 50000., 50001.] {
+            strategy.strategy_data.push(book_ticker(bid, bid + 2., 1));
+        }
+        assert_eq!(strategy.strategy_data.time_normalized_volatility(), None);
+        strategy.calculate_spread();
+        assert_eq!(strategy.sigma, 0.01);
+    }
+
+    #[test]
+    fn test_sigma_floor_and_dampening() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"sigma_floor": 1e-5})));
+        assert_eq!(strategy.dampen_sigma(1e-9), 1e-5);
+        assert_eq!(strategy.dampen_sigma(1e-4), 1e-4);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "sigma_dampening": 0.5,
+            "sigma_baseline_alpha": 0.1
+        })));
+        assert_eq!(strategy.dampen_sigma(1e-3), 1e-3);
+        // baseline only moves a tenth of the way to the quiet reading
+        let sigma = strategy.dampen_sigma(1e-7);
+        let baseline = 1e-3 + 0.1 * (1e-7 - 1e-3);
+        assert!((sigma - (0.5 * 1e-7 + 0.5 * baseline)).abs() < 1e-12);
+        assert!(sigma > 4e-4);
+    }
+
+    #[test]
+    fn test_max_touch_ticks() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"max_touch_ticks": 5})));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let wide = Spread {
+            ask: 1000.,
+            bid: 1000.,
+        };
+        let quote = strategy.build_quote(wide);
+        assert_eq!(quote.bid_price, 49998.5);
+        assert_eq!(quote.ask_price, 50001.5);
+        assert_eq!(quote.bid_qty, 0.001);
+
+        // within the cap the AS prices stand
+        let quote = strategy.build_quote(Spread { ask: 1., bid: 1. });
+        assert_eq!(quote.bid_price, 49999.);
+        assert_eq!(quote.ask_price, 50001.);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "max_touch_ticks": 5,
+            "touch_distance_action": "Skip"
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let quote = strategy.build_quote(wide);
+        assert_eq!(quote.bid_qty, 0.);
+        assert_eq!(quote.ask_qty, 0.);
+        assert_eq!(quote.bid_skip, Some("max_touch_ticks"));
+    }
+
+    #[test]
+    fn test_seed_from_trades() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"estimate_window": 1000})));
+        let start = 1637000000000;
+        strategy.start_time = start + 10_000;
+        let trades: Vec<LastTrade> = (0..50)
+            .map(|i| LastTrade {
+                price: 50000. + (i % 5) as f64 * 0.1,
+                timestamp: start + i * 50,
+            })
+            .collect();
+        strategy.seed_from_trades(&trades);
+
+        // ready on the first live tick
+        let ts = start + 2500;
+        assert!(strategy.ie.on_tick(49999.9, 50000.1, ts));
+        assert!(strategy.window_filled(ts));
+
+        let mut cold = AvellanedaStoikov::new(config(json!({"estimate_window": 1000})));
+        cold.start_time = start + 2000;
+        cold.seed_from_trades(&[]);
+        assert!(!cold.ie.on_tick(49999.9, 50000.1, ts));
+        assert!(!cold.window_filled(ts));
+    }
+
+    #[test]
+    fn test_adverse_selection_gate() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"adverse_margin_bps": 1.0})));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let spread = Spread { ask: 10., bid: 10. };
+
+        // calm market with frequent fills
+        strategy.sigma = 1e-6;
+        strategy.buy_a = 10.;
+        strategy.sell_a = 10.;
+        strategy.buy_k = 0.01;
+        strategy.sell_k = 0.01;
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.001);
+        assert_eq!(quote.ask_qty, 0.001);
+
+        // volatile market where fills at this distance are rare
+        strategy.sigma = 0.01;
+        strategy.buy_a = 0.01;
+        strategy.sell_a = 0.01;
+        strategy.buy_k = 1.;
+        strategy.sell_k = 1.;
+        let quote = strategy.build_quote(spread);
+        assert_eq!(quote.bid_qty, 0.);
+        assert_eq!(quote.ask_qty, 0.);
+    }
+
+    #[actix_rt::test]
+    async fn test_shutdown_waits_for_quoting_task() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.shutdown().await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 4);
+        assert!(matches!(calls[1], Call::LimitBuy { .. }));
+        assert!(matches!(calls[2], Call::LimitSell { .. }));
+        assert_eq!(
+            calls.last(),
+            Some(&Call::CancelAll {
+                symbol: "BTCUSDT".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quoting_task_on_tokio_runtime() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+
+        // cancel first, then the new quotes
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(matches!(calls[0], Call::CancelAll { .. }));
+        assert!(matches!(calls[1], Call::LimitBuy { price, .. } if price == 49990.));
+        assert!(matches!(calls[2], Call::LimitSell { price, .. } if price == 50010.));
+    }
+
+    #[tokio::test]
+    async fn test_targeted_cancels() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"targeted_cancels": true})),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+
+        // nothing known resting, clean slate first
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(matches!(calls[0], Call::CancelAll { .. }));
+
+        // only the bid moved
+        exchange.clear_calls();
+        let moved = Quote {
+            bid_price: 49995.,
+            ..quote
+        };
+        strategy.spawn_quote(moved, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[0],
+            Call::Cancel {
+                symbol: "BTCUSDT".into(),
+                order_id: 1
+            }
+        );
+        assert!(matches!(calls[1], Call::LimitBuy { price, .. } if price == 49995.));
+
+        // a failed cancel falls back to cancel_all and reposts both
+        exchange.clear_calls();
+        exchange.fail_next("code: -2011, msg: Unknown order sent.");
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 4);
+        assert!(matches!(calls[1], Call::CancelAll { .. }));
+        assert!(matches!(calls[2], Call::LimitBuy { .. }));
+        assert!(matches!(calls[3], Call::LimitSell { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_prune_resting() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"targeted_cancels": true})),
+            exchange.clone(),
+        );
+        let order = |order_id: u64| {
+            Some(RestingOrder {
+                order_id: order_id,
+                price: 50000.,
+                qty: 0.001,
+                posted_at: 1,
+            })
+        };
+        let both = RestingQuotes {
+            bid: order(1),
+            ask: order(2),
+        };
+
+        strategy.open_orders.insert(1);
+        strategy.open_orders.insert(2);
+        *strategy.resting.lock().unwrap() = both;
+        strategy.prune_resting();
+        assert_eq!(*strategy.resting.lock().unwrap(), both);
+
+        // the bid filled before its cycle kept it
+        strategy.open_orders.remove(&1);
+        strategy.prune_resting();
+        assert_eq!(*strategy.resting.lock().unwrap(), RestingQuotes::default());
+
+        // an out-of-band cancel_all leaves nothing resting
+        *strategy.resting.lock().unwrap() = both;
+        strategy.cancel_all_orders().await.unwrap();
+        assert_eq!(*strategy.resting.lock().unwrap(), RestingQuotes::default());
+    }
+
+    #[tokio::test]
+    async fn test_min_time_in_book() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"targeted_cancels": true, "min_time_in_book_ms": 2000})),
+            exchange.clone(),
+        );
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 1000));
+        let quote = strategy.hold_young_sides(strategy.build_quote(Spread { ask: 10., bid: 10. }));
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+        assert_eq!(exchange.calls().len(), 3);
+
+        // the target moved 1.5s later, both sides stay put
+        exchange.clear_calls();
+        strategy
+            .strategy_data
+            .push(book_ticker(49989., 49991., 2500));
+        let quote = strategy.hold_young_sides(strategy.build_quote(Spread { ask: 10., bid: 10. }));
+        assert_eq!(quote.bid_price, 49990.);
+        assert_eq!(quote.ask_price, 50010.);
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+        assert!(exchange.calls().is_empty());
+
+        // a side the quote drops is still pulled
+        let mut dropped = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        dropped.ask_qty = 0.;
+        let dropped = strategy.hold_young_sides(dropped);
+        assert_eq!(dropped.bid_price, 49990.);
+        assert_eq!(dropped.ask_qty, 0.);
+
+        // old enough, repriced
+        strategy
+            .strategy_data
+            .push(book_ticker(49989., 49991., 3000));
+        let quote = strategy.hold_young_sides(strategy.build_quote(Spread { ask: 10., bid: 10. }));
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.quote_task.take().unwrap().await.unwrap();
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 4);
+        assert!(matches!(calls[2], Call::LimitBuy { price, .. } if price == 49980.));
+        assert!(matches!(calls[3], Call::LimitSell { price, .. } if price == 50000.));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_after_cycle() {
+        let exchange = MockExchange::new();
+        exchange.set_delay(5);
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        // a stoploss cancel while the cycle is still posting
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.cancel_all_orders().await.unwrap();
+
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 4);
+        assert!(matches!(calls[0], Call::CancelAll { .. }));
+        assert!(matches!(calls[1], Call::LimitBuy { .. }));
+        assert!(matches!(calls[2], Call::LimitSell { .. }));
+        assert!(matches!(calls[3], Call::CancelAll { .. }));
+        assert!(strategy.quote_task.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_quoting_cycles_do_not_interleave() {
+        let exchange = MockExchange::new();
+        exchange.set_delay(5);
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        let first = strategy.quote_task.take().unwrap();
+        let quote = strategy.build_quote(Spread { ask: 20., bid: 20. });
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        let second = strategy.quote_task.take().unwrap();
+        first.await.unwrap();
+        second.await.unwrap();
+
+        let calls = exchange.calls();
+        assert_eq!(calls.len(), 6);
+        for (i, price) in [(0, 49990.), (3, 49980.)] {
+            assert!(matches!(calls[i], Call::CancelAll { .. }));
+            assert!(matches!(calls[i + 1], Call::LimitBuy { price: p, .. } if p == price));
+            assert!(matches!(calls[i + 2], Call::LimitSell { .. }));
+        }
+    }
+
+    #[test]
+    fn test_maker_rebate_tightens_min_edge() {
+        let spread = Spread { ask: 10., bid: 10. };
+
+        // 10 over 50000 is 2 bps, short of 1 bps net of a 2 bps fee
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "min_edge_bps": 1.0,
+            "maker_fee_bps": 2.0
+        })));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let with_fee = strategy.build_quote(spread);
+        assert_eq!(with_fee.bid_price, 49985.);
+        assert_eq!(with_fee.ask_price, 50015.);
+
+        // a 0.5 bps rebate only needs 0.5 bps gross
+        strategy.config.maker_fee_bps = -0.5;
+        let with_rebate = strategy.build_quote(spread);
+        assert_eq!(with_rebate.bid_price, 49990.);
+        assert_eq!(with_rebate.ask_price, 50010.);
+        assert!(
+            with_rebate.ask_price - with_rebate.bid_price < with_fee.ask_price - with_fee.bid_price
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_replay_same_logic_no_diff() {
+        let config = config(json!({"estimate_window": 1000, "period": 1000}));
+        let mut scenario = Scenario::new("BTCUSDT", 1637000000000);
+        for i in 0..40 {
+            let bid = 50000. + (i % 3) as f64 * 0.1;
+            scenario = scenario.book_ticker(bid, bid + 0.2).wait(100);
+        }
+        let ticks: Vec<Box<BookTickerEvent>> = scenario
+            .events()
+            .into_iter()
+            .filter_map(|x| match x {
+                FuturesWebsocketEvent::BookTicker(x) => Some(x),
+                _ => None,
+            })
+            .collect();
+
+        let mut recorder = AvellanedaStoikov::with_client(config.clone(), MockExchange::new());
+        let recorded = recorder.replay(ticks.clone()).await;
+        assert!(!recorded.is_empty());
+
+        let path = std::env::temp_dir().join("rainmaker_test_replay_quotes.csv");
+        let path = path.to_str().unwrap();
+        crate::replay::write_quotes(path, &recorded).unwrap();
+        let recorded = crate::replay::read_quotes(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut strategy = AvellanedaStoikov::with_client(config, MockExchange::new());
+        let replayed = strategy.replay(ticks).await;
+        assert!(crate::replay::diff(&recorded, &replayed).is_empty());
+    }
+
+    #[test]
+    fn test_periods() {
+        let strategy = AvellanedaStoikov::new(config(json!({})));
+        assert_eq!(strategy.quote_period, 2000);
+        assert_eq!(strategy.estimate_cadence.period, 2000);
+        assert_eq!(strategy.sigma_cadence.period, 2000);
+
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "quote_period": 1000,
+            "estimate_period": 300,
+            "sigma_period": 700
+        })));
+        assert_eq!(strategy.quote_period, 1000);
+
+        // sigma only moves on its own cadence
+        for (i, ts) in [0u64, 400, 700].iter().enumerate() {
+            let bid = 50000. + 100. * i as f64;
+            strategy
+                .strategy_data
+                .push(book_ticker(bid, bid + 0.1, *ts));
+            strategy
+                .strategy_data
+                .push(book_ticker(bid + 50., bid + 50.1, *ts));
+            let before = strategy.sigma;
+            strategy.calculate_spread();
+            assert_eq!(strategy.sigma != before, *ts != 400, "ts {}", ts);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_stopprofit_ladder() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"stopprofit_ladder_qty": 0.003})),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(50500., 50500.1, 1));
+
+        // small positions still go out at market
+        strategy.position.position_amount = 0.002;
+        strategy.exit_profit("Stop stopprofit").await;
+        assert_eq!(
+            exchange.calls(),
+            vec![Call::MarketSell {
+                symbol: "BTCUSDT".into(),
+                qty: 0.002,
+                client_order_id: None,
+                reduce_only: true,
+            }]
+        );
+        exchange.clear_calls();
+
+        strategy.position.position_amount = 0.01;
+        strategy.exit_profit("Stop stopprofit").await;
+        let ladder: Vec<(f64, f64)> = exchange
+            .calls()
+            .into_iter()
+            .map(|x| match x {
+                Call::LimitSell {
+                    qty,
+                    price,
+                    time_in_force,
+                    ..
+                } => {
+                    assert_eq!(time_in_force, "IOC");
+                    (qty, price)
+                }
+                call => panic!("unexpected {:?}", call),
+            })
+            .collect();
+        assert_eq!(
+            ladder,
+            vec![
+                (0.003, 50500.),
+                (0.003, 50499.9),
+                (0.003, 50499.8),
+                (0.001, 50499.7)
+            ]
+        );
+        assert!(strategy.pending_flatten.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_zero_entry_price_no_stoploss() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pin_filters": true,
+                "estimate_window": 1000,
+                "period": 1000
+            })),
+            exchange.clone(),
+        );
+
+        let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+            .position(0.01, 0.)
+            .ticks(50000., 50000.2, 100, 30)
+            .book_ticker(49000., 49000.2)
+            .channel();
+        strategy.run_forever(rx).await.unwrap();
+
+        assert!(!strategy.pnl_context);
+        assert_eq!(strategy.unrealized_pnl, 0.);
+        assert!(!strategy.in_stoploss);
+        assert!(!exchange
+            .calls()
+            .iter()
+            .any(|x| matches!(x, Call::MarketSell { .. })));
+    }
+
+    #[actix_rt::test]
+    async fn test_max_order_failures() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"max_order_failures": 3})),
+            exchange.clone(),
+        );
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+        let quote = strategy.build_quote(Spread { ask: 10., bid: 10. });
+        let cycle = |strategy: &AvellanedaStoikov| {
+            AvellanedaStoikov::post_quote(
+                exchange.clone(),
+                strategy.pair.clone(),
+                quote,
+                PositionSide::Both,
+                PositionSide::Both,
+                strategy.quote_context(),
+            )
+        };
+
+        for _ in 0..4 {
+            exchange.fail_next_order("code: -1121, msg: Invalid symbol.");
+        }
+        cycle(&strategy).await;
+        assert!(!strategy.update_pause(1000).await);
+        cycle(&strategy).await;
+        assert!(strategy.update_pause(2000).await);
+        assert!(strategy.update_pause(61999).await);
+        assert_eq!(strategy.health().lock().unwrap().order_failures, 4);
+
+        // one retry after the pause, rejected again
+        assert!(!strategy.update_pause(62000).await);
+        exchange.fail_next_order("code: -1121, msg: Invalid symbol.");
+        exchange.fail_next_order("code: -1121, msg: Invalid symbol.");
+        cycle(&strategy).await;
+        assert!(strategy.update_pause(63000).await);
+
+        // an accepted order clears it
+        assert!(!strategy.update_pause(123000).await);
+        cycle(&strategy).await;
+        assert_eq!(strategy.order_failures.load(Ordering::SeqCst), 0);
+        assert!(!strategy.update_pause(124000).await);
+    }
+
+    #[actix_rt::test]
+    async fn test_scheduled_flatten() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "flatten_at_utc_ms": 22 * 3600000,
+                "pause_after_flatten": true
+            })),
+            exchange.clone(),
+        );
+        strategy.position.position_amount = 0.01;
+        strategy.position.entry_price = 50000.;
+        let day = 1637020800000; // 2021-11-16T00:00:00Z
+        let flatten = Call::MarketSell {
+            symbol: "BTCUSDT".into(),
+            qty: 0.01,
+            client_order_id: None,
+            reduce_only: true,
+        };
+        // started past yesterday's flatten, nothing to do until 22:00
+        for ts in [day + 3600000, day + 22 * 3600000 - 1000] {
+            strategy.strategy_data.push(book_ticker(49999., 50001., ts));
+            strategy.check_scheduled_flatten(ts).await;
+        }
+        assert!(exchange.calls().is_empty());
+
+        for ts in [
+            day + 22 * 3600000 + 100,
+            day + 22 * 3600000 + 200,
+            day + 23 * 3600000,
+        ] {
+            strategy.strategy_data.push(book_ticker(49999., 50001., ts));
+            strategy.check_scheduled_flatten(ts).await;
+        }
+        assert_eq!(
+            exchange.calls(),
+            vec![
+                Call::CancelAll {
+                    symbol: "BTCUSDT".into()
+                },
+                flatten.clone()
+            ]
+        );
+
+        // paused through the rest of the session
+        assert!(strategy.update_pause(day + 23 * 3600000).await);
+        assert!(!strategy.update_pause(day + 24 * 3600000).await);
+
+        // and again the next day
+        let ts = day + 46 * 3600000;
+        strategy.strategy_data.push(book_ticker(49999., 50001., ts));
+        strategy.check_scheduled_flatten(ts).await;
+        let calls = exchange.calls();
+        assert_eq!(calls.iter().filter(|x| **x == flatten).count(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_pause_windows() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pause_windows": [{"start_ms": 1000, "end_ms": 2000}],
+                "funding_pause_ms": 60000
+            })),
+            exchange.clone(),
+        );
+        let day = 1637020800000; // 2021-11-16T00:00:00Z, a funding time
+
+        assert!(!strategy.update_pause(day + 3600000).await);
+        assert!(strategy.update_pause(1000).await);
+        assert!(strategy.update_pause(2000).await);
+        assert!(!strategy.update_pause(2001).await);
+        assert_eq!(exchange.calls().len(), 1);
+
+        // around funding, derived from the 8h schedule
+        assert!(strategy.update_pause(day + 8 * 3600000 - 60000).await);
+        assert!(strategy.update_pause(day + 8 * 3600000 + 60000).await);
+        assert!(!strategy.update_pause(day + 8 * 3600000 + 60001).await);
+        assert_eq!(exchange.calls().len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_pause_file() {
+        let path = std::env::temp_dir().join(format!("rainmaker-pause-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({
+                "pause_file": path.to_str().unwrap(),
+                "pause_file_poll_ms": 1000
+            })),
+            exchange.clone(),
+        );
+
+        assert!(!strategy.update_pause(1000).await);
+        std::fs::write(&path, "").unwrap();
+        // not polled again yet
+        assert!(!strategy.update_pause(1500).await);
+        assert!(strategy.update_pause(2000).await);
+        assert!(strategy.update_pause(3000).await);
+        assert_eq!(exchange.calls().len(), 1);
+
+        std::fs::write(&path, "RESUME").unwrap();
+        assert!(!strategy.update_pause(4000).await);
+        std::fs::write(&path, "PAUSE\n").unwrap();
+        assert!(strategy.update_pause(5000).await);
+        assert_eq!(exchange.calls().len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!strategy.update_pause(6000).await);
+        assert!(!strategy.in_pause);
+    }
+
+    #[actix_rt::test]
+    async fn test_infer_tick_size() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(
+            config(json!({"tick_size": 0.0, "infer_tick_size": 5})),
+            exchange.clone(),
+        );
+        assert!(strategy.config.validate().is_ok());
+
+        for (i, (bid, ask)) in [
+            (50000.1, 50000.3),
+            (50000.1, 50000.3),
+            (50000.4, 50000.7),
+            (50000.2, 50000.3),
+            (49999.9, 50000.5),
+        ]
+        .iter()
+        .enumerate()
+        {
+            assert!(strategy.tick_samples.is_some());
+            strategy
+                .on_tick(book_ticker(*bid, *ask, 1637000000000 + i as u64 * 100))
+                .await
+                .unwrap();
+        }
+
+        assert!(strategy.tick_samples.is_none());
+        assert_eq!(strategy.tick_size, 0.1);
+        assert_eq!(strategy.tick_round, 1);
+        assert!(exchange.calls().is_empty());
+    }
+
+    #[test]
+    fn test_decision_record() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({
+            "log_decisions": true,
+            "post_fill_cooldown_ms": 5000
+        })));
+        strategy
+            .strategy_data
+            .push(book_ticker(49999., 50001., 1000));
+        strategy.last_sell_fill = 900;
+        let spread = Spread { ask: 10., bid: 10. };
+        let quote = strategy.build_quote(spread);
+        let decision = strategy.decision(1000, spread, &quote);
+
+        let record: serde_json::Value = serde_json::to_value(&decision).unwrap();
+        for field in [
+            "timestamp",
+            "wap",
+            "sigma",
+            "q",
+            "buy_a",
+            "buy_k",
+            "sell_a",
+            "sell_k",
+            "bid_offset",
+            "ask_offset",
+            "bid_price",
+            "bid_qty",
+            "ask_price",
+            "ask_qty",
+            "bid_placed",
+            "ask_placed",
+            "bid_skip",
+            "ask_skip",
+        ]
+        .iter()
+        {
+            assert!(record.get(field).is_some(), "{}", field);
+        }
+        assert_eq!(record["bid_placed"], json!(true));
+        assert_eq!(record["bid_skip"], json!(null));
+        assert_eq!(record["ask_placed"], json!(false));
+        assert_eq!(record["ask_skip"], json!("post_fill_cooldown"));
+        assert_eq!(decision.record().bid_price, quote.bid_price);
+    }
+
+    #[test]
+    fn test_apply_filters() {
+        let mut strategy = AvellanedaStoikov::new(config(json!({"tick_size": 0.01})));
+
+        strategy.apply_filters(&SymbolFilters {
+            tick_size: 0.001,
+            step_size: 0.1,
+            min_qty: 0.1,
+            min_notional: 5.,
+            multiplier_up: 1.05,
+            multiplier_down: 0.95,
+        });
+
+        assert_eq!(strategy.tick_size, 0.001);
+        assert_eq!(strategy.tick_round, 3);
+        assert_eq!(strategy.step_size, 0.1);
+        assert_eq!(strategy.min_notional, 5.);
+        assert_eq!(strategy.percent_price, Some((1.05, 0.95)));
+    }
+
+    #[actix_rt::test]
+    async fn test_percent_price_reprice() {
+        let exchange = MockExchange::new();
+        let mut strategy = AvellanedaStoikov::with_client(config(json!({})), exchange.clone());
+        strategy.percent_price = Some((1.05, 0.95));
+        strategy.strategy_data.push(book_ticker(49999., 50001., 1));
+
+        // band [47500, 52500] around the wap, the bid is too deep
+        let quote = strategy.build_quote(Spread {
+            ask: 10.,
+            bid: 3000.,
+        });
+        assert_eq!(
+            quote.band,
+            Some(PriceBand {
+                low: 47500.,
+                high: 52500.
+            })
+        );
+        exchange.fail_next_order("-4024 Limit price can't be lower than 47500.0.");
+        strategy.spawn_quote(quote, PositionSide::Both, PositionSide::Both);
+        strategy.shutdown().await;
+
+        let prices: Vec<_> = exchange
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::LimitBuy { price, .. } => Some(("buy", price)),
+                Call::LimitSell { price, .. } => Some(("sell", price)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            prices,
+            vec![("buy", 47000.), ("buy", 47500.), ("sell", 50010.)]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_strategy_tag() {
+        let order_id = |call: &Call| match call {
+            Call::LimitBuy {
+                client_order_id, ..
+            }
+            | Call::LimitSell {
+                client_order_id, ..
+            }
+            | Call::MarketBuy {
+                client_order_id, ..
+            }
+            | Call::MarketSell {
+                client_order_id, ..
+            } => Some(client_order_id.clone()),
+            _ => None,
+        };
+        let run = |tag: serde_json::Value| async move {
+            let exchange = MockExchange::new();
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "strategy_tag": tag
+                })),
+                exchange.clone(),
+            );
+            let rx = Scenario::new("BTCUSDT", get_timestamp().unwrap())
+                .ticks(50000., 50000.2, 100, 30)
+                .channel();
+            strategy.run_forever(rx).await.unwrap();
+            strategy.position.position_amount = 0.01;
+            strategy.flatten("test", StoplossOrderType::Market).await;
+            exchange
+                .calls()
+                .iter()
+                .filter_map(order_id)
+                .collect::<Vec<_>>()
+        };
+
+        let ids: Vec<String> = run(json!("mm1")).await.into_iter().flatten().collect();
+        assert!(ids.iter().any(|x| x.starts_with("mm1-b0-")));
+        assert!(ids.iter().any(|x| x.starts_with("mm1-s0-")));
+        // the flatten sells the long
+        assert!(ids.last().unwrap().starts_with("mm1-s0-"));
+        assert!(ids.iter().all(|x| x.len() <= 36));
+        let unique: HashSet<&String> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len());
+
+        // untagged, the exchange names the orders
+        let ids = run(json!(null)).await;
+        assert!(!ids.is_empty());
+        assert!(ids.iter().all(|x| x.is_none()));
+
+        let mut cfg = config(json!({}));
+        cfg.strategy_tag = Some("mm-1".into());
+        assert!(cfg.validate().is_err());
+        cfg.strategy_tag = Some("mm.1".into());
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_max_runtime() {
+        let run = |flatten: bool| async move {
+            let exchange = MockExchange::new();
+            let mut strategy = AvellanedaStoikov::with_client(
+                config(json!({
+                    "pin_filters": true,
+                    "estimate_window": 1000,
+                    "period": 1000,
+                    "max_runtime_ms": 1000,
+                    "max_runtime_flatten": flatten
+                })),
+                exchange.clone(),
+            );
+            // simulated clock, the exchange time is the tick time
+            let start = get_timestamp().unwrap();
+            strategy.start_time = start;
+            strategy.clock = util::ClockOffset::pinned(0);
+            let rx = Scenario::new("BTCUSDT", start)
+                .position(0.01, 50000.)
+                .ticks(50000., 50000.2, 100, 30)
+                .channel();
+            strategy.run_forever(rx).await.unwrap();
+            let last = *strategy.strategy_data.timestamp.back().unwrap();
+            (last - start, exchange.calls())
+        };
+
+        // stopped on the tick at the deadline, not at the end of the feed
+        let (elapsed, calls) = run(true).await;
+        assert_eq!(elapsed, 1000);
+        let n = calls.len();
+        assert_eq!(
+            calls[n - 2],
+            Call::CancelAll {
+                symbol: "BTCUSDT".into()
+            }
+        );
+        assert_eq!(
+            calls[n - 1],
+            Call::MarketSell {
+                symbol: "BTCUSDT".into(),
+                qty: 0.01,
+                client_order_id: None,
+                reduce_only: true,
+            }
+        );
+
+        // without the flatten the inventory is left alone
+        let (elapsed, calls) = run(false).await;
+        assert_eq!(elapsed, 1000);
+        assert!(!calls.iter().any(|x| matches!(x, Call::MarketSell { .. })));
+        assert!(matches!(calls.last(), Some(Call::CancelAll { .. })));
     }
 }
@@ -4,7 +4,7 @@ use crate::strategies::eie::{
     intensity_estimator::IntensityEstimator,
     intensity_info::IntensityInfo,
 };
-use crate::util;
+use crate::util::{self, RoundMode};
 
 use exrs::okex_v5::ws_model::OrderBookEvent;
 use exrs::okex_v5::{
@@ -508,12 +508,17 @@ impl AvellanedaStoikov {
                             }
                         }
 
-                        let mut sell_price = util::round_to(last_wap + spread.ask, tick_round);
+                        let mut sell_price =
+                            util::round_to_mode(last_wap + spread.ask, tick_round, RoundMode::Ceil);
                         if spread.ask < 0. {
                             sell_price = ask_price;
                         };
 
-                        let mut buy_price = util::round_to(last_wap - spread.bid, tick_round);
+                        let mut buy_price = util::round_to_mode(
+                            last_wap - spread.bid,
+                            tick_round,
+                            RoundMode::Floor,
+                        );
                         if spread.bid < 0. {
                             buy_price = bid_price;
                         };
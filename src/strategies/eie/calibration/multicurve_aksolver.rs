@@ -3,6 +3,7 @@ use log::debug;
 use super::traits::AbstractAkSolver;
 use std::time::Instant;
 
+#[derive(Clone)]
 pub struct MultiCurveAkSolver {
     pub spread_specification: Vec<f64>,
     pub a_estimates: Vec<f64>,
@@ -40,4 +41,8 @@ impl AbstractAkSolver for MultiCurveAkSolver {
         debug!("MultiCurveAkSolver time: {:?}", ins.elapsed());
         return (a_mean, k_mean);
     }
+
+    fn box_clone(&self) -> Box<dyn AbstractAkSolver> {
+        Box::new(self.clone())
+    }
 }
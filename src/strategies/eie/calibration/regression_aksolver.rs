@@ -3,6 +3,7 @@ use linreg::linear_regression_of;
 use log::debug;
 use std::time::Instant;
 
+#[derive(Clone)]
 pub struct RegressionAkSolver {
     pub last_valid_value: (f64, f64),
     pub spread_specification: Vec<f64>,
@@ -33,4 +34,8 @@ impl AbstractAkSolver for RegressionAkSolver {
         debug!("RegressionAkSolver time: {:?}", ins.elapsed());
         return (intercept.exp(), -slope);
     }
+
+    fn box_clone(&self) -> Box<dyn AbstractAkSolver> {
+        Box::new(self.clone())
+    }
 }
@@ -2,6 +2,7 @@ use super::aksolver_factory::AkSolverFactory;
 use super::empirical_intensity_estimator::EmpiricalIntensityEstimator;
 use super::traits::AbstractAkSolver;
 
+#[derive(Clone)]
 pub struct SpreadIntensityCurve {
     pub intensity_estimators: Vec<EmpiricalIntensityEstimator>,
     pub intensity_estimates: Vec<f64>,
@@ -51,4 +52,12 @@ impl SpreadIntensityCurve {
 
         return self.aksolver.solve_ak(&self.intensity_estimates);
     }
+
+    /// Spread buckets with fills in the last estimate.
+    pub fn nonzero_buckets(&self) -> usize {
+        self.intensity_estimates
+            .iter()
+            .filter(|x| **x > 0.0)
+            .count()
+    }
 }
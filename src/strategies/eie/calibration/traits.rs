@@ -12,6 +12,8 @@ pub trait AbstractAkSolver: Sync + Send {
 
     fn solve_ak(&mut self, intensities: &[f64]) -> (f64, f64);
 
+    fn box_clone(&self) -> Box<dyn AbstractAkSolver>;
+
     fn mean(&self, data: &[f64]) -> Option<f64> {
         let sum = data.iter().sum::<f64>();
         let count = data.len();
@@ -22,3 +24,9 @@ pub trait AbstractAkSolver: Sync + Send {
         }
     }
 }
+
+impl Clone for Box<dyn AbstractAkSolver> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
@@ -1 +1,45 @@
-pub struct EstimatorExecutor {}
+use super::{intensity_estimator::IntensityEstimator, intensity_info::IntensityInfo};
+use std::sync::{Arc, Mutex};
+
+/// A fit finished off the tick loop: when it was asked for, the A/k and the
+/// (buy, sell) spread buckets with fills it saw.
+pub type Fit = (u64, IntensityInfo, (usize, usize));
+
+/// Runs A/k fits on the blocking pool, one at a time, each over a copy of
+/// the estimator taken when it was submitted so ticks keep flowing into
+/// the original meanwhile.
+#[derive(Debug, Default)]
+pub struct EstimatorExecutor {
+    done: Arc<Mutex<Option<Fit>>>,
+    running: bool,
+}
+
+impl EstimatorExecutor {
+    /// Start a fit at `ts`, false if the last one is still running.
+    pub fn submit(&mut self, ie: &IntensityEstimator, ts: u64) -> bool {
+        if self.running {
+            return false;
+        }
+        self.running = true;
+        let mut ie = ie.clone();
+        let done = self.done.clone();
+        tokio::task::spawn_blocking(move || {
+            let ii = ie.estimate(ts);
+            *done.lock().unwrap() = Some((ts, ii, ie.nonzero_buckets()));
+        });
+        true
+    }
+
+    /// The fit finished since the last call, if any.
+    pub fn poll(&mut self) -> Option<Fit> {
+        let fit = self.done.lock().unwrap().take();
+        if fit.is_some() {
+            self.running = false;
+        }
+        fit
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+}
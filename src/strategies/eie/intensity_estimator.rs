@@ -5,6 +5,7 @@ use super::{
     intensity_info::IntensityInfo,
 };
 
+#[derive(Clone)]
 pub struct IntensityEstimator {
     sell_execution_intensity: SpreadIntensityCurve,
     buy_execution_intensity: SpreadIntensityCurve,
@@ -77,4 +78,12 @@ impl IntensityEstimator {
             self.sell_execution_intensity.estimate_ak(ts, window_start),
         );
     }
+
+    /// (buy, sell) spread buckets with fills in the last estimate.
+    pub fn nonzero_buckets(&self) -> (usize, usize) {
+        (
+            self.buy_execution_intensity.nonzero_buckets(),
+            self.sell_execution_intensity.nonzero_buckets(),
+        )
+    }
 }
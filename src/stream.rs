@@ -0,0 +1,61 @@
+use tokio::sync::mpsc;
+
+/// Merge the `sources` into one receiver, e.g. the book ticker and user data
+/// streams into the one the strategy consumes. Each source is forwarded in
+/// order by its own task, so the events of a stream keep their order; across
+/// streams they interleave as they arrive. A full merged channel makes the
+/// forwarders wait rather than drop, which backs up into the sources.
+pub fn merge<T: Send + 'static>(
+    sources: Vec<mpsc::Receiver<T>>,
+    capacity: usize,
+) -> mpsc::Receiver<T> {
+    let (tx, rx) = mpsc::channel(capacity);
+    for mut source in sources {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(event) = source.recv().await {
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_merge_keeps_stream_order() {
+        let (book_tx, book_rx) = mpsc::channel(4);
+        let (account_tx, account_rx) = mpsc::channel(4);
+        let (mark_tx, mark_rx) = mpsc::channel(4);
+        // smaller than what is sent, the senders have to wait for the reader
+        let mut rx = merge(vec![book_rx, account_rx, mark_rx], 2);
+
+        let feed = |tx: mpsc::Sender<(u8, u32)>, stream: u8| async move {
+            for i in 0..50 {
+                tx.send((stream, i)).await.unwrap();
+                if i % 7 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+        tokio::spawn(feed(book_tx, 0));
+        tokio::spawn(feed(account_tx, 1));
+        tokio::spawn(feed(mark_tx, 2));
+
+        let mut next = [0u32; 3];
+        let mut streams = Vec::new();
+        while let Some((stream, i)) = rx.recv().await {
+            assert_eq!(i, next[stream as usize], "stream {}", stream);
+            next[stream as usize] += 1;
+            streams.push(stream);
+        }
+        assert_eq!(next, [50, 50, 50]);
+        // interleaved, not one stream after the other
+        assert!(streams[..50].iter().any(|x| *x != streams[0]));
+    }
+}
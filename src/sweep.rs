@@ -0,0 +1,216 @@
+use crate::config::Config;
+use crate::exchange::mock::MockExchange;
+use crate::replay::{self, Report};
+use crate::strategies::avellaneda_stoikov::AvellanedaStoikov;
+
+use anyhow::{anyhow, Result};
+use exrs::binance_f::ws_model::BookTickerEvent;
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::thread;
+
+/// Report field a sweep is ranked by.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Metric {
+    Sharpe,
+    Pnl,
+    /// Smallest max drawdown first.
+    Drawdown,
+}
+
+impl Default for Metric {
+    fn default() -> Self {
+        Metric::Sharpe
+    }
+}
+
+impl Metric {
+    /// Score of a report, higher is better.
+    pub fn score(&self, report: &Report) -> f64 {
+        match self {
+            Metric::Sharpe => report.sharpe,
+            Metric::Pnl => report.pnl,
+            Metric::Drawdown => -report.max_drawdown,
+        }
+    }
+}
+
+/// Config overrides of one run and how it did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepResult {
+    pub params: Vec<(String, Value)>,
+    pub report: Report,
+}
+
+/// Every combination of the `axes` values, the last axis varying fastest.
+pub fn grid(axes: &[(String, Vec<Value>)]) -> Vec<Vec<(String, Value)>> {
+    axes.iter()
+        .fold(vec![Vec::new()], |combos, (name, values)| {
+            combos
+                .iter()
+                .flat_map(|combo| {
+                    values.iter().map(move |value| {
+                        let mut combo = combo.clone();
+                        combo.push((name.clone(), value.clone()));
+                        combo
+                    })
+                })
+                .collect()
+        })
+}
+
+fn run(
+    base: &Value,
+    params: &[(String, Value)],
+    ticks: Vec<Box<BookTickerEvent>>,
+) -> Result<Report> {
+    let mut merged = base.clone();
+    let fields = merged
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("base config must be an object"))?;
+    for (name, value) in params {
+        fields.insert(name.clone(), value.clone());
+    }
+    let config: Config = serde_json::from_value(merged)?;
+    config.validate()?;
+
+    actix_rt::System::new().block_on(async move {
+        let mut strategy = AvellanedaStoikov::with_client(config, MockExchange::new());
+        let quotes = strategy.replay(ticks.clone()).await;
+        Ok(replay::simulate(&ticks, &quotes))
+    })
+}
+
+/// Replay `ticks` under `base` overridden by every combination of `axes`,
+/// each run on a thread of its own when `parallel`. Best `metric` first.
+pub fn sweep(
+    base: &Value,
+    axes: &[(String, Vec<Value>)],
+    ticks: &[Box<BookTickerEvent>],
+    metric: Metric,
+    parallel: bool,
+) -> Result<Vec<SweepResult>> {
+    let combos = grid(axes);
+    let reports: Vec<Result<Report>> = if parallel {
+        let handles: Vec<_> = combos
+            .iter()
+            .map(|params| {
+                let base = base.clone();
+                let params = params.clone();
+                let ticks = ticks.to_vec();
+                thread::spawn(move || run(&base, &params, ticks))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|x| {
+                x.join()
+                    .unwrap_or_else(|_| Err(anyhow!("sweep run panicked")))
+            })
+            .collect()
+    } else {
+        combos
+            .iter()
+            .map(|params| run(base, params, ticks.to_vec()))
+            .collect()
+    };
+
+    let mut results = Vec::with_capacity(combos.len());
+    for (params, report) in combos.into_iter().zip(reports) {
+        let report = report.map_err(|e| anyhow!("{:?}: {}", params, e))?;
+        results.push(SweepResult {
+            params: params,
+            report: report,
+        });
+    }
+    // NaN scores rank last
+    let score = |x: &SweepResult| match metric.score(&x.report) {
+        s if s.is_nan() => f64::NEG_INFINITY,
+        s => s,
+    };
+    results.sort_by(|a, b| score(b).partial_cmp(&score(a)).unwrap_or(Ordering::Equal));
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exchange::mock::Scenario;
+    use exrs::binance_f::ws_model::FuturesWebsocketEvent;
+    use serde_json::json;
+
+    #[test]
+    fn test_grid() {
+        let axes = vec![
+            ("gamma".to_string(), vec![json!(0.1), json!(0.2)]),
+            (
+                "period".to_string(),
+                vec![json!(1000), json!(2000), json!(3000)],
+            ),
+        ];
+        let combos = grid(&axes);
+        assert_eq!(combos.len(), 6);
+        assert_eq!(
+            combos[1],
+            vec![
+                ("gamma".to_string(), json!(0.1)),
+                ("period".to_string(), json!(2000))
+            ]
+        );
+        assert_eq!(grid(&[]), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn test_sweep_gamma() {
+        let base = json!({
+            "api_key": null,
+            "secret_key": null,
+            "base_asset": "BTC",
+            "quote_asset": "USDT",
+            "order_qty": 0.001,
+            "tick_size": 0.1,
+            "n_spreads": 10,
+            "estimate_window": 1000,
+            "period": 1000,
+            "sigma_tick_period": 100,
+            "gamma": 0.2,
+            "sigma_multiplier": 1.0,
+            "stoploss": 0.01,
+            "stoploss_sleep": 300000,
+            "stopprofit": 0.01,
+            "trailing_stop": 0.005,
+            "q_max": 30.0
+        });
+        let mut scenario = Scenario::new("BTCUSDT", 1637000000000);
+        for i in 0..40 {
+            let bid = 50000. + (i % 3) as f64 * 0.1;
+            scenario = scenario.book_ticker(bid, bid + 0.2).wait(100);
+        }
+        let ticks: Vec<Box<BookTickerEvent>> = scenario
+            .events()
+            .into_iter()
+            .filter_map(|x| match x {
+                FuturesWebsocketEvent::BookTicker(x) => Some(x),
+                _ => None,
+            })
+            .collect();
+
+        let axes = vec![("gamma".to_string(), vec![json!(0.1), json!(0.5)])];
+        let results = sweep(&base, &axes, &ticks, Metric::Sharpe, true).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|x| x.report.quotes > 0));
+        assert!(results[0].report.sharpe >= results[1].report.sharpe);
+
+        let mut gammas: Vec<f64> = results
+            .iter()
+            .map(|x| x.params[0].1.as_f64().unwrap())
+            .collect();
+        gammas.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(gammas, vec![0.1, 0.5]);
+
+        // a combination failing validation fails the sweep
+        let axes = vec![("gamma".to_string(), vec![json!(-1.0)])];
+        assert!(sweep(&base, &axes, &ticks, Metric::Sharpe, false).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use exrs::binance_f::userstream::FuturesUserStream;
+use log::{info, warn};
+
+/// The listenKey calls of the user data stream.
+#[async_trait]
+pub trait ListenKeyClient: Send + Sync {
+    /// Open a user data stream, returning its listenKey.
+    async fn start(&self) -> Result<String>;
+
+    async fn keep_alive(&self, listen_key: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl ListenKeyClient for FuturesUserStream {
+    async fn start(&self) -> Result<String> {
+        let answer = FuturesUserStream::start(self).await?;
+        Ok(answer.listen_key)
+    }
+
+    async fn keep_alive(&self, listen_key: &str) -> Result<()> {
+        FuturesUserStream::keep_alive(self, listen_key).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeepAlive {
+    /// The listenKey was extended.
+    Alive,
+    /// The listenKey had expired, the stream has to resubscribe with this
+    /// one.
+    Renewed(String),
+    /// Neither extended nor renewed, account events are not coming in.
+    Failed,
+}
+
+/// Extend `listen_key`, opening a new stream when it is rejected, e.g. -1125
+/// once it expired.
+pub async fn keep_alive(client: &dyn ListenKeyClient, listen_key: &str) -> KeepAlive {
+    let err = match client.keep_alive(listen_key).await {
+        Ok(()) => return KeepAlive::Alive,
+        Err(err) => err,
+    };
+    warn!("keep_alive Error: {}, renew listen key", err);
+    match client.start().await {
+        Ok(listen_key) => {
+            info!("listen key renewed");
+            KeepAlive::Renewed(listen_key)
+        }
+        Err(err) => {
+            warn!("renew listen key Error: {}", err);
+            KeepAlive::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockUserStream {
+        keys: Mutex<VecDeque<Result<String>>>,
+        keep_alives: Mutex<Vec<String>>,
+        live: Mutex<Option<String>>,
+    }
+
+    #[async_trait]
+    impl ListenKeyClient for MockUserStream {
+        async fn start(&self) -> Result<String> {
+            let key = self
+                .keys
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Err(anyhow!("no key")))?;
+            *self.live.lock().unwrap() = Some(key.clone());
+            Ok(key)
+        }
+
+        async fn keep_alive(&self, listen_key: &str) -> Result<()> {
+            self.keep_alives
+                .lock()
+                .unwrap()
+                .push(listen_key.to_string());
+            match self.live.lock().unwrap().as_deref() {
+                Some(live) if live == listen_key => Ok(()),
+                _ => Err(anyhow!("code: -1125, msg: This listenKey does not exist.")),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_keep_alive_renews_expired_key() {
+        let client = MockUserStream::default();
+        client.keys.lock().unwrap().push_back(Ok("first".into()));
+        client.keys.lock().unwrap().push_back(Ok("second".into()));
+        let key = client.start().await.unwrap();
+
+        assert_eq!(keep_alive(&client, &key).await, KeepAlive::Alive);
+
+        // expired server side
+        *client.live.lock().unwrap() = None;
+        assert_eq!(
+            keep_alive(&client, &key).await,
+            KeepAlive::Renewed("second".into())
+        );
+        assert_eq!(keep_alive(&client, "second").await, KeepAlive::Alive);
+        assert_eq!(
+            *client.keep_alives.lock().unwrap(),
+            vec!["first", "first", "second"]
+        );
+
+        // and no new one to be had
+        *client.live.lock().unwrap() = None;
+        assert_eq!(keep_alive(&client, "second").await, KeepAlive::Failed);
+    }
+}
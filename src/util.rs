@@ -1,5 +1,255 @@
+/// Direction `round_to_mode` moves a value onto the decimal grid.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RoundMode {
+    Nearest,
+    Floor,
+    Ceil,
+}
+
+/// Round `v` to `len` decimals. Floor and ceil tolerate float noise sitting
+/// just past a grid point, e.g. 0.1 + 0.2 ceils to 0.3, not 0.4.
+pub fn round_to_mode(v: f64, len: u32, mode: RoundMode) -> f64 {
+    let scale = 10i32.pow(len) as f64;
+    let scaled = v * scale;
+    let rounded = match mode {
+        RoundMode::Nearest => scaled.round(),
+        RoundMode::Floor => (scaled + 1e-9).floor(),
+        RoundMode::Ceil => (scaled - 1e-9).ceil(),
+    };
+    rounded / scale
+}
+
 pub fn round_to(v: f64, len: u32) -> f64 {
-    (v * 10i32.pow(len) as f64).floor() / 10i32.pow(len) as f64
+    round_to_mode(v, len, RoundMode::Nearest)
+}
+
+/// Number of decimals in the shortest representation of `v`, e.g. 0.001 -> 3.
+pub fn decimals(v: f64) -> u32 {
+    let s = v.to_string();
+    let v: Vec<&str> = s.split(".").collect();
+    if v.len() == 1 {
+        return 0;
+    }
+    v[1].len() as u32
+}
+
+/// Floor `v` to a multiple of `step`, tolerating float noise just below the
+/// next step. A zero step leaves `v` unchanged.
+pub fn floor_to_step(v: f64, step: f64) -> f64 {
+    if step <= 0f64 {
+        return v;
+    }
+    round_to((v / step + 1e-9).floor() * step, decimals(step))
+}
+
+/// Fires at most once per `period` ms of the clock it is fed.
+#[derive(Debug, Clone, Copy)]
+pub struct Cadence {
+    pub period: u64,
+    last: Option<u64>,
+}
+
+impl Cadence {
+    pub fn new(period: u64) -> Self {
+        Cadence {
+            period: period,
+            last: None,
+        }
+    }
+
+    /// True on the first call and then once `period` has passed since the
+    /// last time it fired.
+    pub fn fire(&mut self, now: u64) -> bool {
+        match self.last {
+            Some(last) if now < last + self.period => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Threshold with a hysteresis band: breached while armed and past
+/// `threshold + band`, and once disarmed re-armed only by falling back under
+/// `threshold - band`.
+#[derive(Debug, Clone, Copy)]
+pub struct Latch {
+    pub band: f64,
+    armed: bool,
+}
+
+impl Latch {
+    pub fn new(band: f64) -> Self {
+        Latch {
+            band: band,
+            armed: true,
+        }
+    }
+
+    pub fn breached(&self, value: f64, threshold: f64) -> bool {
+        self.armed && value > threshold + self.band
+    }
+
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+
+    pub fn update(&mut self, value: f64, threshold: f64) {
+        if !self.armed && value < threshold - self.band {
+            self.armed = true;
+        }
+    }
+}
+
+/// Exponential moving average over `span` samples, alpha `2 / (span + 1)`.
+/// A sample further than `snap` (relative) from the average resets it, so
+/// large moves are followed at once.
+#[derive(Debug, Clone, Copy)]
+pub struct Ema {
+    alpha: f64,
+    snap: Option<f64>,
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn new(span: usize, snap: Option<f64>) -> Self {
+        Ema {
+            alpha: 2f64 / (span as f64 + 1f64),
+            snap: snap,
+            value: None,
+        }
+    }
+
+    pub fn update(&mut self, x: f64) -> f64 {
+        let value = match self.value {
+            Some(value)
+                if self
+                    .snap
+                    .is_none_or(|snap| (x / value - 1f64).abs() <= snap) =>
+            {
+                value + self.alpha * (x - value)
+            }
+            _ => x,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Offset (ms) of the exchange clock over the local one, smoothed from the
+/// event times seen against the local time they arrived at. Pinned when
+/// the events are not live, e.g. in a replay.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockOffset {
+    offset: Option<i64>,
+    pinned: bool,
+}
+
+impl ClockOffset {
+    pub fn pinned(offset: i64) -> Self {
+        ClockOffset {
+            offset: Some(offset),
+            pinned: true,
+        }
+    }
+
+    pub fn observe(&mut self, exchange_ms: u64, local_ms: u64) {
+        if self.pinned {
+            return;
+        }
+        let sample = exchange_ms as i64 - local_ms as i64;
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (sample - offset) / 8,
+            None => sample,
+        });
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0)
+    }
+
+    /// A local time on the exchange clock.
+    pub fn to_exchange(&self, local_ms: u64) -> u64 {
+        (local_ms as i64 + self.offset()).max(0) as u64
+    }
+}
+
+/// Seedable delay in [0, max_ms], splitmix64, so instances seeded apart
+/// spread their requests and a fixed seed replays the same delays.
+#[derive(Debug, Clone, Copy)]
+pub struct Jitter {
+    state: u64,
+    pub max_ms: u64,
+}
+
+impl Jitter {
+    pub fn new(seed: u64, max_ms: u64) -> Self {
+        Jitter {
+            state: seed,
+            max_ms: max_ms,
+        }
+    }
+
+    fn mix(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.mix() % (self.max_ms + 1)
+    }
+
+    /// Uniform in [0, 1), from the same stream.
+    pub fn uniform(&mut self) -> f64 {
+        (self.mix() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub const DAY_MS: u64 = 86_400_000;
+/// Binance futures fund every 8h from 00:00 UTC.
+pub const FUNDING_INTERVAL_MS: u64 = 8 * 3_600_000;
+
+/// Start (ms) of the daily session containing `ts`, sessions rolling over at
+/// `offset` ms past UTC midnight.
+pub fn session_start(ts: u64, offset: u64) -> u64 {
+    let offset = offset % DAY_MS;
+    (ts + DAY_MS - offset) / DAY_MS * DAY_MS + offset - DAY_MS
+}
+
+/// Scale a per-tick volatility to `days` of ticks `tick_interval_ms` apart,
+/// square root of time.
+pub fn annualize(sigma: f64, tick_interval_ms: f64, days: f64) -> f64 {
+    sigma * (days * DAY_MS as f64 / tick_interval_ms).sqrt()
+}
+
+/// Finest grid the `prices` lie on: the GCD of their differences, at 1e-8
+/// resolution. None until two distinct prices were seen.
+pub fn price_grid(prices: &[f64]) -> Option<f64> {
+    fn gcd(a: i64, b: i64) -> i64 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    let scale = 1e8;
+    let mut ticks: Vec<i64> = prices.iter().map(|x| (x * scale).round() as i64).collect();
+    ticks.sort_unstable();
+    ticks.dedup();
+    let grid = ticks.windows(2).fold(0, |acc, x| gcd(acc, x[1] - x[0]));
+    if grid == 0 {
+        return None;
+    }
+    Some(round_to(grid as f64 / scale, 8))
 }
 
 pub fn round_same(a: f64, b: f64) -> f64 {
@@ -23,4 +273,148 @@ mod test {
         let ret = round_same(a, b);
         println!("ret: {:?}", ret);
     }
+
+    #[test]
+    fn test_round_to_mode() {
+        assert_eq!(round_to_mode(1.25, 1, RoundMode::Nearest), 1.3);
+        assert_eq!(round_to_mode(1.24, 1, RoundMode::Nearest), 1.2);
+        assert_eq!(round_to_mode(2.675, 2, RoundMode::Nearest), 2.68);
+        assert_eq!(round_to(1.26, 1), 1.3);
+
+        assert_eq!(round_to_mode(1.29, 1, RoundMode::Floor), 1.2);
+        assert_eq!(round_to_mode(-1.21, 1, RoundMode::Floor), -1.3);
+        assert_eq!(round_to_mode(1.0 - 0.9, 1, RoundMode::Floor), 0.1);
+        assert_eq!(round_to_mode(50010., 1, RoundMode::Floor), 50010.);
+
+        assert_eq!(round_to_mode(1.21, 1, RoundMode::Ceil), 1.3);
+        assert_eq!(round_to_mode(-1.29, 1, RoundMode::Ceil), -1.2);
+        assert_eq!(round_to_mode(0.1 + 0.2, 1, RoundMode::Ceil), 0.3);
+        assert_eq!(round_to_mode(50010., 1, RoundMode::Ceil), 50010.);
+    }
+
+    #[test]
+    fn test_price_grid() {
+        assert_eq!(price_grid(&[50000.1, 50000.4, 50001.2]), Some(0.1));
+        assert_eq!(price_grid(&[1.2345, 1.2346, 1.2349]), Some(0.0001));
+        assert_eq!(price_grid(&[100., 105., 115.]), Some(5.));
+        assert_eq!(price_grid(&[100., 100.]), None);
+        assert_eq!(price_grid(&[]), None);
+    }
+
+    #[test]
+    fn test_floor_to_step() {
+        assert_eq!(floor_to_step(0.0129, 0.001), 0.012);
+        assert_eq!(floor_to_step(0.003, 0.001), 0.003);
+        assert_eq!(floor_to_step(0.0009, 0.001), 0.);
+        assert_eq!(floor_to_step(7., 5.), 5.);
+        assert_eq!(floor_to_step(0.0129, 0.), 0.0129);
+    }
+
+    #[test]
+    fn test_latch() {
+        let mut latch = Latch::new(0.1);
+        let mut fired = 0;
+        // oscillating right at the threshold of 1
+        for value in [0.95, 1.05, 1.15, 0.98, 1.2, 0.95, 1.12].iter() {
+            latch.update(*value, 1.);
+            if latch.breached(*value, 1.) {
+                latch.disarm();
+                fired += 1;
+            }
+        }
+        assert_eq!(fired, 1);
+
+        // back under 0.9 re-arms
+        latch.update(0.85, 1.);
+        assert!(latch.breached(1.15, 1.));
+        assert!(!Latch::new(0.).breached(1., 1.));
+    }
+
+    #[test]
+    fn test_ema() {
+        let mut ema = Ema::new(3, Some(0.01));
+        assert_eq!(ema.value(), None);
+        assert_eq!(ema.update(100.), 100.);
+        assert_eq!(ema.update(102.), 101.);
+        assert_eq!(ema.update(100.), 100.5);
+        // a 2% jump is past the snap, taken as is
+        assert_eq!(ema.update(102.5), 102.5);
+
+        let mut ema = Ema::new(1, None);
+        ema.update(100.);
+        assert_eq!(ema.update(200.), 200.);
+    }
+
+    #[test]
+    fn test_clock_offset() {
+        let mut clock = ClockOffset::default();
+        assert_eq!(clock.to_exchange(1000), 1000);
+
+        // exchange 5s ahead, give or take the transit
+        clock.observe(1_005_000, 1_000_000);
+        clock.observe(1_005_800, 1_001_000);
+        assert_eq!(clock.offset(), 4975);
+        assert_eq!(clock.to_exchange(2_000_000), 2_004_975);
+
+        let mut clock = ClockOffset::pinned(0);
+        clock.observe(1_005_000, 1_000_000);
+        assert_eq!(clock.to_exchange(1000), 1000);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut a = Jitter::new(42, 500);
+        let mut b = Jitter::new(42, 500);
+        let delays: Vec<u64> = (0..100).map(|_| a.next()).collect();
+        assert_eq!(delays, (0..100).map(|_| b.next()).collect::<Vec<u64>>());
+        assert!(delays.iter().all(|x| *x <= 500));
+        assert!(delays.iter().any(|x| *x != delays[0]));
+
+        let mut c = Jitter::new(43, 500);
+        assert_ne!(delays, (0..100).map(|_| c.next()).collect::<Vec<u64>>());
+        assert_eq!(Jitter::new(42, 0).next(), 0);
+
+        let mut d = Jitter::new(42, 0);
+        let draws: Vec<f64> = (0..100).map(|_| d.uniform()).collect();
+        assert!(draws.iter().all(|x| (0.0..1.0).contains(x)));
+        assert!(draws.iter().any(|x| *x < 0.5) && draws.iter().any(|x| *x >= 0.5));
+    }
+
+    #[test]
+    fn test_cadence() {
+        let mut estimate = Cadence::new(300);
+        let mut sigma = Cadence::new(700);
+        let (mut estimates, mut sigmas) = (0, 0);
+        for now in (0..3000).step_by(100) {
+            estimates += estimate.fire(now) as u32;
+            sigmas += sigma.fire(now) as u32;
+        }
+        assert_eq!(estimates, 10);
+        assert_eq!(sigmas, 5);
+    }
+
+    #[test]
+    fn test_annualize() {
+        // 100ms ticks, 315_360_000 a year
+        let annual = annualize(1e-4, 100., 365.);
+        assert!((annual - 1e-4 * 315_360_000f64.sqrt()).abs() < 1e-12);
+        assert!((annual - 1.7758).abs() < 1e-4);
+        // 4x the interval halves it
+        assert!((annualize(1e-4, 400., 365.) - annual / 2.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_session_start() {
+        let day = 1637020800000; // 2021-11-16T00:00:00Z
+        assert_eq!(session_start(day, 0), day);
+        assert_eq!(session_start(day + 1, 0), day);
+        assert_eq!(session_start(day - 1, 0), day - DAY_MS);
+
+        let offset = 8 * 3_600_000;
+        assert_eq!(
+            session_start(day + offset - 1, offset),
+            day + offset - DAY_MS
+        );
+        assert_eq!(session_start(day + offset, offset), day + offset);
+    }
 }